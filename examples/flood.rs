@@ -0,0 +1,89 @@
+//! Loopback flood benchmark
+//! Keeps a window of queries in flight from several client threads for a few seconds
+//! and reports how many responses per second came back
+//!
+//! cargo run --release -- --resolver <ip:port> [--batch]
+//! cargo run --release --example flood -- [server ip:port] [seconds] [threads] [window]
+
+use std::net::{ SocketAddr, UdpSocket };
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+use std::thread;
+use std::time::{ Duration, Instant };
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let server: SocketAddr = args.get(1).map_or("127.0.0.1:2053", |x| x.as_str()).parse().expect("Invalid server address");
+    let seconds: u64 = args.get(2).map_or(5, |x| x.parse().expect("Invalid number of seconds"));
+    let threads: usize = args.get(3).map_or(4, |x| x.parse().expect("Invalid number of threads"));
+    let window: usize = args.get(4).map_or(32, |x| x.parse().expect("Invalid window size"));
+
+    let answered = Arc::new(AtomicUsize::new(0));
+    let lost = Arc::new(AtomicUsize::new(0));
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let clients: Vec<_> = (0..threads).map(|_| {
+        let answered = answered.clone();
+        let lost = lost.clone();
+        let stop = stop.clone();
+
+        thread::spawn(move || flood(server, window, &answered, &lost, &stop))
+    }).collect();
+
+    let start = Instant::now();
+    thread::sleep(Duration::from_secs(seconds));
+    stop.store(true, Ordering::Relaxed);
+    let elapsed = start.elapsed();
+
+    for client in clients {
+        client.join().expect("Client thread panicked");
+    }
+
+    let answered = answered.load(Ordering::Relaxed);
+    println!("{} responses in {:.2?}, {:.0} queries/s, {} timed out",
+        answered, elapsed, answered as f64 / elapsed.as_secs_f64(), lost.load(Ordering::Relaxed));
+}
+
+/// Send queries from one socket, topping the window back up as responses arrive
+fn flood(server: SocketAddr, window: usize, answered: &AtomicUsize, lost: &AtomicUsize, stop: &AtomicBool) {
+    let local: SocketAddr = if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse().unwrap();
+    let udp_socket = UdpSocket::bind(local).expect("Failed to bind client socket");
+    udp_socket.connect(server).expect("Failed to connect client socket");
+    udp_socket.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+
+    let mut id: u16 = 0;
+    let mut buf = [0; 512];
+
+    for _ in 0..window {
+        send_query(&udp_socket, &mut id);
+    }
+
+    while !stop.load(Ordering::Relaxed) {
+        match udp_socket.recv(&mut buf) {
+            Ok(_) => {
+                answered.fetch_add(1, Ordering::Relaxed);
+                send_query(&udp_socket, &mut id);
+            }
+            Err(_) => {
+                // assume the whole window was dropped and start it over
+                lost.fetch_add(window, Ordering::Relaxed);
+                for _ in 0..window {
+                    send_query(&udp_socket, &mut id);
+                }
+            }
+        }
+    }
+}
+
+/// Send an A query for example.com with the next id
+fn send_query(udp_socket: &UdpSocket, id: &mut u16) {
+    *id = id.wrapping_add(1);
+
+    let mut query = Vec::with_capacity(29);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    query.extend_from_slice(b"\x07example\x03com\x00");
+    query.extend_from_slice(&[0, 1, 0, 1]);
+
+    let _ = udp_socket.send(&query);
+}
@@ -0,0 +1,35 @@
+//! Who may use the server as a resolver, so it isn't an open one for anyone who can reach the socket
+//! Clients in the --allow-recursion networks, 127.0.0.0/8 and ::1 unless it's given, get every answer,
+//! others only what's answered from the server itself: local zones, the hosts file, blocked, special-use and CHAOS names
+//! Their questions that would go to the cache, an upstream or the root servers get REFUSED with the question and id echoed
+
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr };
+use std::sync::OnceLock;
+
+use crate::xfr::Network;
+
+static ALLOWED: OnceLock<Vec<Network>> = OnceLock::new();
+
+/// Set the clients that may have names resolved past the server's own answers, only the first call has any effect
+pub fn set_allowed(networks: Vec<Network>) {
+    let _ = ALLOWED.set(networks);
+}
+
+/// Networks that may have names resolved, the loopback addresses unless --allow-recursion is given
+pub fn allowed() -> &'static [Network] {
+    ALLOWED.get_or_init(|| vec![
+        Network { addr: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 0)), prefix_len: 8 },
+        Network { addr: IpAddr::V6(Ipv6Addr::LOCALHOST), prefix_len: 128 },
+    ])
+}
+
+/// Whether a client may have names resolved past the server's own answers
+pub fn is_allowed(client: IpAddr) -> bool {
+    // an IPv4 client on a dual stack socket shows up as ::ffff:a.b.c.d
+    let client = match client {
+        IpAddr::V6(x) => x.to_ipv4_mapped().map_or(client, IpAddr::V4),
+        x => x,
+    };
+
+    allowed().iter().any(|x| x.contains(client))
+}
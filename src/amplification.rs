@@ -0,0 +1,44 @@
+//! Amplification guard for UDP, so a spoofed query can't get back a response many times its size
+//! With --max-amplification <factor> a UDP response more than factor times the size of its query is cut down to its header
+//! and question with TC set, so the client has to retry over TCP, where its address can't be spoofed
+//! Clients that sent a valid server cookie have proven their address and always get the whole response,
+//! and nothing sent over TCP, DoT, DoH or DoQ is touched
+
+use std::net::IpAddr;
+use std::sync::OnceLock;
+
+use crate::cookies::{ self, Cookie };
+use crate::rrl;
+use crate::stats;
+
+static FACTOR: OnceLock<f64> = OnceLock::new();
+
+/// Truncate UDP responses more than factor times the size of their query from now on, only the first call has any effect
+pub fn set_factor(factor: f64) {
+    let _ = FACTOR.set(factor);
+}
+
+/// How much of a UDP response to a query to send, all of it or just its header and question with TC set
+/// The response is truncated in place when it's too large for the query and the client has no valid cookie
+pub fn limit(query: &[u8], client: IpAddr, res: &mut [u8]) -> usize {
+    if FACTOR.get().is_none() {
+        return res.len();
+    }
+
+    let verified = matches!(cookies::check(query, client), Cookie::VALID(_));
+    limit_sized(query.len(), verified, res)
+}
+
+/// How much of a UDP response to a query of query_len bytes to send, verified if the client sent a valid server cookie
+pub fn limit_sized(query_len: usize, verified: bool, res: &mut [u8]) -> usize {
+    let factor = match FACTOR.get() {
+        Some(x) => *x,
+        None => return res.len(),
+    };
+    if verified || res.len() < 12 || res.len() as f64 <= query_len as f64 * factor {
+        return res.len();
+    }
+
+    stats::count_tc_forced();
+    rrl::slip(res)
+}
@@ -0,0 +1,467 @@
+//! Async serving on a tokio runtime
+//! UDP and TCP queries are tasks rather than threads and forwarded lookups wait on the runtime,
+//! so thousands of in-flight queries only cost memory
+//! Recursive resolution and raced lookups still run on the blocking pool
+//! Only built with the "async" feature; run with --sync to use the threaded server instead
+
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr };
+use std::sync::Arc;
+use std::time::{ Duration, Instant };
+
+use log::{ debug, error, trace, warn };
+use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+use tokio::net::{ TcpListener, TcpStream, UdpSocket };
+
+use crate::acl;
+use crate::amplification;
+use crate::cache;
+use crate::dns64;
+use crate::error::DnsError;
+use crate::forwarding;
+use crate::idna;
+use crate::packet::{ DnsPacket, DnsQuestion, QueryType, ResCode, Opcode };
+use crate::query_log::{ self, Source };
+use crate::resolve::{ self, Resolution };
+use crate::rrl;
+use crate::socks5;
+use crate::stats;
+use crate::transport::{ self, Transport };
+use crate::upstreams::{ Failure, Protocol, Strategy };
+use crate::xfr;
+
+// tasks move between threads so errors need to be Send
+type Result<T> = std::result::Result<T, DnsError>;
+
+// Largest response sent over UDP, anything bigger is truncated with TC set
+const UDP_MAX_SIZE: usize = 512;
+
+// Connections with no new query for this long are closed
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serve already bound UDP sockets and TCP listeners until the process exits
+/// Binding happens up front with std sockets so bind errors are reported the same way in both modes
+pub fn run(udp_sockets: Vec<std::net::UdpSocket>, tcp_listeners: Vec<std::net::TcpListener>, resolution: Resolution) -> Result<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+
+    runtime.block_on(async move {
+        for tcp_listener in tcp_listeners {
+            tcp_listener.set_nonblocking(true)?;
+            let tcp_listener = TcpListener::from_std(tcp_listener)?;
+            tokio::spawn(serve_tcp(tcp_listener, resolution));
+        }
+
+        let mut receivers = Vec::new();
+        for udp_socket in udp_sockets {
+            udp_socket.set_nonblocking(true)?;
+            let udp_socket = Arc::new(UdpSocket::from_std(udp_socket)?);
+            receivers.push(tokio::spawn(serve_udp(udp_socket, resolution)));
+        }
+
+        for receiver in receivers {
+            receiver.await?;
+        }
+
+        Ok(())
+    })
+}
+
+/// Receive packets on a socket forever, answering each in its own task
+async fn serve_udp(udp_socket: Arc<UdpSocket>, resolution: Resolution) {
+    loop {
+        let mut req = vec![0; UDP_MAX_SIZE];
+
+        let (size, source) = match udp_socket.recv_from(&mut req).await {
+            Ok(x) => x,
+            Err(e) => {
+                error!("An error occurred: {}", e);
+                continue;
+            }
+        };
+        req.truncate(size);
+
+        trace!("Received {} bytes from {}", size, source);
+
+        let udp_socket = udp_socket.clone();
+        tokio::spawn(async move {
+            let sent = match handle_query_bytes(&req, UDP_MAX_SIZE, source.ip(), &resolution, Transport::UDP).await {
+                Ok(mut res) => match rrl::limit(source.ip(), &mut res) {
+                    Some(len) => {
+                        let len = amplification::limit(&req, source.ip(), &mut res[..len]);
+                        udp_socket.send_to(&res[..len], source).await.map(|_| ()).map_err(DnsError::from)
+                    }
+                    None => Ok(()),
+                },
+                Err(e) => Err(e),
+            };
+
+            if let Err(e) = sent {
+                error!("An error occurred: {}", e);
+            }
+        });
+    }
+}
+
+/// Accept DNS over TCP connections, serving each in its own task
+async fn serve_tcp(listener: TcpListener, resolution: Resolution) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                tokio::spawn(async move {
+                    if let Err(e) = handle_tcp_connection(stream, peer, resolution).await {
+                        error!("An error occurred on a TCP connection: {}", e);
+                    }
+                });
+            }
+            Err(e) => error!("Failed to accept TCP connection: {}", e),
+        }
+    }
+}
+
+/// Answer length prefixed queries on a connection until the client closes it or goes idle
+async fn handle_tcp_connection(mut stream: TcpStream, peer: SocketAddr, resolution: Resolution) -> Result<()> {
+    loop {
+        // the client closing the connection or going idle between messages ends it quietly
+        let len = match tokio::time::timeout(TCP_IDLE_TIMEOUT, stream.read_u16()).await {
+            Ok(Ok(x)) => x as usize,
+            Ok(Err(_)) | Err(_) => return Ok(()),
+        };
+        if len == 0 {
+            return Err(DnsError::EmptyMessage);
+        }
+
+        let mut req = vec![0; len];
+        stream.read_exact(&mut req).await?;
+
+        trace!("Received {} bytes over TCP from {}", len, peer);
+
+        // zone transfers take a run of messages, anything else gets one
+        let messages = match xfr::answer(&req, peer.ip()) {
+            Some(x) => x?,
+            None => vec![handle_query_bytes(&req, transport::MAX_TCP_MESSAGE, peer.ip(), &resolution, Transport::TCP).await?],
+        };
+        for res in messages {
+            let framed = transport::frame_message(&res)?;
+            stream.write_all(&framed).await?;
+        }
+    }
+}
+
+/// Handle a query from a client given as raw bytes, returning a response of at most max_size bytes
+/// Only forwarded lookups are async, everything else runs through the blocking handler
+pub async fn handle_query_bytes(req: &[u8], max_size: usize, client: IpAddr, resolution: &Resolution, transport: Transport) -> Result<Vec<u8>> {
+    let default = match resolution {
+        Resolution::Forward(x) if x.strategy() != Strategy::RACE && socks5::proxy().is_none() => *x,
+        // races and the SOCKS5 proxy need the blocking handler's sockets,
+        // and names under a forwarding rule are still forwarded there
+        _ => return handle_blocking(req, max_size, client, resolution, transport).await,
+    };
+
+    let request = match DnsPacket::from_bytes(req) {
+        Ok(x) if x.header.opcode == Opcode::QUERY && !x.questions.is_empty() && x.questions.iter().all(|x| !resolve::is_local(x)) && acl::is_allowed(client) => x,
+        // malformed and unsupported requests, names in local zones, queries mixing them with names to forward
+        // and clients that may not have names resolved are answered by the blocking handler, off the runtime's threads as it may forward
+        _ => return handle_blocking(req, max_size, client, resolution, transport).await,
+    };
+
+    let started = Instant::now();
+    let mut response = DnsPacket::response_to(&request);
+    // noted for the query log just before it's written, the task may move between threads until then
+    let mut source = (Source::CACHE, None);
+
+    for ques in request.questions {
+        let upstreams = forwarding::find(&ques.name).map_or(default, |x| &x.upstreams);
+
+        if let Some(cached) = cache::lookup(&ques.name, ques.q_type, move |name, q_type| resolve::refresh(name, q_type, Some(upstreams))) {
+            debug!("Received query: {} {}, answered from the cache", idna::to_unicode(&ques.name), ques.q_type);
+            if cached.header.res_code != ResCode::NO_ERR {
+                response.header.res_code = cached.header.res_code;
+            }
+            response.answers.extend(cached.answers);
+            response.authorities.extend(cached.authorities);
+            response.questions.push(ques);
+
+            continue;
+        }
+
+        let resolver = upstreams.select();
+        let start = Instant::now();
+
+        debug!("Received query: {} {}, forwarding to {}", idna::to_unicode(&ques.name), ques.q_type, resolver);
+        source = (Source::FORWARDED, Some(resolver.addr));
+
+        let result = match resolver.protocol {
+            Protocol::UDP => lookup(request.header.id, &ques, &resolver.addr).await,
+            // encrypted upstreams are only spoken by the blocking clients
+            #[allow(unreachable_patterns)]
+            _ => {
+                let id = request.header.id;
+                let ques = ques.clone();
+
+                tokio::task::spawn_blocking(move || {
+                    resolve::forward_lookup(id, &ques, resolver)
+                })
+                .await?
+            }
+        };
+
+        match &result {
+            Ok(res) => upstreams.record_success(resolver.addr, start.elapsed(), res.header.res_code),
+            Err(e) => upstreams.record_failure(resolver.addr, Failure::of(e)),
+        }
+
+        // the rest of a CNAME chain the upstream stopped partway through is looked up by the blocking forwarder
+        let result = match result {
+            Ok(res) if !matches!(resolve::cname_target(&res.answers, &ques.name, ques.q_type), Ok(None)) => {
+                let ques = ques.clone();
+
+                tokio::task::spawn_blocking(move || {
+                    resolve::chase_cnames(res, &ques.name, ques.q_type, |x| resolve::forward_chain(x, ques.q_type, upstreams))
+                })
+                .await?
+            }
+            x => x,
+        };
+
+        // so are the A records AAAA answers are synthesized from
+        let mut result = match result {
+            Ok(res) if dns64::wanted(&res, &ques.name, ques.q_type).is_some() => {
+                let ques = ques.clone();
+
+                tokio::task::spawn_blocking(move || {
+                    dns64::complete(res, &ques.name, ques.q_type, |x| resolve::forward_chain(x, QueryType::A, upstreams))
+                })
+                .await?
+            }
+            x => x,
+        };
+
+        if let Ok(result) = &mut result {
+            cache::store(&ques.name, ques.q_type, result);
+        }
+
+        // failed lookups fall back on stale records with --serve-stale
+        let result = match result {
+            Ok(res) if res.header.res_code != ResCode::SERV_FAIL => Ok(res),
+            failed => match cache::stale(&ques.name, ques.q_type, move |name, q_type| resolve::refresh(name, q_type, Some(upstreams))) {
+                Some(stale) => {
+                    warn!("Lookup of {} failed, answering with stale records", ques.name);
+                    Ok(stale)
+                }
+                None => failed,
+            },
+        };
+
+        match result {
+            Ok(result) => {
+                if result.header.res_code != ResCode::NO_ERR {
+                    response.header.res_code = result.header.res_code;
+                }
+                response.answers.extend(result.answers);
+                response.authorities.extend(result.authorities);
+                response.resources.extend(result.resources);
+            }
+            Err(e) => {
+                warn!("Lookup of {} failed: {}", ques.name, e);
+                response.header.res_code = ResCode::SERV_FAIL;
+            }
+        }
+
+        response.questions.push(ques);
+    }
+
+    let res = resolve::encode_response(&mut response, max_size)?;
+    query_log::note(source.0, source.1);
+    resolve::log_answered(client, transport, &res, started);
+
+    Ok(res)
+}
+
+/// Answer a query with the blocking handler on the blocking pool
+async fn handle_blocking(req: &[u8], max_size: usize, client: IpAddr, resolution: &Resolution, transport: Transport) -> Result<Vec<u8>> {
+    let req = req.to_vec();
+    let resolution = *resolution;
+
+    tokio::task::spawn_blocking(move || {
+        resolve::handle_query_sized(&req, max_size, client, &resolution, transport)
+    })
+    .await?
+}
+
+/// Forward a single question to the resolver, resending it each time an attempt times out
+async fn lookup(id: u16, question: &DnsQuestion, resolver: &SocketAddr) -> Result<DnsPacket> {
+    let mut query = DnsPacket::new();
+    query.header.id = id;
+    query.header.query_res = false;
+    query.header.rec_des = true;
+    query.questions.push(question.clone());
+
+    let bytes = query.to_bytes()?;
+
+    // an ephemeral port per lookup, connected so only the resolver's replies are received
+    let local = match resolver {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let udp_socket = UdpSocket::bind(local).await?;
+    udp_socket.connect(resolver).await?;
+
+    let timeout = resolve::lookup_timeout();
+    let attempts = resolve::lookup_retries() + 1;
+    let mut buf = vec![0; UDP_MAX_SIZE];
+    let mut received = None;
+
+    // the socket is connected so only the resolver's packets arrive, the rest are checked here
+    // and dropped without ending the wait when they don't answer the query
+    'attempts: for attempt in 1..=attempts {
+        if attempt > 1 {
+            stats::count_retry();
+        }
+        udp_socket.send(&bytes).await?;
+
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let size = match tokio::time::timeout_at(deadline, udp_socket.recv(&mut buf)).await {
+                Ok(x) => x?,
+                Err(_) => {
+                    warn!("No response from {} within {:?} (attempt {} of {})", resolver, timeout, attempt, attempts);
+                    stats::count_timeout();
+                    continue 'attempts;
+                }
+            };
+
+            let res = match DnsPacket::from_bytes(&buf[..size]) {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("Dropping a malformed response from {}: {}", resolver, e);
+                    continue;
+                }
+            };
+            if let Err(e) = resolve::check_response(&res, &query) {
+                warn!("Dropping a response from {}: {}", resolver, e);
+                continue;
+            }
+
+            received = Some(res);
+            break 'attempts;
+        }
+    }
+
+    let mut res = match received {
+        Some(x) => x,
+        None => return Err(DnsError::UpstreamTimeout { upstream: resolver.to_string(), attempts: attempts }),
+    };
+
+    // The answer didn't fit in a UDP packet, ask again over TCP for all of it
+    if res.header.trunc {
+        debug!("Truncated response from {}, retrying over TCP", resolver);
+
+        let full = match tokio::time::timeout(timeout, lookup_tcp(&bytes, resolver)).await {
+            Ok(x) => x?,
+            Err(_) => return Err(DnsError::UpstreamTimeout { upstream: resolver.to_string(), attempts: 1 }),
+        };
+        res = DnsPacket::from_bytes(&full)?;
+        resolve::check_response(&res, &query)?;
+    }
+    resolve::scrub_answers(&mut res, &question.name, *resolver);
+
+    Ok(res)
+}
+
+/// Send a query to the resolver over a new TCP connection and read back its response
+async fn lookup_tcp(query: &[u8], resolver: &SocketAddr) -> Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(resolver).await?;
+
+    let framed = transport::frame_message(query)?;
+    stream.write_all(&framed).await?;
+
+    let len = stream.read_u16().await? as usize;
+    let mut res = vec![0; len];
+    stream.read_exact(&mut res).await?;
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    use crate::packet::DnsRecord;
+    use crate::upstreams::{ Upstream, Upstreams };
+
+    /// An upstream answering every A query with 192.0.2.60
+    fn upstream() -> SocketAddr {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        thread::spawn(move || loop {
+            let mut buf = [0; 512];
+            let (size, client) = socket.recv_from(&mut buf).unwrap();
+            let query = DnsPacket::from_bytes(&buf[..size]).unwrap();
+            let mut res = DnsPacket::response_to(&query)
+                .question(query.questions[0].clone())
+                .answer(DnsRecord::A { domain: query.questions[0].name.clone(), addr_v4: Ipv4Addr::new(192, 0, 2, 60), ttl: 60 });
+            socket.send_to(&res.to_bytes().unwrap(), client).unwrap();
+        });
+
+        addr
+    }
+
+    fn forward_to(addr: SocketAddr) -> Resolution {
+        Resolution::Forward(Box::leak(Box::new(Upstreams::new(vec![Upstream::udp(addr)], Strategy::SEQUENTIAL))))
+    }
+
+    /// The answer to a query for names of type A, from a runtime on the calling thread
+    fn ask(names: &[&str], resolution: Resolution) -> DnsPacket {
+        let mut query = DnsPacket::new();
+        query.header.rec_des = true;
+        for name in names {
+            query.questions.push(DnsQuestion::new(name.to_string(), QueryType::A));
+        }
+        let req = query.to_bytes().unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let client = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let res = runtime.block_on(handle_query_bytes(&req, UDP_MAX_SIZE, client, &resolution, Transport::UDP)).unwrap();
+
+        DnsPacket::from_bytes(&res).unwrap()
+    }
+
+    fn addresses(res: &DnsPacket) -> Vec<(&str, Ipv4Addr)> {
+        res.answers.iter()
+            .filter_map(|x| match x {
+                DnsRecord::A { domain, addr_v4, .. } => Some((domain.as_str(), *addr_v4)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn query_is_forwarded_over_udp() {
+        let res = ask(&["forwarded.async.pine-dns.com"], forward_to(upstream()));
+
+        assert_eq!(res.header.res_code, ResCode::NO_ERR);
+        assert_eq!(addresses(&res), [("forwarded.async.pine-dns.com", Ipv4Addr::new(192, 0, 2, 60))]);
+    }
+
+    #[test]
+    fn lookup_gives_up_with_servfail_once_every_attempt_times_out() {
+        let timeout = Duration::from_millis(100);
+        resolve::set_test_lookup_timeout(timeout);
+        let dead = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let start = Instant::now();
+        let res = ask(&["timeout.async.pine-dns.com"], forward_to(dead.local_addr().unwrap()));
+
+        assert_eq!(res.header.res_code, ResCode::SERV_FAIL);
+        assert!(start.elapsed() >= timeout * (resolve::lookup_retries() + 1) as u32);
+    }
+
+    #[test]
+    fn query_mixing_local_and_forwarded_names_is_still_forwarded() {
+        let res = ask(&["nothing.invalid", "mixed.async.pine-dns.com"], forward_to(upstream()));
+
+        assert_eq!(res.questions.len(), 2);
+        assert_eq!(addresses(&res), [("mixed.async.pine-dns.com", Ipv4Addr::new(192, 0, 2, 60))]);
+    }
+}
@@ -0,0 +1,86 @@
+//! Base64 decoding, RFC 4648
+//! DoH carries GET queries as unpadded base64url in the query string, TSIG keys are given as standard base64
+
+use crate::error::DnsError;
+
+type Result<T> = std::result::Result<T, DnsError>;
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const URL_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// Decode standard base64 text, with or without trailing padding
+pub fn decode(input: &str) -> Result<Vec<u8>> {
+    decode_with(input, ALPHABET)
+}
+
+/// Decode base64url text, with or without trailing padding
+pub fn decode_url(input: &str) -> Result<Vec<u8>> {
+    decode_with(input, URL_ALPHABET)
+}
+
+fn decode_with(input: &str, alphabet: &[u8; 64]) -> Result<Vec<u8>> {
+    let input = input.trim_end_matches('=').as_bytes();
+
+    // a single leftover character can't encode a whole byte
+    if input.len() % 4 == 1 {
+        return Err(DnsError::InvalidBase64("length".to_string()));
+    }
+
+    let mut out = Vec::with_capacity(input.len() * 3 / 4);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+
+    for &c in input {
+        let value = match alphabet.iter().position(|&x| x == c) {
+            Some(x) => x as u32,
+            None => return Err(DnsError::InvalidBase64(format!("character {:?}", c as char))),
+        };
+
+        acc = (acc << 6) | value;
+        bits += 6;
+
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_rfc_4648_vectors() {
+        for (text, bytes) in [
+            ("", ""),
+            ("Zg==", "f"),
+            ("Zm8=", "fo"),
+            ("Zm9v", "foo"),
+            ("Zm9vYg==", "foob"),
+            ("Zm9vYmE=", "fooba"),
+            ("Zm9vYmFy", "foobar"),
+        ] {
+            assert_eq!(decode(text).unwrap(), bytes.as_bytes());
+            assert_eq!(decode(text.trim_end_matches('=')).unwrap(), bytes.as_bytes());
+        }
+    }
+
+    #[test]
+    fn decodes_each_alphabet_only() {
+        assert_eq!(decode("+/8=").unwrap(), [0xfb, 0xff]);
+        assert_eq!(decode_url("-_8").unwrap(), [0xfb, 0xff]);
+        assert!(decode("-_8").is_err());
+        assert!(decode_url("+/8=").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_text() {
+        for text in ["Z", "Zm9vY", "Zm9v!", "Zm 9v"] {
+            assert!(decode(text).is_err(), "{}", text);
+        }
+    }
+}
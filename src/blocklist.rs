@@ -0,0 +1,361 @@
+//! Blocking ads and trackers with domain lists given with --blocklist <path>, repeat it to merge several
+//! A list has a domain per line, ex. doubleclick.net, or is in the hosts(5) format with the address ignored, ex. 0.0.0.0 doubleclick.net
+//! A listed domain blocks itself and every name under it by whole labels, so doubleclick.net blocks ads.doubleclick.net but not notdoubleclick.net
+//! The domains are kept in a hash set, a name is matched by looking up each of its suffixes, so even a list of a million domains
+//! costs a handful of lookups per query
+//! Blocked names are answered without touching the cache or any upstream, as --block-mode says:
+//! NXDOMAIN with an SOA of the listed domain (the default), 0.0.0.0 and :: (null), REFUSED or the --sinkhole addresses,
+//! ex. of a local block page, the address modes answering other types with NODATA
+//! A pattern like *.cdn.tracker.example in a list blocks only the names under cdn.tracker.example, not the domain itself
+//! --allowlist <path> lists in the same formats and --allow <domain> exempt domains and the names under them, or just the names under them
+//! with a pattern, even when a domain above them is blocked, the most specific rule winning as find describes
+//! The hosts file and local zones come first, the lists are read again on SIGHUP
+
+use std::collections::HashSet;
+use std::fs;
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr };
+use std::sync::atomic::{ AtomicU32, Ordering };
+use std::sync::{ OnceLock, RwLock };
+
+use log::{ error, info, warn };
+
+use crate::dns_name;
+use crate::ede::{ self, Ede };
+use crate::error::DnsError;
+use crate::packet::{ DnsPacket, DnsRecord, QueryType, QuestionRef, ResCode };
+use crate::stats;
+
+type Result<T> = std::result::Result<T, DnsError>;
+
+static SOURCES: OnceLock<Sources> = OnceLock::new();
+static RULES: RwLock<Option<Rules>> = RwLock::new(None);
+static MODE: OnceLock<BlockMode> = OnceLock::new();
+static SINKHOLE: OnceLock<(Ipv4Addr, Ipv6Addr)> = OnceLock::new();
+// TTL of the answers, and of the negative ones through the SOA's minimum
+static TTL: AtomicU32 = AtomicU32::new(300);
+
+// Names every hosts file lists for the machine itself, never meant to be blocked
+const HOSTS_DEFAULTS: [&str; 6] = ["localhost", "localhost.localdomain", "local", "broadcasthost", "ip6-localhost", "ip6-loopback"];
+
+/// Where the rules come from, kept to read them again
+struct Sources {
+    blocklists: Vec<String>,
+    allowlists: Vec<String>,
+    allowed: Vec<String>, // given with --allow
+}
+
+/// The domains blocked and allowed, normalized, those of patterns without the leading *.
+#[derive(Default)]
+struct Rules {
+    block: HashSet<Box<str>>,
+    block_wildcard: HashSet<Box<str>>,
+    allow: HashSet<Box<str>>,
+    allow_wildcard: HashSet<Box<str>>,
+}
+
+impl Rules {
+    /// The domain a name is blocked by under these rules, as find describes
+    fn find(&self, name: &str) -> Option<String> {
+        let name = dns_name::normalize(name);
+
+        for suffix in suffixes(&name) {
+            let below = suffix.len() < name.len();
+
+            if self.allow.contains(suffix) {
+                return None;
+            }
+            if self.block.contains(suffix) {
+                return Some(suffix.to_string());
+            }
+            if below && self.allow_wildcard.contains(suffix) {
+                return None;
+            }
+            if below && self.block_wildcard.contains(suffix) {
+                return Some(suffix.to_string());
+            }
+        }
+
+        None
+    }
+}
+
+/// What a blocked name is answered with
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BlockMode {
+    /// NXDOMAIN with an SOA, so the negative answer gets cached
+    NXDOMAIN,
+    /// 0.0.0.0 for A and :: for AAAA, so browsers fail fast instead of trying the next address
+    NULL,
+    /// REFUSED without any records
+    REFUSED,
+    /// The --sinkhole addresses, ex. of a server showing a block page
+    SINKHOLE,
+}
+
+impl BlockMode {
+    /// Every mode, in the order stats lists them
+    pub const ALL: [BlockMode; 4] = [BlockMode::NXDOMAIN, BlockMode::NULL, BlockMode::REFUSED, BlockMode::SINKHOLE];
+
+    /// Parse a mode as given on the command line
+    pub fn from_name(name: &str) -> Option<BlockMode> {
+        match name {
+            "nxdomain" => Some(BlockMode::NXDOMAIN),
+            "null" => Some(BlockMode::NULL),
+            "refused" => Some(BlockMode::REFUSED),
+            "sinkhole" => Some(BlockMode::SINKHOLE),
+            _ => None,
+        }
+    }
+
+    /// The mode as given on the command line
+    pub fn name(&self) -> &'static str {
+        match self {
+            BlockMode::NXDOMAIN => "nxdomain",
+            BlockMode::NULL => "null",
+            BlockMode::REFUSED => "refused",
+            BlockMode::SINKHOLE => "sinkhole",
+        }
+    }
+}
+
+/// Set how blocked names are answered, NXDOMAIN unless --block-mode is given, only the first call has any effect
+/// A sinkhole missing an address family answers that family with 0.0.0.0 or ::
+pub fn set_mode(mode: BlockMode, sinkhole_v4: Option<Ipv4Addr>, sinkhole_v6: Option<Ipv6Addr>) {
+    let _ = MODE.set(mode);
+    let _ = SINKHOLE.set((sinkhole_v4.unwrap_or(Ipv4Addr::UNSPECIFIED), sinkhole_v6.unwrap_or(Ipv6Addr::UNSPECIFIED)));
+}
+
+/// Set the TTL of the answers to blocked names, 300 unless --block-ttl is given
+pub fn set_ttl(ttl: u32) {
+    TTL.store(ttl, Ordering::Relaxed);
+}
+
+/// Read the lists and block their domains from now on, except those of the allowlists and --allow values,
+/// failing if a list can't be read or an --allow value isn't a domain
+/// Answers the number of unique domains and patterns blocked and allowed
+pub fn load(blocklists: &[String], allowlists: &[String], allowed: &[String]) -> Result<(usize, usize)> {
+    let _ = SOURCES.set(Sources {
+        blocklists: blocklists.to_vec(),
+        allowlists: allowlists.to_vec(),
+        allowed: allowed.to_vec(),
+    });
+
+    let mut rules = Rules::default();
+    for path in blocklists {
+        let text = fs::read_to_string(path).map_err(|e| DnsError::Config(format!("{}: {}", path, e)))?;
+        parse(&text, path, &mut rules.block, &mut rules.block_wildcard);
+    }
+    for path in allowlists {
+        let text = fs::read_to_string(path).map_err(|e| DnsError::Config(format!("{}: {}", path, e)))?;
+        parse(&text, path, &mut rules.allow, &mut rules.allow_wildcard);
+    }
+    for domain in allowed {
+        let (name, wildcard) = match domain.strip_prefix("*.") {
+            Some(x) => (dns_name::normalize(x), true),
+            None => (dns_name::normalize(domain), false),
+        };
+        if name.is_empty() {
+            return Err(DnsError::Config(format!("{} isn't a domain", domain)));
+        }
+        dns_name::validate(&name).map_err(|e| DnsError::Config(format!("{}: {}", domain, e)))?;
+
+        if wildcard {
+            rules.allow_wildcard.insert(name.into_boxed_str());
+        } else {
+            rules.allow.insert(name.into_boxed_str());
+        }
+    }
+
+    let counts = (rules.block.len() + rules.block_wildcard.len(), rules.allow.len() + rules.allow_wildcard.len());
+    *RULES.write().unwrap() = Some(rules);
+
+    Ok(counts)
+}
+
+/// Read the lists again, keeping the rules read before if one can't be read
+pub fn reload() {
+    let sources = match SOURCES.get() {
+        Some(x) => x,
+        None => return,
+    };
+
+    match load(&sources.blocklists, &sources.allowlists, &sources.allowed) {
+        Ok((blocked, allowed)) => info!("Reloaded {} blocked and {} allowed domains", blocked, allowed),
+        Err(e) => error!("Failed to reload the blocklists, still blocking the old domains: {}", e),
+    }
+}
+
+/// The domain a name is blocked by, None if it isn't blocked or is allowed
+/// The name and each domain above it are tried in turn, the name itself first, and at each the first rule that applies decides:
+///  1. allowed, the domain is on an allowlist
+///  2. blocked, the domain is on a blocklist
+///  3. allowed, *.domain is on an allowlist and the name is under the domain
+///  4. blocked, *.domain is on a blocklist and the name is under the domain
+///
+/// So the rule for the deepest domain wins, ex. allowing good.ads.example beats blocking ads.example,
+/// and blocking ads.example beats allowing *.example
+pub fn find(name: &str) -> Option<String> {
+    RULES.read().unwrap().as_ref()?.find(name)
+}
+
+/// Whether a question seen before parsing is blocked, its name only copied if a list is loaded
+pub fn is_blocked_question(question: &QuestionRef) -> bool {
+    RULES.read().unwrap().is_some() && find(&question.name()).is_some()
+}
+
+/// The answer to a question for a blocked name in the --block-mode, None if it isn't blocked
+pub fn answer(name: &str, q_type: QueryType) -> Option<DnsPacket> {
+    let domain = find(name)?;
+    let mode = *MODE.get_or_init(|| BlockMode::NXDOMAIN);
+    let ttl = TTL.load(Ordering::Relaxed);
+    stats::count_blocked(mode);
+
+    let mut res = DnsPacket::new();
+    res.extended_errors.push(Ede::new(ede::BLOCKED, &format!("{} is on a blocklist", domain)));
+
+    let (v4, v6) = match mode {
+        BlockMode::NXDOMAIN => return Some(res.authoritative().with_rcode(ResCode::NX_DOMAIN).authority(soa(&domain, ttl))),
+        BlockMode::REFUSED => return Some(res.with_rcode(ResCode::REFUSED)),
+        BlockMode::NULL => (Ipv4Addr::UNSPECIFIED, Ipv6Addr::UNSPECIFIED),
+        BlockMode::SINKHOLE => *SINKHOLE.get_or_init(|| (Ipv4Addr::UNSPECIFIED, Ipv6Addr::UNSPECIFIED)),
+    };
+
+    let res = res.authoritative();
+    Some(match q_type {
+        QueryType::A => res.answer(DnsRecord::A { domain: name.to_string(), addr_v4: v4, ttl: ttl }),
+        QueryType::AAAA => res.answer(DnsRecord::AAAA { domain: name.to_string(), addr: v6, ttl: ttl }),
+        _ => res.authority(soa(&domain, ttl)),
+    })
+}
+
+/// Add the domains and patterns of a list to their sets, skipping malformed lines with a single warning for the whole list
+fn parse(text: &str, path: &str, domains: &mut HashSet<Box<str>>, wildcards: &mut HashSet<Box<str>>) {
+    let mut skipped = 0;
+
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or("");
+        let mut fields = line.split_whitespace();
+        let first = match fields.next() {
+            Some(x) => x,
+            None => continue,
+        };
+
+        // the hosts format lists an address first, ex. 0.0.0.0 doubleclick.net
+        let names: Vec<&str> = match first.parse::<IpAddr>() {
+            Ok(_) => fields.collect(),
+            Err(_) => vec![first],
+        };
+
+        for name in names {
+            let (name, set) = match name.strip_prefix("*.") {
+                Some(x) => (dns_name::normalize(x), &mut *wildcards),
+                None => (dns_name::normalize(name), &mut *domains),
+            };
+            if HOSTS_DEFAULTS.contains(&name.as_str()) || name.parse::<IpAddr>().is_ok() {
+                continue;
+            }
+            if name.is_empty() || dns_name::validate(&name).is_err() {
+                skipped += 1;
+                continue;
+            }
+
+            set.insert(name.into_boxed_str());
+        }
+    }
+
+    if skipped > 0 {
+        warn!("Skipped {} malformed names in {}", skipped, path);
+    }
+}
+
+/// A normalized name and every domain above it, ex. ads.doubleclick.net, doubleclick.net and net
+fn suffixes(name: &str) -> impl Iterator<Item = &str> {
+    let starts = name.char_indices()
+        // a dot escaped inside a label doesn't start a new one
+        .filter(|(i, c)| *c == '.' && !name[..*i].ends_with('\\'))
+        .map(|(i, _)| i + 1);
+
+    std::iter::once(0).chain(starts).map(move |i| &name[i..])
+}
+
+/// The SOA a blocked name is answered with, as if its listed domain were an empty zone served here
+fn soa(domain: &str, ttl: u32) -> DnsRecord {
+    DnsRecord::SOA {
+        domain: domain.to_string(),
+        m_name: "localhost".to_string(),
+        r_name: "nobody.invalid".to_string(),
+        serial: 1,
+        refresh: ttl,
+        retry: ttl,
+        expire: ttl,
+        minimum: ttl,
+        ttl: ttl,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCKLIST: &str = "\
+0.0.0.0 ads.example
+tracker.example          # and everything under it
+*.cdn.tracker.example
+*.example
+localhost
+not a domain..
+";
+
+    const ALLOWLIST: &str = "\
+good.ads.example
+*.safe.tracker.example
+*.cdn.tracker.example
+";
+
+    fn rules() -> Rules {
+        let mut rules = Rules::default();
+        parse(BLOCKLIST, "blocklist", &mut rules.block, &mut rules.block_wildcard);
+        parse(ALLOWLIST, "allowlist", &mut rules.allow, &mut rules.allow_wildcard);
+        rules
+    }
+
+    #[test]
+    fn most_specific_rule_wins() {
+        let rules = rules();
+        let table: [(&str, Option<&str>); 16] = [
+            // exact block covers the domain and the names under it, by whole labels
+            ("ads.example", Some("ads.example")),
+            ("x.ads.example", Some("ads.example")),
+            ("Pixel.ADS.example.", Some("ads.example")),
+            ("notads.example", Some("example")),
+            // exact allow beats a block above it
+            ("good.ads.example", None),
+            ("www.good.ads.example", None),
+            // exact block beats the wildcard allow above it
+            ("tracker.example", Some("tracker.example")),
+            ("www.tracker.example", Some("tracker.example")),
+            // wildcard allow beats the block of a domain above it
+            ("a.safe.tracker.example", None),
+            ("safe.tracker.example", Some("tracker.example")),
+            // the same pattern on both lists allows, and leaves the domain itself to the rules above it
+            ("a.cdn.tracker.example", None),
+            ("cdn.tracker.example", Some("tracker.example")),
+            // a wildcard block covers only the names under its domain
+            ("other.example", Some("example")),
+            ("example", None),
+            // names the lists skipped are never blocked
+            ("localhost", None),
+            ("unrelated.test", None),
+        ];
+
+        for (name, blocked_by) in table {
+            assert_eq!(rules.find(name).as_deref(), blocked_by, "{}", name);
+        }
+    }
+
+    #[test]
+    fn suffixes_split_on_unescaped_dots() {
+        assert_eq!(suffixes("ads.doubleclick.net").collect::<Vec<_>>(), ["ads.doubleclick.net", "doubleclick.net", "net"]);
+        assert_eq!(suffixes("a\\.b.net").collect::<Vec<_>>(), ["a\\.b.net", "net"]);
+    }
+}
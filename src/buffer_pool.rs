@@ -0,0 +1,180 @@
+//! Pools of reusable PacketBuffers, 512 byte ones for UDP and 65535 byte ones for stream transports
+//! Query handling checks buffers out of here instead of allocating new ones per query
+
+use std::sync::Mutex;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+
+use crate::packet::PacketBuffer;
+use crate::transport;
+
+// Buffers beyond these are dropped on release rather than kept around
+const MAX_POOLED: usize = 64;
+const MAX_POOLED_STREAM: usize = 16;
+// Sizes of the buffers the pools hand out
+const POOLED_SIZE: usize = 512;
+const STREAM_SIZE: usize = transport::MAX_TCP_MESSAGE;
+
+static POOL: Mutex<Vec<PacketBuffer>> = Mutex::new(Vec::new());
+static STREAM_POOL: Mutex<Vec<PacketBuffer>> = Mutex::new(Vec::new());
+
+static ACQUIRED: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+/// Counters showing how often the pool had to allocate
+#[derive(Copy, Clone, Debug)]
+pub struct PoolStats {
+    pub acquired: usize,  // buffers handed out
+    pub allocated: usize, // buffers that had to be freshly allocated
+}
+
+/// Take a zeroed 512 byte buffer from the pool, allocating only if it is empty
+pub fn acquire() -> PacketBuffer {
+    acquire_sized(POOLED_SIZE)
+}
+
+/// Take a zeroed buffer of a size, from the pool of that size if there is one
+/// Sizes without a pool, ex. a UDP listener's larger payload, are always allocated
+pub fn acquire_sized(size: usize) -> PacketBuffer {
+    ACQUIRED.fetch_add(1, Ordering::Relaxed);
+
+    let pooled = match pool(size).map(Mutex::lock) {
+        Some(Ok(mut pool)) => pool.pop(),
+        _ => None,
+    };
+
+    match pooled {
+        Some(buf) => buf,
+        None => {
+            ALLOCATED.fetch_add(1, Ordering::Relaxed);
+            PacketBuffer::with_size(size)
+        }
+    }
+}
+
+/// A buffer holding a copy of some bytes, taken from the pool of the smallest size they fit in
+/// It's cut to their length so reads past the end fail as they do on PacketBuffer::from_bytes, released it grows back
+pub fn acquire_with(bytes: &[u8]) -> PacketBuffer {
+    let size = if bytes.len() <= POOLED_SIZE { POOLED_SIZE } else { STREAM_SIZE.max(bytes.len()) };
+
+    let mut buf = acquire_sized(size);
+    buf.buf[..bytes.len()].copy_from_slice(bytes);
+    buf.buf.truncate(bytes.len());
+
+    buf
+}
+
+/// Return a buffer to the pool of its size, one without a pool is dropped
+/// Only the first `used` bytes (or up to pos, whichever is further) are zeroed
+pub fn release(mut buf: PacketBuffer, used: usize) {
+    // a buffer cut short by acquire_with is still the size it was allocated at, and zero past its length
+    let size = buf.buf.capacity();
+    let Some(pool) = pool(size) else { return };
+
+    let used = used.max(buf.pos).min(buf.buf.len());
+    buf.buf[..used].fill(0);
+    buf.buf.resize(size, 0);
+    buf.pos = 0;
+
+    let max = if size == POOLED_SIZE { MAX_POOLED } else { MAX_POOLED_STREAM };
+    if let Ok(mut pool) = pool.lock() {
+        if pool.len() < max {
+            pool.push(buf);
+        }
+    }
+}
+
+/// The pool of buffers of a size, if there is one
+fn pool(size: usize) -> Option<&'static Mutex<Vec<PacketBuffer>>> {
+    match size {
+        POOLED_SIZE => Some(&POOL),
+        STREAM_SIZE => Some(&STREAM_POOL),
+        _ => None,
+    }
+}
+
+impl PoolStats {
+    /// Snapshot of the pool counters
+    /// Under steady load allocated stays flat while acquired keeps growing
+    pub fn current() -> PoolStats {
+        PoolStats {
+            acquired: ACQUIRED.load(Ordering::Relaxed),
+            allocated: ALLOCATED.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{ IpAddr, Ipv4Addr };
+    use std::thread;
+
+    use crate::packet::{ DnsPacket, DnsQuestion, QueryType, ResCode };
+    use crate::resolve::{ self, Resolution };
+    use crate::transport::Transport;
+    use crate::upstreams::{ Strategy, Upstream, Upstreams };
+
+    #[test]
+    fn steady_load_allocates_next_to_no_buffers() {
+        // localhost is answered here, the upstream is never asked
+        let upstreams = Upstreams::new(vec![Upstream::udp("192.0.2.1:53".parse().unwrap())], Strategy::SEQUENTIAL);
+        let resolution = Resolution::Forward(Box::leak(Box::new(upstreams)));
+        let mut query = DnsPacket::new();
+        query.questions.push(DnsQuestion::new("localhost".to_string(), QueryType::A));
+        let req = query.to_bytes().unwrap();
+
+        let before = PoolStats::current();
+        let threads: Vec<_> = (0..4).map(|_| {
+            let req = req.clone();
+            thread::spawn(move || {
+                for _ in 0..500 {
+                    let res = resolve::handle_query_bytes(&req, IpAddr::V4(Ipv4Addr::LOCALHOST), &resolution, Transport::TCP).unwrap();
+                    assert_eq!(DnsPacket::from_bytes(&res).unwrap().answers.len(), 1);
+                }
+            })
+        }).collect();
+        for x in threads {
+            x.join().unwrap();
+        }
+        let after = PoolStats::current();
+
+        // a request and a response buffer a query, allocated only while the pools fill up,
+        // other tests running alongside take some of their own
+        let acquired = after.acquired - before.acquired;
+        let allocated = after.allocated - before.allocated;
+        assert!(acquired >= 4000, "{}", acquired);
+        assert!(allocated * 50 < acquired, "{} of {} buffers allocated", allocated, acquired);
+    }
+
+    #[test]
+    fn bytes_are_copied_into_the_smallest_pooled_size_they_fit() {
+        let buf = acquire_with(&[1, 2, 3]);
+        assert_eq!(buf.buf.capacity(), POOLED_SIZE);
+        assert_eq!(buf.buf, [1, 2, 3]);
+        release(buf, 3);
+
+        let buf = acquire_with(&[7; 600]);
+        assert_eq!(buf.buf.capacity(), STREAM_SIZE);
+        assert_eq!(buf.buf.len(), 600);
+        release(buf, 600);
+
+        // every buffer waiting in a pool was zeroed and grown back to its size on its way back
+        assert!(POOL.lock().unwrap().iter().all(|x| x.buf.len() == POOLED_SIZE && x.buf.iter().all(|b| *b == 0)));
+        assert!(STREAM_POOL.lock().unwrap().iter().all(|x| x.buf.len() == STREAM_SIZE && x.buf[..600].iter().all(|b| *b == 0)));
+    }
+
+    #[test]
+    fn truncated_query_is_malformed_rather_than_read_on_into_zeros() {
+        let upstreams = Upstreams::new(vec![Upstream::udp("192.0.2.1:53".parse().unwrap())], Strategy::SEQUENTIAL);
+        let resolution = Resolution::Forward(Box::leak(Box::new(upstreams)));
+        let mut query = DnsPacket::new();
+        query.questions.push(DnsQuestion::new("localhost".to_string(), QueryType::A));
+        let req = query.to_bytes().unwrap();
+
+        // without the question's type and class, which zeros after the end would have stood in for
+        let res = resolve::handle_query_bytes(&req[..req.len() - 4], IpAddr::V4(Ipv4Addr::LOCALHOST), &resolution, Transport::TCP).unwrap();
+        let res = DnsPacket::from_bytes(&res).unwrap();
+        assert_eq!(res.header.id, query.header.id);
+        assert_eq!(res.header.res_code, ResCode::FORM_ERR);
+    }
+}
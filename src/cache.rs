@@ -0,0 +1,1209 @@
+//! Answers kept for their TTL so repeated queries are answered without looking them up again
+//! Answers are cached as RRsets, the records of one name and type, until the smallest TTL among them runs out
+//! A CNAME chain is cached link by link, so a cached CNAME and cached records for its target answer together
+//! Negative answers are cached as RFC 2308 describes: NXDOMAIN for the name, whatever type is asked for next,
+//! and an empty NOERROR answer (NODATA) for the name and type
+//! They're kept for the TTL of the SOA in the authority section, at most its minimum field and --negative-ttl,
+//! and answered with that SOA
+//! TTLs are clamped to --min-ttl and --max-ttl as records are cached, the client asking sees the clamped ones too
+//! Records are answered with the whole seconds they have left as their TTL, at least --min-served-ttl
+//! With --serve-stale expired entries are kept for a while longer (RFC 8767), when a lookup fails or
+//! the upstream answers SERVFAIL the stale answer is given with a TTL of 30 seconds and refreshed in the background
+//! Entries hit at least --prefetch-hits times are looked up again in the background when hit in the last tenth
+//! of their TTL, so popular names don't expire in front of a client
+//! At most --cache-size RRsets are kept, and as many negative answers, when a shard is full an expired entry makes room
+//! if there is one and the least recently used otherwise
+//! The cache is split into shards by a hash of the name, each behind its own lock, so lookups of different names
+//! don't wait on each other, and lookups only take read locks: recency and hits are counted with atomics
+//! The cache can be flushed whole or by name, and dumped as JSON lines to see what's in it
+//! With --cache-file it's saved on shutdown and loaded again on startup, minus the time it spent on disk
+
+use std::cmp::Reverse;
+use std::collections::{ BTreeMap, HashMap };
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{ Hash, Hasher };
+use std::io;
+use std::mem;
+use std::sync::{ Mutex, OnceLock, PoisonError, RwLock };
+use std::sync::atomic::{ AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering };
+use std::thread;
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+
+use log::{ debug, warn };
+
+use crate::dns_name;
+use crate::ede::{ self, Ede };
+use crate::error::DnsError;
+use crate::idna;
+use crate::json;
+use crate::packet::{ DnsPacket, DnsQuestion, DnsRecord, PacketBuffer, QueryType, ResCode };
+use crate::resolve::{ self, MAX_CNAME_CHAIN, TYPE_RRSIG };
+
+type Error = DnsError;
+type Result<T> = std::result::Result<T, Error>;
+
+static SHARDS: OnceLock<Vec<RwLock<Cache>>> = OnceLock::new();
+static NEGATIVE_TTL: AtomicU32 = AtomicU32::new(300);
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+static EVICTIONS: AtomicU64 = AtomicU64::new(0);
+static MIN_TTL: AtomicU32 = AtomicU32::new(0);
+static MAX_TTL: AtomicU32 = AtomicU32::new(86400);
+static MIN_SERVED_TTL: AtomicU32 = AtomicU32::new(0);
+static CAPACITY: AtomicUsize = AtomicUsize::new(10_000);
+static SERVE_STALE: AtomicBool = AtomicBool::new(false);
+static STALE_RETENTION: AtomicU64 = AtomicU64::new(3600);
+static STALE: AtomicU64 = AtomicU64::new(0);
+static PREFETCH_HITS: AtomicU32 = AtomicU32::new(3);
+static PREFETCHES: AtomicU64 = AtomicU64::new(0);
+static REFRESHING: Mutex<Vec<(String, QueryType)>> = Mutex::new(Vec::new()); // being looked up in the background
+
+// Parts the cache is split into, each with its share of the capacity
+const SHARD_COUNT: usize = 16;
+
+// TTL of stale records, RFC 8767 section 4
+const STALE_TTL: u32 = 30;
+
+// Start of a saved cache, followed by the version of its format
+const FILE_MAGIC: &[u8] = b"PINECACHE";
+const FILE_VERSION: u8 = 1;
+
+/// One shard, the entries of the names hashed to it
+struct Cache {
+    rrsets: Lru<(String, QueryType), RRset>,              // by normalized owner and type
+    negative: Lru<(String, Option<QueryType>), Negative>, // the type for NODATA, none for NXDOMAIN
+}
+
+impl Cache {
+    fn new() -> Cache {
+        let capacity = CAPACITY.load(Ordering::Relaxed).div_ceil(SHARD_COUNT);
+
+        Cache {
+            rrsets: Lru::new(capacity),
+            negative: Lru::new(capacity),
+        }
+    }
+}
+
+/// The shards, made with the capacity set by their first use
+fn shards() -> &'static [RwLock<Cache>] {
+    SHARDS.get_or_init(|| (0..SHARD_COUNT).map(|_| RwLock::new(Cache::new())).collect())
+}
+
+/// The shard a normalized name's entries are kept in
+fn shard(name: &str) -> &'static RwLock<Cache> {
+    let mut hasher = DefaultHasher::new();
+    name.hash(&mut hasher);
+
+    &shards()[hasher.finish() as usize % SHARD_COUNT]
+}
+
+/// A map holding at most capacity entries, each until it expires
+/// Entries are stamped with a generation each time they're used, and indexed by it and by when they expire,
+/// so the entry to evict is the first of either index
+/// Using an entry only stamps it, the recency index catches up when eviction comes across it
+struct Lru<K, V> {
+    entries: HashMap<K, Slot<V>>,
+    recency: BTreeMap<u64, K>,              // by generation last indexed, least recently used first once caught up
+    expiry: BTreeMap<(Instant, u64), K>,    // by when it expires, then the generation it was inserted in
+    generation: AtomicU64,
+    capacity: usize,
+    evictions: u64,
+}
+
+struct Slot<V> {
+    value: V,
+    used: AtomicU64, // generation last used
+    indexed: u64,    // generation it's filed under in the recency index
+    inserted: u64,
+    expires: Instant,
+    lifetime: Duration, // from insertion to expiry
+    hits: AtomicU64,
+}
+
+struct RRset {
+    records: Vec<DnsRecord>, // kept until the smallest of their TTLs runs out
+}
+
+struct Negative {
+    res_code: ResCode,
+    soa: Option<DnsRecord>,
+}
+
+/// Counters showing how often queries were answered from the cache
+#[derive(Copy, Clone, Debug)]
+pub struct CacheStats {
+    pub hits: u64,     // queries answered from the cache
+    pub misses: u64,   // queries that had to be looked up
+    pub evictions: u64, // live entries dropped to make room
+    pub stale: u64,     // failed lookups answered with stale records
+    pub prefetches: u64, // popular entries looked up again before they expired
+    pub rrsets: usize,  // RRsets kept, live or not yet removed
+    pub negative: usize,
+    pub capacity: usize, // of each
+}
+
+/// Entries and evictions of one shard of the cache, to see how evenly names spread over them
+#[derive(Copy, Clone, Debug)]
+pub struct ShardStats {
+    pub rrsets: usize,
+    pub negative: usize,
+    pub evictions: u64,
+}
+
+impl CacheStats {
+    /// Snapshot of the cache counters
+    pub fn current() -> CacheStats {
+        let shards = ShardStats::current();
+
+        CacheStats {
+            hits: HITS.load(Ordering::Relaxed),
+            misses: MISSES.load(Ordering::Relaxed),
+            evictions: EVICTIONS.load(Ordering::Relaxed),
+            stale: STALE.load(Ordering::Relaxed),
+            prefetches: PREFETCHES.load(Ordering::Relaxed),
+            rrsets: shards.iter().map(|x| x.rrsets).sum(),
+            negative: shards.iter().map(|x| x.negative).sum(),
+            capacity: CAPACITY.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl ShardStats {
+    /// Snapshot of every shard
+    pub fn current() -> Vec<ShardStats> {
+        shards().iter()
+            .map(|x| {
+                let cache = x.read().unwrap_or_else(PoisonError::into_inner);
+
+                ShardStats {
+                    rrsets: cache.rrsets.entries.len(),
+                    negative: cache.negative.entries.len(),
+                    evictions: cache.rrsets.evictions + cache.negative.evictions,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Most RRsets kept, and negative answers (default 10000), 0 turns the cache off
+/// Split evenly over the shards, rounded up, and only takes effect before the cache is first used
+pub fn set_capacity(capacity: usize) {
+    CAPACITY.store(capacity, Ordering::Relaxed);
+}
+
+/// Clamp the TTL of records cached to at least min and at most max seconds (default 0 and 86400)
+/// A min above 0 means records with TTL 0 are cached too
+pub fn set_ttl_limits(min: u32, max: u32) {
+    MIN_TTL.store(min, Ordering::Relaxed);
+    MAX_TTL.store(max, Ordering::Relaxed);
+}
+
+/// Lowest TTL cached records are answered with, 0 or 1 for resolvers that take 0 as don't cache (default 0)
+pub fn set_min_served_ttl(ttl: u32) {
+    MIN_SERVED_TTL.store(ttl, Ordering::Relaxed);
+}
+
+/// Answer with expired records when a lookup fails, keeping them for retention after they expire
+pub fn set_serve_stale(enabled: bool, retention: Duration) {
+    SERVE_STALE.store(enabled, Ordering::Relaxed);
+    STALE_RETENTION.store(retention.as_secs(), Ordering::Relaxed);
+}
+
+/// How often an entry must be hit before it's prefetched, 0 turns prefetching off (default 3)
+pub fn set_prefetch_hits(hits: u32) {
+    PREFETCH_HITS.store(hits, Ordering::Relaxed);
+}
+
+/// Longest a negative answer is kept, in seconds, and how long one without a SOA is kept (default 300)
+pub fn set_negative_ttl(secs: u32) {
+    NEGATIVE_TTL.store(secs, Ordering::Relaxed);
+}
+
+/// The cached answer to a question: its records, following cached CNAMEs, or a negative answer with its SOA
+/// None when any of it is missing or expired, the whole question is looked up then
+/// A popular answer about to expire is looked up again in the background with refresh
+pub fn lookup(qname: &str, q_type: QueryType, refresh: impl FnOnce(&str, QueryType) -> Result<DnsPacket> + Send + 'static) -> Option<DnsPacket> {
+    let Some((found, prefetch)) = find(qname, q_type, false, Instant::now()) else {
+        MISSES.fetch_add(1, Ordering::Relaxed);
+        return None;
+    };
+
+    HITS.fetch_add(1, Ordering::Relaxed);
+    if prefetch && refresh_in_background(qname, q_type, refresh) {
+        debug!("Prefetching {} {} before it expires", idna::to_unicode(qname), q_type);
+        PREFETCHES.fetch_add(1, Ordering::Relaxed);
+    }
+
+    Some(found)
+}
+
+/// Fall back on stale records when a lookup failed or the upstream answered SERVFAIL, if --serve-stale allows
+/// The name is looked up again with refresh in the background meanwhile, and cached if that works
+pub fn or_stale(result: Result<DnsPacket>, qname: &str, q_type: QueryType, refresh: impl FnOnce(&str, QueryType) -> Result<DnsPacket> + Send + 'static) -> Result<DnsPacket> {
+    let failure = match &result {
+        Ok(res) if res.header.res_code != ResCode::SERV_FAIL => return result,
+        Ok(_) => "SERVFAIL".to_string(),
+        Err(e) => e.to_string(),
+    };
+
+    match stale(qname, q_type, refresh) {
+        Some(mut x) => {
+            warn!("Lookup of {} failed: {}, answering with stale records", qname, failure);
+            let code = if x.header.res_code == ResCode::NX_DOMAIN { ede::STALE_NXDOMAIN_ANSWER } else { ede::STALE_ANSWER };
+            x.extended_errors.push(Ede::new(code, &failure));
+            Ok(x)
+        }
+        None => result,
+    }
+}
+
+/// Stale records for a question whose lookup failed, if --serve-stale is on and any are kept,
+/// refreshing them in the background
+pub fn stale(qname: &str, q_type: QueryType, refresh: impl FnOnce(&str, QueryType) -> Result<DnsPacket> + Send + 'static) -> Option<DnsPacket> {
+    if !SERVE_STALE.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    let (found, _) = find(qname, q_type, true, Instant::now())?;
+    STALE.fetch_add(1, Ordering::Relaxed);
+    refresh_in_background(qname, q_type, refresh);
+
+    Some(found)
+}
+
+/// Look a question up again on its own thread and cache the answer, unless it's already being looked up
+/// Returns whether a lookup was started
+fn refresh_in_background(qname: &str, q_type: QueryType, refresh: impl FnOnce(&str, QueryType) -> Result<DnsPacket> + Send + 'static) -> bool {
+    let key = (dns_name::normalize(qname), q_type);
+    {
+        let mut refreshing = REFRESHING.lock().unwrap_or_else(PoisonError::into_inner);
+        if refreshing.contains(&key) {
+            return false;
+        }
+        refreshing.push(key.clone());
+    }
+
+    thread::spawn(move || {
+        match refresh(&key.0, key.1) {
+            Ok(mut res) => store(&key.0, key.1, &mut res),
+            Err(e) => warn!("Refreshing {} {:?} failed: {}", idna::to_unicode(&key.0), key.1, e),
+        }
+
+        REFRESHING.lock().unwrap_or_else(PoisonError::into_inner).retain(|x| *x != key);
+    });
+
+    true
+}
+
+/// The answer from the cache as of now and whether any of it is due to be prefetched
+/// Stale entries are used too when stale is set, their records answered with STALE_TTL
+fn find(qname: &str, q_type: QueryType, stale: bool, now: Instant) -> Option<(DnsPacket, bool)> {
+    // ANY is answered with whatever the upstream has, never from here
+    if q_type == QueryType::UNKNOWN(255) {
+        return None;
+    }
+
+    let grace = match stale {
+        true => Duration::from_secs(STALE_RETENTION.load(Ordering::Relaxed)),
+        false => Duration::ZERO,
+    };
+    let ttl = |expires: Instant| match stale {
+        true => STALE_TTL,
+        false => remaining(expires, now),
+    };
+
+    let mut res = DnsPacket::new();
+    let mut name = dns_name::normalize(qname);
+    let mut prefetch = false;
+
+    for _ in 0..=MAX_CNAME_CHAIN {
+        let cache = shard(&name).read().unwrap_or_else(PoisonError::into_inner);
+
+        // the signatures of an RRset are cached as one of their own, and taken out again for clients without DO
+        let signatures = |covered: QueryType| {
+            cache.rrsets.get(&(name.clone(), QueryType::UNKNOWN(TYPE_RRSIG)), now, grace).into_iter()
+                .flat_map(move |slot| slot.value.records.iter().filter(move |x| covers(x, covered)).map(move |x| with_ttl(x, ttl(slot.expires))))
+        };
+
+        if let Some(slot) = cache.rrsets.get(&(name.clone(), q_type), now, grace) {
+            prefetch |= slot.due_for_prefetch(now);
+            res.answers.extend(slot.value.records.iter().map(|x| with_ttl(x, ttl(slot.expires))));
+            res.answers.extend(signatures(q_type));
+            return Some((res, prefetch));
+        }
+
+        if q_type != QueryType::CNAME {
+            if let Some(slot) = cache.rrsets.get(&(name.clone(), QueryType::CNAME), now, grace) {
+                prefetch |= slot.due_for_prefetch(now);
+                res.answers.extend(slot.value.records.iter().map(|x| with_ttl(x, ttl(slot.expires))));
+                res.answers.extend(signatures(QueryType::CNAME));
+                name = match slot.value.records.first() {
+                    Some(DnsRecord::CNAME { host, .. }) => dns_name::normalize(host),
+                    _ => return None,
+                };
+                continue;
+            }
+        }
+
+        let slot = match cache.negative.get(&(name.clone(), None), now, grace) {
+            Some(x) => x,
+            None => cache.negative.get(&(name, Some(q_type)), now, grace)?,
+        };
+        prefetch |= slot.due_for_prefetch(now);
+
+        res.header.res_code = slot.value.res_code;
+        if let Some(soa) = &slot.value.soa {
+            res.authorities.push(with_ttl(soa, ttl(slot.expires)));
+        }
+
+        return Some((res, prefetch));
+    }
+
+    None
+}
+
+/// Remember the answer to a question, clamping the TTLs of its answers first
+/// Its answer section is cached RRset by RRset, a negative answer, NXDOMAIN or NOERROR without answers, as such
+/// Responses with answers aren't cached as negative, ex. a CNAME chain ending in NXDOMAIN says nothing about the name asked for
+pub fn store(qname: &str, q_type: QueryType, res: &mut DnsPacket) {
+    if res.header.trunc || q_type == QueryType::UNKNOWN(255) {
+        return;
+    }
+
+    clamp_ttls(&mut res.answers);
+
+    match res.header.res_code {
+        _ if !res.answers.is_empty() => store_answers(&res.answers),
+        ResCode::NX_DOMAIN => store_negative((dns_name::normalize(qname), None), res),
+        ResCode::NO_ERR => store_negative((dns_name::normalize(qname), Some(q_type)), res),
+        _ => (),
+    }
+}
+
+/// Bring the TTL of each record within --min-ttl and --max-ttl
+fn clamp_ttls(records: &mut [DnsRecord]) {
+    let (min, max) = (MIN_TTL.load(Ordering::Relaxed), MAX_TTL.load(Ordering::Relaxed));
+
+    for record in records.iter_mut() {
+        let ttl = record.ttl();
+        let clamped = ttl.clamp(min, max);
+        if clamped != ttl {
+            if resolve::is_verbose() {
+                debug!("Clamped the TTL of {} {} from {} to {}", idna::to_unicode(record.domain()), record.query_type(), ttl, clamped);
+            }
+            record.set_ttl(clamped);
+        }
+    }
+}
+
+fn store_answers(answers: &[DnsRecord]) {
+    let mut rrsets: HashMap<(String, QueryType), Vec<DnsRecord>> = HashMap::new();
+    for record in answers {
+        let rrset = rrsets.entry((dns_name::normalize(record.domain()), record.query_type())).or_default();
+        // an upstream may repeat a record, it's served once
+        if !rrset.iter().any(|x| x.rdata_eq(record)) {
+            rrset.push(record.clone());
+        }
+    }
+
+    let now = Instant::now();
+
+    for (key, records) in rrsets {
+        let ttl = records.iter().map(|x| x.ttl()).min().unwrap_or(0);
+        if ttl == 0 {
+            continue;
+        }
+
+        let rrset = RRset {
+            records: records,
+        };
+        let mut cache = shard(&key.0).write().unwrap_or_else(PoisonError::into_inner);
+        cache.rrsets.insert(key, rrset, now + Duration::from_secs(ttl as u64), now);
+    }
+}
+
+fn store_negative(key: (String, Option<QueryType>), res: &DnsPacket) {
+    let max = NEGATIVE_TTL.load(Ordering::Relaxed);
+    let soa = res.authorities.iter().find(|x| matches!(x, DnsRecord::SOA { .. }));
+    let ttl = match soa {
+        Some(DnsRecord::SOA { ttl, minimum, .. }) => (*ttl).min(*minimum).min(max),
+        _ => max,
+    };
+    if ttl == 0 {
+        return;
+    }
+
+    let now = Instant::now();
+    let expires = now + Duration::from_secs(ttl as u64);
+
+    let negative = Negative {
+        res_code: res.header.res_code,
+        soa: soa.cloned(),
+    };
+    let mut cache = shard(&key.0).write().unwrap_or_else(PoisonError::into_inner);
+    cache.negative.insert(key, negative, expires, now);
+}
+
+/// Drop every entry, returning how many there were
+/// Lookups in flight still cache their answers when they finish
+pub fn flush() -> usize {
+    shards().iter()
+        .map(|x| {
+            let mut cache = x.write().unwrap_or_else(PoisonError::into_inner);
+            let flushed = cache.rrsets.entries.len() + cache.negative.entries.len();
+            *cache = Cache::new();
+
+            flushed
+        })
+        .sum()
+}
+
+/// Drop every entry owned by a name, all types and negative answers, returning how many there were
+/// Names a cached CNAME points to keep their entries
+pub fn flush_name(name: &str) -> usize {
+    let name = dns_name::normalize(name);
+    let mut cache = shard(&name).write().unwrap_or_else(PoisonError::into_inner);
+
+    let rrsets: Vec<_> = cache.rrsets.entries.keys().filter(|x| x.0 == name).cloned().collect();
+    let negative: Vec<_> = cache.negative.entries.keys().filter(|x| x.0 == name).cloned().collect();
+    for key in &rrsets {
+        cache.rrsets.remove(key);
+    }
+    for key in &negative {
+        cache.negative.remove(key);
+    }
+
+    rrsets.len() + negative.len()
+}
+
+/// The cache as JSON lines, first a summary with the entry counts, a rough memory estimate in bytes,
+/// the top names by hits and the counts per shard, then one object per entry
+/// ex. {"name":"example.com.","type":1,"TTL":299,"hits":4,"data":["93.184.216.34"]}
+/// Negative entries have a Status instead of data, and type null for NXDOMAIN
+pub fn dump(top: usize) -> String {
+    let now = Instant::now();
+
+    let mut entries = Vec::new();
+    let mut hits: HashMap<String, u64> = HashMap::new();
+    let mut memory = 0;
+    let (mut rrsets, mut negative) = (0, 0);
+
+    for shard in shards() {
+        let cache = shard.read().unwrap_or_else(PoisonError::into_inner);
+        rrsets += cache.rrsets.entries.len();
+        negative += cache.negative.entries.len();
+
+        for ((name, q_type), slot) in &cache.rrsets.entries {
+            let slot_hits = slot.hits.load(Ordering::Relaxed);
+            *hits.entry(name.clone()).or_default() += slot_hits;
+            memory += footprint::<(String, QueryType), RRset>(name)
+                + slot.value.records.iter().map(|x| mem::size_of::<DnsRecord>() + x.domain().len() + x.rdata_string().len()).sum::<usize>();
+
+            let data: Vec<String> = slot.value.records.iter().map(|x| json::quote(&x.rdata_string())).collect();
+            entries.push(format!(
+                "{{\"name\":{},\"type\":{},\"TTL\":{},\"hits\":{},\"data\":[{}]}}",
+                json::quote(&json::fqdn(name)),
+                q_type.to_u16(),
+                remaining(slot.expires, now),
+                slot_hits,
+                data.join(","),
+            ));
+        }
+
+        for ((name, q_type), slot) in &cache.negative.entries {
+            let slot_hits = slot.hits.load(Ordering::Relaxed);
+            *hits.entry(name.clone()).or_default() += slot_hits;
+            memory += footprint::<(String, Option<QueryType>), Negative>(name);
+
+            entries.push(format!(
+                "{{\"name\":{},\"type\":{},\"TTL\":{},\"hits\":{},\"Status\":{}}}",
+                json::quote(&json::fqdn(name)),
+                q_type.map_or("null".to_string(), |x| x.to_u16().to_string()),
+                remaining(slot.expires, now),
+                slot_hits,
+                slot.value.res_code.to_u16(),
+            ));
+        }
+    }
+
+    let mut popular: Vec<(String, u64)> = hits.into_iter().collect();
+    popular.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    let popular: Vec<String> = popular.iter()
+        .take(top)
+        .map(|(name, hits)| format!("{{\"name\":{},\"hits\":{}}}", json::quote(&json::fqdn(name)), hits))
+        .collect();
+
+    let shards: Vec<String> = ShardStats::current().iter()
+        .map(|x| format!("{{\"rrsets\":{},\"negative\":{},\"evictions\":{}}}", x.rrsets, x.negative, x.evictions))
+        .collect();
+
+    let mut out = format!(
+        "{{\"rrsets\":{},\"negative\":{},\"memory\":{},\"top\":[{}],\"shards\":[{}]}}\n",
+        rrsets,
+        negative,
+        memory,
+        popular.join(","),
+        shards.join(","),
+    );
+
+    entries.sort();
+    for entry in entries {
+        out.push_str(&entry);
+        out.push('\n');
+    }
+
+    out
+}
+
+/// Save the live entries to a file, the most hit first and at most max of them, returning how many were saved
+/// The file is FILE_MAGIC, the version and when it was saved in seconds since the epoch,
+/// then per entry the seconds it has left and the length of a DNS message holding it
+/// An RRset's message has it as the answer, a negative answer's has the question it answers,
+/// with type ANY for NXDOMAIN, its rcode and its SOA
+pub fn save(path: &str, max: usize) -> Result<usize> {
+    let mut entries: Vec<(u64, u32, DnsPacket)> = Vec::new(); // hits, seconds left and message
+    let now = Instant::now();
+
+    for shard in shards() {
+        let cache = shard.read().unwrap_or_else(PoisonError::into_inner);
+
+        for slot in cache.rrsets.entries.values() {
+            let mut message = DnsPacket::new();
+            message.answers = slot.value.records.clone();
+            entries.push((slot.hits.load(Ordering::Relaxed), slot.expires.saturating_duration_since(now).as_secs() as u32, message));
+        }
+
+        for ((name, q_type), slot) in &cache.negative.entries {
+            let mut message = DnsPacket::new();
+            message.header.res_code = slot.value.res_code;
+            message.questions.push(DnsQuestion::new(name.clone(), q_type.unwrap_or(QueryType::UNKNOWN(255))));
+            message.authorities.extend(slot.value.soa.clone());
+            entries.push((slot.hits.load(Ordering::Relaxed), slot.expires.saturating_duration_since(now).as_secs() as u32, message));
+        }
+    }
+
+    entries.retain(|x| x.1 > 0);
+    entries.sort_by_key(|x| Reverse(x.0));
+    entries.truncate(max);
+
+    let saved_at = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |x| x.as_secs());
+    let mut out = FILE_MAGIC.to_vec();
+    out.push(FILE_VERSION);
+    out.extend(saved_at.to_be_bytes());
+
+    let mut saved = 0;
+    let mut buf = PacketBuffer::with_size(u16::MAX as usize);
+    for (_, ttl, mut message) in entries {
+        // the rare RRset too big for one message is left out
+        buf.pos = 0;
+        if message.write(&mut buf).is_err() {
+            continue;
+        }
+        saved += 1;
+
+        out.extend(ttl.to_be_bytes());
+        out.extend((buf.pos as u16).to_be_bytes());
+        out.extend(&buf.buf[..buf.pos]);
+    }
+
+    // written next to it and renamed over it, so a crash while saving leaves the old file
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, out)?;
+    fs::rename(&tmp, path)?;
+
+    Ok(saved)
+}
+
+/// Load the entries saved in a file, counting the time since it was saved off their TTLs, returning how many were still live
+/// A missing file loads nothing, one that's corrupt or of another version is an error and none of it is loaded
+pub fn load(path: &str) -> Result<usize> {
+    let bytes = match fs::read(path) {
+        Ok(x) => x,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+
+    let start = FILE_MAGIC.len() + 9;
+    if bytes.len() < start || !bytes.starts_with(FILE_MAGIC) {
+        return Err(DnsError::Config("Not a saved cache".to_string()));
+    }
+    let version = bytes[FILE_MAGIC.len()];
+    if version != FILE_VERSION {
+        return Err(DnsError::Config(format!("Saved in format version {}, expected {}", version, FILE_VERSION)));
+    }
+    let saved_at = u64::from_be_bytes(bytes[FILE_MAGIC.len() + 1..start].try_into().map_err(|_| DnsError::Config("Not a saved cache".to_string()))?);
+    let elapsed = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |x| x.as_secs()).saturating_sub(saved_at);
+
+    let mut entries = Vec::new();
+    let mut pos = start;
+    while pos < bytes.len() {
+        let prefix = bytes.get(pos..pos + 6).ok_or_else(|| DnsError::Config("Truncated entry".to_string()))?;
+        let ttl = u32::from_be_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]);
+        let len = u16::from_be_bytes([prefix[4], prefix[5]]) as usize;
+        let message = bytes.get(pos + 6..pos + 6 + len).ok_or_else(|| DnsError::Config("Truncated entry".to_string()))?;
+
+        entries.push((ttl, DnsPacket::from_bytes(message)?));
+        pos += 6 + len;
+    }
+
+    let now = Instant::now();
+    let mut loaded = 0;
+
+    for (ttl, message) in entries {
+        let ttl = (ttl as u64).saturating_sub(elapsed);
+        if ttl == 0 {
+            continue;
+        }
+        let expires = now + Duration::from_secs(ttl);
+
+        match message.questions.first() {
+            Some(question) => {
+                let q_type = Some(question.q_type).filter(|x| *x != QueryType::UNKNOWN(255));
+                let negative = Negative {
+                    res_code: message.header.res_code,
+                    soa: message.authorities.into_iter().next(),
+                };
+                let name = dns_name::normalize(&question.name);
+                let mut cache = shard(&name).write().unwrap_or_else(PoisonError::into_inner);
+                cache.negative.insert((name, q_type), negative, expires, now);
+            }
+            None => {
+                let Some(first) = message.answers.first() else {
+                    continue;
+                };
+                let key = (dns_name::normalize(first.domain()), first.query_type());
+                let mut cache = shard(&key.0).write().unwrap_or_else(PoisonError::into_inner);
+                cache.rrsets.insert(key, RRset { records: message.answers }, expires, now);
+            }
+        }
+        loaded += 1;
+    }
+
+    Ok(loaded)
+}
+
+/// Rough size of an entry without what its value points to, the key is stored in the map and both indexes
+fn footprint<K, V>(name: &str) -> usize {
+    mem::size_of::<(K, Slot<V>)>() + 2 * mem::size_of::<(u64, K)>() + 3 * name.len()
+}
+
+impl<K: Clone + Eq + Hash, V> Lru<K, V> {
+    fn new(capacity: usize) -> Lru<K, V> {
+        Lru {
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            expiry: BTreeMap::new(),
+            generation: AtomicU64::new(0),
+            capacity: capacity,
+            evictions: 0,
+        }
+    }
+
+    /// The entry for a key unless it expired more than grace ago, marking it the most recently used and counting the hit
+    /// Works through a shared reference, so under a read lock
+    fn get(&self, key: &K, now: Instant, grace: Duration) -> Option<&Slot<V>> {
+        let slot = self.entries.get(key).filter(|x| x.expires + grace > now)?;
+
+        slot.used.store(self.generation.fetch_add(1, Ordering::Relaxed) + 1, Ordering::Relaxed);
+        slot.hits.fetch_add(1, Ordering::Relaxed);
+
+        Some(slot)
+    }
+
+    /// Add or replace the value for a key, evicting another entry first when full
+    fn insert(&mut self, key: K, value: V, expires: Instant, now: Instant) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.remove(&key);
+        while self.entries.len() >= self.capacity {
+            self.evict(now);
+        }
+
+        let generation = *self.generation.get_mut() + 1;
+        *self.generation.get_mut() = generation;
+        self.recency.insert(generation, key.clone());
+        self.expiry.insert((expires, generation), key.clone());
+        self.entries.insert(key, Slot {
+            value: value,
+            used: AtomicU64::new(generation),
+            indexed: generation,
+            inserted: generation,
+            expires: expires,
+            lifetime: expires.saturating_duration_since(now),
+            hits: AtomicU64::new(0),
+        });
+    }
+
+    /// Drop the entry that expired first if it has, the least recently used otherwise
+    fn evict(&mut self, now: Instant) {
+        let expired = match self.expiry.keys().next() {
+            Some((expires, _)) => *expires <= now,
+            None => return,
+        };
+
+        if expired {
+            if let Some(key) = self.expiry.values().next().cloned() {
+                self.remove(&key);
+            }
+            return;
+        }
+
+        EVICTIONS.fetch_add(1, Ordering::Relaxed);
+        self.evictions += 1;
+
+        // entries used since they were filed are moved up to where they belong until the first one wasn't
+        while let Some((&indexed, key)) = self.recency.iter().next() {
+            let key = key.clone();
+            let Some(slot) = self.entries.get_mut(&key) else {
+                self.recency.remove(&indexed);
+                continue;
+            };
+
+            let used = *slot.used.get_mut();
+            if used == indexed {
+                self.remove(&key);
+                return;
+            }
+
+            slot.indexed = used;
+            self.recency.remove(&indexed);
+            self.recency.insert(used, key);
+        }
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some(slot) = self.entries.remove(key) {
+            self.recency.remove(&slot.indexed);
+            self.expiry.remove(&(slot.expires, slot.inserted));
+        }
+    }
+}
+
+impl<V> Slot<V> {
+    /// Whether the entry is popular and in the last tenth of its TTL, worth looking up again before it expires
+    fn due_for_prefetch(&self, now: Instant) -> bool {
+        let hits = PREFETCH_HITS.load(Ordering::Relaxed);
+
+        hits > 0 && self.hits.load(Ordering::Relaxed) >= hits as u64 && self.expires > now && self.expires - now <= self.lifetime / 10
+    }
+}
+
+/// Whether a record is an RRSIG over the records of a type, its type covered being the first field
+fn covers(record: &DnsRecord, q_type: QueryType) -> bool {
+    match record {
+        DnsRecord::UNKNOWN { q_type: TYPE_RRSIG, data, .. } => data.len() >= 2 && u16::from_be_bytes([data[0], data[1]]) == q_type.to_u16(),
+        _ => false,
+    }
+}
+
+/// The TTL to answer with for an entry expiring at expires, rounded down
+fn remaining(expires: Instant, now: Instant) -> u32 {
+    (expires.saturating_duration_since(now).as_secs() as u32).max(MIN_SERVED_TTL.load(Ordering::Relaxed))
+}
+
+/// A copy of a record with another TTL
+fn with_ttl(record: &DnsRecord, ttl: u32) -> DnsRecord {
+    let mut record = record.clone();
+    record.set_ttl(ttl);
+
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{ IpAddr, Ipv4Addr, SocketAddr, UdpSocket };
+
+    use crate::resolve::Resolution;
+    use crate::transport::Transport;
+    use crate::upstreams::{ Strategy, Upstream, Upstreams };
+
+    // the cache is shared by every test, each caches names of its own
+
+    // nothing should be looked up again
+    fn never(_: &str, _: QueryType) -> Result<DnsPacket> {
+        Err(DnsError::NoResolver)
+    }
+
+    fn soa(zone: &str, ttl: u32, minimum: u32) -> DnsRecord {
+        DnsRecord::SOA {
+            domain: zone.to_string(),
+            m_name: format!("ns.{}", zone),
+            r_name: format!("hostmaster.{}", zone),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum,
+            ttl,
+        }
+    }
+
+    fn negative(res_code: ResCode, authority: Option<DnsRecord>) -> DnsPacket {
+        let mut res = DnsPacket::new().with_rcode(res_code);
+        res.authorities.extend(authority);
+        res
+    }
+
+    #[test]
+    fn nxdomain_covers_every_type_under_the_soa_minimum() {
+        store("missing.nx.test", QueryType::A, &mut negative(ResCode::NX_DOMAIN, Some(soa("nx.test", 3600, 60))));
+
+        for q_type in [QueryType::A, QueryType::AAAA, QueryType::MX] {
+            let res = lookup("Missing.NX.test.", q_type, never).unwrap();
+            assert_eq!(res.header.res_code, ResCode::NX_DOMAIN);
+            assert!(res.answers.is_empty());
+            assert!(matches!(&res.authorities[..], [DnsRecord::SOA { domain, ttl, .. }] if domain == "nx.test" && *ttl <= 60 && *ttl >= 59));
+        }
+    }
+
+    #[test]
+    fn nodata_covers_only_its_type() {
+        store("v4only.nodata.test", QueryType::AAAA, &mut negative(ResCode::NO_ERR, Some(soa("nodata.test", 30, 900))));
+
+        let res = lookup("v4only.nodata.test", QueryType::AAAA, never).unwrap();
+        assert_eq!(res.header.res_code, ResCode::NO_ERR);
+        assert!(res.answers.is_empty());
+        assert!(matches!(&res.authorities[..], [DnsRecord::SOA { ttl, .. }] if *ttl <= 30 && *ttl >= 29));
+
+        assert!(lookup("v4only.nodata.test", QueryType::A, never).is_none());
+    }
+
+    #[test]
+    fn negative_answers_without_soa_are_kept_for_the_negative_ttl() {
+        store("nosoa.nx.test", QueryType::A, &mut negative(ResCode::NX_DOMAIN, None));
+
+        let res = lookup("nosoa.nx.test", QueryType::A, never).unwrap();
+        assert_eq!(res.header.res_code, ResCode::NX_DOMAIN);
+        assert!(res.authorities.is_empty());
+
+        // SERVFAIL isn't an answer, nothing is kept
+        store("failed.nx.test", QueryType::A, &mut negative(ResCode::SERV_FAIL, None));
+        assert!(lookup("failed.nx.test", QueryType::A, never).is_none());
+    }
+
+    fn a(name: &str, addr: [u8; 4], ttl: u32) -> DnsRecord {
+        DnsRecord::A { domain: name.to_string(), addr_v4: addr.into(), ttl }
+    }
+
+    #[test]
+    fn answers_are_cached_by_rrset_and_cname_link() {
+        let mut res = DnsPacket::new();
+        res.answers.push(DnsRecord::CNAME { domain: "www.link.test".to_string(), host: "Link.test".to_string(), ttl: 600 });
+        res.answers.push(a("link.test", [192, 0, 2, 1], 60));
+        res.answers.push(a("link.test", [192, 0, 2, 2], 30));
+        store("www.link.test", QueryType::A, &mut res);
+
+        // the RRset is kept as long as its smallest TTL
+        let target = lookup("link.test", QueryType::A, never).unwrap();
+        assert_eq!(target.a_records().count(), 2);
+        assert!(target.a_records().all(|(_, _, ttl)| (29..=30).contains(&ttl)));
+
+        // the CNAME and its target's records answer together, as they would from separate lookups
+        let mut link = DnsPacket::new();
+        link.answers.push(DnsRecord::CNAME { domain: "alias.link.test".to_string(), host: "link.test".to_string(), ttl: 600 });
+        store("alias.link.test", QueryType::A, &mut link);
+
+        let chained = lookup("alias.link.test", QueryType::A, never).unwrap();
+        assert_eq!(chained.cname_records().next().map(|(_, host, _)| host), Some("link.test"));
+        assert_eq!(chained.a_records().count(), 2);
+
+        // the CNAME alone answers a question for it
+        assert_eq!(lookup("www.link.test", QueryType::CNAME, never).unwrap().answers.len(), 1);
+        assert!(lookup("www.link.test", QueryType::AAAA, never).is_none());
+    }
+
+    #[test]
+    fn ttls_count_down_as_time_passes() {
+        let stored = Instant::now();
+        let mut res = DnsPacket::new();
+        res.answers.push(a("countdown.test", [192, 0, 2, 1], 300));
+        store("countdown.test", QueryType::A, &mut res);
+
+        let ttl = |after: Duration| find("countdown.test", QueryType::A, false, stored + after)
+            .map(|(res, _)| res.answers[0].ttl());
+        assert_eq!(ttl(Duration::ZERO), Some(300));
+        assert_eq!(ttl(Duration::from_secs(10)), Some(290));
+        assert_eq!(ttl(Duration::from_millis(299_500)), Some(0));
+        assert_eq!(ttl(Duration::from_secs(301)), None);
+
+        // rounded down, and never below the lowest TTL served
+        let now = Instant::now();
+        assert_eq!(remaining(now + Duration::from_millis(90_900), now), 90);
+        assert_eq!(remaining(now, now + Duration::from_secs(5)), MIN_SERVED_TTL.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn threads_storing_and_looking_up_at_once_see_their_own_answers() {
+        let mut shared = DnsPacket::new();
+        shared.answers.push(a("shared.stress.test", [192, 0, 2, 255], 300));
+        store("shared.stress.test", QueryType::A, &mut shared);
+
+        let threads: Vec<_> = (0..8u8)
+            .map(|t| thread::spawn(move || {
+                for n in 0..200u8 {
+                    let name = format!("{}.{}.stress.test", n, t);
+                    let mut res = DnsPacket::new();
+                    res.answers.push(a(&name, [192, 0, t, n], 300));
+                    store(&name, QueryType::A, &mut res);
+
+                    // read back with the other threads writing to the same shards
+                    for m in (0..=n).step_by(37) {
+                        let name = format!("{}.{}.stress.test", m, t);
+                        let found = lookup(&name, QueryType::A, never).unwrap();
+                        assert_eq!(found.a_records().map(|(_, addr, _)| addr).collect::<Vec<_>>(), [IpAddr::V4(Ipv4Addr::new(192, 0, t, m))]);
+                    }
+                    assert!(lookup("shared.stress.test", QueryType::A, never).is_some());
+                }
+            }))
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // 1600 names spread over every shard
+        assert!(ShardStats::current().iter().all(|x| x.rrsets > 0));
+    }
+
+    /// An upstream on UDP answering A with 93.184.216.34 until it's been idle a while, returning how many queries it got
+    fn upstream(ttl: u32) -> (SocketAddr, thread::JoinHandle<usize>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let upstream = thread::spawn(move || {
+            socket.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+            let mut buf = [0; 512];
+            let mut queries = 0;
+            while let Ok((size, client)) = socket.recv_from(&mut buf) {
+                queries += 1;
+                let query = DnsPacket::from_bytes(&buf[..size]).unwrap();
+                let mut res = DnsPacket::response_to(&query)
+                    .question(query.questions[0].clone())
+                    .answer(a(&query.questions[0].name, [93, 184, 216, 34], ttl));
+                socket.send_to(&res.to_bytes().unwrap(), client).unwrap();
+            }
+            queries
+        });
+
+        (addr, upstream)
+    }
+
+    /// Ask for a name's A records as a client with EDNS would, through the upstream
+    fn ask(name: &str, upstream: SocketAddr) -> DnsPacket {
+        let upstreams = Upstreams::new(vec![Upstream::udp(upstream)], Strategy::SEQUENTIAL);
+        let resolution = Resolution::Forward(Box::leak(Box::new(upstreams)));
+
+        let mut query = DnsPacket::new();
+        query.header.query_res = false;
+        query.questions.push(DnsQuestion::new(name.to_string(), QueryType::A));
+        query.resources.push(DnsRecord::OPT { payload_size: 1232, ext_rcode: 0, version: 0, flags: 0, data: Vec::new() });
+        let req = query.to_bytes().unwrap();
+
+        let res = resolve::handle_query_bytes(&req, IpAddr::V4(Ipv4Addr::LOCALHOST), &resolution, Transport::UDP).unwrap();
+        DnsPacket::from_bytes(&res).unwrap()
+    }
+
+    #[test]
+    fn second_identical_query_is_not_sent_upstream() {
+        let (addr, upstream) = upstream(300);
+
+        let first = ask("once.pine-dns.com", addr);
+        let second = ask("once.pine-dns.com", addr);
+
+        assert_eq!(upstream.join().unwrap(), 1);
+        assert_eq!(first.get_first_addr(), Some(IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34))));
+        assert_eq!(second.get_first_addr(), first.get_first_addr());
+    }
+
+    /// Serve-stale is on for the whole process while it's held, the other tests don't look up expired names
+    struct ServeStale;
+
+    impl ServeStale {
+        fn on() -> ServeStale {
+            set_serve_stale(true, Duration::from_secs(3600));
+            ServeStale
+        }
+    }
+
+    impl Drop for ServeStale {
+        fn drop(&mut self) {
+            set_serve_stale(false, Duration::from_secs(3600));
+        }
+    }
+
+    #[test]
+    fn dead_upstream_gets_stale_records_once_they_expire() {
+        let _stale = ServeStale::on();
+        let (addr, upstream) = upstream(1);
+
+        let fresh = ask("stale.pine-dns.com", addr);
+        // the upstream's socket is closed once it's done, nothing answers on its port any more
+        assert_eq!(upstream.join().unwrap(), 1);
+        thread::sleep(Duration::from_millis(1100));
+
+        let stale = ask("stale.pine-dns.com", addr);
+        assert_eq!(stale.header.res_code, ResCode::NO_ERR);
+        assert_eq!(stale.get_first_addr(), fresh.get_first_addr());
+        assert!(stale.answers.iter().all(|x| x.ttl() == STALE_TTL));
+        let errors = match &stale.resources[..] {
+            [DnsRecord::OPT { data, .. }] => ede::parse(data),
+            x => panic!("Expected one OPT record, got {:?}", x),
+        };
+        assert!(matches!(&errors[..], [x] if x.code == ede::STALE_ANSWER), "{:?}", errors);
+    }
+
+    #[test]
+    fn lru_stays_within_capacity_keeping_recently_used_entries() {
+        let mut lru: Lru<u32, u32> = Lru::new(4);
+        let now = Instant::now();
+        let later = now + Duration::from_secs(300);
+
+        for key in 0..4 {
+            lru.insert(key, key, later, now);
+        }
+        // 0 and 1 are used again, 2 is then the least recently used
+        assert!(lru.get(&0, now, Duration::ZERO).is_some());
+        assert!(lru.get(&1, now, Duration::ZERO).is_some());
+
+        for key in 4..6 {
+            lru.insert(key, key, later, now);
+        }
+
+        assert_eq!(lru.entries.len(), 4);
+        assert_eq!(lru.evictions, 2);
+        let mut kept: Vec<u32> = lru.entries.keys().copied().collect();
+        kept.sort();
+        assert_eq!(kept, [0, 1, 4, 5]);
+    }
+
+    #[test]
+    fn lru_evicts_expired_entries_first() {
+        let mut lru: Lru<u32, u32> = Lru::new(3);
+        let now = Instant::now();
+
+        lru.insert(0, 0, now + Duration::from_secs(300), now);
+        lru.insert(1, 1, now + Duration::from_secs(1), now);
+        lru.insert(2, 2, now + Duration::from_secs(300), now);
+
+        // 1 has expired by now, and goes though 0 was used least recently
+        let now = now + Duration::from_secs(2);
+        lru.insert(3, 3, now + Duration::from_secs(300), now);
+
+        assert_eq!(lru.entries.len(), 3);
+        assert_eq!(lru.evictions, 0);
+        assert!(lru.get(&1, now, Duration::ZERO).is_none());
+        assert!(lru.get(&0, now, Duration::ZERO).is_some());
+
+        // with nothing expired the least recently used goes
+        lru.insert(4, 4, now + Duration::from_secs(300), now);
+        assert_eq!(lru.evictions, 1);
+        assert!(!lru.entries.contains_key(&2));
+    }
+
+    #[test]
+    fn zero_capacity_keeps_nothing() {
+        let mut lru: Lru<u32, u32> = Lru::new(0);
+        let now = Instant::now();
+        lru.insert(0, 0, now + Duration::from_secs(300), now);
+
+        assert!(lru.entries.is_empty());
+    }
+
+    /// A saved cache file of a version, saved at seconds since the epoch, with entries of the seconds they have left and their message
+    fn cache_file(version: u8, saved_at: u64, entries: &[(u32, Vec<u8>)]) -> Vec<u8> {
+        let mut out = FILE_MAGIC.to_vec();
+        out.push(version);
+        out.extend(saved_at.to_be_bytes());
+        for (ttl, message) in entries {
+            out.extend(ttl.to_be_bytes());
+            out.extend((message.len() as u16).to_be_bytes());
+            out.extend(message);
+        }
+
+        out
+    }
+
+    /// When a saved cache file was saved and its entries
+    fn read_cache_file(bytes: &[u8]) -> (u64, Vec<(u32, Vec<u8>)>) {
+        let start = FILE_MAGIC.len() + 9;
+        let saved_at = u64::from_be_bytes(bytes[FILE_MAGIC.len() + 1..start].try_into().unwrap());
+
+        let mut entries = Vec::new();
+        let mut pos = start;
+        while pos < bytes.len() {
+            let ttl = u32::from_be_bytes(bytes[pos..pos + 4].try_into().unwrap());
+            let len = u16::from_be_bytes([bytes[pos + 4], bytes[pos + 5]]) as usize;
+            entries.push((ttl, bytes[pos + 6..pos + 6 + len].to_vec()));
+            pos += 6 + len;
+        }
+
+        (saved_at, entries)
+    }
+
+    fn rrset_message(name: &str) -> Vec<u8> {
+        let mut message = DnsPacket::new();
+        message.answers.push(a(name, [192, 0, 2, 1], 300));
+        message.to_bytes().unwrap()
+    }
+
+    fn temp_file(tag: &str) -> String {
+        std::env::temp_dir().join(format!("pine-dns-cache-{}-{}", std::process::id(), tag)).to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn corrupt_cache_files_load_nothing() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let entries = [(300, rrset_message("first.corrupt.test")), (300, rrset_message("second.corrupt.test"))];
+        let good = cache_file(FILE_VERSION, now, &entries);
+
+        let mut wrong_magic = good.clone();
+        wrong_magic[0] = b'X';
+        let newer = cache_file(FILE_VERSION + 1, now, &entries);
+        // cut off partway through the second entry's message
+        let truncated = good[..good.len() - 5].to_vec();
+
+        let path = temp_file("corrupt");
+        for bytes in [wrong_magic, newer, truncated] {
+            fs::write(&path, bytes).unwrap();
+            assert!(load(&path).is_err());
+            assert!(lookup("first.corrupt.test", QueryType::A, never).is_none());
+            assert!(lookup("second.corrupt.test", QueryType::A, never).is_none());
+        }
+
+        // and the file they were made from loads
+        fs::write(&path, good).unwrap();
+        assert_eq!(load(&path).unwrap(), 2);
+        let _ = fs::remove_file(&path);
+        assert!(lookup("second.corrupt.test", QueryType::A, never).is_some());
+    }
+
+    #[test]
+    fn saved_entries_load_with_the_time_since_counted_off_their_ttls() {
+        let mut res = DnsPacket::new();
+        res.answers.push(a("roundtrip.saved.test", [192, 0, 2, 1], 300));
+        store("roundtrip.saved.test", QueryType::A, &mut res);
+
+        let path = temp_file("roundtrip");
+        assert!(save(&path, usize::MAX).unwrap() > 0);
+
+        // only this test's entry is loaded back, so the rest of the cache isn't touched,
+        // as if it had been saved 100 seconds ago
+        let (saved_at, entries) = read_cache_file(&fs::read(&path).unwrap());
+        let ours: Vec<_> = entries.into_iter()
+            .filter(|(_, message)| DnsPacket::from_bytes(message).unwrap().answers.first().is_some_and(|x| x.domain() == "roundtrip.saved.test"))
+            .collect();
+        assert_eq!(ours.len(), 1);
+        fs::write(&path, cache_file(FILE_VERSION, saved_at - 100, &ours)).unwrap();
+
+        flush_name("roundtrip.saved.test");
+        assert!(lookup("roundtrip.saved.test", QueryType::A, never).is_none());
+
+        assert_eq!(load(&path).unwrap(), 1);
+        let _ = fs::remove_file(&path);
+        let res = lookup("roundtrip.saved.test", QueryType::A, never).unwrap();
+        assert!((198..=200).contains(&res.answers[0].ttl()));
+    }
+}
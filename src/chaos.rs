@@ -0,0 +1,141 @@
+//! CHAOS class questions about the server itself, answered here and never sent upstream
+//! TXT questions for version.bind and version.server get the version, or --version-string <text> in its place,
+//! hostname.bind and id.server the --identity <text> and authors.bind the authors
+//! TXT questions for names under stats.pine get the current value of a counter, ex. queries.stats.pine or uptime.stats.pine,
+//! for clients allowed recursion only, so anything with dig can watch the server without another port
+//! Any other CHAOS question, and one whose answer is hidden or not configured, gets REFUSED
+
+use std::net::IpAddr;
+use std::sync::OnceLock;
+
+use crate::acl;
+use crate::dns_name;
+use crate::packet::{ DnsPacket, DnsRecord, QueryType, ResCode };
+use crate::stats::Stats;
+
+// The zone the counters are named under
+const STATS_ZONE: &str = "stats.pine";
+
+static VERSION: OnceLock<Option<String>> = OnceLock::new();
+static IDENTITY: OnceLock<String> = OnceLock::new();
+
+/// Answer version.bind with a text of its own, or refuse it with None, only the first call has any effect
+pub fn set_version(version: Option<String>) {
+    let _ = VERSION.set(version);
+}
+
+/// Answer hostname.bind and id.server with the server's identity, refused until it's set, only the first call has any effect
+pub fn set_identity(identity: String) {
+    let _ = IDENTITY.set(identity);
+}
+
+/// The answer to a CHAOS class question from a client, authoritative or REFUSED
+pub fn answer(name: &str, q_type: QueryType, client: IpAddr) -> DnsPacket {
+    let name_lower = dns_name::normalize(name);
+    let text = match (name_lower.as_str(), q_type) {
+        (x, QueryType::TXT) if dns_name::is_subdomain(x, STATS_ZONE) => {
+            let counter = x.strip_suffix(STATS_ZONE).and_then(|x| x.strip_suffix('.')).unwrap_or("");
+            acl::is_allowed(client).then(|| stat(counter)).flatten()
+        }
+        ("version.bind" | "version.server", QueryType::TXT) => VERSION
+            .get_or_init(|| Some(format!("pine-dns {}", env!("CARGO_PKG_VERSION"))))
+            .clone(),
+        ("hostname.bind" | "id.server", QueryType::TXT) => IDENTITY.get().cloned(),
+        ("authors.bind", QueryType::TXT) => Some(env!("CARGO_PKG_AUTHORS").replace(':', ", ")).filter(|x| !x.is_empty()),
+        _ => None,
+    };
+
+    match text {
+        Some(text) => DnsPacket::new().authoritative().answer(DnsRecord::CHAOS_TXT { domain: name.to_string(), data: text }),
+        None => DnsPacket::new().with_rcode(ResCode::REFUSED),
+    }
+}
+
+/// The current value of the counter a name under stats.pine is for, None if there's no such counter
+fn stat(counter: &str) -> Option<String> {
+    let stats = Stats::current();
+    let cache = &stats.cache;
+
+    let value = match counter {
+        "queries" => stats.queries,
+        "uptime" => stats.uptime,
+        "timeouts" => stats.timeouts,
+        "retries" => stats.retries,
+        "blocked" => stats.blocked.iter().map(|(_, x)| x).sum(),
+        "refused-recursion" => stats.refused_recursion,
+        "rate-limited" => stats.rate_limited_dropped + stats.rate_limited_slipped,
+        "rebinding-filtered" => stats.rebinding_filtered,
+        "tc-forced" => stats.tc_forced,
+        "log-dropped" => stats.log_dropped,
+        "cache-hits" => cache.hits,
+        "cache-misses" => cache.misses,
+        "cache-evictions" => cache.evictions,
+        "cache-stale" => cache.stale,
+        "cache-prefetches" => cache.prefetches,
+        "cache-entries" => (cache.rrsets + cache.negative) as u64,
+        // the rcodes by name, ex. nx_domain.stats.pine
+        x => stats.by_res_code.iter().find(|(res_code, _)| format!("{:?}", res_code).eq_ignore_ascii_case(x))?.1,
+    };
+
+    Some(value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    use crate::stats;
+
+    /// The counter a CHAOS TXT question for it is answered with, as a client on loopback asks it
+    fn counter(name: &str) -> u64 {
+        let res = answer(name, QueryType::TXT, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        match &res.answers[..] {
+            [DnsRecord::CHAOS_TXT { data, .. }] => data.parse().unwrap(),
+            x => panic!("Expected one TXT record for {}, got {:?} {:?}", name, res.header.res_code, x),
+        }
+    }
+
+    #[test]
+    fn counters_go_up_in_the_answers() {
+        let queries = counter("queries.stats.pine");
+        let nxdomain = counter("NX_DOMAIN.stats.pine");
+        let timeouts = counter("timeouts.stats.pine");
+
+        stats::count_response([QueryType::A].into_iter(), ResCode::NX_DOMAIN);
+        stats::count_timeout();
+
+        // other tests count as well, so at least by one
+        assert!(counter("queries.stats.pine") > queries);
+        assert!(counter("nx_domain.stats.pine") > nxdomain);
+        assert!(counter("timeouts.stats.pine") > timeouts);
+    }
+
+    #[test]
+    fn counters_are_refused_to_clients_not_allowed_recursion() {
+        let res = answer("queries.stats.pine", QueryType::TXT, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+        assert_eq!(res.header.res_code, ResCode::REFUSED);
+        assert!(res.answers.is_empty());
+
+        let res = answer("nonsense.stats.pine", QueryType::TXT, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(res.header.res_code, ResCode::REFUSED);
+    }
+
+    #[test]
+    fn version_is_written_as_a_txt_record_in_the_chaos_class() {
+        let version = format!("pine-dns {}", env!("CARGO_PKG_VERSION"));
+        let mut res = answer("version.bind", QueryType::TXT, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert!(res.header.authoritative);
+
+        let expected = [
+            // version.bind TXT CH, ttl 0 and the text as one character string
+            &b"\x07version\x04bind\x00\x00\x10\x00\x03\x00\x00\x00\x00"[..],
+            &(version.len() as u16 + 1).to_be_bytes(),
+            &[version.len() as u8],
+            version.as_bytes(),
+        ].concat();
+
+        // after the header, with no question
+        assert_eq!(&res.to_bytes().unwrap()[12..], expected);
+    }
+}
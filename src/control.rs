@@ -0,0 +1,221 @@
+//! Control channel, a unix socket at --control <path> taking one command per line and answering each with a line of JSON
+//!   stats                 the stats, as SIGUSR2 logs them
+//!   upstreams             each upstream's health and latency percentiles, ex. {"upstreams":[{"addr":"9.9.9.9:53","state":"active","p50_ms":16,"p99_ms":64,...}]}
+//!   flush-cache           empty the cache, ex. {"flushed":42}
+//!   flush-name <domain>   drop the cached entries owned by a name, ex. {"name":"example.com.","flushed":2}
+//!   reload-zones          read the zone files again, and the views' zones and hosts files
+//!   reload-blocklists     read the blocklists again
+//!   reload                everything SIGHUP reloads
+//!   quit                  stop the server the way SIGTERM does
+//! A command that fails or isn't known gets {"error":"..."} and the connection stays open for the next one
+//! The socket is only accessible to the user the server runs as, and on Linux connections from other users are turned away too
+//! `pine-dns ctl <command>` sends a command and prints the answer
+
+use std::fs::{ self, Permissions };
+use std::io::{ self, BufRead, BufReader, Read, Write };
+use std::os::unix::fs::{ FileTypeExt, PermissionsExt };
+use std::os::unix::net::{ UnixListener, UnixStream };
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use log::{ error, info, warn };
+
+use crate::blocklist;
+use crate::cache;
+use crate::dns_name;
+use crate::hosts;
+use crate::json;
+use crate::reload;
+use crate::stats::Stats;
+use crate::upstreams;
+use crate::views;
+
+/// Where the socket is unless --control says otherwise
+pub const DEFAULT_PATH: &str = "/run/pine-dns.sock";
+
+// The longest command line read, anything longer ends the connection
+const MAX_LINE: usize = 1024;
+
+/// Listen for commands on a socket at path from a thread of its own, each connection served by a thread of its own
+/// A socket left behind by an earlier run is replaced, anything else at the path is an error
+pub fn serve(path: &str) -> io::Result<()> {
+    remove_stale(path)?;
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, Permissions::from_mode(0o600))?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    thread::spawn(move || {
+                        if let Err(e) = handle_connection(stream) {
+                            warn!("Control connection failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => error!("Failed to accept a control connection: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Send a command to the server listening at path, returning its answer without the newline
+pub fn send(path: &str, command: &str) -> io::Result<String> {
+    let mut stream = UnixStream::connect(path)?;
+    writeln!(stream, "{}", command)?;
+
+    let mut answer = String::new();
+    BufReader::new(stream).read_line(&mut answer)?;
+    if answer.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The server closed the connection without answering"));
+    }
+
+    Ok(answer.trim_end().to_string())
+}
+
+fn handle_connection(stream: UnixStream) -> io::Result<()> {
+    if !is_same_user(&stream) {
+        warn!("Turned away a control connection from another user");
+        return Ok(());
+    }
+
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        if (&mut reader).take(MAX_LINE as u64).read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        if !line.ends_with('\n') && line.len() >= MAX_LINE {
+            writeln!(writer, "{}", error_line("Command too long"))?;
+            return Ok(());
+        }
+
+        let command = line.trim();
+        if command.is_empty() {
+            continue;
+        }
+
+        info!("Control command: {}", command);
+        writeln!(writer, "{}", run(command))?;
+    }
+}
+
+/// Run a command, returning its answer as a line of JSON
+fn run(command: &str) -> String {
+    let mut words = command.split_whitespace();
+    let name = words.next().unwrap_or("");
+    let arg = words.next();
+    if words.next().is_some() {
+        return error_line(&format!("Too many arguments for {}", name));
+    }
+
+    match (name, arg) {
+        ("stats", None) => Stats::current().to_json(),
+        ("upstreams", None) => format!("{{\"upstreams\":[{}]}}", upstreams_summary().join(",")),
+        ("flush-cache", None) => format!("{{\"flushed\":{}}}", cache::flush()),
+        ("flush-name", Some(domain)) => match dns_name::validate(domain) {
+            Ok(()) => format!("{{\"name\":{},\"flushed\":{}}}", json::quote(&json::fqdn(domain)), cache::flush_name(domain)),
+            Err(e) => error_line(&format!("Invalid name {}: {}", domain, e)),
+        },
+        ("flush-name", None) => error_line("flush-name needs a domain, ex. flush-name example.com"),
+        ("reload-zones", None) => {
+            reload::reload_all();
+            views::reload();
+            "{\"reloaded\":\"zones\"}".to_string()
+        }
+        ("reload-blocklists", None) => {
+            blocklist::reload();
+            "{\"reloaded\":\"blocklists\"}".to_string()
+        }
+        ("reload", None) => {
+            reload::reload_all();
+            hosts::reload();
+            views::reload();
+            blocklist::reload();
+            "{\"reloaded\":\"all\"}".to_string()
+        }
+        // through the signal thread, so the stats are logged, the query log written out and the cache saved as on SIGTERM
+        ("quit", None) => {
+            unsafe { libc::kill(libc::getpid(), libc::SIGTERM) };
+            "{\"stopping\":true}".to_string()
+        }
+        ("stats" | "upstreams" | "flush-cache" | "reload-zones" | "reload-blocklists" | "reload" | "quit", Some(_)) => {
+            error_line(&format!("{} takes no arguments", name))
+        }
+        _ => error_line(&format!("Unknown command {}, expected stats, upstreams, flush-cache, flush-name, reload-zones, reload-blocklists, reload or quit", name)),
+    }
+}
+
+/// Each upstream's state, latency percentiles and failures by kind, shorter than the stats give them
+fn upstreams_summary() -> Vec<String> {
+    let ms = |x: Option<Duration>| x.map_or_else(|| "null".to_string(), |x| x.as_millis().to_string());
+
+    upstreams::all_stats().iter()
+        .map(|x| {
+            format!(
+                "{{\"addr\":{},\"state\":\"{}\",\"p50_ms\":{},\"p99_ms\":{},\"queries\":{},\"timeouts\":{},\"connection_errors\":{},\"formerr\":{},\"servfail\":{}}}",
+                json::quote(&x.addr.to_string()),
+                x.state().name(),
+                ms(x.percentile(0.5)),
+                ms(x.percentile(0.99)),
+                x.queries,
+                x.timeouts,
+                x.connection_errors,
+                x.form_errs,
+                x.serv_fails,
+            )
+        })
+        .collect()
+}
+
+fn error_line(msg: &str) -> String {
+    format!("{{\"error\":{}}}", json::quote(msg))
+}
+
+/// Remove a socket nobody is listening on any more, refusing to start if a server still is or the path is something else
+fn remove_stale(path: &str) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(x) => x,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "Something other than a socket is in the way"));
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(io::ErrorKind::AddrInUse, "Another server is listening on it"));
+    }
+
+    fs::remove_file(Path::new(path))
+}
+
+/// Whether the peer runs as the same user as the server, or as root
+#[cfg(target_os = "linux")]
+fn is_same_user(stream: &UnixStream) -> bool {
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    let mut cred: libc::ucred = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let err = unsafe {
+        libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED, &mut cred as *mut _ as *mut libc::c_void, &mut len)
+    };
+    if err != 0 {
+        return false;
+    }
+
+    cred.uid == 0 || cred.uid == unsafe { libc::geteuid() }
+}
+
+/// Elsewhere the permissions of the socket are all there is
+#[cfg(not(target_os = "linux"))]
+fn is_same_user(_stream: &UnixStream) -> bool {
+    true
+}
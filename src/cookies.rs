@@ -0,0 +1,292 @@
+//! DNS cookies (RFC 7873), so a client can prove it's at the address its queries come from without TCP
+//! With --cookies a client sending the COOKIE EDNS option gets a server cookie back in every response,
+//! made from a secret of this instance, the client cookie and the client's address in the RFC 9018 layout:
+//! version 1, three reserved bytes, the time it was made and the first 8 bytes of an HMAC-SHA256 over them
+//! The secret is replaced every --cookie-rotation <seconds>, the one before it still accepted for --cookie-grace <seconds>
+//! so clients holding a cookie from just before the rotation aren't turned away, every response carries a fresh cookie
+//! With --require-cookies UDP queries without a valid server cookie aren't answered: those with only a client cookie,
+//! or a stale or foreign one, get BADCOOKIE and a fresh cookie to retry with, those without any get an empty
+//! truncated response so the client retries over TCP, which, like the other stream transports, is always answered
+//! BADCOOKIE is the extended rcode 23, split between the header and the OPT record when the response is written
+
+use std::net::IpAddr;
+use std::sync::{ OnceLock, RwLock };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+
+use crate::hmac;
+use crate::packet::{ DnsPacket, DnsRecord, LISTENER_PAYLOAD };
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+static SECRETS: RwLock<Option<Secrets>> = RwLock::new(None);
+static REQUIRED: AtomicBool = AtomicBool::new(false);
+
+// Option code of COOKIE in an OPT record
+const OPTION_COOKIE: u16 = 10;
+// The client cookie is always 8 bytes, the server cookie 8 to 32, ours 16
+const CLIENT_LEN: usize = 8;
+const SERVER_LEN: usize = 16;
+const VERSION: u8 = 1;
+// How far ahead of our clock a cookie's time may be, RFC 9018 section 4.3
+const MAX_FUTURE: u32 = 300;
+
+#[derive(Copy, Clone, Debug)]
+struct Config {
+    rotation: Duration,
+    grace: Duration,
+}
+
+/// The secret server cookies are made with, and the one it replaced
+struct Secrets {
+    current: [u8; 32],
+    previous: Option<[u8; 32]>,
+    since: Instant, // when current was due to replace previous
+}
+
+/// The COOKIE option of a query
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Cookie {
+    /// No OPT record, or one without a COOKIE option
+    MISSING,
+    /// A COOKIE option of a length no cookie can have, answered with FORMERR
+    MALFORMED,
+    /// A client cookie alone, or with a server cookie that's stale or wasn't made here
+    UNVERIFIED([u8; CLIENT_LEN]),
+    /// A client cookie with a server cookie made here for it and the client's address
+    VALID([u8; CLIENT_LEN]),
+}
+
+impl Secrets {
+    fn new(now: Instant) -> Secrets {
+        Secrets {
+            current: rand::random(),
+            previous: None,
+            since: now,
+        }
+    }
+
+    /// Replace the secret if it's been in use for a rotation or more
+    /// Rotations missed while nothing was asked are made up for, so the previous secret is only kept
+    /// if it was replaced a single rotation ago, and it counts from when it was due to be replaced
+    fn rotate(&mut self, now: Instant, rotation: Duration) {
+        let elapsed = now.saturating_duration_since(self.since);
+        if elapsed < rotation {
+            return;
+        }
+
+        let missed = (elapsed.as_nanos() / rotation.as_nanos().max(1)) as u32;
+        self.previous = if missed == 1 { Some(self.current) } else { None };
+        self.current = rand::random();
+        self.since += rotation * missed;
+    }
+
+    /// The secrets a server cookie is checked against, the previous one only within the grace window after the rotation
+    fn accepted(&self, now: Instant, grace: Duration) -> impl Iterator<Item = &[u8; 32]> {
+        let previous = self.previous.as_ref().filter(|_| now < self.since + grace);
+
+        std::iter::once(&self.current).chain(previous)
+    }
+}
+
+/// Give clients server cookies, replacing the secret every rotation and accepting the previous one for grace after,
+/// and with required set refuse UDP queries without a valid one, only the first call has any effect
+pub fn enable(rotation: Duration, grace: Duration, required: bool) {
+    if CONFIG.set(Config { rotation: rotation, grace: grace }).is_ok() {
+        *SECRETS.write().unwrap() = Some(Secrets::new(Instant::now()));
+        REQUIRED.store(required, Ordering::Relaxed);
+    }
+}
+
+/// Whether cookies are handed out
+pub fn is_enabled() -> bool {
+    CONFIG.get().is_some()
+}
+
+/// Whether UDP queries without a valid server cookie are refused
+pub fn is_required() -> bool {
+    REQUIRED.load(Ordering::Relaxed)
+}
+
+/// The COOKIE option of a query from a client, checking its server cookie, MISSING unless cookies are enabled
+pub fn check(req: &[u8], client: IpAddr) -> Cookie {
+    let config = match CONFIG.get() {
+        Some(x) => x,
+        None => return Cookie::MISSING,
+    };
+
+    let option = match find_option(req) {
+        Some(x) => x,
+        None => return Cookie::MISSING,
+    };
+    if option.len() != CLIENT_LEN && !(CLIENT_LEN + 8..=CLIENT_LEN + 32).contains(&option.len()) {
+        return Cookie::MALFORMED;
+    }
+
+    let mut client_cookie = [0; CLIENT_LEN];
+    client_cookie.copy_from_slice(&option[..CLIENT_LEN]);
+    let server_cookie = &option[CLIENT_LEN..];
+    if server_cookie.len() != SERVER_LEN || server_cookie[0] != VERSION {
+        return Cookie::UNVERIFIED(client_cookie);
+    }
+
+    let made = u32::from_be_bytes([server_cookie[4], server_cookie[5], server_cookie[6], server_cookie[7]]);
+    if made > unix_time().wrapping_add(MAX_FUTURE) {
+        return Cookie::UNVERIFIED(client_cookie);
+    }
+
+    let now = Instant::now();
+    rotate(now, config.rotation);
+
+    let secrets = SECRETS.read().unwrap();
+    let valid = secrets.as_ref()
+        .is_some_and(|x| x.accepted(now, config.grace).any(|secret| server_cookie == make(secret, &client_cookie, client, made)));
+
+    if valid {
+        Cookie::VALID(client_cookie)
+    } else {
+        Cookie::UNVERIFIED(client_cookie)
+    }
+}
+
+/// The OPT record answering a query's cookie, with a fresh server cookie for the client, None if it sent no cookie
+pub fn opt_record(cookie: Cookie, client: IpAddr) -> Option<DnsRecord> {
+    let client_cookie = match cookie {
+        Cookie::UNVERIFIED(x) | Cookie::VALID(x) => x,
+        Cookie::MISSING | Cookie::MALFORMED => return None,
+    };
+
+    let secret = SECRETS.read().unwrap().as_ref()?.current;
+    let server_cookie = make(&secret, &client_cookie, client, unix_time());
+
+    let mut data = Vec::with_capacity(4 + CLIENT_LEN + SERVER_LEN);
+    data.extend_from_slice(&OPTION_COOKIE.to_be_bytes());
+    data.extend_from_slice(&((CLIENT_LEN + SERVER_LEN) as u16).to_be_bytes());
+    data.extend_from_slice(&client_cookie);
+    data.extend_from_slice(&server_cookie);
+
+    Some(DnsRecord::OPT {
+        payload_size: LISTENER_PAYLOAD,
+        ext_rcode: 0,
+        version: 0,
+        flags: 0,
+        data: data,
+    })
+}
+
+/// Replace the secret if it's due, the write lock only taken when it is
+fn rotate(now: Instant, rotation: Duration) {
+    let due = SECRETS.read().unwrap().as_ref().is_some_and(|x| now.saturating_duration_since(x.since) >= rotation);
+    if due {
+        if let Some(secrets) = SECRETS.write().unwrap().as_mut() {
+            secrets.rotate(now, rotation);
+        }
+    }
+}
+
+/// A server cookie for a client cookie and address, made with a secret at a time
+fn make(secret: &[u8; 32], client_cookie: &[u8; CLIENT_LEN], client: IpAddr, made: u32) -> [u8; SERVER_LEN] {
+    let mut cookie = [0; SERVER_LEN];
+    cookie[0] = VERSION;
+    cookie[4..8].copy_from_slice(&made.to_be_bytes());
+
+    // an IPv4 client on a dual stack socket gets the same cookie as over IPv4
+    let client = match client {
+        IpAddr::V6(x) => x.to_ipv4_mapped().map_or(client, IpAddr::V4),
+        x => x,
+    };
+
+    let mut data = Vec::with_capacity(CLIENT_LEN + 8 + 16);
+    data.extend_from_slice(client_cookie);
+    data.extend_from_slice(&cookie[..8]);
+    match client {
+        IpAddr::V4(x) => data.extend_from_slice(&x.octets()),
+        IpAddr::V6(x) => data.extend_from_slice(&x.octets()),
+    }
+    cookie[8..].copy_from_slice(&hmac::hmac_sha256(secret, &data)[..8]);
+
+    cookie
+}
+
+fn unix_time() -> u32 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |x| x.as_secs() as u32)
+}
+
+/// The data of the first COOKIE option in a query's OPT record, None if there's no OPT record or it has none
+fn find_option(req: &[u8]) -> Option<&[u8]> {
+    let (_, data) = DnsPacket::peek_opt(req)?;
+
+    // options are a code, a length and that many bytes each
+    let mut i = 0;
+    while i + 4 <= data.len() {
+        let code = u16::from_be_bytes([data[i], data[i + 1]]);
+        let len = u16::from_be_bytes([data[i + 2], data[i + 3]]) as usize;
+        let value = data.get(i + 4..i + 4 + len)?;
+        if code == OPTION_COOKIE {
+            return Some(value);
+        }
+        i += 4 + len;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROTATION: Duration = Duration::from_secs(60);
+    const GRACE: Duration = Duration::from_secs(10);
+    const CLIENT_COOKIE: [u8; CLIENT_LEN] = [1, 2, 3, 4, 5, 6, 7, 8];
+
+    fn client() -> IpAddr {
+        IpAddr::from([192, 0, 2, 1])
+    }
+
+    /// Whether a server cookie made with a secret is accepted by the secrets at a time
+    fn accepted(secrets: &Secrets, now: Instant, cookie: &[u8; SERVER_LEN]) -> bool {
+        secrets.accepted(now, GRACE).any(|secret| make(secret, &CLIENT_COOKIE, client(), 1000) == *cookie)
+    }
+
+    #[test]
+    fn previous_secret_is_accepted_only_for_the_grace_after_a_rotation() {
+        let start = Instant::now();
+        let mut secrets = Secrets::new(start);
+        let first = make(&secrets.current, &CLIENT_COOKIE, client(), 1000);
+
+        // not due yet
+        secrets.rotate(start + ROTATION - Duration::from_secs(1), ROTATION);
+        assert!(secrets.previous.is_none());
+        assert!(accepted(&secrets, start + ROTATION - Duration::from_secs(1), &first));
+
+        // noticed late, but the grace counts from when the rotation was due
+        secrets.rotate(start + ROTATION + Duration::from_secs(5), ROTATION);
+        let second = make(&secrets.current, &CLIENT_COOKIE, client(), 1000);
+        assert_ne!(first, second);
+        assert!(accepted(&secrets, start + ROTATION + Duration::from_secs(9), &first));
+        assert!(!accepted(&secrets, start + ROTATION + GRACE, &first));
+        assert!(accepted(&secrets, start + ROTATION + GRACE, &second));
+
+        // nor is one made for another address
+        let other = make(&secrets.current, &CLIENT_COOKIE, IpAddr::from([198, 51, 100, 1]), 1000);
+        assert!(!accepted(&secrets, start + ROTATION, &other));
+    }
+
+    #[test]
+    fn missed_rotations_drop_the_previous_secret() {
+        let start = Instant::now();
+        let mut secrets = Secrets::new(start);
+        let first = make(&secrets.current, &CLIENT_COOKIE, client(), 1000);
+
+        // nothing asked for two and a half rotations
+        secrets.rotate(start + ROTATION * 5 / 2, ROTATION);
+        assert!(secrets.previous.is_none());
+        assert_eq!(secrets.since, start + ROTATION * 2);
+        assert!(!accepted(&secrets, start + ROTATION * 2, &first));
+
+        // the next rotation is due a whole rotation after the missed one was
+        secrets.rotate(start + ROTATION * 3, ROTATION);
+        assert!(secrets.previous.is_some());
+        assert_eq!(secrets.since, start + ROTATION * 3);
+    }
+}
@@ -0,0 +1,207 @@
+//! DNS64, RFC 6147: AAAA answers made up from A records for IPv6-only clients behind a NAT64
+//! When a name has A records but no AAAA, each IPv4 address is embedded in the last 32 bits of a /96 prefix,
+//! 64:ff9b::/96 unless another is configured, and answered as AAAA with the A record's TTL
+//! Addresses in private ranges aren't synthesized unless allowed, the NAT64 can't reach them anyway
+
+use std::net::{ Ipv4Addr, Ipv6Addr };
+use std::sync::OnceLock;
+
+use log::debug;
+
+use crate::dns_name;
+use crate::error::DnsError;
+use crate::idna;
+use crate::packet::{ DnsPacket, DnsRecord, QueryType, ResCode };
+
+type Error = DnsError;
+type Result<T> = std::result::Result<T, Error>;
+
+static DNS64: OnceLock<Dns64> = OnceLock::new();
+
+/// The well-known prefix, RFC 6052 section 2.1
+pub const WELL_KNOWN_PREFIX: Ipv6Addr = Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0);
+
+/// How AAAA records are synthesized
+#[derive(Copy, Clone, Debug)]
+pub struct Dns64 {
+    pub prefix: Ipv6Addr, // the /96 the IPv4 address goes after
+    pub allow_private: bool,
+}
+
+/// Turn on DNS64, only the first call has any effect
+pub fn set_dns64(dns64: Dns64) {
+    let _ = DNS64.set(dns64);
+}
+
+/// The DNS64 settings, None while it's off
+pub fn dns64() -> Option<&'static Dns64> {
+    DNS64.get()
+}
+
+/// Parse a prefix written as an IPv6 network of length 96, ex. 64:ff9b::/96
+/// The length may be left out, the last 32 bits must be zero
+pub fn parse_prefix(value: &str) -> Result<Ipv6Addr> {
+    let (addr, len) = match value.split_once('/') {
+        Some((addr, len)) => (addr, len),
+        None => (value, "96"),
+    };
+
+    if len != "96" {
+        return Err(DnsError::Config(format!("Only /96 prefixes are supported, not /{}", len)));
+    }
+    let prefix = addr.parse::<Ipv6Addr>().map_err(|e| DnsError::Config(format!("{}: {}", addr, e)))?;
+    if prefix.segments()[6..] != [0, 0] {
+        return Err(DnsError::Config(format!("{} has bits set past the first 96", prefix)));
+    }
+
+    Ok(prefix)
+}
+
+/// Embed an IPv4 address in a /96 prefix, RFC 6052 section 2.2
+pub fn embed(prefix: Ipv6Addr, addr: Ipv4Addr) -> Ipv6Addr {
+    let mut octets = prefix.octets();
+    octets[12..].copy_from_slice(&addr.octets());
+
+    Ipv6Addr::from(octets)
+}
+
+/// The name whose A records should be synthesized from, if DNS64 is on and an AAAA response has no AAAA records
+/// The end of the response's CNAME chain from qname, the chain itself stays in the answer
+pub fn wanted(res: &DnsPacket, qname: &str, q_type: QueryType) -> Option<String> {
+    dns64()?;
+
+    missing_aaaa(res, qname, q_type)
+}
+
+/// The end of the CNAME chain from qname when an AAAA response has no AAAA records
+fn missing_aaaa(res: &DnsPacket, qname: &str, q_type: QueryType) -> Option<String> {
+    if q_type != QueryType::AAAA || res.header.res_code != ResCode::NO_ERR {
+        return None;
+    }
+    if res.aaaa_records().next().is_some() {
+        return None;
+    }
+
+    let mut name = dns_name::normalize(qname);
+    for _ in 0..res.answers.len() {
+        match res.cname_records().find(|(domain, _, _)| dns_name::eq_ignore_case(domain, &name)) {
+            Some((_, host, _)) => name = dns_name::normalize(host),
+            None => break,
+        }
+    }
+
+    Some(name)
+}
+
+/// Complete an AAAA response without AAAA records from the A records of the name, looked up with lookup_a
+/// The response is returned unchanged when DNS64 is off, it isn't needed or there's nothing to synthesize from
+pub fn complete(res: DnsPacket, qname: &str, q_type: QueryType, lookup_a: impl FnOnce(&str) -> Result<DnsPacket>) -> Result<DnsPacket> {
+    let (Some(dns64), Some(name)) = (dns64(), wanted(&res, qname, q_type)) else {
+        return Ok(res);
+    };
+
+    synthesize(dns64, res, &name, lookup_a)
+}
+
+/// Add AAAA records made from the A records of name to a response
+fn synthesize(dns64: &Dns64, mut res: DnsPacket, name: &str, lookup_a: impl FnOnce(&str) -> Result<DnsPacket>) -> Result<DnsPacket> {
+    let a = lookup_a(name)?;
+    let synthesized: Vec<DnsRecord> = a.a_records()
+        .filter(|(domain, addr_v4, _)| dns_name::eq_ignore_case(domain, name) && (dns64.allow_private || !is_private(addr_v4)))
+        .map(|(domain, addr_v4, ttl)| DnsRecord::AAAA {
+            domain: domain.to_string(),
+            addr: embed(dns64.prefix, addr_v4),
+            ttl,
+        })
+        .collect();
+
+    if !synthesized.is_empty() {
+        debug!("Synthesized {} AAAA records for {} from its A records", synthesized.len(), idna::to_unicode(name));
+        res.answers.extend(synthesized);
+        // the SOA only said there was no AAAA
+        res.authorities.clear();
+        // made up here, so nothing a validator could have vouched for
+        res.header.auth_data = false;
+    }
+
+    Ok(res)
+}
+
+/// Addresses a NAT64 in front of the internet has no route to, RFC 6147 section 5.1.4
+fn is_private(addr: &Ipv4Addr) -> bool {
+    addr.is_private() || addr.is_loopback() || addr.is_link_local() || addr.is_unspecified() || addr.is_broadcast()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::DnsQuestion;
+
+    const DNS64: Dns64 = Dns64 { prefix: WELL_KNOWN_PREFIX, allow_private: false };
+
+    /// A response to name's query of a type, with the records given as answers, or the zone's SOA when there are none
+    fn response(name: &str, q_type: QueryType, answers: Vec<DnsRecord>) -> DnsPacket {
+        let mut query = DnsPacket::new();
+        query.questions.push(DnsQuestion::new(name.to_string(), q_type));
+        let mut res = DnsPacket::response_to(&query).question(query.questions[0].clone());
+
+        if answers.is_empty() {
+            res.authorities.push(DnsRecord::SOA {
+                domain: "example.com".to_string(),
+                m_name: "ns1.example.com".to_string(),
+                r_name: "hostmaster.example.com".to_string(),
+                serial: 1,
+                refresh: 3600,
+                retry: 600,
+                expire: 86400,
+                minimum: 300,
+                ttl: 300,
+            });
+        }
+        res.answers = answers;
+        res
+    }
+
+    fn a(name: &str, addr_v4: Ipv4Addr) -> DnsRecord {
+        DnsRecord::A { domain: name.to_string(), addr_v4: addr_v4, ttl: 120 }
+    }
+
+    fn aaaa(name: &str, addr: Ipv6Addr) -> DnsRecord {
+        DnsRecord::AAAA { domain: name.to_string(), addr: addr, ttl: 300 }
+    }
+
+    #[test]
+    fn a_only_name_gets_aaaa_made_up_from_its_a_records() {
+        let res = response("v4only.example.com", QueryType::AAAA, Vec::new());
+        assert_eq!(missing_aaaa(&res, "v4only.example.com", QueryType::AAAA).as_deref(), Some("v4only.example.com"));
+        let res = synthesize(&DNS64, res, "v4only.example.com", |name| {
+            assert_eq!(name, "v4only.example.com");
+            Ok(response(name, QueryType::A, vec![a(name, Ipv4Addr::new(192, 0, 2, 33)), a(name, Ipv4Addr::new(10, 0, 0, 1))]))
+        }).unwrap();
+
+        // the private address is left out, and the A record's TTL kept
+        assert_eq!(res.answers, [DnsRecord::AAAA { domain: "v4only.example.com".to_string(), addr: "64:ff9b::c000:221".parse().unwrap(), ttl: 120 }]);
+        assert!(res.authorities.is_empty());
+    }
+
+    #[test]
+    fn name_with_both_keeps_its_own_aaaa() {
+        let own = aaaa("dual.example.com", "2001:db8::1".parse().unwrap());
+        let res = response("dual.example.com", QueryType::AAAA, vec![own.clone()]);
+
+        // nothing to synthesize, so no A lookup
+        assert_eq!(missing_aaaa(&res, "dual.example.com", QueryType::AAAA), None);
+        assert_eq!(res.answers, [own]);
+    }
+
+    #[test]
+    fn name_with_neither_stays_empty() {
+        let res = response("none.example.com", QueryType::AAAA, Vec::new());
+        assert_eq!(missing_aaaa(&res, "none.example.com", QueryType::AAAA).as_deref(), Some("none.example.com"));
+        let res = synthesize(&DNS64, res, "none.example.com", |name| Ok(response(name, QueryType::A, Vec::new()))).unwrap();
+
+        assert!(res.answers.is_empty());
+        // still says there's no AAAA
+        assert!(matches!(res.authorities[..], [DnsRecord::SOA { .. }]));
+    }
+}
@@ -0,0 +1,164 @@
+//! Canonical handling of domain names
+//! Names arrive in mixed case, with or without a trailing dot and sometimes with
+//! presentation format escapes, so all comparisons should go through here
+
+use crate::error::DnsError;
+
+type Result<T> = std::result::Result<T, DnsError>;
+
+// RFC 1035 - max DNS label length of 63 chars and 253 chars of dotted text for the whole name
+const MAX_LABEL_LEN: usize = 63;
+const MAX_NAME_LEN: usize = 253;
+
+/// Canonical form of a name used for comparisons and cache keys
+/// Lowercases ASCII (RFC 4343), strips a single trailing dot and
+/// decodes \DDD and \X escapes other than an escaped dot, which stays part of its label
+/// ex. "Example.COM." becomes "example.com"
+pub fn normalize(name: &str) -> String {
+    let name = strip_root(name);
+
+    if !name.contains('\\') {
+        return name.to_ascii_lowercase();
+    }
+
+    let mut out = String::with_capacity(name.len());
+    let mut chars = name.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c.to_ascii_lowercase());
+            continue;
+        }
+
+        match chars.next() {
+            // \DDD decimal escape
+            Some(d) if d.is_ascii_digit() => {
+                let mut value = d as u32 - '0' as u32;
+                for _ in 0..2 {
+                    match chars.peek() {
+                        Some(x) if x.is_ascii_digit() => {
+                            value = value * 10 + (*x as u32 - '0' as u32);
+                            chars.next();
+                        }
+                        _ => break,
+                    }
+                }
+                match char::from_u32(value) {
+                    Some('.') => out.push_str("\\."),
+                    Some('\\') => out.push_str("\\\\"),
+                    Some(x) if value <= 0xFF => out.push(x.to_ascii_lowercase()),
+                    _ => out.push('\\'),
+                }
+            }
+            // an escaped dot is a literal dot inside a label, it and backslashes stay
+            // escaped so normalizing twice gives the same result
+            Some('.') => out.push_str("\\."),
+            Some('\\') => out.push_str("\\\\"),
+            Some(x) => out.push(x.to_ascii_lowercase()),
+            None => out.push('\\'),
+        }
+    }
+
+    out
+}
+
+/// Compare two names ignoring case and a trailing dot
+/// ex. eq_ignore_case("Example.COM.", "example.com") is true
+pub fn eq_ignore_case(a: &str, b: &str) -> bool {
+    if a.contains('\\') || b.contains('\\') {
+        return normalize(a) == normalize(b);
+    }
+
+    strip_root(a).eq_ignore_ascii_case(strip_root(b))
+}
+
+/// Check whether a name is the zone itself or inside it, comparing whole labels ignoring case
+/// ex. is_subdomain("www.Corp.internal.", "corp.internal") is true, is_subdomain("notcorp.internal", "corp.internal") is false
+/// Every name is inside the root zone ("" or ".")
+pub fn is_subdomain(name: &str, zone: &str) -> bool {
+    let name = normalize(name);
+    let zone = normalize(zone);
+
+    if zone.is_empty() || name == zone {
+        return true;
+    }
+
+    match name.strip_suffix(zone.as_str()).and_then(|x| x.strip_suffix('.')) {
+        // the dot must end a label rather than be escaped inside one
+        Some(rest) => rest.chars().rev().take_while(|x| *x == '\\').count() % 2 == 0,
+        None => false,
+    }
+}
+
+/// Check that every label is 1 to 63 characters and the whole name fits on the wire
+/// The root name ("" or ".") is valid
+pub fn validate(name: &str) -> Result<()> {
+    let name = strip_root(name);
+    if name.is_empty() {
+        return Ok(());
+    }
+
+    if name.len() > MAX_NAME_LEN {
+        return Err(DnsError::NameTooLong);
+    }
+
+    for label in name.split('.') {
+        if label.is_empty() {
+            return Err(DnsError::EmptyLabel(name.to_string()));
+        }
+        if label.len() > MAX_LABEL_LEN {
+            return Err(DnsError::LabelTooLong);
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove a single trailing dot, unless it is escaped
+fn strip_root(name: &str) -> &str {
+    match name.strip_suffix('.') {
+        Some(x) if !x.ends_with('\\') => x,
+        _ => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAMES: [&str; 8] = ["Example.COM.", "example.com", ".", "", "WWW.example.com", "a\\.b.Example", "a\\\\.b", "\\065BC.example"];
+
+    #[test]
+    fn normalize_is_idempotent() {
+        for name in NAMES {
+            let once = normalize(name);
+            assert_eq!(normalize(&once), once, "{}", name);
+        }
+    }
+
+    #[test]
+    fn equal_ignoring_case_and_root() {
+        assert_eq!(normalize("Example.COM."), "example.com");
+        assert!(eq_ignore_case("Example.COM.", "example.com"));
+        assert!(eq_ignore_case(".", ""));
+        assert!(eq_ignore_case("\\065bc.example", "abc.EXAMPLE."));
+        assert!(!eq_ignore_case("a\\.b.example", "a.b.example"));
+        assert!(!eq_ignore_case("example.com", "example.co"));
+
+        for a in NAMES {
+            for b in NAMES {
+                assert_eq!(eq_ignore_case(a, b), normalize(a) == normalize(b), "{} {}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn subdomains_by_whole_labels() {
+        assert!(is_subdomain("www.Corp.internal.", "corp.internal"));
+        assert!(is_subdomain("corp.internal", "corp.internal."));
+        assert!(is_subdomain("anything", "."));
+        assert!(!is_subdomain("notcorp.internal", "corp.internal"));
+        // the escaped dot is inside the label a.corp
+        assert!(!is_subdomain("a\\.corp.internal", "corp.internal"));
+    }
+}
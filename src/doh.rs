@@ -0,0 +1,390 @@
+//! DNS over HTTPS endpoint, RFC 8484
+//! A small HTTP/1.1 server answering wire format queries on /dns-query
+//! sent either as a POST body or base64url encoded in a GET query string,
+//! and JSON queries on /resolve?name=example.com&type=A
+//! Served as plain HTTP (for use behind a TLS proxy) or wrapped in TLS with the "tls" feature
+
+use std::io::{ BufRead, BufReader, Read, Write };
+use std::net::{ IpAddr, SocketAddr, TcpListener, TcpStream };
+use std::thread;
+
+use log::{ error, trace };
+
+use crate::base64;
+use crate::error::DnsError;
+use crate::json;
+use crate::packet::{ DnsPacket, DnsQuestion, QueryType };
+use crate::padding;
+use crate::resolve::{ self, Resolution };
+use crate::transport::{ self, Transport };
+
+type Result<T> = std::result::Result<T, DnsError>;
+
+/// Path wire format queries are served on
+pub const DOH_PATH: &str = "/dns-query";
+
+/// Path JSON queries are served on
+pub const JSON_PATH: &str = "/resolve";
+
+// Media type of wire format DNS messages
+const DNS_MESSAGE: &str = "application/dns-message";
+
+// Media type of JSON DNS messages
+const DNS_JSON: &str = "application/dns-json";
+
+// Request line and headers together may not exceed this
+const MAX_HEAD_LEN: usize = 8192;
+
+/// A parsed HTTP request
+struct Request {
+    method: String,
+    target: String,
+    headers: Vec<(String, String)>,
+    keep_alive: bool,
+}
+
+/// An HTTP response waiting to be written
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    max_age: Option<u32>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    /// Value of a header, matched case insensitively
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Path without the query string
+    fn path(&self) -> &str {
+        match self.target.split_once('?') {
+            Some((path, _)) => path,
+            None => &self.target,
+        }
+    }
+
+    /// Value of a query string parameter
+    fn param(&self, name: &str) -> Option<&str> {
+        let (_, query) = self.target.split_once('?')?;
+
+        query.split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+}
+
+impl Response {
+    /// A successful response that may be cached for max_age seconds
+    fn ok(content_type: &'static str, body: Vec<u8>, max_age: u32) -> Response {
+        Response {
+            status: 200,
+            content_type: content_type,
+            max_age: Some(max_age),
+            body: body,
+        }
+    }
+
+    /// A plain text error response
+    fn error(status: u16, msg: &str) -> Response {
+        Response {
+            status: status,
+            content_type: "text/plain",
+            max_age: None,
+            body: format!("{}\n", msg).into_bytes(),
+        }
+    }
+
+    fn write(&self, stream: &mut impl Write, keep_alive: bool) -> Result<()> {
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\ncontent-type: {}\r\ncontent-length: {}\r\n",
+            self.status, reason(self.status), self.content_type, self.body.len()
+        );
+        if let Some(max_age) = self.max_age {
+            head.push_str(&format!("cache-control: max-age={}\r\n", max_age));
+        }
+        if self.status == 405 {
+            head.push_str("allow: GET, POST\r\n");
+        }
+        if !keep_alive {
+            head.push_str("connection: close\r\n");
+        }
+        head.push_str("\r\n");
+
+        let mut out = head.into_bytes();
+        out.extend_from_slice(&self.body);
+
+        stream.write_all(&out)?;
+        stream.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Accept plain HTTP connections, serving each on its own thread
+pub fn serve_http(listener: TcpListener, resolution: Resolution) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                thread::spawn(move || {
+                    if let Err(e) = handle_http_connection(stream, &resolution) {
+                        error!("An error occurred on an HTTP connection: {}", e);
+                    }
+                });
+            }
+            Err(e) => error!("Failed to accept HTTP connection: {}", e),
+        }
+    }
+}
+
+fn handle_http_connection(mut stream: TcpStream, resolution: &Resolution) -> Result<()> {
+    let peer = stream.peer_addr()?;
+    transport::set_idle_timeout(&stream)?;
+
+    serve_connection(&mut stream, peer, resolution)
+}
+
+/// Answer HTTP requests on any stream, plain TCP or wrapped in TLS
+/// Connections are kept alive between requests unless the client asks otherwise
+pub fn serve_connection<S: Read + Write>(stream: &mut S, peer: SocketAddr, resolution: &Resolution) -> Result<()> {
+    let mut reader = BufReader::new(stream);
+
+    loop {
+        let req = match read_head(&mut reader) {
+            Ok(Some(x)) => x,
+            Ok(None) => return Ok(()),
+            Err(e) if transport::is_closed(&e) => return Ok(()),
+            Err(e) => {
+                // the rest of the stream can't be trusted after a bad head, answer and hang up
+                let res = Response::error(400, &e.to_string());
+                return res.write(reader.get_mut(), false);
+            }
+        };
+
+        trace!("Received {} {} over HTTP from {}", req.method, req.path(), peer);
+
+        let result = if req.path() == JSON_PATH {
+            answer_json(&req, peer.ip(), resolution)
+        } else {
+            read_query(&mut reader, &req).map(|query| answer(&query, peer.ip(), resolution))
+        };
+
+        let (res, keep_alive) = match result {
+            Ok(res) => (res, req.keep_alive),
+            // an unread body may still be on the stream so the connection can't be reused
+            Err(res) => (res, false),
+        };
+
+        res.write(reader.get_mut(), keep_alive)?;
+
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+/// Read the request line and headers
+/// Returns None if the client closed the connection before sending anything
+fn read_head<R: BufRead>(reader: &mut R) -> Result<Option<Request>> {
+    let mut lines = Vec::new();
+    let mut total = 0;
+
+    loop {
+        let mut line = String::new();
+        let n = reader.by_ref().take((MAX_HEAD_LEN - total) as u64).read_line(&mut line)?;
+
+        if n == 0 {
+            if lines.is_empty() && total == 0 {
+                return Ok(None);
+            }
+            return Err(DnsError::Http("Request head is too long or incomplete".to_string()));
+        }
+        total += n;
+
+        let line = line.trim_end_matches(&['\r', '\n'][..]).to_string();
+        if line.is_empty() {
+            // tolerate blank lines before the request line, RFC 9112 section 2.2
+            if lines.is_empty() {
+                continue;
+            }
+            break;
+        }
+        lines.push(line);
+    }
+
+    let mut request_line = lines[0].split(' ');
+    let (method, target, version) = match (request_line.next(), request_line.next(), request_line.next()) {
+        (Some(m), Some(t), Some(v)) if v.starts_with("HTTP/1.") => (m, t, v),
+        _ => return Err(DnsError::Http(format!("Malformed request line: {}", lines[0]))),
+    };
+
+    let mut headers = Vec::new();
+    for line in &lines[1..] {
+        match line.split_once(':') {
+            Some((key, value)) => headers.push((key.trim().to_string(), value.trim().to_string())),
+            None => return Err(DnsError::Http(format!("Malformed header: {}", line))),
+        }
+    }
+
+    let mut req = Request {
+        method: method.to_string(),
+        target: target.to_string(),
+        headers: headers,
+        keep_alive: version != "HTTP/1.0",
+    };
+
+    if let Some(conn) = req.header("connection") {
+        if conn.eq_ignore_ascii_case("close") {
+            req.keep_alive = false;
+        } else if conn.eq_ignore_ascii_case("keep-alive") {
+            req.keep_alive = true;
+        }
+    }
+
+    Ok(Some(req))
+}
+
+/// Pull the wire format query out of a request, or the error response to send instead
+fn read_query<R: BufRead>(reader: &mut R, req: &Request) -> std::result::Result<Vec<u8>, Response> {
+    if req.path() != DOH_PATH {
+        return Err(Response::error(404, "Not found"));
+    }
+
+    let query = match req.method.as_str() {
+        "GET" => {
+            let encoded = req.param("dns")
+                .ok_or_else(|| Response::error(400, "Missing dns parameter"))?;
+
+            base64::decode_url(encoded)
+                .map_err(|e| Response::error(400, &e.to_string()))?
+        }
+        "POST" => {
+            let content_type = req.header("content-type").unwrap_or("");
+            let media_type = content_type.split(';').next().unwrap_or("").trim();
+            if !media_type.eq_ignore_ascii_case(DNS_MESSAGE) {
+                return Err(Response::error(415, "Content type must be application/dns-message"));
+            }
+
+            if req.header("transfer-encoding").is_some() {
+                return Err(Response::error(411, "Chunked bodies are not supported, send a content-length"));
+            }
+
+            let len = req.header("content-length")
+                .ok_or_else(|| Response::error(411, "Missing content-length"))?
+                .parse::<usize>()
+                .map_err(|_| Response::error(400, "Invalid content-length"))?;
+
+            if len > transport::MAX_TCP_MESSAGE {
+                return Err(Response::error(413, "DNS message too large"));
+            }
+
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body)
+                .map_err(|_| Response::error(400, "Request body ended early"))?;
+
+            body
+        }
+        _ => return Err(Response::error(405, "Method not allowed")),
+    };
+
+    if query.len() < 12 {
+        return Err(Response::error(400, "Query is shorter than a DNS header"));
+    }
+    if query.len() > transport::MAX_TCP_MESSAGE {
+        return Err(Response::error(413, "DNS message too large"));
+    }
+
+    Ok(query)
+}
+
+/// Resolve a wire format query into an HTTP response
+/// Responses may be cached for as long as the shortest answer TTL, RFC 8484 section 5.1
+fn answer(query: &[u8], client: IpAddr, resolution: &Resolution) -> Response {
+    let res = match resolve::handle_query_bytes(query, client, resolution, Transport::HTTPS) {
+        Ok(x) => x,
+        Err(e) => return Response::error(400, &e.to_string()),
+    };
+
+    let max_age = match DnsPacket::from_bytes(&res) {
+        Ok(packet) => min_ttl(&packet),
+        Err(_) => 0,
+    };
+
+    Response::ok(DNS_MESSAGE, padding::pad_response(query, res), max_age)
+}
+
+/// Resolve a /resolve?name=<name>&type=<type> query into a JSON response
+/// The type may be a mnemonic or a number and defaults to A
+fn answer_json(req: &Request, client: IpAddr, resolution: &Resolution) -> std::result::Result<Response, Response> {
+    if req.method != "GET" {
+        return Err(Response::error(405, "Method not allowed"));
+    }
+
+    let name = req.param("name")
+        .and_then(percent_decode)
+        .ok_or_else(|| Response::error(400, "Missing or invalid name parameter"))?;
+
+    let q_type = match req.param("type") {
+        Some(x) => QueryType::from_name(x).ok_or_else(|| Response::error(400, "Unknown type"))?,
+        None => QueryType::A,
+    };
+
+    let mut query = DnsPacket::new();
+    query.header.id = rand::random();
+    query.header.query_res = false;
+    query.header.rec_des = true;
+    query.questions.push(DnsQuestion::new(name, q_type));
+
+    let bytes = query.to_bytes().map_err(|e| Response::error(400, &e.to_string()))?;
+    let res = resolve::handle_query_bytes(&bytes, client, resolution, Transport::HTTPS)
+        .and_then(|x| DnsPacket::from_bytes(&x))
+        .map_err(|e| Response::error(500, &e.to_string()))?;
+
+    let body = json::packet_to_json(&res).into_bytes();
+
+    Ok(Response::ok(DNS_JSON, body, min_ttl(&res)))
+}
+
+/// Shortest TTL in the answer section, or 0 if there are no answers
+fn min_ttl(packet: &DnsPacket) -> u32 {
+    packet.answers.iter().map(|x| x.ttl()).min().unwrap_or(0)
+}
+
+/// Decode %XX escapes in a query string value
+fn percent_decode(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(out).ok()
+}
+
+/// Reason phrase for the status codes used here
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        500 => "Internal Server Error",
+        _ => "Error",
+    }
+}
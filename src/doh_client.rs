@@ -0,0 +1,261 @@
+//! DNS over HTTPS upstream, RFC 8484
+//! Queries are POSTed in wire format over HTTP/1.1 and the wire format body of the response is returned
+//! Connections are kept open after a query so the next one skips the TCP and TLS handshakes
+//! Only built with the "tls" feature
+
+use std::fmt;
+use std::io::{ self, BufRead, BufReader, Read, Write };
+use std::net::SocketAddr;
+use std::sync::{ Arc, Mutex };
+use std::time::Duration;
+
+use rustls::{ ClientConfig, ServerName };
+
+use crate::error::DnsError;
+use crate::tls::{ self, ClientStream };
+use crate::transport;
+
+type Result<T> = std::result::Result<T, DnsError>;
+
+const HTTPS_PORT: u16 = 443;
+const DEFAULT_PATH: &str = "/dns-query";
+const DNS_MESSAGE: &str = "application/dns-message";
+
+// Longest status line and headers accepted from the server
+const MAX_HEAD_LEN: usize = 8192;
+
+// Idle connections kept per upstream, extra ones are closed
+const MAX_IDLE: usize = 4;
+
+/// An upstream reached at an https:// URL
+pub struct HttpsUpstream {
+    url: String,
+    addr: SocketAddr,
+    authority: String, // sent as the Host header
+    path: String,
+    server_name: ServerName,
+    config: Arc<ClientConfig>,
+    idle: Mutex<Vec<BufReader<ClientStream>>>,
+}
+
+impl HttpsUpstream {
+    /// Parse an https://host[:port][/path] URL
+    /// A hostname is resolved once through the bootstrap resolver, IP addresses are used as they are
+    pub fn parse(url: &str, bootstrap: Option<SocketAddr>) -> Result<HttpsUpstream> {
+        let rest = url.strip_prefix("https://").ok_or_else(|| DnsError::Config("URL must start with https://".to_string()))?;
+
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, DEFAULT_PATH),
+        };
+        let (host, port) = tls::split_authority(authority, HTTPS_PORT)?;
+        let addr = tls::resolve_upstream(host, port, bootstrap)?;
+
+        let server_name = ServerName::try_from(host).map_err(|_| DnsError::Config(format!("Invalid server name {}", host)))?;
+
+        Ok(HttpsUpstream {
+            url: url.to_string(),
+            addr: addr,
+            authority: authority.to_string(),
+            path: path.to_string(),
+            server_name: server_name,
+            config: tls::client_config(tls::HTTP_ALPN),
+            idle: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Address the URL's host was resolved to
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Send a query and return the server's wire format response
+    pub fn exchange(&self, query: &[u8], timeout: Duration) -> Result<Vec<u8>> {
+        // the server may have closed an idle connection since it was last used, so a reused
+        // connection found closed is replaced with a new one
+        if let Some(mut conn) = self.take_idle() {
+            match self.request(&mut conn, query) {
+                Ok((res, keep_alive)) => {
+                    if keep_alive {
+                        self.keep_idle(conn);
+                    }
+                    return Ok(res);
+                }
+                Err(e) if is_stale(&e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut conn = BufReader::new(tls::connect(&self.addr, &self.server_name, self.config.clone(), timeout)?);
+
+        let (res, keep_alive) = self.request(&mut conn, query)?;
+        if keep_alive {
+            self.keep_idle(conn);
+        }
+
+        Ok(res)
+    }
+
+    /// POST a query on a connection, returning the response body and whether the connection can be reused
+    fn request(&self, conn: &mut BufReader<ClientStream>, query: &[u8]) -> Result<(Vec<u8>, bool)> {
+        let head = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\nAccept: {}\r\nContent-Length: {}\r\n\r\n",
+            self.path, self.authority, DNS_MESSAGE, DNS_MESSAGE, query.len()
+        );
+
+        // head and body in one write so they go out in the same TLS record
+        let mut req = head.into_bytes();
+        req.extend_from_slice(query);
+
+        let stream = conn.get_mut();
+        stream.write_all(&req)?;
+        stream.flush()?;
+
+        let mut budget = MAX_HEAD_LEN;
+        let status_line = read_line(conn, &mut budget)?;
+
+        let mut parts = status_line.split_whitespace();
+        let version = parts.next().unwrap_or("");
+        let status = parts.next()
+            .and_then(|x| x.parse::<u16>().ok())
+            .ok_or_else(|| DnsError::Http(format!("Invalid status line from {}: {}", self.url, status_line)))?;
+
+        let mut keep_alive = version == "HTTP/1.1";
+        let mut content_length = None;
+        let mut chunked = false;
+        let mut content_type = String::new();
+
+        loop {
+            let line = read_line(conn, &mut budget)?;
+            if line.is_empty() {
+                break;
+            }
+
+            let (name, value) = match line.split_once(':') {
+                Some((name, value)) => (name.trim().to_ascii_lowercase(), value.trim()),
+                None => return Err(DnsError::Http(format!("Invalid header from {}: {}", self.url, line))),
+            };
+
+            match name.as_str() {
+                "content-length" => content_length = Some(value.parse::<usize>().map_err(|_| DnsError::Http(format!("Invalid content-length from {}: {}", self.url, value)))?),
+                "transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+                "connection" => keep_alive = !value.eq_ignore_ascii_case("close"),
+                "content-type" => content_type = value.to_ascii_lowercase(),
+                _ => {}
+            }
+        }
+
+        let body = if chunked {
+            read_chunked(conn)?
+        } else if let Some(len) = content_length {
+            if len > transport::MAX_TCP_MESSAGE {
+                return Err(DnsError::Http(format!("Response of {} bytes from {} is too large", len, self.url)));
+            }
+            let mut body = vec![0; len];
+            conn.read_exact(&mut body)?;
+            body
+        } else {
+            // without a length the body runs until the server closes the connection
+            keep_alive = false;
+            let mut body = Vec::new();
+            conn.by_ref().take(transport::MAX_TCP_MESSAGE as u64 + 1).read_to_end(&mut body)?;
+            body
+        };
+
+        if status != 200 {
+            return Err(DnsError::Http(format!("{} answered with HTTP status {}", self.url, status)));
+        }
+        if !content_type.starts_with(DNS_MESSAGE) {
+            return Err(DnsError::Http(format!("{} answered with content type {:?} instead of {}", self.url, content_type, DNS_MESSAGE)));
+        }
+        if body.len() > transport::MAX_TCP_MESSAGE {
+            return Err(DnsError::Http(format!("Response from {} is too large", self.url)));
+        }
+
+        Ok((body, keep_alive))
+    }
+
+    fn take_idle(&self) -> Option<BufReader<ClientStream>> {
+        match self.idle.lock() {
+            Ok(mut idle) => idle.pop(),
+            Err(_) => None,
+        }
+    }
+
+    fn keep_idle(&self, conn: BufReader<ClientStream>) {
+        if let Ok(mut idle) = self.idle.lock() {
+            if idle.len() < MAX_IDLE {
+                idle.push(conn);
+            }
+        }
+    }
+}
+
+impl fmt::Display for HttpsUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.url)
+    }
+}
+
+impl fmt::Debug for HttpsUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HttpsUpstream({} at {})", self.url, self.addr)
+    }
+}
+
+/// True if an error means the server had already closed the connection
+fn is_stale(e: &DnsError) -> bool {
+    match e {
+        DnsError::UnexpectedEof => true,
+        DnsError::Io(x) => matches!(x.kind(),
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe),
+        _ => false,
+    }
+}
+
+/// Read a CRLF terminated line without the line ending, taking its length out of the budget
+fn read_line<R: BufRead>(reader: &mut R, budget: &mut usize) -> Result<String> {
+    let mut line = String::new();
+    let n = reader.by_ref().take(*budget as u64).read_line(&mut line)?;
+
+    if n == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed before the response was complete").into());
+    }
+    if !line.ends_with('\n') {
+        return Err(DnsError::Http("Response head is too long".to_string()));
+    }
+    *budget -= n;
+
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+/// Read a chunked body, RFC 9112 section 7.1
+fn read_chunked<R: BufRead>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    let mut budget = MAX_HEAD_LEN;
+
+    loop {
+        let line = read_line(reader, &mut budget)?;
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| DnsError::Http(format!("Invalid chunk size: {}", line)))?;
+
+        if size == 0 {
+            // skip any trailer fields up to the blank line ending the body
+            while !read_line(reader, &mut budget)?.is_empty() {}
+            return Ok(body);
+        }
+        if body.len() + size > transport::MAX_TCP_MESSAGE {
+            return Err(DnsError::Http("Chunked response is too large".to_string()));
+        }
+
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..])?;
+
+        // the CRLF after each chunk
+        read_line(reader, &mut budget)?;
+    }
+}
@@ -0,0 +1,302 @@
+//! DNS over TLS upstream, RFC 7858
+//! Every query shares one long lived connection, each sent under an id unique on that connection,
+//! and a reader thread hands responses back by id since the server may answer out of order
+//! A closed or broken connection is replaced by a new one on the next query
+//! Only built with the "tls" feature
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{ self, Read, Write };
+use std::net::{ Shutdown, SocketAddr, TcpStream };
+use std::sync::{ mpsc, Arc, Mutex };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::thread;
+use std::time::Duration;
+
+use log::{ error, warn };
+use rustls::{ ClientConfig, ClientConnection, ServerName, StreamOwned };
+
+use crate::error::DnsError;
+use crate::tls;
+use crate::transport;
+
+type Result<T> = std::result::Result<T, DnsError>;
+
+/// An upstream reached at a tls://ip[:port][#name] address
+pub struct TlsUpstream {
+    url: String,
+    addr: SocketAddr,
+    server_name: ServerName,
+    config: Arc<ClientConfig>,
+    fallback: Option<SocketAddr>, // plain resolver used when no TLS session can be set up
+    conn: Mutex<Option<Arc<Connection>>>,
+}
+
+impl TlsUpstream {
+    /// Parse a tls://host[:port][#name] address, the certificate is checked against the name after #
+    /// or the host when there is none
+    /// A hostname is resolved once through the bootstrap resolver, IP addresses are used as they are
+    pub fn parse(url: &str, bootstrap: Option<SocketAddr>, fallback: Option<SocketAddr>) -> Result<TlsUpstream> {
+        let rest = url.strip_prefix("tls://").ok_or_else(|| DnsError::Config("Address must start with tls://".to_string()))?;
+
+        let (authority, name) = match rest.split_once('#') {
+            Some((authority, name)) => (authority, Some(name)),
+            None => (rest, None),
+        };
+
+        let (host, port) = tls::split_authority(authority, tls::DOT_PORT)?;
+        let addr = tls::resolve_upstream(host, port, bootstrap)?;
+
+        let name = name.unwrap_or(host);
+        let server_name = ServerName::try_from(name).map_err(|_| DnsError::Config(format!("Invalid server name {}", name)))?;
+
+        Ok(TlsUpstream {
+            url: url.to_string(),
+            addr: addr,
+            server_name: server_name,
+            config: tls::client_config(tls::DOT_ALPN),
+            fallback: fallback,
+            conn: Mutex::new(None),
+        })
+    }
+
+    /// Address the upstream was resolved to
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Send a query and return the server's response
+    pub fn exchange(&self, query: &[u8], timeout: Duration) -> Result<Vec<u8>> {
+        if query.len() < 12 {
+            return Err(DnsError::BufferOverrun { pos: query.len(), needed: 12 - query.len() });
+        }
+
+        let (conn, reused) = match self.connection(timeout) {
+            Ok(x) => x,
+            Err(e) => match self.fallback {
+                // plain DNS is better than no answer when it has been allowed
+                Some(addr) => {
+                    warn!("No TLS session with {}: {}, falling back to {} over TCP", self, e, addr);
+                    return transport::exchange_tcp(query, &addr, timeout);
+                }
+                None => return Err(DnsError::NoTlsSession { upstream: self.to_string(), failure: Box::new(e) }),
+            },
+        };
+
+        match conn.query(query, &self.url, timeout) {
+            Ok(x) => Ok(x),
+            // the server may have closed a connection that sat idle, so try once more on a new one
+            Err(_) if reused && conn.is_closed() => {
+                let (conn, _) = self.connection(timeout)?;
+                conn.query(query, &self.url, timeout)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The open connection, or a new one if there is none
+    /// Also returns whether the connection was already open
+    fn connection(&self, timeout: Duration) -> Result<(Arc<Connection>, bool)> {
+        // held while connecting so concurrent queries wait for one connection instead of racing to open several
+        let mut current = self.conn.lock().map_err(|_| DnsError::LockPoisoned("Connection"))?;
+
+        if let Some(conn) = current.as_ref() {
+            if !conn.is_closed() {
+                return Ok((conn.clone(), true));
+            }
+        }
+
+        let conn = Connection::open(&self.addr, &self.server_name, self.config.clone(), timeout)?;
+        *current = Some(conn.clone());
+
+        Ok((conn, false))
+    }
+}
+
+impl fmt::Display for TlsUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.url)
+    }
+}
+
+impl fmt::Debug for TlsUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TlsUpstream({} at {})", self.url, self.addr)
+    }
+}
+
+/// One TLS connection shared by every query in flight
+/// Writers lock the TLS state to encrypt and send, the reader thread locks it to decrypt what arrives
+struct Connection {
+    tls: Mutex<ClientConnection>,
+    sock: TcpStream,
+    pending: Mutex<HashMap<u16, mpsc::Sender<Vec<u8>>>>, // queries waiting on a response, by id
+    closed: AtomicBool,
+}
+
+impl Connection {
+    /// Connect and complete the handshake, then start the reader thread
+    fn open(addr: &SocketAddr, server_name: &ServerName, config: Arc<ClientConfig>, timeout: Duration) -> Result<Arc<Connection>> {
+        let StreamOwned { conn: tls, sock } = tls::connect(addr, server_name, config, timeout)?;
+
+        // the reader waits for as long as the server keeps the connection open
+        sock.set_read_timeout(None)?;
+        let reader = sock.try_clone()?;
+
+        let conn = Arc::new(Connection {
+            tls: Mutex::new(tls),
+            sock: sock,
+            pending: Mutex::new(HashMap::new()),
+            closed: AtomicBool::new(false),
+        });
+
+        let shared = conn.clone();
+        thread::spawn(move || {
+            if let Err(e) = shared.read_responses(reader) {
+                if !transport::is_closed(&e) {
+                    error!("DNS over TLS connection failed: {}", e);
+                }
+            }
+            shared.close();
+        });
+
+        Ok(conn)
+    }
+
+    /// Send a query under an id free on this connection and wait for its response, naming the upstream if it doesn't come
+    fn query(&self, query: &[u8], upstream: &str, timeout: Duration) -> Result<Vec<u8>> {
+        let (sender, receiver) = mpsc::channel();
+
+        let id = {
+            let mut pending = self.pending.lock().map_err(|_| DnsError::LockPoisoned("Pending queries"))?;
+            let id = loop {
+                let id = rand::random::<u16>();
+                if !pending.contains_key(&id) {
+                    break id;
+                }
+            };
+            pending.insert(id, sender);
+            id
+        };
+
+        let mut msg = query.to_vec();
+        msg[0..2].copy_from_slice(&id.to_be_bytes());
+
+        if let Err(e) = self.send(&msg) {
+            self.close();
+            return Err(e);
+        }
+
+        match receiver.recv_timeout(timeout) {
+            Ok(mut res) => {
+                // answer under the id the query was asked with
+                res[0..2].copy_from_slice(&query[0..2]);
+                Ok(res)
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                if let Ok(mut pending) = self.pending.lock() {
+                    pending.remove(&id);
+                }
+                Err(DnsError::UpstreamTimeout { upstream: upstream.to_string(), attempts: 1 })
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(DnsError::ConnectionClosed(upstream.to_string())),
+        }
+    }
+
+    /// Encrypt and send one length prefixed message
+    fn send(&self, msg: &[u8]) -> Result<()> {
+        let framed = transport::frame_message(msg)?;
+        let mut tls = self.tls.lock().map_err(|_| DnsError::LockPoisoned("TLS"))?;
+
+        tls.writer().write_all(&framed)?;
+        while tls.wants_write() {
+            tls.write_tls(&mut &self.sock)?;
+        }
+
+        Ok(())
+    }
+
+    /// Decrypt everything the server sends, passing each response to the query waiting on its id
+    /// Returns once the connection closes
+    fn read_responses(&self, mut reader: TcpStream) -> Result<()> {
+        let mut buf = [0; 16384];
+        let mut plain: Vec<u8> = Vec::new();
+
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                return Ok(());
+            }
+
+            let mut peer_closed = false;
+            {
+                let mut tls = self.tls.lock().map_err(|_| DnsError::LockPoisoned("TLS"))?;
+                let mut data = &buf[..n];
+
+                while !data.is_empty() {
+                    tls.read_tls(&mut data)?;
+                    tls.process_new_packets()?;
+
+                    let mut chunk = [0; 4096];
+                    loop {
+                        match tls.reader().read(&mut chunk) {
+                            Ok(0) => {
+                                peer_closed = true;
+                                break;
+                            }
+                            Ok(x) => plain.extend_from_slice(&chunk[..x]),
+                            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                            Err(e) => return Err(e.into()),
+                        }
+                    }
+                }
+
+                // alerts and key updates go out as soon as rustls has them
+                while tls.wants_write() {
+                    tls.write_tls(&mut &self.sock)?;
+                }
+            }
+
+            // hand over every complete message received so far
+            while plain.len() >= 2 {
+                let len = ((plain[0] as usize) << 8) | (plain[1] as usize);
+                if plain.len() < len + 2 {
+                    break;
+                }
+
+                let msg: Vec<u8> = plain.drain(..len + 2).skip(2).collect();
+                if msg.len() < 2 {
+                    continue;
+                }
+
+                let id = u16::from_be_bytes([msg[0], msg[1]]);
+                let waiting = self.pending.lock().ok().and_then(|mut x| x.remove(&id));
+
+                match waiting {
+                    Some(sender) => {
+                        let _ = sender.send(msg);
+                    }
+                    None => warn!("DNS over TLS response with unknown id {}", id),
+                }
+            }
+
+            if peer_closed {
+                return Ok(());
+            }
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    /// Stop using the connection, failing every query still waiting on it
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        let _ = self.sock.shutdown(Shutdown::Both);
+
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.clear();
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Extended DNS Errors (RFC 8914), an EDNS option saying why a query got the answer it did
+//! Responses made here carry one for upstream failures, stale answers, blocked and filtered names, refusals
+//! and expired zones, and the ones an upstream sent, ex. DNSSEC Bogus from a validating resolver, are logged and passed on
+//! Only clients that sent an OPT record get them, the text is cut to MAX_TEXT bytes and at most MAX_ERRORS go
+//! in a response, so they fit in the smallest response along with the question
+
+use std::fmt;
+
+use crate::packet::{ DnsPacket, DnsRecord, QueryType, LISTENER_PAYLOAD };
+
+// Option code of an Extended DNS Error in an OPT record
+const OPTION_EDE: u16 = 15;
+const MAX_TEXT: usize = 64;
+const MAX_ERRORS: usize = 3;
+
+// Info codes, RFC 8914 section 4
+pub const OTHER: u16 = 0;
+pub const STALE_ANSWER: u16 = 3;
+pub const BLOCKED: u16 = 15;
+pub const FILTERED: u16 = 17;
+pub const PROHIBITED: u16 = 18;
+pub const STALE_NXDOMAIN_ANSWER: u16 = 19;
+pub const NOT_SUPPORTED: u16 = 21;
+pub const NO_REACHABLE_AUTHORITY: u16 = 22;
+pub const NETWORK_ERROR: u16 = 23;
+
+/// An info code with its extra text
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Ede {
+    pub code: u16,
+    pub text: String, // UTF-8, at most MAX_TEXT bytes, empty if there's none
+}
+
+impl Ede {
+    /// An error with its text cut to MAX_TEXT bytes, at a character boundary
+    pub fn new(code: u16, text: &str) -> Ede {
+        let mut end = text.len().min(MAX_TEXT);
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        Ede {
+            code: code,
+            text: text[..end].to_string(),
+        }
+    }
+
+    /// The option as it goes in an OPT record's data
+    fn encode(&self) -> Vec<u8> {
+        let mut option = Vec::with_capacity(6 + self.text.len());
+        option.extend_from_slice(&OPTION_EDE.to_be_bytes());
+        option.extend_from_slice(&((2 + self.text.len()) as u16).to_be_bytes());
+        option.extend_from_slice(&self.code.to_be_bytes());
+        option.extend_from_slice(self.text.as_bytes());
+
+        option
+    }
+}
+
+/// ex. Network Error (23): all upstreams timed out
+impl fmt::Display for Ede {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", name(self.code), self.code)?;
+        if !self.text.is_empty() {
+            write!(f, ": {}", self.text)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The Extended DNS Errors in the options of an OPT record, their text cut like ours
+/// Options running past the end are left out
+pub fn parse(options: &[u8]) -> Vec<Ede> {
+    let mut errors = Vec::new();
+
+    // options are a code, a length and that many bytes each
+    let mut i = 0;
+    while i + 4 <= options.len() {
+        let code = u16::from_be_bytes([options[i], options[i + 1]]);
+        let len = u16::from_be_bytes([options[i + 2], options[i + 3]]) as usize;
+        let Some(value) = options.get(i + 4..i + 4 + len) else { break };
+        if code == OPTION_EDE && len >= 2 {
+            errors.push(Ede::new(u16::from_be_bytes([value[0], value[1]]), &String::from_utf8_lossy(&value[2..])));
+        }
+        i += 4 + len;
+    }
+
+    errors
+}
+
+/// Move a response's errors into its OPT record, adding one if it has none, when the client sent an OPT record
+/// Otherwise they're dropped, a client without EDNS couldn't read them
+/// A client that sent an OPT record always gets one back (RFC 6891 section 7), even without errors
+pub fn attach(response: &mut DnsPacket, edns: bool) {
+    let errors: Vec<Ede> = response.extended_errors.drain(..).take(MAX_ERRORS).collect();
+    if !edns {
+        return;
+    }
+
+    if !response.resources.iter().any(|x| x.query_type() == QueryType::OPT) {
+        response.resources.push(DnsRecord::OPT {
+            payload_size: LISTENER_PAYLOAD,
+            ext_rcode: 0,
+            version: 0,
+            flags: 0,
+            data: Vec::new(),
+        });
+    }
+
+    // a response only ever has the one OPT record
+    if let Some(DnsRecord::OPT { data, .. }) = response.resources.iter_mut().find(|x| x.query_type() == QueryType::OPT) {
+        for error in &errors {
+            data.extend(error.encode());
+        }
+    }
+}
+
+/// The name of an info code as RFC 8914 lists it
+fn name(code: u16) -> &'static str {
+    match code {
+        0 => "Other Error",
+        1 => "Unsupported DNSKEY Algorithm",
+        2 => "Unsupported DS Digest Type",
+        3 => "Stale Answer",
+        4 => "Forged Answer",
+        5 => "DNSSEC Indeterminate",
+        6 => "DNSSEC Bogus",
+        7 => "Signature Expired",
+        8 => "Signature Not Yet Valid",
+        9 => "DNSKEY Missing",
+        10 => "RRSIGs Missing",
+        11 => "No Zone Key Bit Set",
+        12 => "NSEC Missing",
+        13 => "Cached Error",
+        14 => "Not Ready",
+        15 => "Blocked",
+        16 => "Censored",
+        17 => "Filtered",
+        18 => "Prohibited",
+        19 => "Stale NXDOMAIN Answer",
+        20 => "Not Authoritative",
+        21 => "Not Supported",
+        22 => "No Reachable Authority",
+        23 => "Network Error",
+        24 => "Invalid Data",
+        _ => "Unknown Error",
+    }
+}
@@ -0,0 +1,248 @@
+//! Errors from reading and writing packets and from lookups, so a caller can tell a truncated packet
+//! from a name that doesn't fit on the wire or an upstream that never answered
+//! Every module returns a DnsError, io::Errors are taken in as Io and the TLS and QUIC libraries' errors as Tls and Quic
+//! Settings and files that can't be used are Config, everything met while answering has a variant of its own
+
+use std::io;
+
+use thiserror::Error;
+
+use crate::packet::ResCode;
+
+#[derive(Debug, Error)]
+pub enum DnsError {
+    /// A read or write went past the end of a buffer, needed bytes more than it has from pos
+    #[error("End of buffer at {pos}, {needed} more bytes needed")]
+    BufferOverrun { pos: usize, needed: usize },
+    /// A packet ended partway through a field
+    #[error("Unexpected end of packet")]
+    UnexpectedEof,
+    #[error("Single label exceeds 63 characters of length")]
+    LabelTooLong,
+    #[error("Name exceeds 253 characters of length")]
+    NameTooLong,
+    #[error("Empty label in {0}")]
+    EmptyLabel(String),
+    /// A compressed name jumped more often than any real one needs to, likely in a loop
+    #[error("Limit of {0} jumps exceeded")]
+    CompressionLoop(usize),
+    /// A section counts more entries than ParseLimits allows
+    #[error("Format error: {count} {section} entries exceeds the limit of {max}")]
+    TooManyRecords { section: &'static str, count: u16, max: u16 },
+    /// Every attempt to reach an upstream went unanswered
+    #[error("No response from {upstream} after {attempts} attempts")]
+    UpstreamTimeout { upstream: String, attempts: usize },
+    /// An upstream answered, but with an error rcode where an answer was needed
+    #[error("Upstream answered {0:?}")]
+    UpstreamRefused(ResCode),
+    /// A response that doesn't answer the query it came for, ex. with another id or question
+    #[error("{0}")]
+    MismatchedResponse(String),
+    /// Both address lookups for a name failed
+    #[error("Looking up the addresses of {name} failed: {v4} (A), {v6} (AAAA)")]
+    AddressLookup { name: String, v4: Box<DnsError>, v6: Box<DnsError> },
+    /// A name looked up to reach an upstream has no address
+    #[error("{host} has no A or AAAA record at {resolver}")]
+    NoAddress { host: String, resolver: String },
+    #[error("No resolver to send the query to")]
+    NoResolver,
+    #[error("CNAME loop from {from} back to {to}")]
+    CnameLoop { from: String, to: String },
+    #[error("CNAME chain from {qname} is longer than {max}")]
+    CnameChainTooLong { qname: String, max: usize },
+    /// The root servers' answer to the priming query gave none of them an address
+    #[error("The priming response has no root name server with an address")]
+    NoRootServers,
+    /// A referral back to a zone already passed on the way down, chain is the zones in order
+    #[error("Delegation loop resolving {qname}, {zone} referred back to {target} ({chain})")]
+    DelegationLoop { qname: String, zone: String, target: String, chain: String },
+    #[error("Gave up on {qname} after {referrals} referrals ({chain})")]
+    TooManyReferrals { qname: String, referrals: usize, chain: String },
+    #[error("Gave up on {qname} after {queries} queries to name servers")]
+    TooManyQueries { qname: String, queries: usize },
+    #[error("Referral to {0} without name servers")]
+    NoNameServers(String),
+    #[error("No address for {host}, a name server for {zone}")]
+    NoNameServerAddress { host: String, zone: String },
+    /// A name server whose address can only be found by asking it, chain is the names being resolved
+    #[error("Circular dependency, name server {host} is needed to find itself ({chain} -> {host})")]
+    CircularDependency { host: String, chain: String },
+    #[error("Name servers nest deeper than {max} looking up {host} ({chain})")]
+    NameServersTooDeep { host: String, max: usize, chain: String },
+    /// Every name server of a zone answered, none of them for the zone
+    #[error("All {count} name servers for {zone} are lame")]
+    LameServers { zone: String, count: usize },
+    #[error("No name server for {0} answered")]
+    NoNameServerAnswer(String),
+    /// No TLS connection could be set up to an encrypted upstream
+    #[error("No TLS session with {upstream}: {failure}")]
+    NoTlsSession { upstream: String, failure: Box<DnsError> },
+    /// A connection to an upstream closed with queries still waiting on it
+    #[error("Connection to {0} closed before the response arrived")]
+    ConnectionClosed(String),
+    /// The SOCKS5 proxy couldn't be reached or didn't grant a request
+    #[error("SOCKS5 proxy {proxy}: {failure}")]
+    Proxy { proxy: String, failure: String },
+    /// An HTTP request or response that breaks the protocol or isn't a DNS message, from a DoH client or upstream
+    #[error("{0}")]
+    Http(String),
+    /// A zone transfer or SOA check that a primary didn't answer as it should
+    #[error("{0}")]
+    Transfer(String),
+    #[error("Zero length TCP message")]
+    EmptyMessage,
+    #[error("TCP message of {size} bytes exceeds the limit of {max}")]
+    MessageTooLarge { size: usize, max: usize },
+    /// peek_question only borrows the one question of a packet, a packet with more or less is parsed in full
+    #[error("Expected a single question, found {0}")]
+    QuestionCount(u16),
+    #[error("Compressed question names are not supported by peek_question")]
+    CompressedQuestion,
+    /// A TSIG record that can't be read, ex. cut short or not the last record
+    #[error("Malformed TSIG: {0}")]
+    MalformedTsig(&'static str),
+    /// An UPDATE whose zone section isn't a single SOA question
+    #[error("Malformed UPDATE: {0}")]
+    MalformedUpdate(String),
+    #[error("Unknown type {0}")]
+    UnknownType(String),
+    #[error("Unknown rcode {0}")]
+    UnknownRcode(String),
+    #[error("Rcode {0} doesn't fit in 12 bits")]
+    RcodeTooLarge(u16),
+    /// A record that can't be written, ex. TXT data longer than its rdata can hold
+    #[error("{0}")]
+    InvalidRecord(String),
+    #[error("Unable to punycode encode label {0}")]
+    Punycode(String),
+    #[error("Invalid base64 {0}")]
+    InvalidBase64(String),
+    /// A zone that breaks the rules, from a master file or a transfer, ex. a CNAME along with other records
+    #[error("{0}")]
+    InvalidZone(String),
+    /// Changes to a zone, from IXFR or the journal, that don't apply to the version it's at
+    #[error("{0}")]
+    InvalidChange(String),
+    /// A setting, or a file it names, that can't be used, ex. an invalid --dns64-prefix or an unreadable blocklist
+    #[error("{0}")]
+    Config(String),
+    #[error("{0} lock poisoned")]
+    LockPoisoned(&'static str),
+    /// A task that panicked or was cancelled before answering
+    #[error("Task failed: {0}")]
+    TaskFailed(String),
+    #[error("{0}")]
+    Io(io::Error),
+    /// A TLS handshake or record failed, to an upstream or from a client
+    #[cfg(feature = "tls")]
+    #[error("TLS: {0}")]
+    Tls(#[from] rustls::Error),
+    /// A QUIC connection failed or was closed partway through a stream
+    #[cfg(feature = "doq")]
+    #[error("QUIC: {0}")]
+    Quic(#[from] quinn::ConnectionError),
+}
+
+impl DnsError {
+    /// Whether the error is in the packet itself rather than in getting or answering it
+    pub fn is_malformed(&self) -> bool {
+        matches!(
+            self,
+            DnsError::BufferOverrun { .. }
+                | DnsError::UnexpectedEof
+                | DnsError::LabelTooLong
+                | DnsError::NameTooLong
+                | DnsError::EmptyLabel(_)
+                | DnsError::CompressionLoop(_)
+                | DnsError::TooManyRecords { .. }
+                | DnsError::MalformedTsig(_)
+                | DnsError::MalformedUpdate(_)
+        )
+    }
+
+    /// The rcode to answer a query with when handling it failed this way,
+    /// FORMERR for a malformed query and SERVFAIL for anything else
+    pub fn res_code(&self) -> ResCode {
+        if self.is_malformed() {
+            ResCode::FORM_ERR
+        } else {
+            ResCode::SERV_FAIL
+        }
+    }
+}
+
+/// An io::Read running out of packet is a truncated packet, anything else stays an io::Error
+impl From<io::Error> for DnsError {
+    fn from(e: io::Error) -> DnsError {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => DnsError::UnexpectedEof,
+            _ => DnsError::Io(e),
+        }
+    }
+}
+
+/// A task that panicked or was cancelled before answering
+#[cfg(feature = "async")]
+impl From<tokio::task::JoinError> for DnsError {
+    fn from(e: tokio::task::JoinError) -> DnsError {
+        DnsError::TaskFailed(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{ DnsPacket, DnsQuestion, QueryType };
+
+    fn write_name(name: &str) -> Result<Vec<u8>, DnsError> {
+        let mut packet = DnsPacket::new();
+        packet.questions.push(DnsQuestion::new(name.to_string(), QueryType::A));
+        packet.to_bytes()
+    }
+
+    #[test]
+    fn long_label_is_label_too_long() {
+        let e = write_name(&format!("{}.example", "a".repeat(64))).unwrap_err();
+        assert!(matches!(e, DnsError::LabelTooLong));
+        assert_eq!(e.res_code(), ResCode::FORM_ERR);
+    }
+
+    #[test]
+    fn long_label_once_punycoded_is_label_too_long() {
+        // 20 characters fit in a label, but not once encoded
+        let label: String = (0..20).filter_map(|x| char::from_u32(0x4E00 + x * 997)).collect();
+        let e = write_name(&format!("{}.example", label)).unwrap_err();
+        assert!(matches!(e, DnsError::LabelTooLong));
+        assert_eq!(e.res_code(), ResCode::FORM_ERR);
+    }
+
+    #[test]
+    fn truncated_packet_is_malformed() {
+        let bytes = write_name("example.com").unwrap();
+
+        for len in [5, 12, 20, bytes.len() - 1] {
+            let e = DnsPacket::from_bytes(&bytes[..len]).unwrap_err();
+            assert!(matches!(e, DnsError::BufferOverrun { .. } | DnsError::UnexpectedEof), "{} bytes: {:?}", len, e);
+            assert_eq!(e.res_code(), ResCode::FORM_ERR);
+        }
+    }
+
+    #[test]
+    fn pointer_loop_is_compression_loop() {
+        // one question whose name points at itself
+        let mut bytes = vec![0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        bytes.extend_from_slice(&[0xC0, 0x0C, 0x00, 0x01, 0x00, 0x01]);
+
+        let e = DnsPacket::from_bytes(&bytes).unwrap_err();
+        assert!(matches!(e, DnsError::CompressionLoop(_)), "{:?}", e);
+        assert_eq!(e.res_code(), ResCode::FORM_ERR);
+    }
+
+    #[test]
+    fn other_errors_are_servfail() {
+        let e = DnsError::from(io::Error::new(io::ErrorKind::ConnectionRefused, "refused"));
+        assert!(matches!(e, DnsError::Io(_)));
+        assert_eq!(e.res_code(), ResCode::SERV_FAIL);
+        assert!(matches!(DnsError::from(io::Error::from(io::ErrorKind::UnexpectedEof)), DnsError::UnexpectedEof));
+    }
+}
@@ -0,0 +1,610 @@
+//! Single threaded, non-blocking serving with poll(2)
+//! Listening sockets and one ephemeral socket per in-flight upstream lookup are all polled together,
+//! so a lookup is a state machine (query sent, then awaiting the response) rather than a blocking call
+//! Attempts that outlive the lookup timeout are expired by a timer wheel and resent,
+//! until the retries run out and the client is answered with SERVFAIL
+//! Truncated upstream responses are passed on with TC set, the client's TCP retry then goes
+//! through the TCP listener's thread, which fetches the whole answer over TCP
+//! Only forwarding is supported, recursive resolution still needs the threaded server
+//! Lookups aren't raced, with the race strategy each goes to the better of the two upstreams
+//! Lookups can't go through a SOCKS5 proxy, --proxy needs the threaded server, and neither does --dns64 work here
+//! Only single question queries are taken, others get FORMERR, and NOTIFY and UPDATE are answered NOTIMP
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{ Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket };
+use std::os::unix::io::AsRawFd;
+use std::time::{ Duration, Instant };
+
+use log::{ debug, error, trace, warn };
+
+use crate::acl;
+use crate::amplification;
+use crate::cache;
+use crate::dns64;
+use crate::dns_name;
+use crate::error::DnsError;
+use crate::forwarding;
+use crate::idna;
+use crate::packet::{ self, DnsHeader, DnsPacket, DnsQuestion, DnsRecord, PacketBuffer, QueryType, ResCode, Opcode };
+use crate::query_log::{ self, Source };
+use crate::resolve::{ self, Resolution };
+use crate::rrl;
+use crate::socks5;
+use crate::stats;
+use crate::transport::Transport;
+use crate::upstreams::{ Failure, Protocol, Upstreams };
+
+type Result<T> = std::result::Result<T, DnsError>;
+
+// Largest message sent or received over UDP
+const UDP_MAX_SIZE: usize = 512;
+
+// New lookups beyond this are refused with SERVFAIL rather than opening more sockets
+const MAX_PENDING: usize = 1024;
+
+// Timer wheel resolution, the number of slots is sized to span the lookup timeout
+const TICK: Duration = Duration::from_millis(100);
+
+/// A lookup waiting on the upstream
+struct Pending {
+    listener: usize,       // index of the socket the query arrived on
+    client: SocketAddr,
+    req_len: usize,        // size of the client's query, what --max-amplification measures the response against
+    received: Instant,     // when the client's query arrived
+    req_header: DnsHeader,
+    question: DnsQuestion,
+    upstreams: &'static Upstreams, // the default ones or a forwarding rule's
+    resolver: SocketAddr,  // upstream picked for the lookup
+    upstream: UdpSocket,
+    started: Instant,
+    query: Vec<u8>,        // kept to resend when an attempt times out
+    sent: DnsPacket,       // the query as sent, responses are checked against it
+    attempts: usize,
+    chain: Vec<DnsRecord>, // answers so far while following a CNAME chain the upstream stopped partway through
+}
+
+/// Hashed timer wheel of lookup tokens
+/// Each slot holds the tokens expiring during one tick; tokens of finished lookups are just skipped
+struct TimerWheel {
+    slots: Vec<Vec<u64>>,
+    current: usize,
+    last_tick: Instant,
+}
+
+impl TimerWheel {
+    /// A wheel able to hold delays up to span
+    fn new(now: Instant, span: Duration) -> TimerWheel {
+        let slots = (span.as_millis() / TICK.as_millis()) as usize + 2;
+
+        TimerWheel {
+            slots: vec![Vec::new(); slots],
+            current: 0,
+            last_tick: now,
+        }
+    }
+
+    /// Schedule a token to expire after a delay shorter than the wheel's span
+    fn insert(&mut self, token: u64, delay: Duration) {
+        let ticks = (delay.as_millis() / TICK.as_millis()) as usize + 1;
+        let slot = (self.current + ticks.min(self.slots.len() - 1)) % self.slots.len();
+
+        self.slots[slot].push(token);
+    }
+
+    /// Advance to now, returning every token whose slot was passed
+    fn expire(&mut self, now: Instant) -> Vec<u64> {
+        let mut expired = Vec::new();
+
+        while now.duration_since(self.last_tick) >= TICK {
+            self.last_tick += TICK;
+            self.current = (self.current + 1) % self.slots.len();
+            expired.append(&mut self.slots[self.current]);
+        }
+
+        expired
+    }
+
+    /// Time until the next tick is due
+    fn next_tick(&self, now: Instant) -> Duration {
+        (self.last_tick + TICK).saturating_duration_since(now)
+    }
+}
+
+/// Serve already bound UDP sockets from the calling thread until an unrecoverable error
+pub fn run(listeners: Vec<UdpSocket>, resolution: Resolution) -> Result<()> {
+    let upstreams = match resolution {
+        Resolution::Forward(x) => x,
+        Resolution::Recursive => return Err(DnsError::Config("The event loop only forwards, give a --resolver".to_string())),
+    };
+    let rule_upstreams = forwarding::rules().iter().flat_map(|x| x.upstreams.all());
+    if upstreams.all().iter().chain(rule_upstreams).any(|x| !matches!(x.protocol, Protocol::UDP)) {
+        return Err(DnsError::Config("The event loop only forwards over plain UDP, drop the encrypted --resolver".to_string()));
+    }
+    if let Some(proxy) = socks5::proxy() {
+        return Err(DnsError::Config(format!("The event loop can't send lookups through the proxy {}, drop --proxy or --event-loop", proxy)));
+    }
+    if dns64::dns64().is_some() {
+        return Err(DnsError::Config("The event loop can't synthesize AAAA records, drop --dns64 or --event-loop".to_string()));
+    }
+
+    for listener in &listeners {
+        listener.set_nonblocking(true)?;
+    }
+
+    let mut pending: HashMap<u64, Pending> = HashMap::new();
+    let mut next_token: u64 = 0;
+    let timeout = resolve::lookup_timeout();
+    let attempts = resolve::lookup_retries() + 1;
+    let mut wheel = TimerWheel::new(Instant::now(), timeout);
+    let mut buf = [0; UDP_MAX_SIZE];
+
+    loop {
+        // listeners first, then one entry per pending lookup in the order of tokens
+        let tokens: Vec<u64> = pending.keys().copied().collect();
+        let mut fds: Vec<libc::pollfd> = listeners.iter()
+            .map(|x| x.as_raw_fd())
+            .chain(tokens.iter().map(|x| pending[x].upstream.as_raw_fd()))
+            .map(|fd| libc::pollfd { fd: fd, events: libc::POLLIN, revents: 0 })
+            .collect();
+
+        // only wake up for the timer when something can expire
+        let wait = if pending.is_empty() {
+            -1
+        } else {
+            wheel.next_tick(Instant::now()).as_millis() as libc::c_int + 1
+        };
+
+        let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, wait) };
+        if ready < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e.into());
+        }
+
+        // expire before handling new queries so the wheel has caught up when they are inserted
+        for token in wheel.expire(Instant::now()) {
+            if let Some(mut lookup) = pending.remove(&token) {
+                warn!("No response for {} within {:?} (attempt {} of {})", lookup.question.name, timeout, lookup.attempts, attempts);
+                stats::count_timeout();
+
+                if lookup.attempts < attempts {
+                    lookup.attempts += 1;
+                    stats::count_retry();
+
+                    match lookup.upstream.send(&lookup.query) {
+                        Ok(_) => {
+                            wheel.insert(token, timeout);
+                            pending.insert(token, lookup);
+                            continue;
+                        }
+                        Err(e) => error!("Failed to resend {}: {}", lookup.question.name, e),
+                    }
+                }
+
+                lookup.upstreams.record_failure(lookup.resolver, Failure::TIMEOUT);
+
+                let mut response = packet::response_to(&lookup.req_header);
+                response.header.res_code = ResCode::SERV_FAIL;
+                respond(&listeners, &lookup, response);
+            }
+        }
+
+        for (i, fd) in fds.iter().enumerate() {
+            if fd.revents == 0 {
+                continue;
+            }
+
+            if i < listeners.len() {
+                // drain everything queued on the listener
+                loop {
+                    let (size, client) = match listeners[i].recv_from(&mut buf) {
+                        Ok(x) => x,
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => {
+                            error!("An error occurred: {}", e);
+                            break;
+                        }
+                    };
+
+                    trace!("Received {} bytes from {}", size, client);
+
+                    match start_lookup(&buf[..size], i, client, upstreams, pending.len()) {
+                        Ok(Started::Pending(lookup)) => {
+                            let token = next_token;
+                            next_token += 1;
+
+                            wheel.insert(token, timeout);
+                            pending.insert(token, *lookup);
+                        }
+                        Ok(Started::Answered(mut res)) => send(&listeners[i], &mut res, client, size),
+                        Err(e) => error!("An error occurred: {}", e),
+                    }
+                }
+            } else {
+                let token = tokens[i - listeners.len()];
+                if let Some(mut lookup) = pending.remove(&token) {
+                    match finish_lookup(&mut lookup, &mut buf) {
+                        Finished::Answered(response) => respond(&listeners, &lookup, response),
+                        // nothing that answers the query yet, keep waiting on the upstream
+                        Finished::Dropped => {
+                            pending.insert(token, lookup);
+                        }
+                        // a new query under a new token so the old one's timer can't cut it short
+                        Finished::Following => {
+                            let token = next_token;
+                            next_token += 1;
+
+                            wheel.insert(token, timeout);
+                            pending.insert(token, lookup);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// What became of a new query
+enum Started {
+    /// Sent upstream, waiting on the response, boxed as it's much larger than an answer
+    Pending(Box<Pending>),
+    /// Answered straight away, no upstream needed
+    Answered(Vec<u8>),
+}
+
+/// Parse a query and send it to the selected upstream from a fresh ephemeral socket
+fn start_lookup(req: &[u8], listener: usize, client: SocketAddr, upstreams: &'static Upstreams, in_flight: usize) -> Result<Started> {
+    let started = Instant::now();
+
+    // anything not forwarded is answered here, the blocking resolver is only ever asked about names it answers itself
+    let mut req_header = DnsHeader::new();
+    req_header.read(&mut PacketBuffer::from_bytes(req))?;
+    let request = match DnsPacket::from_bytes(req) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Malformed query: {}", e);
+            return error_answer(&req_header, None, e.res_code(), client, started);
+        }
+    };
+    if request.header.opcode != Opcode::QUERY {
+        debug!("Received {:?} request, not supported by the event loop", request.header.opcode);
+        return error_answer(&req_header, None, ResCode::NOT_IMP, client, started);
+    }
+    if request.questions.len() != 1 {
+        debug!("Received query with {} questions, answering FORMERR", request.questions.len());
+        return error_answer(&req_header, None, ResCode::FORM_ERR, client, started);
+    }
+    if resolve::is_local(&request.questions[0]) {
+        return Ok(Started::Answered(resolve::handle_query_sized(req, UDP_MAX_SIZE, client.ip(), &Resolution::Forward(upstreams), Transport::UDP)?));
+    }
+    if !acl::is_allowed(client.ip()) {
+        debug!("Received query: {} {}, refused, recursion isn't allowed for {}", idna::to_unicode(&request.questions[0].name), request.questions[0].q_type, client);
+        query_log::note(Source::REFUSED, None);
+        stats::count_refused_recursion();
+        return error_answer(&req_header, Some(request.questions[0].clone()), ResCode::REFUSED, client, started);
+    }
+
+    let question = request.questions[0].clone();
+    let upstreams = forwarding::find(&question.name).map_or(upstreams, |x| &x.upstreams);
+
+    if let Some(cached) = cache::lookup(&question.name, question.q_type, move |name, q_type| resolve::refresh(name, q_type, Some(upstreams))) {
+        debug!("Received query: {} {}, answered from the cache", idna::to_unicode(&question.name), question.q_type);
+        query_log::note(Source::CACHE, None);
+
+        let mut response = DnsPacket::response_to(&request).with_rcode(cached.header.res_code).question(question);
+        response.answers = cached.answers;
+        response.authorities = cached.authorities;
+
+        let bytes = resolve::encode_response(&mut response, UDP_MAX_SIZE)?;
+        resolve::log_answered(client.ip(), Transport::UDP, &bytes, started);
+
+        return Ok(Started::Answered(bytes));
+    }
+
+    if in_flight >= MAX_PENDING {
+        warn!("Too many lookups in flight, refusing {}", question.name);
+
+        let mut response = DnsPacket::response_to(&request).with_rcode(ResCode::SERV_FAIL).question(question);
+
+        let bytes = resolve::encode_response(&mut response, UDP_MAX_SIZE)?;
+        resolve::log_answered(client.ip(), Transport::UDP, &bytes, started);
+
+        return Ok(Started::Answered(bytes));
+    }
+
+    let mut query = upstream_query(&question.name, question.q_type);
+
+    let resolver = upstreams.select().addr;
+    debug!("Received query: {} {}, forwarding to {}", idna::to_unicode(&question.name), question.q_type, resolver);
+
+    let local = match resolver {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let upstream = UdpSocket::bind(local)?;
+    upstream.connect(resolver)?;
+    upstream.set_nonblocking(true)?;
+
+    let bytes = query.to_bytes()?;
+    upstream.send(&bytes)?;
+
+    Ok(Started::Pending(Box::new(Pending {
+        listener: listener,
+        client: client,
+        req_len: req.len(),
+        received: started,
+        req_header: request.header,
+        question: question,
+        upstreams: upstreams,
+        resolver: resolver,
+        upstream: upstream,
+        started: Instant::now(),
+        query: bytes,
+        sent: query,
+        attempts: 1,
+        chain: Vec::new(),
+    })))
+}
+
+/// A response carrying just an rcode, and the question when there's one to echo
+fn error_answer(req_header: &DnsHeader, question: Option<DnsQuestion>, res_code: ResCode, client: SocketAddr, started: Instant) -> Result<Started> {
+    let mut response = packet::response_to(req_header);
+    response.header.res_code = res_code;
+    response.questions.extend(question);
+
+    let bytes = resolve::encode_response(&mut response, UDP_MAX_SIZE)?;
+    resolve::log_answered(client.ip(), Transport::UDP, &bytes, started);
+
+    Ok(Started::Answered(bytes))
+}
+
+/// A query for the upstream with a random id
+fn upstream_query(name: &str, q_type: QueryType) -> DnsPacket {
+    let mut query = DnsPacket::new();
+    query.header.id = rand::random::<u16>();
+    query.header.query_res = false;
+    query.header.rec_des = true;
+    query.questions.push(DnsQuestion::new(name.to_string(), q_type));
+
+    query
+}
+
+/// What became of a pending lookup when its socket was readable
+enum Finished {
+    /// Done, the client's response
+    Answered(DnsPacket),
+    /// Nothing that answers the query arrived, still waiting
+    Dropped,
+    /// The upstream stopped partway through a CNAME chain, the next name was asked for
+    Following,
+}
+
+/// Read the upstream's response to a pending lookup and build the client's response
+/// What arrived is dropped if it doesn't answer the query so the lookup keeps waiting,
+/// and a CNAME chain the upstream stopped partway through is followed with another query on the same socket
+fn finish_lookup(lookup: &mut Pending, buf: &mut [u8]) -> Finished {
+    let mut response = packet::response_to(&lookup.req_header);
+
+    let size = match lookup.upstream.recv(buf) {
+        Ok(x) => x,
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Finished::Dropped,
+        Err(e) => {
+            lookup.upstreams.record_failure(lookup.resolver, Failure::CONNECTION);
+            warn!("Lookup of {} failed: {}", lookup.question.name, e);
+            response.header.res_code = ResCode::SERV_FAIL;
+
+            return Finished::Answered(response);
+        }
+    };
+
+    // the socket is connected so only the upstream's packets arrive, a malformed or mismatched one
+    // may still be spoofed and mustn't end the lookup
+    let mut res = match DnsPacket::from_bytes(&buf[..size]) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Dropping a malformed response from {}: {}", lookup.resolver, e);
+            return Finished::Dropped;
+        }
+    };
+    if let Err(e) = resolve::check_response(&res, &lookup.sent) {
+        warn!("Dropping a response from {}: {}", lookup.resolver, e);
+        return Finished::Dropped;
+    }
+
+    lookup.upstreams.record_success(lookup.resolver, lookup.started.elapsed(), res.header.res_code);
+    resolve::scrub_answers(&mut res, &lookup.sent.questions[0].name, lookup.resolver);
+
+    let mut answers = std::mem::take(&mut lookup.chain);
+    answers.extend(res.answers);
+
+    let asked = &lookup.sent.questions[0].name;
+    match resolve::cname_target(&answers, &lookup.question.name, lookup.question.q_type) {
+        // the last query didn't get any further, the name simply has no such records
+        Ok(Some(target)) if !dns_name::eq_ignore_case(&target, asked) && res.header.res_code == ResCode::NO_ERR && !res.header.trunc => {
+            debug!("Following the CNAME chain from {} to {} {}", idna::to_unicode(&lookup.question.name), idna::to_unicode(&target), lookup.question.q_type);
+
+            let mut query = upstream_query(&target, lookup.question.q_type);
+            let sent = query.to_bytes().and_then(|bytes| {
+                lookup.upstream.send(&bytes)?;
+                Ok(bytes)
+            });
+
+            match sent {
+                Ok(bytes) => {
+                    lookup.query = bytes;
+                    lookup.sent = query;
+                    lookup.started = Instant::now();
+                    lookup.attempts = 1;
+                    lookup.chain = answers;
+
+                    return Finished::Following;
+                }
+                Err(e) => {
+                    warn!("Lookup of {} failed: {}", target, e);
+                    response.header.res_code = ResCode::SERV_FAIL;
+
+                    return Finished::Answered(response);
+                }
+            }
+        }
+        Ok(_) => (),
+        Err(e) => {
+            warn!("Lookup of {} failed: {}", lookup.question.name, e);
+            response.header.res_code = ResCode::SERV_FAIL;
+
+            return Finished::Answered(response);
+        }
+    }
+
+    response.header.trunc = res.header.trunc;
+    response.header.res_code = res.header.res_code;
+    response.answers = answers;
+    response.authorities = res.authorities;
+    response.resources = res.resources;
+    cache::store(&lookup.question.name, lookup.question.q_type, &mut response);
+
+    Finished::Answered(response)
+}
+
+/// Send a finished lookup's response from the socket its query arrived on
+fn respond(listeners: &[UdpSocket], lookup: &Pending, mut response: DnsPacket) {
+    if response.header.res_code == ResCode::SERV_FAIL {
+        let upstreams = lookup.upstreams;
+        if let Some(stale) = cache::stale(&lookup.question.name, lookup.question.q_type, move |name, q_type| resolve::refresh(name, q_type, Some(upstreams))) {
+            warn!("Lookup of {} failed, answering with stale records", lookup.question.name);
+            response.header.res_code = stale.header.res_code;
+            response.answers = stale.answers;
+            response.authorities = stale.authorities;
+            response.resources.clear();
+        }
+    }
+    response.questions.push(lookup.question.clone());
+
+    match resolve::encode_response(&mut response, UDP_MAX_SIZE) {
+        Ok(mut res) => {
+            query_log::note(Source::FORWARDED, Some(lookup.resolver));
+            resolve::log_answered(lookup.client.ip(), Transport::UDP, &res, lookup.received);
+            send(&listeners[lookup.listener], &mut res, lookup.client, lookup.req_len)
+        }
+        Err(e) => error!("An error occurred: {}", e),
+    }
+}
+
+/// Send a response to a query of req_len bytes to a client, unless --rrl drops it
+/// Cookies can't be used with the event loop, so --max-amplification never finds one
+fn send(listener: &UdpSocket, res: &mut [u8], client: SocketAddr, req_len: usize) {
+    let sent = match rrl::limit(client.ip(), res) {
+        Some(x) => amplification::limit_sized(req_len, false, &mut res[..x]),
+        None => return,
+    };
+
+    if let Err(e) = listener.send_to(&res[..sent], client) {
+        error!("Failed to send response to {}: {}", client, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    use crate::upstreams::{ Strategy, Upstream };
+
+    // how long each attempt waits on the upstream
+    const TIMEOUT: Duration = Duration::from_millis(200);
+
+    /// An event loop on a thread of its own forwarding to an upstream that never answers, the address it listens on
+    fn dead_upstream_loop() -> SocketAddr {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let upstreams: &'static Upstreams = Box::leak(Box::new(Upstreams::new(vec![Upstream::udp(upstream.local_addr().unwrap())], Strategy::SEQUENTIAL)));
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let _upstream = upstream;
+            resolve::set_test_lookup_timeout(TIMEOUT);
+            run(vec![listener], Resolution::Forward(upstreams)).unwrap();
+        });
+
+        addr
+    }
+
+    fn query(id: u16, names: &[&str]) -> Vec<u8> {
+        let mut query = DnsPacket::new();
+        query.header.id = id;
+        for name in names {
+            query.questions.push(DnsQuestion::new(name.to_string(), QueryType::A));
+        }
+
+        query.to_bytes().unwrap()
+    }
+
+    fn receive(client: &UdpSocket) -> DnsPacket {
+        let mut buf = [0; UDP_MAX_SIZE];
+        let size = client.recv(&mut buf).unwrap();
+
+        DnsPacket::from_bytes(&buf[..size]).unwrap()
+    }
+
+    #[test]
+    fn queries_not_forwarded_are_answered_while_a_lookup_waits_on_a_dead_upstream() {
+        let server = dead_upstream_loop();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(server).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let start = Instant::now();
+        client.send(&query(1, &["dead.event-loop.pine-dns.com"])).unwrap();
+        client.send(&query(2, &["one.event-loop.pine-dns.com", "two.event-loop.pine-dns.com"])).unwrap();
+        client.send(&query(3, &["nothing.invalid"])).unwrap();
+
+        // the two question query and the special-use name are answered before the first attempt upstream even times out
+        let res = receive(&client);
+        assert_eq!((res.header.id, res.header.res_code), (2, ResCode::FORM_ERR));
+        let res = receive(&client);
+        assert_eq!((res.header.id, res.header.res_code), (3, ResCode::NX_DOMAIN));
+        assert!(start.elapsed() < TIMEOUT);
+
+        // while the lookup is given up on only once every attempt has timed out
+        let res = receive(&client);
+        assert_eq!((res.header.id, res.header.res_code), (1, ResCode::SERV_FAIL));
+        assert!(start.elapsed() >= TIMEOUT * (resolve::lookup_retries() + 1) as u32);
+    }
+
+    #[test]
+    fn requests_other_than_queries_are_not_implemented() {
+        let server = dead_upstream_loop();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(server).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let mut notify = DnsPacket::new();
+        notify.header.opcode = Opcode::NOTIFY;
+        notify.questions.push(DnsQuestion::new("notify.event-loop.pine-dns.com".to_string(), QueryType::SOA));
+        client.send(&notify.to_bytes().unwrap()).unwrap();
+
+        let res = receive(&client);
+        assert_eq!((res.header.id, res.header.res_code), (notify.header.id, ResCode::NOT_IMP));
+    }
+
+    #[test]
+    fn timer_wheel_expires_each_token_once_its_delay_has_passed() {
+        let now = Instant::now();
+        let mut wheel = TimerWheel::new(now, Duration::from_secs(1));
+        assert_eq!(wheel.next_tick(now), TICK);
+
+        wheel.insert(1, Duration::from_millis(200));
+        wheel.insert(2, Duration::from_millis(500));
+        // longer than the wheel spans, expired at the end of the span instead of wrapping around early
+        wheel.insert(3, Duration::from_secs(10));
+
+        assert!(wheel.expire(now + Duration::from_millis(100)).is_empty());
+        assert_eq!(wheel.expire(now + Duration::from_millis(300)), [1]);
+        assert_eq!(wheel.expire(now + Duration::from_millis(600)), [2]);
+        assert_eq!(wheel.expire(now + Duration::from_millis(1100)), [3]);
+        assert!(wheel.expire(now + Duration::from_secs(3)).is_empty());
+    }
+}
@@ -0,0 +1,46 @@
+//! Conditional forwarding, queries for names in a configured domain go to that domain's own upstreams
+//! ex. corp.internal=10.0.0.2:53 sends corp.internal and everything under it to 10.0.0.2,
+//! whatever --resolver or recursive resolution would have done with it
+//! The rule for the longest matching domain wins, compared by whole labels ignoring case
+
+use std::sync::OnceLock;
+
+use crate::dns_name;
+use crate::packet::QuestionRef;
+use crate::upstreams::Upstreams;
+
+static RULES: OnceLock<Vec<ForwardRule>> = OnceLock::new();
+
+/// Upstreams serving one domain
+#[derive(Debug)]
+pub struct ForwardRule {
+    pub domain: String, // normalized, ex. corp.internal
+    pub upstreams: Upstreams,
+}
+
+/// Install the forwarding rules, only the first call has any effect
+pub fn set_rules(mut rules: Vec<ForwardRule>) {
+    // most labels first so the first match found is the longest
+    rules.sort_by_key(|x| std::cmp::Reverse(label_count(&x.domain)));
+
+    let _ = RULES.set(rules);
+}
+
+/// Every rule, longest domain first
+pub fn rules() -> &'static [ForwardRule] {
+    RULES.get().map_or(&[], |x| x.as_slice())
+}
+
+/// The rule for the longest domain containing a name, if any
+pub fn find(name: &str) -> Option<&'static ForwardRule> {
+    rules().iter().find(|x| dns_name::is_subdomain(name, &x.domain))
+}
+
+/// The rule for the longest domain containing a question's name, if any
+pub fn find_question(question: &QuestionRef) -> Option<&'static ForwardRule> {
+    rules().iter().find(|x| question.is_within(&x.domain))
+}
+
+fn label_count(domain: &str) -> usize {
+    domain.split('.').filter(|x| !x.is_empty()).count()
+}
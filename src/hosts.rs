@@ -0,0 +1,206 @@
+//! Static host overrides from a hosts(5) file given with --hosts <path>, lines like `10.0.0.5 nas.local nas`
+//! A and AAAA questions for a listed name are answered authoritatively from the file, ahead of the zones, the cache and any upstream,
+//! a name listed with addresses of the other family only getting NODATA, and PTR questions for a listed address get its first name
+//! Other types for a listed name are resolved as usual
+//! Malformed lines are skipped with a warning, the file is read again on SIGHUP
+//! A --view can have a hosts file of its own, read the same way
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr };
+use std::sync::atomic::{ AtomicU32, Ordering };
+use std::sync::{ OnceLock, RwLock };
+
+use log::{ error, info, warn };
+
+use crate::dns_name;
+use crate::error::DnsError;
+use crate::packet::{ DnsPacket, DnsRecord, QueryType, QuestionRef, ResCode };
+
+type Result<T> = std::result::Result<T, DnsError>;
+
+static PATH: OnceLock<String> = OnceLock::new();
+static HOSTS: RwLock<Option<Hosts>> = RwLock::new(None);
+static TTL: AtomicU32 = AtomicU32::new(300);
+
+/// The names and addresses of a hosts file
+pub struct Hosts {
+    addrs: HashMap<String, Vec<IpAddr>>, // by normalized name, in the order listed
+    names: HashMap<IpAddr, String>,      // the first name listed for each address
+}
+
+/// Set the TTL of the records answered from the hosts file, 300 unless --hosts-ttl is given
+pub fn set_ttl(ttl: u32) {
+    TTL.store(ttl, Ordering::Relaxed);
+}
+
+/// Read the hosts file and answer from it from now on, failing only if it can't be read
+/// Answers the number of names read
+pub fn load(path: &str) -> Result<usize> {
+    let _ = PATH.set(path.to_string());
+
+    let hosts = Hosts::read(path)?;
+    let count = hosts.name_count();
+    *HOSTS.write().unwrap() = Some(hosts);
+
+    Ok(count)
+}
+
+/// Read the hosts file again, keeping the names read before if it can't be read
+pub fn reload() {
+    let path = match PATH.get() {
+        Some(x) => x,
+        None => return,
+    };
+
+    match load(path) {
+        Ok(n) => info!("Reloaded {} names from {}", n, path),
+        Err(e) => error!("Failed to reload the hosts file, still answering from the old one: {}", e),
+    }
+}
+
+/// Whether a question is answered from the hosts file
+pub fn is_local(name: &str, q_type: QueryType) -> bool {
+    HOSTS.read().unwrap().as_ref().is_some_and(|x| x.is_local(name, q_type))
+}
+
+/// Whether a question seen before parsing is answered from the hosts file, its name only copied if there is one
+pub fn is_local_question(question: &QuestionRef) -> bool {
+    HOSTS.read().unwrap().as_ref().is_some_and(|x| x.is_local_question(question))
+}
+
+/// The answer to a question from the hosts file, None if it isn't answered from there
+pub fn answer(name: &str, q_type: QueryType) -> Option<DnsPacket> {
+    HOSTS.read().unwrap().as_ref()?.answer(name, q_type)
+}
+
+impl Hosts {
+    /// Read a hosts file, failing only if it can't be read
+    pub fn read(path: &str) -> Result<Hosts> {
+        let text = fs::read_to_string(path).map_err(|e| DnsError::Config(format!("{}: {}", path, e)))?;
+
+        Ok(parse(&text, path))
+    }
+
+    /// The number of names listed
+    pub fn name_count(&self) -> usize {
+        self.addrs.len()
+    }
+
+    /// Whether a question is answered from this file
+    pub fn is_local(&self, name: &str, q_type: QueryType) -> bool {
+        match q_type {
+            QueryType::A | QueryType::AAAA => self.addrs.contains_key(&dns_name::normalize(name)),
+            QueryType::PTR => reverse_addr(name).is_some_and(|x| self.names.contains_key(&x)),
+            _ => false,
+        }
+    }
+
+    /// Whether a question seen before parsing is answered from this file, its name only copied if it might be
+    pub fn is_local_question(&self, question: &QuestionRef) -> bool {
+        let types = [QueryType::A, QueryType::AAAA, QueryType::PTR];
+
+        types.contains(&question.q_type) && self.is_local(&question.name(), question.q_type)
+    }
+
+    /// The answer to a question from this file, None if it isn't answered from here
+    pub fn answer(&self, name: &str, q_type: QueryType) -> Option<DnsPacket> {
+        let ttl = TTL.load(Ordering::Relaxed);
+
+        let mut res = DnsPacket::new();
+        res.header.authoritative = true;
+        res.header.res_code = ResCode::NO_ERR;
+
+        match q_type {
+            QueryType::A | QueryType::AAAA => {
+                let addrs = self.addrs.get(&dns_name::normalize(name))?;
+                res.answers = addrs.iter()
+                    .filter_map(|addr| match (addr, q_type) {
+                        (IpAddr::V4(x), QueryType::A) => Some(DnsRecord::A { domain: name.to_string(), addr_v4: *x, ttl: ttl }),
+                        (IpAddr::V6(x), QueryType::AAAA) => Some(DnsRecord::AAAA { domain: name.to_string(), addr: *x, ttl: ttl }),
+                        _ => None,
+                    })
+                    .collect();
+            }
+            QueryType::PTR => {
+                let host = self.names.get(&reverse_addr(name)?)?;
+                res.answers.push(DnsRecord::PTR { domain: name.to_string(), host: host.clone(), ttl: ttl });
+            }
+            _ => return None,
+        }
+
+        Some(res)
+    }
+}
+
+/// Parse the lines of a hosts file, an address followed by its names, skipping the malformed ones with a warning
+fn parse(text: &str, path: &str) -> Hosts {
+    let mut hosts = Hosts {
+        addrs: HashMap::new(),
+        names: HashMap::new(),
+    };
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("");
+        let mut fields = line.split_whitespace();
+        let addr = match fields.next() {
+            Some(x) => x,
+            None => continue,
+        };
+
+        // a scoped IPv6 address, ex. fe80::1%eth0, doesn't parse and is skipped too
+        let addr = match addr.parse::<IpAddr>() {
+            Ok(x) => x,
+            Err(_) => {
+                warn!("Skipping {} line {}: invalid address {}", path, i + 1, addr);
+                continue;
+            }
+        };
+
+        let names: Vec<&str> = fields.collect();
+        if names.is_empty() {
+            warn!("Skipping {} line {}: no names for {}", path, i + 1, addr);
+            continue;
+        }
+
+        for name in names {
+            if let Err(e) = dns_name::validate(name) {
+                warn!("Skipping {} on {} line {}: {}", name, path, i + 1, e);
+                continue;
+            }
+            let name = dns_name::normalize(name);
+
+            let addrs = hosts.addrs.entry(name.clone()).or_default();
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+            hosts.names.entry(addr).or_insert(name);
+        }
+    }
+
+    hosts
+}
+
+/// The address a reverse name stands for, ex. 5.0.0.10.in-addr.arpa for 10.0.0.5, None if it isn't a whole one
+fn reverse_addr(name: &str) -> Option<IpAddr> {
+    let name = dns_name::normalize(name);
+
+    if let Some(rest) = name.strip_suffix(".in-addr.arpa") {
+        let octets: Vec<u8> = rest.split('.').rev().map(|x| x.parse::<u8>().ok()).collect::<Option<_>>()?;
+        let octets: [u8; 4] = octets.try_into().ok()?;
+
+        return Some(IpAddr::V4(Ipv4Addr::from(octets)));
+    }
+
+    if let Some(rest) = name.strip_suffix(".ip6.arpa") {
+        let nibbles: Vec<u8> = rest.split('.').rev().map(|x| u8::from_str_radix(x, 16).ok().filter(|_| x.len() == 1)).collect::<Option<_>>()?;
+        if nibbles.len() != 32 {
+            return None;
+        }
+        let bits = nibbles.iter().fold(0u128, |acc, x| (acc << 4) | *x as u128);
+
+        return Some(IpAddr::V6(Ipv6Addr::from(bits)));
+    }
+
+    None
+}
@@ -0,0 +1,286 @@
+//! Internationalized domain names
+//! Converts between unicode labels and their punycode (RFC 3492) "xn--" form
+//! so that names like bücher.example can be written to the wire
+
+use crate::error::DnsError;
+
+type Result<T> = std::result::Result<T, DnsError>;
+
+// RFC 3492 bootstring parameters for punycode
+const BASE: u32 = 36;
+const T_MIN: u32 = 1;
+const T_MAX: u32 = 26;
+const SKEW: u32 = 38;
+const DAMP: u32 = 700;
+const INITIAL_BIAS: u32 = 72;
+const INITIAL_N: u32 = 128;
+
+const ACE_PREFIX: &str = "xn--";
+const MAX_LABEL_LEN: usize = 63;
+
+/// Convert a domain name to its ASCII compatible form
+/// ex. bücher.example becomes xn--bcher-kva.example
+/// Labels that are already ASCII are only lowercased
+pub fn to_ascii(name: &str) -> Result<String> {
+    let mapped = map(name);
+    let mut out = String::with_capacity(mapped.len());
+    let mut delim = "";
+
+    for label in mapped.split('.') {
+        out.push_str(delim);
+        delim = ".";
+
+        let encoded = if label.is_ascii() {
+            label.to_string()
+        } else {
+            let chars: Vec<char> = label.chars().collect();
+            match encode(&chars) {
+                Some(x) => format!("{}{}", ACE_PREFIX, x),
+                None => return Err(DnsError::Punycode(label.to_string())),
+            }
+        };
+
+        // RFC 1035 - max DNS label length of 63 chars, checked after encoding
+        if encoded.len() > MAX_LABEL_LEN {
+            return Err(DnsError::LabelTooLong);
+        }
+
+        out.push_str(&encoded);
+    }
+
+    Ok(out)
+}
+
+/// Convert a domain name to its unicode form for display
+/// ex. xn--bcher-kva.example becomes bücher.example
+/// Labels that fail to decode are left as they are
+pub fn to_unicode(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    let mut delim = "";
+
+    for label in name.split('.') {
+        out.push_str(delim);
+        delim = ".";
+
+        let decoded = match label.get(..ACE_PREFIX.len()) {
+            Some(prefix) if prefix.eq_ignore_ascii_case(ACE_PREFIX) => decode(&label[ACE_PREFIX.len()..]),
+            _ => None,
+        };
+
+        match decoded {
+            Some(x) => out.push_str(&x),
+            None => out.push_str(label),
+        }
+    }
+
+    out
+}
+
+/// Basic IDNA mapping
+/// Lowercases the name and maps the alternate full stops (UTS #46) to '.'
+fn map(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '\u{3002}' | '\u{FF0E}' | '\u{FF61}' => '.',
+            _ => c,
+        })
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Bias adaptation function, RFC 3492 section 6.1
+fn adapt(delta: u32, num_points: u32, first_time: bool) -> u32 {
+    let mut delta = if first_time { delta / DAMP } else { delta / 2 };
+    delta += delta / num_points;
+
+    let mut k = 0;
+    while delta > ((BASE - T_MIN) * T_MAX) / 2 {
+        delta /= BASE - T_MIN;
+        k += BASE;
+    }
+
+    k + (((BASE - T_MIN + 1) * delta) / (delta + SKEW))
+}
+
+/// Threshold for the digit at position k given the current bias
+fn threshold(k: u32, bias: u32) -> u32 {
+    if k <= bias {
+        T_MIN
+    } else if k >= bias + T_MAX {
+        T_MAX
+    } else {
+        k - bias
+    }
+}
+
+/// 0..25 map to a..z and 26..35 map to 0..9
+fn encode_digit(d: u32) -> char {
+    match d {
+        0..=25 => (b'a' + d as u8) as char,
+        _ => (b'0' + (d - 26) as u8) as char,
+    }
+}
+
+fn decode_digit(c: char) -> Option<u32> {
+    match c {
+        'a'..='z' => Some(c as u32 - 'a' as u32),
+        'A'..='Z' => Some(c as u32 - 'A' as u32),
+        '0'..='9' => Some(c as u32 - '0' as u32 + 26),
+        _ => None,
+    }
+}
+
+/// Punycode encode a single label, RFC 3492 section 6.3
+/// Returns None on overflow
+fn encode(input: &[char]) -> Option<String> {
+    let mut output: String = input.iter().filter(|c| c.is_ascii()).collect();
+
+    let basic_len = output.len() as u32;
+    let mut handled = basic_len;
+
+    if basic_len > 0 {
+        output.push('-');
+    }
+
+    let mut n = INITIAL_N;
+    let mut delta: u32 = 0;
+    let mut bias = INITIAL_BIAS;
+
+    while (handled as usize) < input.len() {
+        // smallest code point not yet handled
+        let m = input.iter()
+                     .map(|c| *c as u32)
+                     .filter(|c| *c >= n)
+                     .min()?;
+
+        delta = delta.checked_add((m - n).checked_mul(handled + 1)?)?;
+        n = m;
+
+        for c in input {
+            let c = *c as u32;
+
+            if c < n {
+                delta = delta.checked_add(1)?;
+            }
+
+            if c == n {
+                let mut q = delta;
+                let mut k = BASE;
+
+                loop {
+                    let t = threshold(k, bias);
+                    if q < t {
+                        break;
+                    }
+                    output.push(encode_digit(t + (q - t) % (BASE - t)));
+                    q = (q - t) / (BASE - t);
+                    k += BASE;
+                }
+
+                output.push(encode_digit(q));
+                bias = adapt(delta, handled + 1, handled == basic_len);
+                delta = 0;
+                handled += 1;
+            }
+        }
+
+        delta = delta.checked_add(1)?;
+        n = n.checked_add(1)?;
+    }
+
+    Some(output)
+}
+
+/// Punycode decode a single label (without the xn-- prefix), RFC 3492 section 6.2
+/// Returns None on malformed input
+fn decode(input: &str) -> Option<String> {
+    let (basic, extended) = match input.rfind('-') {
+        Some(x) => (&input[..x], &input[x + 1..]),
+        None => ("", input),
+    };
+
+    if !basic.is_ascii() {
+        return None;
+    }
+
+    let mut output: Vec<char> = basic.chars().collect();
+
+    let mut n = INITIAL_N;
+    let mut i: u32 = 0;
+    let mut bias = INITIAL_BIAS;
+
+    let mut digits = extended.chars();
+
+    while let Some(first) = digits.next() {
+        let old_i = i;
+        let mut w: u32 = 1;
+        let mut k = BASE;
+        let mut c = first;
+
+        loop {
+            let digit = decode_digit(c)?;
+            i = i.checked_add(digit.checked_mul(w)?)?;
+
+            let t = threshold(k, bias);
+            if digit < t {
+                break;
+            }
+
+            w = w.checked_mul(BASE - t)?;
+            k += BASE;
+            c = digits.next()?;
+        }
+
+        let len = output.len() as u32 + 1;
+        bias = adapt(i - old_i, len, old_i == 0);
+        n = n.checked_add(i / len)?;
+        i %= len;
+
+        output.insert(i as usize, char::from_u32(n)?);
+        i += 1;
+    }
+
+    Some(output.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{ DnsPacket, DnsQuestion, QueryType };
+
+    #[test]
+    fn encodes_known_labels() {
+        // RFC 3492 section 7.1 and common examples
+        assert_eq!(to_ascii("bücher.example").unwrap(), "xn--bcher-kva.example");
+        assert_eq!(to_ascii("München.de").unwrap(), "xn--mnchen-3ya.de");
+        assert_eq!(to_ascii("例え.テスト").unwrap(), "xn--r8jz45g.xn--zckzah");
+        assert_eq!(to_ascii("Example.COM").unwrap(), "example.com");
+    }
+
+    #[test]
+    fn round_trips_through_the_wire() {
+        for name in ["bücher.example", "例え.テスト", "ελληνικά.gr", "plain.example"] {
+            let mut packet = DnsPacket::new();
+            packet.questions.push(DnsQuestion::new(name.to_string(), QueryType::A));
+            let bytes = packet.to_bytes().unwrap();
+
+            let read = DnsPacket::from_bytes(&bytes).unwrap();
+            assert_eq!(read.questions[0].name, to_ascii(name).unwrap());
+            assert_eq!(to_unicode(&read.questions[0].name), name);
+        }
+    }
+
+    #[test]
+    fn rejects_labels_too_long_once_encoded() {
+        let label: String = (0..20).filter_map(|x| char::from_u32(0x4E00 + x * 997)).collect();
+        assert!(label.chars().count() < MAX_LABEL_LEN);
+        assert!(matches!(to_ascii(&format!("{}.example", label)), Err(DnsError::LabelTooLong)));
+        assert!(matches!(to_ascii(&format!("{}.example", "a".repeat(64))), Err(DnsError::LabelTooLong)));
+    }
+
+    #[test]
+    fn leaves_undecodable_labels_alone() {
+        assert_eq!(to_unicode("xn--a-!.example"), "xn--a-!.example");
+        assert_eq!(to_unicode("xn--bcher-kva.example"), "bücher.example");
+    }
+}
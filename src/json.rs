@@ -0,0 +1,84 @@
+//! JSON rendering of DNS messages
+//! Follows the application/dns-json format used by Google and Cloudflare's resolve APIs
+//! ex. {"Status":0,"Answer":[{"name":"example.com.","type":1,"TTL":300,"data":"93.184.216.34"}]}
+
+use crate::packet::{ DnsPacket, DnsRecord };
+
+/// Render a packet as a dns-json object
+/// Record data is in presentation format, unknown types in the RFC 3597 \# form
+pub fn packet_to_json(packet: &DnsPacket) -> String {
+    let header = &packet.header;
+
+    let mut out = format!(
+        "{{\"Status\":{},\"TC\":{},\"RD\":{},\"RA\":{},\"AD\":{},\"CD\":{}",
+        header.res_code.to_u16(),
+        header.trunc,
+        header.rec_des,
+        header.rec_av,
+        header.auth_data,
+        header.checking_disabled,
+    );
+
+    let questions: Vec<String> = packet.questions.iter()
+        .map(|x| format!("{{\"name\":{},\"type\":{}}}", quote(&fqdn(&x.name)), x.q_type.to_u16()))
+        .collect();
+    out.push_str(&format!(",\"Question\":[{}]", questions.join(",")));
+
+    let sections = [
+        ("Answer", &packet.answers),
+        ("Authority", &packet.authorities),
+        ("Additional", &packet.resources),
+    ];
+
+    for (name, records) in sections {
+        if records.is_empty() {
+            continue;
+        }
+
+        let records: Vec<String> = records.iter().map(record_to_json).collect();
+        out.push_str(&format!(",\"{}\":[{}]", name, records.join(",")));
+    }
+
+    out.push('}');
+    out
+}
+
+fn record_to_json(record: &DnsRecord) -> String {
+    format!(
+        "{{\"name\":{},\"type\":{},\"TTL\":{},\"data\":{}}}",
+        quote(&fqdn(record.domain())),
+        record.query_type().to_u16(),
+        record.ttl(),
+        quote(&record.rdata_string()),
+    )
+}
+
+/// Fully qualified form of a name, with the trailing dot
+pub fn fqdn(name: &str) -> String {
+    if name.ends_with('.') {
+        name.to_string()
+    } else {
+        format!("{}.", name)
+    }
+}
+
+/// Quote and escape a JSON string
+pub fn quote(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+    out
+}
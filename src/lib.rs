@@ -0,0 +1,74 @@
+//! A DNS server that forwards to upstream resolvers or resolves from the root servers
+//! It keeps a cache and serves zones, blocklists and views over plain and encrypted transports
+//! packet reads and writes DNS messages
+//! resolve looks names up through upstreams or the root servers and answers the queries of clients with them
+//! server runs the whole thing from a command line, the way the pine-dns binary does
+
+mod acl;
+mod amplification;
+#[cfg(feature = "async")]
+mod async_server;
+mod base64;
+#[cfg(target_os = "linux")]
+mod batch;
+mod blocklist;
+mod buffer_pool;
+mod cache;
+mod chaos;
+#[cfg(unix)]
+mod control;
+mod cookies;
+mod dns64;
+mod doh;
+#[cfg(feature = "tls")]
+mod doh_client;
+#[cfg(feature = "tls")]
+mod dot_client;
+#[cfg(feature = "doq")]
+mod doq;
+mod dns_name;
+mod ede;
+mod error;
+#[cfg(unix)]
+mod event_loop;
+mod forwarding;
+mod hosts;
+mod hmac;
+mod idna;
+mod json;
+mod logging;
+mod notify;
+mod padding;
+pub mod packet;
+mod pcap;
+mod query_log;
+#[cfg(feature = "tls")]
+mod tls;
+mod rebinding;
+mod recursive;
+mod reload;
+pub mod resolve;
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_os = "macos"))]
+mod reuseport;
+mod rrl;
+mod secondary;
+#[cfg(feature = "serde")]
+mod serialization;
+pub mod server;
+#[cfg(unix)]
+mod signals;
+mod socks5;
+mod special;
+mod stats;
+#[cfg(unix)]
+mod syslog;
+#[cfg(target_os = "linux")]
+mod systemd;
+mod transport;
+mod tsig;
+mod update;
+mod upstreams;
+mod views;
+mod workers;
+mod xfr;
+mod zone;
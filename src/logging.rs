@@ -0,0 +1,72 @@
+//! Log output, leveled and timestamped on stderr, or wherever --log-target stderr|syslog|file:<path> sends it
+//! --log-level error|warn|info|debug|trace|off picks what's written (default info): errors, warnings about upstreams and clients,
+//! startup and configuration at info, every query and how it was answered at debug, and every packet received at trace
+//! Messages under the level are never formatted, and a closed stderr loses them instead of stopping the server
+//! A file is appended to as stderr would be written, and syslog gets the messages without the timestamp, which it adds itself
+//! If syslog can't be reached at startup the messages go to stderr instead, with a warning saying so
+
+use std::fs::OpenOptions;
+use std::io;
+use std::path::PathBuf;
+
+use log::{ warn, LevelFilter };
+
+#[cfg(unix)]
+use crate::syslog::{ self, Syslog };
+
+/// Where log messages go
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    Stderr,
+    #[cfg(unix)]
+    Syslog,
+    File(PathBuf),
+}
+
+/// The level a --log-level value names, None if it names none
+pub fn parse_level(name: &str) -> Option<LevelFilter> {
+    name.parse().ok()
+}
+
+/// The target a --log-target value names, None if it names none
+pub fn parse_target(name: &str) -> Option<Target> {
+    match name {
+        "stderr" => Some(Target::Stderr),
+        #[cfg(unix)]
+        "syslog" => Some(Target::Syslog),
+        _ => name.strip_prefix("file:").filter(|x| !x.is_empty()).map(|x| Target::File(x.into())),
+    }
+}
+
+/// Write log messages up to level to target from now on, only the first call has any effect
+/// Fields of a message, like those of an answered query, follow it as key=value
+/// Fails only if a file target can't be opened
+pub fn init(level: LevelFilter, target: &Target) -> io::Result<()> {
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(level).format_timestamp_millis();
+
+    match target {
+        Target::Stderr => (),
+        #[cfg(unix)]
+        Target::Syslog => match Syslog::connect(level) {
+            Ok(x) => {
+                if log::set_boxed_logger(Box::new(x)).is_ok() {
+                    log::set_max_level(level);
+                }
+                return Ok(());
+            }
+            Err(e) => {
+                let _ = builder.try_init();
+                warn!("Failed to reach syslog on {}, logging to stderr instead: {}", syslog::PATH, e);
+                return Ok(());
+            }
+        },
+        Target::File(path) => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            builder.target(env_logger::Target::Pipe(Box::new(file)));
+        }
+    }
+
+    let _ = builder.try_init();
+    Ok(())
+}
@@ -1,39 +1,101 @@
-mod data_stream;
-pub use data_stream::{ PacketBuffer, DnsHeader, DnsPacket, DnsQuestion, DnsRecord, QueryType, ResCode };
-
-use std::net::{UdpSocket, SocketAddrV4, Ipv4Addr};
+use pine_dns::server;
 
 /// Run the program with ./your_server.sh --resolver <ip:port>
-/// Where ip:port is the ip and port of a valid dns resolver
+/// Where ip:port is the ip and port of a valid dns resolver, ex. 8.8.8.8:53 or [2606:4700:4700::1111]:53
+/// A resolver can also be a DNS over HTTPS URL, ex. https://1.1.1.1/dns-query (needs the "tls" feature)
+/// or a DNS over TLS address checked against the name after #, ex. tls://1.1.1.1:853#cloudflare-dns.com
+/// Add --tls-fallback <ip:port> to send queries to a plain resolver over TCP when no TLS session can be set up
+/// (without it they get SERVFAIL)
+/// Add --bootstrap <ip:port> to resolve upstream URLs given by hostname, ex. https://cloudflare-dns.com/dns-query
+/// Add --proxy socks5://[user:password@]host:port to send every upstream lookup through a SOCKS5 proxy,
+/// UDP lookups are relayed if the proxy supports UDP ASSOCIATE and go over TCP through it otherwise
+/// Repeat --resolver for more upstreams, picked per lookup by --upstream-strategy fastest|round-robin|sequential|race (default sequential)
+/// Add --forward <domain>=<resolver> to send names in a domain to their own resolver, ex. --forward corp.internal=10.0.0.2:53
+/// Repeat it for more domains, or more resolvers for one domain, the longest matching domain wins
+/// Add --hosts <path> to answer A, AAAA and PTR questions for the names of a hosts file, ex. /etc/pine-dns/hosts, ahead of anything else,
+/// with --hosts-ttl <seconds> for the TTL of the answers (default 300), send SIGHUP to read it again
+/// Add --blocklist <path> to answer NXDOMAIN for the domains of a list and every name under them, ex. an ad and tracker list,
+/// with a domain per line or in the hosts format, repeat it to merge more lists, send SIGHUP to read them again
+/// A pattern like *.cdn.tracker.example only blocks the names under the domain, add --allowlist <path> and --allow <domain or pattern>
+/// to exempt domains a list over-blocks, the rule for the deepest domain winning
+/// Add --block-mode null|refused|sinkhole to answer blocked names with 0.0.0.0 and ::, REFUSED or the --sinkhole <ip> addresses instead,
+/// one IPv4 and one IPv6 at most, ex. of a block page, and --block-ttl <seconds> for the TTL of the answers (default 300)
+/// localhost, invalid, test, onion and the reverse names of private addresses are answered here and never sent upstream (RFC 6761),
+/// add --no-special-use <domain> to resolve one of them as usual, ex. --no-special-use 10.in-addr.arpa, or a --forward rule to send it somewhere
+/// Add --view <name>=<network>[,<network>...] to answer the clients of some networks differently, ex. --view internal=192.168.0.0/16,
+/// each query going to the view with the most specific network containing the client and everyone else to the default view,
+/// with --view-zone <name>=<origin>:<path>, --view-hosts <name>=<path> and --view-resolver <name>=<resolver> for the view's own zones,
+/// hosts file and upstreams, repeat them for more, views need the threaded server so they can't be used with --event-loop or the async one
+/// Add --zone <origin>:<path> to answer for a zone from a master file, authoritatively and without any upstream, repeat it for more zones
+/// Send SIGHUP to reload the zone files, or add --zone-poll <seconds> to reload each one whenever it's modified, a file that fails to parse leaves its zone as it was
+/// Add --allow-transfer <ip or network> to let a client pull the zones with AXFR over TCP, ex. --allow-transfer 192.168.1.0/24, repeat it for more
+/// Add --allow-update <ip or network> to let a client change the zones with UPDATE, kept across restarts in a <path>.jnl journal next to each zone file, repeat it for more
+/// Add --tsig-key <name>:<algorithm>:<base64 secret> to let transfers and updates signed with the key through from any address, and sign their answers,
+/// ex. --tsig-key transfer.home.lan:hmac-sha256:c2VjcmV0, repeat it for more keys, and --require-tsig transfer|update to refuse that operation unsigned
+/// Add --secondary <origin>:<primary-ip:port> to serve a zone transferred from a primary and refreshed on its SOA's timers, repeat it for more zones
+/// and --secondary-dir <dir> to save the transferred zones there and serve them from it on the next start
+/// A NOTIFY from a secondary zone's primary has its serial checked right away
+/// Add --also-notify <ip:port> to send a NOTIFY there whenever a zone's serial changes, repeat it for more servers
+/// Without --resolver names are resolved iteratively from the root servers,
+/// add --root-hints <path> to read them from a named.root file instead of the built-in table
+/// Name servers are asked over IPv4 first, add --prefer-ipv6 to try their IPv6 addresses first
+/// Each zone is only told the next label of the name, add --no-qname-minimization to send the whole name everywhere
+/// A client query follows at most --max-referrals <n> referrals (default 16) and sends at most --max-queries <n> queries (default 50)
+/// Add --dns64[=prefix] to answer AAAA queries for names with only A records with addresses in a /96 (default 64:ff9b::/96),
+/// A records in private ranges are skipped unless --dns64-allow-private is given
+/// Only clients on 127.0.0.0/8 and ::1 get names resolved, others just the server's own answers, add --allow-recursion <ip or network>
+/// to let more through, ex. --allow-recursion 192.168.0.0/16, repeat it for more, --allow-recursion 0.0.0.0/0 and ::/0 for everyone
+/// Add --rrl <n> to limit UDP responses to n a second for each client /24 or /56 and kind of response, against spoofed floods using the server as an amplifier,
+/// with bursts of up to --rrl-window <seconds> of them (default 5), every --rrl-slip <n>th limited response sent truncated so real clients retry over TCP
+/// (default 2, 0 drops them all) and at most --rrl-table-size <n> prefixes and kinds tracked (default 10000)
+/// Add --max-amplification <factor> to send UDP responses more than factor times the size of their query truncated, so the client
+/// retries over TCP, unless it sent a valid server cookie, ex. --max-amplification 10
+/// Add --cookies to give clients sending a DNS cookie a server cookie (RFC 7873), made with a secret replaced every --cookie-rotation <seconds>
+/// (default 3600), the one before still accepted for --cookie-grace <seconds> (default 300), and --require-cookies to answer UDP queries
+/// without a valid server cookie with BADCOOKIE, or truncated if they have no cookie at all, cookies need the threaded server
+/// Add --rebind-protection to take A and AAAA records pointing into private, loopback, link-local and other reserved ranges out of
+/// upstream answers, answering NXDOMAIN when none are left, except for names under --forward rules and --rebind-allow <domain>, repeat it for more
+/// Add --bind <ip:port> to listen somewhere other than 127.0.0.1:2053 and [::1]:2053, repeat it for more addresses
+/// When socket activated by systemd the passed in sockets are served and --bind is ignored
+/// Add --workers <n> to set how many threads answer UDP queries (default one per CPU)
+/// Add --reuseport <n> to bind n SO_REUSEPORT sockets per address instead, each answered by its own thread
+/// Add --batch to receive and send UDP in batches with recvmmsg/sendmmsg, a thread per socket and no --workers (Linux only),
+/// a slow lookup holds up the queries behind it
+/// Built with the "async" feature, UDP and TCP are served on a tokio runtime unless --sync is given
+/// Add --event-loop to answer UDP from a single threaded poll loop instead (requires --resolver)
+/// Add --lookup-timeout <ms> to set how long each upstream query waits (default 2000)
+/// and --lookup-retries <n> for how often it is resent before answering SERVFAIL (default 2)
+/// Answers are cached, add --cache-size <n> to keep at most n RRsets (default 10000, 0 turns the cache off)
+/// NXDOMAIN and empty answers are cached for their SOA's negative TTL, at most --negative-ttl <secs> (default 300)
+/// Add --min-ttl <secs> and --max-ttl <secs> to clamp the TTLs of cached records (default 0 and 86400)
+/// Cached records are answered with the TTL they have left, at least --min-served-ttl <0|1> (default 0)
+/// Entries hit --prefetch-hits <n> times are looked up again in the last tenth of their TTL (default 3, 0 turns it off)
+/// Send SIGUSR1 to flush the cache and SIGUSR2 to print the stats and dump the cache to stdout as JSON lines, the stats are printed on exit too
+/// Add --cache-file <path> to save the cache there on SIGTERM or SIGINT and load it on startup, at most --cache-file-max <n> entries (default 10000)
+/// Add --flush-on-start to start with an empty cache anyway
+/// Add --serve-stale to answer with expired records when a lookup fails, kept --stale-retention <secs> past their TTL (default 3600)
+/// Add --minimal-responses to answer with only the answer section, and the SOA of negative answers
+/// Add --log-level error|warn|info|debug|trace|off to pick what's logged (default info), debug logs every query and trace every packet
+/// Add --log-target syslog to log to the local syslog instead of stderr, or file:<path> to append to a file
+/// Add --query-log <path> to write a line for every answered query there, tab separated or --query-log-format json,
+/// rotated at --query-log-max-size <bytes> (default 100000000) with --query-log-keep <n> old files kept (default 5)
+/// Add --pcap <path> to capture every query and response there in pcap format, and --pcap-upstream to capture the UDP exchanges with upstreams too
+/// Add --verbose to dump malformed packets, and with --log-level debug every query and response as dig shows them
+/// CHAOS class TXT questions for version.bind get the version, add --version-string <text> to answer with something else or none to refuse them,
+/// and --identity <text> to answer hostname.bind and id.server, other CHAOS questions are refused
+/// Clients allowed recursion can read the counters with CHAOS TXT questions under stats.pine, ex. dig CH TXT queries.stats.pine
+/// Add --tls-cert <path> --tls-key <path> to also serve DNS over TLS, on --tls-bind <ip:port> (default port 853)
+/// Add --doq to also serve DNS over QUIC with the same certificate, on --doq-bind <ip:port> (default port 853)
+/// Add --doh-bind <ip:port> to serve DNS over HTTP on /dns-query and /resolve, over TLS if a certificate is given
+/// Add --control <path> to take commands on a unix socket there, and run pine-dns ctl [--control <path>] <command> to send one
+/// (default /run/pine-dns.sock), ex. pine-dns ctl flush-name example.com
 fn main() {
     // resolver ip : port
     let args: Vec<String> = std::env::args().collect();
-    let mut recursive = true;
-    let resolver = if args.len() == 3 && args[1] == "--resolver"  {
-        recursive = false;
-        args[2].parse::<SocketAddrV4>().unwrap()       
-    } else {
-        SocketAddrV4::new(Ipv4Addr::new(127,0,0,1), 49810)
-    };
 
-    
-    
-    let udp_socket = UdpSocket::bind("127.0.0.1:2053").expect("Failed to bind to address");
-    
-    loop {
-        if recursive {
-            println!("Resolving Recursively");
-            match data_stream::handle_query_recursively(&udp_socket) {
-                Ok(_) => {},
-                Err(e) => eprintln!("An error occurred: {}", e),
-            }  
-        } else {
-            println!("Resolver: {:#?}", resolver);
-            match data_stream::handle_query_with_resolver(&udp_socket, &resolver) {
-                Ok(_) => {},
-                Err(e) => eprintln!("An error occurred: {}", e),
-            }  
-        }
-             
+    if args.get(1).map(String::as_str) == Some("ctl") {
+        server::ctl(&args);
     }
+
+    server::run(&args);
 }
@@ -0,0 +1,83 @@
+//! Zone change notifications, NOTIFY (RFC 1996)
+//! As a secondary, a NOTIFY from a zone's primary has its serial checked right away instead of at the next refresh,
+//! NOTIFY for any other zone or from anywhere else is refused
+//! As a primary, every zone whose serial changes is announced to the --also-notify servers,
+//! each resent on timeout until it's answered or the attempts run out
+
+use std::net::{ IpAddr, SocketAddr };
+use std::sync::OnceLock;
+use std::thread;
+
+use log::{ error, info, warn };
+
+use crate::idna;
+use crate::packet::{ DnsPacket, DnsQuestion, DnsRecord, QueryType, ResCode, Opcode };
+use crate::resolve;
+use crate::secondary;
+use crate::zone;
+
+static TARGETS: OnceLock<Vec<SocketAddr>> = OnceLock::new();
+
+// Times a NOTIFY is sent before giving up on a server that doesn't answer
+const ATTEMPTS: usize = 5;
+
+/// Set the servers told about changed zones, only the first call has any effect
+pub fn set_targets(targets: Vec<SocketAddr>) {
+    let _ = TARGETS.set(targets);
+}
+
+/// Servers told about changed zones, none unless --also-notify is given
+pub fn targets() -> &'static [SocketAddr] {
+    TARGETS.get().map_or(&[], |x| x.as_slice())
+}
+
+/// Answer a NOTIFY from a client, a copy of its header and question with QR set
+pub fn answer(req: &DnsPacket, client: IpAddr) -> DnsPacket {
+    let mut response = DnsPacket::response_to(req);
+    response.questions = req.questions.clone();
+
+    let question = match req.questions.as_slice() {
+        [x] if x.q_type == QueryType::SOA => x,
+        _ => {
+            info!("Received a NOTIFY from {} without a single SOA question", client);
+            return response.with_rcode(ResCode::FORM_ERR);
+        }
+    };
+
+    match secondary::notified(&question.name, client) {
+        Ok(()) => info!("Received a NOTIFY for {} from {}, checking its serial", idna::to_unicode(&question.name), client),
+        Err(e) => {
+            info!("Refusing a NOTIFY for {} from {}, {}", idna::to_unicode(&question.name), client, e);
+            response.header.res_code = ResCode::REFUSED;
+        }
+    }
+
+    response
+}
+
+/// Tell every --also-notify server that a zone has a new SOA, each from its own thread
+pub fn send(origin: &str, soa: &DnsRecord) {
+    for target in targets() {
+        let origin = origin.to_string();
+        let soa = soa.clone();
+
+        thread::spawn(move || {
+            let mut message = DnsPacket::new();
+            message.header.id = rand::random::<u16>();
+            message.header.query_res = false;
+            message.header.opcode = Opcode::NOTIFY;
+            message.header.authoritative = true;
+            message.questions.push(DnsQuestion::new(origin.clone(), QueryType::SOA));
+            message.answers.push(soa.clone());
+
+            let name = idna::to_unicode(&origin);
+            match resolve::exchange_message(&mut message, *target, ATTEMPTS) {
+                Ok(res) if res.header.res_code == ResCode::NO_ERR => {
+                    info!("Notified {} of {} serial {}", target, name, zone::soa_serial(&soa));
+                }
+                Ok(res) => warn!("{} answered the NOTIFY for {} with {}", target, name, res.header.res_code),
+                Err(e) => error!("Failed to notify {} of {}: {}", target, name, e),
+            }
+        });
+    }
+}
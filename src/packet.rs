@@ -0,0 +1,2848 @@
+//! DNS messages, read from and written to the wire format of RFC 1035 section 4
+//! A DnsPacket is parsed with DnsPacket::from_bytes, from_buf or TryFrom<&[u8]> and written back to a PacketBuffer with write,
+//! or to bytes with to_bytes or TryFrom for Vec<u8>,
+//! over TCP each message goes with its length ahead of it, read_tcp_message and write_tcp_message add and take it off
+//! Reading or writing one that doesn't fit fails with a DnsError saying how, ex. BufferOverrun or LabelTooLong
+
+pub use crate::error::DnsError;
+pub use crate::transport::{ read_tcp_message, write_tcp_message, MAX_TCP_MESSAGE };
+
+use std::fmt;
+use std::io;
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr };
+use std::str::FromStr;
+
+use crate::dns_name;
+use crate::ede::Ede;
+use crate::idna;
+use crate::json;
+
+type Result<T> = std::result::Result<T, DnsError>;
+
+const BUF_SIZE: usize = 512;
+
+/// UDP payload offered in the OPT record of responses, what the listeners receive
+pub(crate) const LISTENER_PAYLOAD: u16 = BUF_SIZE as u16;
+
+pub struct PacketBuffer {
+    pub buf: Vec<u8>, // 512 bytes for UDP packets unless built from bytes or with a larger size
+    pub pos: usize,
+}
+
+impl PacketBuffer {
+    /// Default constructor
+    pub fn new() -> PacketBuffer {
+        PacketBuffer::with_size(BUF_SIZE)
+    }
+
+    /// Constructor for a zeroed buffer of a given size
+    /// Used for transports that allow packets larger than 512 bytes
+    pub fn with_size(size: usize) -> PacketBuffer {
+        PacketBuffer{
+            buf: vec![0; size],
+            pos: 0,
+        }
+    }
+
+    /// Constructor for a buffer holding a copy of the given bytes
+    /// Reads past the end of the bytes fail rather than seeing padding
+    pub fn from_bytes(bytes: &[u8]) -> PacketBuffer {
+        PacketBuffer{
+            buf: bytes.to_vec(),
+            pos: 0,
+        }
+    }
+
+    /// current location in the buffer
+    pub(crate) fn pos(&self) -> usize {
+        self.pos
+    }
+
+    /// move forward X number of indices in the buffer
+    fn step(&mut self, steps: usize) -> Result<()> {
+        self.pos += steps;
+
+        Ok(())
+    }
+
+    /// go to specified index
+    pub(crate) fn move_to_pos(&mut self, pos: usize) -> Result<()> {
+        self.pos = pos;
+
+        Ok(())
+    }
+
+    /// read a single byte without stepping forward
+    fn get_u8(&mut self, pos: usize) -> Result<u8> {
+        if pos >= self.buf.len() {
+            return Err(DnsError::BufferOverrun { pos: pos, needed: 1 });
+        }
+        Ok(self.buf[pos])
+    }
+
+    /// get a range of bytes
+    pub(crate) fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
+        if start + len > self.buf.len() {
+            return Err(DnsError::BufferOverrun { pos: start, needed: start + len - self.buf.len() });
+        }
+        Ok(&self.buf[start..start + len as usize])
+    }
+
+    /// Read two bytes and step two forward
+    /// Built on the [`io::Read`] impl below
+    fn read_u16(&mut self) -> Result<u16> {
+        // https://stackoverflow.com/a/50244328/22930677
+        //         A0                   B0
+        //         +--------+           +--------+
+        //         |XXXXXXXX|           |YYYYYYYY|
+        //         +-------++           +-------++
+        //                 |                    |
+        //  A1 = A0 as u16 |     B1 = B0 as u16 |
+        // +---------------v+   +---------------v+
+        // |00000000XXXXXXXX|   |00000000YYYYYYYY|
+        // +---------------++   +---------------++
+        //                 |                    |
+        //    A2 = A1 << 8 |                    |
+        // +---------------v+                   |
+        // |XXXXXXXX00000000|                   |
+        // +---------------++                   |
+        //                 |              +--+  |
+        //                 +-------------->OR<--+
+        //                                +-++
+        //                                  |
+        //                      V = A2 | B1 |
+        //                  +----------+----v+
+        //                  |XXXXXXXXYYYYYYYY|
+        //                  +----------------+
+        let mut bytes = [0; 2];
+        io::Read::read_exact(self, &mut bytes)?;
+        let res = ((bytes[0] as u16) << 8) | (bytes[1] as u16);
+
+        Ok(res)
+    }
+
+    /// Read four bytes and step four forward
+    /// See also [`read_u16(&mut self)`]
+    fn read_u32(&mut self) -> Result<u32> {
+        let mut bytes = [0; 4];
+        io::Read::read_exact(self, &mut bytes)?;
+        let res = (bytes[0] as u32) << 24
+            | (bytes[1] as u32) << 16
+            | (bytes[2] as u32) << 8
+            | (bytes[3] as u32) << 0;
+
+        Ok(res)
+    }
+
+    /// Read a qname
+    /// ex. [3]www[8]bluesky[3]com[0] appends www.bluesky.com to outstr
+    fn read_qname(&mut self, outstr: &mut String) -> Result<()> {
+        let mut pos = self.pos();
+
+        let mut jumped = false;
+        let max_jumps = 5;
+        let mut jumps_performed = 0;
+
+        let mut delim = "";
+
+        loop {
+            // prevents attack by packets with looping instructions
+            if jumps_performed > max_jumps {
+                return Err(DnsError::CompressionLoop(max_jumps));
+            }
+
+            let len = self.get_u8(pos)?;
+
+            // Checks if the first two bits are set which indicates a jump to
+            // an offset somewhere else in the packet
+            if(len & 0xC0) == 0xC0 {
+                // move past the label
+                if !jumped {
+                    self.move_to_pos(pos + 2)?;
+                }
+
+                let b2 = self.get_u8(pos + 1)? as u16;
+                let offset = (((len as u16) ^ 0xC0) << 8) | b2;
+                pos = offset as usize;
+
+                jumped = true;
+                jumps_performed += 1;
+
+                continue;
+            } 
+            // Reading a single label and appending to the output
+            else {
+                pos += 1;
+
+                if len == 0 {
+                    break;
+                }
+                // add delimiter to set up the string
+                outstr.push_str(delim);
+                // extract ascii values and append to outstr
+                let str_buf = self.get_range(pos, len as usize)?;
+                outstr.push_str(&String::from_utf8_lossy(str_buf).to_lowercase());
+
+                delim = ".";
+                
+                pos += len as usize;
+            }
+        }
+
+        if !jumped {
+            self.move_to_pos(pos)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write a single byte at the current position and increment pos by one
+    fn write(&mut self, val: u8) -> Result<()> {
+        if self.pos >= self.buf.len() {
+            return Err(DnsError::BufferOverrun { pos: self.pos, needed: 1 });
+        }
+        self.buf[self.pos] = val;
+        self.pos += 1;
+        
+        Ok(())
+    }
+
+    /// Write a u8 at the current position, increments pos
+    /// See also [`write(&mut self, val: u8)`]
+    fn write_u8(&mut self, val: u8) -> Result<()> {
+        self.write(val)?;
+
+        Ok(())
+    }
+
+    /// Write a u16 at the current position, increments pos twice
+    /// See also [`write(&mut self, val: u8)`]
+    fn write_u16(&mut self, val: u16) -> Result<()> {
+        // First byte
+        self.write((val >> 8) as u8)?;
+        // Last significant byte (second byte in this case)
+        self.write((val & 0xFF) as u8)?;
+
+        Ok(())
+    }
+
+    /// Write a u32 at the current position, increments pos thrice
+    /// See also [`write(&mut self, val: u8)`] 
+    fn write_u32(&mut self, val: u32) -> Result<()> {
+        // First byte
+        self.write(((val >> 24) & 0xFF) as u8)?;
+        // Second byte
+        self.write(((val >> 16) & 0xFF) as u8)?;
+        // Third byte
+        self.write(((val >> 8) & 0xFF) as u8)?;
+        // Fourth byte
+        self.write(((val >> 0) & 0xFF) as u8)?;
+
+        Ok(())
+    }
+
+    /// Write a slice of raw bytes at the current position, increments pos by its length
+    pub(crate) fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        io::Write::write_all(self, bytes)?;
+
+        Ok(())
+    }
+
+    /// Write a query name in label form
+    /// Non-ASCII labels are punycode encoded first
+    fn write_qname(&mut self, qname: &str) -> Result<()> {
+        let encoded;
+        let qname = if qname.is_ascii() {
+            qname
+        } else {
+            encoded = idna::to_ascii(qname)?;
+            encoded.as_str()
+        };
+
+        // RFC 1035 - max DNS label length of 63 chars
+        dns_name::validate(qname)?;
+
+        // A trailing dot (or the root name ".") doesn't add an empty label
+        let qname = qname.strip_suffix('.').unwrap_or(qname);
+
+        if !qname.is_empty() {
+            for label in qname.split('.') {
+                self.write_u8(label.len() as u8)?;
+                for b in label.as_bytes() {
+                    self.write_u8(*b)?;
+                }
+            }
+        }
+
+        self.write_u8(0)?;
+
+        Ok(())
+    }
+
+    /// Unsafe version of write_u8. Does not check if pos is past the end of the buffer
+    fn set_u8(&mut self, pos: usize, val: u8) -> Result<()> {
+        self.buf[pos] = val;
+
+        Ok(())
+    }
+
+    ///  Unsafe version of write_u16. Does not check if pos is past the end of the buffer
+    fn set_u16(&mut self, pos: usize, val: u16) -> Result<()> {
+        self.set_u8(pos, (val >> 8) as u8)?;
+        self.set_u8(pos + 1, (val & 0xFF) as u8)?;
+
+        Ok(())
+    }
+}
+
+impl PacketBuffer {
+    /// Render the first `len` bytes as a hexdump, 16 bytes per line
+    /// ex. 0000  04 d2 01 00 00 01 00 00  00 00 00 00 03 77 77 77  |.............www|
+    pub fn hexdump(&self, len: usize) -> String {
+        let len = len.min(self.buf.len());
+        let mut out = String::new();
+
+        for (line, chunk) in self.buf[..len].chunks(16).enumerate() {
+            out.push_str(&format!("{:04x}  ", line * 16));
+
+            for i in 0..16 {
+                match chunk.get(i) {
+                    Some(b) => out.push_str(&format!("{:02x} ", b)),
+                    None => out.push_str("   "),
+                }
+                // extra gap between the two halves of the line
+                if i == 7 {
+                    out.push(' ');
+                }
+            }
+
+            out.push_str(" |");
+            for b in chunk {
+                out.push(if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' });
+            }
+            out.push_str("|\n");
+        }
+
+        out
+    }
+}
+
+/// Hexdump of the buffer up to the furthest of pos and the last non-zero byte
+impl fmt::Debug for PacketBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let used = self.buf.iter()
+                           .rposition(|b| *b != 0)
+                           .map_or(0, |x| x + 1)
+                           .max(self.pos);
+
+        write!(f, "PacketBuffer {{ pos: {}, len: {} }}\n{}", self.pos, self.buf.len(), self.hexdump(used))
+    }
+}
+
+/// Reads from the current position, returning 0 bytes once the end of the buffer is reached
+impl io::Read for PacketBuffer {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.buf.len() {
+            return Ok(0);
+        }
+
+        let len = out.len().min(self.buf.len() - self.pos);
+        out[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+
+        Ok(len)
+    }
+}
+
+/// Writes at the current position
+/// Errors with UnexpectedEof when there is no room left, same as [`write(&mut self, val: u8)`]
+impl io::Write for PacketBuffer {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        if self.pos >= self.buf.len() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "End of buffer"));
+        }
+
+        let len = data.len().min(self.buf.len() - self.pos);
+        self.buf[self.pos..self.pos + len].copy_from_slice(&data[..len]);
+        self.pos += len;
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Moves the cursor. Seeking past the end is allowed but reads and writes there will hit the end of the buffer
+impl io::Seek for PacketBuffer {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            io::SeekFrom::Start(x) => (x, 0),
+            io::SeekFrom::End(x) => (self.buf.len() as u64, x),
+            io::SeekFrom::Current(x) => (self.pos as u64, x),
+        };
+
+        let new_pos = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.unsigned_abs())
+        };
+
+        match new_pos {
+            Some(x) => {
+                self.pos = x as usize;
+                Ok(x)
+            }
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid seek to a negative or overflowing position")),
+        }
+    }
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ResCode {
+    NO_ERR,       // 0
+    FORM_ERR,     // 1
+    SERV_FAIL,    // 2
+    NX_DOMAIN,    // 3
+    NOT_IMP,      // 4
+    REFUSED,      // 5
+    YX_DOMAIN,    // 6
+    YX_RR_SET,    // 7
+    NX_RR_SET,    // 8
+    NOT_AUTH,     // 9
+    NOT_ZONE,     // 10
+    BAD_VERS,     // 16 - extended, the EDNS version isn't supported (RFC 6891)
+    BAD_COOKIE,   // 23 - extended, a missing or invalid server cookie (RFC 7873)
+    UNKNOWN(u16), // unassigned, of the 12 bits the header and OPT record carry between them
+}
+
+impl ResCode {
+    /// The code of the 4 bits in the header, those past it need the OPT record, see [`with_ext_bits`]
+    pub fn from_u8(num : u8) -> ResCode {
+        ResCode::from_u16(num as u16)
+    }
+
+    pub fn from_u16(num: u16) -> ResCode {
+        match num {
+            0  => ResCode::NO_ERR,
+            1  => ResCode::FORM_ERR,
+            2  => ResCode::SERV_FAIL,
+            3  => ResCode::NX_DOMAIN,
+            4  => ResCode::NOT_IMP,
+            5  => ResCode::REFUSED,
+            6  => ResCode::YX_DOMAIN,
+            7  => ResCode::YX_RR_SET,
+            8  => ResCode::NX_RR_SET,
+            9  => ResCode::NOT_AUTH,
+            10 => ResCode::NOT_ZONE,
+            16 => ResCode::BAD_VERS,
+            23 => ResCode::BAD_COOKIE,
+            x  => ResCode::UNKNOWN(x),
+        }
+    }
+
+    pub fn to_u16(&self) -> u16 {
+        match *self {
+            ResCode::NO_ERR => 0,
+            ResCode::FORM_ERR => 1,
+            ResCode::SERV_FAIL => 2,
+            ResCode::NX_DOMAIN => 3,
+            ResCode::NOT_IMP => 4,
+            ResCode::REFUSED => 5,
+            ResCode::YX_DOMAIN => 6,
+            ResCode::YX_RR_SET => 7,
+            ResCode::NX_RR_SET => 8,
+            ResCode::NOT_AUTH => 9,
+            ResCode::NOT_ZONE => 10,
+            ResCode::BAD_VERS => 16,
+            ResCode::BAD_COOKIE => 23,
+            ResCode::UNKNOWN(x) => x,
+        }
+    }
+
+    /// The code with the upper 8 bits an OPT record carries, RFC 6891 section 6.1.3
+    pub fn with_ext_bits(&self, ext_rcode: u8) -> ResCode {
+        ResCode::from_u16(((ext_rcode as u16) << 4) | (self.to_u16() & 0x0F))
+    }
+
+    /// The upper 8 bits of the code, sent in the OPT record
+    pub fn ext_bits(&self) -> u8 {
+        (self.to_u16() >> 4) as u8
+    }
+
+    /// Whether the code doesn't fit in the header, only a response with an OPT record can carry it in full
+    pub fn is_extended(&self) -> bool {
+        self.ext_bits() != 0
+    }
+
+    /// The mnemonic of the code as dig shows it, ex. "NXDOMAIN", or RCODE<n> for the ones without one
+    pub fn mnemonic(&self) -> String {
+        match *self {
+            ResCode::NO_ERR => "NOERROR",
+            ResCode::FORM_ERR => "FORMERR",
+            ResCode::SERV_FAIL => "SERVFAIL",
+            ResCode::NX_DOMAIN => "NXDOMAIN",
+            ResCode::NOT_IMP => "NOTIMP",
+            ResCode::REFUSED => "REFUSED",
+            ResCode::YX_DOMAIN => "YXDOMAIN",
+            ResCode::YX_RR_SET => "YXRRSET",
+            ResCode::NX_RR_SET => "NXRRSET",
+            ResCode::NOT_AUTH => "NOTAUTH",
+            ResCode::NOT_ZONE => "NOTZONE",
+            ResCode::BAD_VERS => "BADVERS",
+            ResCode::BAD_COOKIE => "BADCOOKIE",
+            ResCode::UNKNOWN(x) => return format!("RCODE{}", x),
+        }.to_string()
+    }
+}
+
+/// The mnemonic, ex. "NXDOMAIN"
+impl fmt::Display for ResCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.mnemonic())
+    }
+}
+
+/// A mnemonic as dig shows it, ex. "NXDOMAIN", or a name as the stats and query log write it, ex. "NX_DOMAIN", ignoring case
+/// RCODE<n> names are accepted for any code of 12 bits
+impl FromStr for ResCode {
+    type Err = DnsError;
+
+    fn from_str(name: &str) -> Result<ResCode> {
+        let upper = name.to_ascii_uppercase();
+        if let Some(num) = upper.strip_prefix("RCODE").and_then(|x| x.parse::<u16>().ok()).filter(|x| *x < 1 << 12) {
+            return Ok(ResCode::from_u16(num));
+        }
+
+        (0..=23)
+            .map(ResCode::from_u16)
+            .filter(|x| !matches!(x, ResCode::UNKNOWN(_)))
+            .find(|x| x.mnemonic() == upper || format!("{:?}", x) == upper)
+            .ok_or_else(|| DnsError::UnknownRcode(name.to_string()))
+    }
+}
+
+/// Any of the 12 bits the header and OPT record carry between them
+impl TryFrom<u16> for ResCode {
+    type Error = DnsError;
+
+    fn try_from(num: u16) -> Result<ResCode> {
+        if num >= 1 << 12 {
+            return Err(DnsError::RcodeTooLarge(num));
+        }
+
+        Ok(ResCode::from_u16(num))
+    }
+}
+
+impl From<ResCode> for u16 {
+    fn from(res_code: ResCode) -> u16 {
+        res_code.to_u16()
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Opcode {
+    QUERY,       // 0 - Standard query
+    IQUERY,      // 1 - Inverse query, retired by RFC 3425
+    STATUS,      // 2 - Server status, never specified
+    NOTIFY,      // 4 - Zone change notification (RFC 1996)
+    UPDATE,      // 5 - Dynamic update (RFC 2136)
+    UNKNOWN(u8), // unassigned, of the 4 bits
+}
+
+impl Opcode {
+    pub fn from_u8(num: u8) -> Opcode {
+        match num {
+            0 => Opcode::QUERY,
+            1 => Opcode::IQUERY,
+            2 => Opcode::STATUS,
+            4 => Opcode::NOTIFY,
+            5 => Opcode::UPDATE,
+            x => Opcode::UNKNOWN(x),
+        }
+    }
+
+    pub fn to_u8(&self) -> u8 {
+        match *self {
+            Opcode::QUERY => 0,
+            Opcode::IQUERY => 1,
+            Opcode::STATUS => 2,
+            Opcode::NOTIFY => 4,
+            Opcode::UPDATE => 5,
+            Opcode::UNKNOWN(x) => x,
+        }
+    }
+}
+
+/// The mnemonic as dig shows it, ex. "QUERY", or the number for the unassigned ones
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Opcode::UNKNOWN(x) => write!(f, "{}", x),
+            x => write!(f, "{:?}", x),
+        }
+    }
+}
+
+/// The mnemonic of a class, ex. "IN", or CLASS<n> for the ones without one (RFC 3597)
+fn class_name(class: u16) -> String {
+    match class {
+        CLASS_IN => "IN".to_string(),
+        CLASS_CH => "CH".to_string(),
+        x => format!("CLASS{}", x),
+    }
+}
+
+/// The DNSSEC OK flag of an OPT record, asking for DNSSEC records (RFC 3225)
+pub(crate) const DO_BIT: u16 = 0x8000;
+
+/// The Internet class, of nearly every question and record
+pub(crate) const CLASS_IN: u16 = 1;
+/// The CHAOS class, asked for the server's version and identity
+pub(crate) const CLASS_CH: u16 = 3;
+
+/// EXAMPLE HEADER
+/// 1 0 0 0 0 0 0 1  1 0 0 0 0 0 0 0
+/// - -+-+-+- - - -  - -+-+- -+-+-+-
+/// Q    O    A T R  R   Z      R
+/// R    P    A C D  A          C
+///      C                      O
+///      O                      D
+///      D                      E
+///      E
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(remote = "Self"))]
+pub struct DnsHeader {
+    pub id: u16,                 // 16 bits
+
+    pub query_res: bool,         // 1 bit (0 query, 1 response)
+    pub opcode: Opcode,          // 4 bits
+    pub authoritative: bool,     // 1 bit - authoritative answer
+
+    pub trunc: bool,             // 1 bit - truncated message
+    pub rec_des: bool,           // 1 bit - recursion desired
+    pub rec_av: bool,            // 1 bit - recursion available
+
+    pub reserved: bool,          // 3 bits - reserved (DNSSEC queries)
+    pub auth_data: bool,         // 1 bit  - resolver believes data is authentic (validated by DNSSEC). Uses one of the reserved bits.
+    pub checking_disabled: bool, // 1 bit  - disable signature validation if true. Uses one of the reserved bits.
+    
+    pub res_code: ResCode,       // 4 bits - response code, 12 with the OPT record's
+
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub ques_count: u16,         // 16 bits - entries in Question Section
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub ans_count: u16,          // 16 bits - entries in Answer Section
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub auth_count: u16,         // 16 bits - entries in Authority Section
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub res_count: u16           // 16 bits - entries in Additional Section
+}
+
+impl DnsHeader {
+    /// Default constructor
+    pub fn new() -> DnsHeader {
+        DnsHeader {
+            id: 1234,
+
+            query_res: true,
+            opcode: Opcode::QUERY,
+            authoritative: false,
+
+            trunc: false,
+            rec_des: false,
+            rec_av: false,
+
+            reserved: false,
+            auth_data: false,
+            checking_disabled: false,
+            
+            res_code: ResCode::NO_ERR,
+
+            ques_count: 0,
+            ans_count: 0,
+            auth_count: 0,
+            res_count: 0,
+        }
+    }
+
+    pub fn read(&mut self, buf: &mut PacketBuffer) -> Result<()> {
+        // ID is 2 bytes
+        self.id = buf.read_u16()?;
+        // Info fields take up another 2 bytes
+        let tags = buf.read_u16()?;
+
+        // shift one byte (removes the second byte)
+        let tags_first_byte = (tags >> 8) as u8;       
+        // Mask and leave only the last significant byte (the second one in this case)
+        // 0xFF = 0..0 1111 1111
+        let tags_second_byte = (tags & 0xFF) as u8;    
+
+        // Mask to check only the first bit
+        self.query_res = (tags_first_byte & (1 << 7)) > 0;
+        // Shift 3 bits right and mask the last byte
+        // 0x0F = 0000 1111 
+        self.opcode = Opcode::from_u8((tags_first_byte >> 3) & 0x0F);
+        // Mask to check only the sixth bit
+        self.authoritative = (tags_first_byte & (1 << 2)) > 0;
+
+        // Mask to check only the seventh bit
+        self.trunc = (tags_first_byte & (1 << 1)) > 0;
+        // Mask to check only the eigth bit
+        self.rec_des = (tags_first_byte & (1 << 0)) > 0;
+        // Mask to check only the first bit
+        self.rec_av = (tags_second_byte & (1 << 7)) > 0;
+
+        // Mask to check only the second bit
+        self.reserved = (tags_second_byte & (1 << 6)) > 0;
+        // Mask to check only the third bit
+        self.auth_data = (tags_second_byte & (1 << 5)) > 0;
+        // Mask to check only the fourth bit
+        self.checking_disabled = (tags_second_byte & (1 << 4)) > 0;
+        
+        // Mask to check only the last four bits
+        self.res_code = ResCode::from_u8(tags_second_byte & 0x0F);
+
+        // The entry counts are all two bytes
+        self.ques_count = buf.read_u16()?;
+        self.ans_count = buf.read_u16()?;
+        self.auth_count = buf.read_u16()?;
+        self.res_count = buf.read_u16()?;
+
+        Ok(())
+    }
+
+    pub fn write(&self, buf: &mut PacketBuffer) -> Result<()> {
+        buf.write_u16(self.id)?;
+
+        buf.write_u8(
+            (self.rec_des as u8)
+                | ((self.trunc as u8) << 1)
+                | ((self.authoritative as u8) << 2)
+                | ((self.opcode.to_u8() & 0x0F) << 3)
+                | ((self.query_res as u8) << 7) as u8,   
+        )?;
+
+        buf.write_u8(
+            (self.res_code.to_u16() & 0x0F) as u8
+                | ((self.checking_disabled as u8) << 4)
+                | ((self.auth_data as u8) << 5)
+                | ((self.reserved as u8) << 6)
+                | ((self.rec_av as u8) << 7)
+        )?;
+
+        buf.write_u16(self.ques_count)?;
+        buf.write_u16(self.ans_count)?;
+        buf.write_u16(self.auth_count)?;
+        buf.write_u16(self.res_count)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub enum QueryType {
+    UNKNOWN(u16),
+    A,      // 1 - Alias
+    NS,     // 2 - Name Server
+    CNAME,  // 5 - Canonical Name
+    SOA,    // 6 - Start of Authority
+    PTR,    // 12 - Domain Name Pointer
+    MX,     // 15 - Mail Exchange
+    TXT,    // 16 - Text
+    AAAA,   // 28 - IPv6 Alias
+    SRV,    // 33 - Service Locator
+    OPT,    // 41 - EDNS pseudo record
+}
+
+impl QueryType {
+    pub fn to_u16(&self) -> u16 {
+        match *self {
+            QueryType::UNKNOWN(x) => x,
+            QueryType::A => 1,
+            QueryType::NS => 2,
+            QueryType::CNAME => 5,
+            QueryType::SOA => 6,
+            QueryType::PTR => 12,
+            QueryType::MX => 15,
+            QueryType::TXT => 16,
+            QueryType::AAAA => 28,
+            QueryType::SRV => 33,
+            QueryType::OPT => 41,
+        }
+    }
+
+    pub fn from_u16(num: u16) -> QueryType {
+        match num {
+            1 => QueryType::A,
+            2 => QueryType::NS,
+            5 => QueryType::CNAME,
+            6 => QueryType::SOA,
+            12 => QueryType::PTR,
+            15 => QueryType::MX,
+            16 => QueryType::TXT,
+            28 => QueryType::AAAA,
+            33 => QueryType::SRV,
+            41 => QueryType::OPT,
+            _ => QueryType::UNKNOWN(num),
+        }
+    } 
+
+    /// Look up a type by its mnemonic (ex. "AAAA") or number, ignoring case
+    /// "TYPE65" style names from RFC 3597 are accepted for any number
+    pub fn from_name(name: &str) -> Option<QueryType> {
+        if let Ok(num) = name.parse::<u16>() {
+            return Some(QueryType::from_u16(num));
+        }
+
+        let name = name.to_ascii_uppercase();
+        match name.as_str() {
+            "A" => Some(QueryType::A),
+            "NS" => Some(QueryType::NS),
+            "CNAME" => Some(QueryType::CNAME),
+            "SOA" => Some(QueryType::SOA),
+            "PTR" => Some(QueryType::PTR),
+            "MX" => Some(QueryType::MX),
+            "TXT" => Some(QueryType::TXT),
+            "AAAA" => Some(QueryType::AAAA),
+            "SRV" => Some(QueryType::SRV),
+            "OPT" => Some(QueryType::OPT),
+            _ => name.strip_prefix("TYPE")
+                .and_then(|x| x.parse::<u16>().ok())
+                .map(QueryType::from_u16),
+        }
+    }
+
+    /// The mnemonic of the type, ex. "AAAA", or TYPE<n> for the ones without one (RFC 3597)
+    pub fn mnemonic(&self) -> String {
+        match *self {
+            QueryType::UNKNOWN(x) => format!("TYPE{}", x),
+            x => format!("{:?}", x),
+        }
+    }
+}
+
+/// The mnemonic, ex. "AAAA", or TYPE<n> for the ones without one
+impl fmt::Display for QueryType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.mnemonic())
+    }
+}
+
+/// As from_name, a mnemonic, a TYPE<n> name or a number
+impl FromStr for QueryType {
+    type Err = DnsError;
+
+    fn from_str(name: &str) -> Result<QueryType> {
+        QueryType::from_name(name).ok_or_else(|| DnsError::UnknownType(name.to_string()))
+    }
+}
+
+/// Every number is a type, those without a record of their own are unknown ones,
+/// so this can't fail and TryFrom<u16> comes with it
+impl From<u16> for QueryType {
+    fn from(num: u16) -> QueryType {
+        QueryType::from_u16(num)
+    }
+}
+
+impl From<QueryType> for u16 {
+    fn from(q_type: QueryType) -> u16 {
+        q_type.to_u16()
+    }
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(remote = "Self"))]
+pub struct DnsQuestion {
+    pub name: String,
+    pub q_type: QueryType,
+    pub class: u16, // CLASS_IN unless read otherwise
+}
+
+/// Names are compared case insensitively and ignoring a trailing dot
+impl PartialEq for DnsQuestion {
+    fn eq(&self, other: &DnsQuestion) -> bool {
+        self.q_type == other.q_type && self.class == other.class && dns_name::eq_ignore_case(&self.name, &other.name)
+    }
+}
+
+impl Eq for DnsQuestion {}
+
+impl DnsQuestion {
+    /// Constructor
+    pub fn new(name: String, q_type: QueryType) -> DnsQuestion {
+        DnsQuestion { 
+            name: name,
+            q_type: q_type,
+            class: CLASS_IN,
+        }
+    }
+
+    /// Read the question section from a dns packet
+    pub fn read(&mut self, buf: &mut PacketBuffer) -> Result<()> {
+        buf.read_qname(&mut self.name)?;
+        self.q_type = QueryType::from_u16(buf.read_u16()?);
+        self.class = buf.read_u16()?;
+
+        Ok(())
+    }
+
+    /// Write the question section to a PacketBuffer
+    /// Should be used only after writing DnsHeader to the PacketBuffer
+    pub fn write(&self, buf: &mut PacketBuffer) -> Result<()> {
+        buf.write_qname(&self.name)?;
+
+        let q_type_u16 = self.q_type.to_u16();
+        buf.write_u16(q_type_u16)?;
+        buf.write_u16(self.class)?;
+
+        Ok(())
+    }
+}
+
+/// Borrowed view of a single question straight out of a PacketBuffer
+/// Lets the forwarding fast path validate a question and copy it verbatim without building Strings
+#[derive(Copy, Clone, Debug)]
+pub struct QuestionRef<'a> {
+    raw: &'a [u8],  // label bytes + terminating 0 + type + class
+    pub q_type: QueryType,
+    pub class: u16,
+}
+
+impl<'a> QuestionRef<'a> {
+    /// The name in wire format, ex. [3]www[8]bluesky[3]com[0]
+    pub fn name_bytes(&self) -> &'a [u8] {
+        &self.raw[..self.raw.len() - 4]
+    }
+
+    /// The whole question section as it appeared on the wire
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.raw
+    }
+
+    /// Check whether a dotted name refers to the same name as this question
+    /// Compared case insensitively without allocating
+    pub fn name_matches(&self, name: &str) -> bool {
+        let name = name.strip_suffix('.').unwrap_or(name);
+        let mut other = name.split('.').filter(|label| !label.is_empty());
+
+        for label in self.labels() {
+            match other.next() {
+                Some(x) if x.as_bytes().eq_ignore_ascii_case(label) => {},
+                _ => return false,
+            }
+        }
+
+        other.next().is_none()
+    }
+
+    /// Check whether the name is a dotted domain or inside it, comparing whole labels ignoring case
+    /// ex. www.corp.internal is within corp.internal but notcorp.internal isn't
+    pub fn is_within(&self, domain: &str) -> bool {
+        let domain = domain.strip_suffix('.').unwrap_or(domain);
+        let domain_labels = domain.split('.').filter(|label| !label.is_empty());
+
+        let skip = match self.labels().count().checked_sub(domain_labels.clone().count()) {
+            Some(x) => x,
+            None => return false,
+        };
+
+        self.labels()
+            .skip(skip)
+            .zip(domain_labels)
+            .all(|(label, x)| label.eq_ignore_ascii_case(x.as_bytes()))
+    }
+
+    /// The name as dotted text, lowercased like a parsed question's
+    pub fn name(&self) -> String {
+        self.labels()
+            .map(|x| String::from_utf8_lossy(x).to_lowercase())
+            .collect::<Vec<_>>()
+            .join(".")
+    }
+
+    /// Iterate over the labels of the name without copying them
+    pub fn labels(&self) -> impl Iterator<Item = &'a [u8]> {
+        let mut rest = self.name_bytes();
+
+        std::iter::from_fn(move || {
+            let len = *rest.first()? as usize;
+            if len == 0 {
+                return None;
+            }
+            let label = &rest[1..1 + len];
+            rest = &rest[1 + len..];
+
+            Some(label)
+        })
+    }
+}
+
+/// Writes the name as dotted text, decoding xn-- labels for readability
+impl<'a> fmt::Display for QuestionRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.labels()
+            .map(|x| String::from_utf8_lossy(x))
+            .collect::<Vec<_>>()
+            .join(".");
+
+        f.write_str(&idna::to_unicode(&name))
+    }
+}
+
+/// A name as Display shows it, fully qualified with xn-- labels decoded
+/// The zone and JSON output keep the ASCII form
+fn shown_name(name: &str) -> String {
+    json::fqdn(&idna::to_unicode(name))
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(remote = "Self", tag = "type"))]
+pub enum DnsRecord {
+    UNKNOWN {
+        domain: String,
+        q_type: u16,
+        #[cfg_attr(feature = "serde", serde(skip))]
+        len: u16,
+        #[cfg_attr(feature = "serde", serde(with = "crate::serialization::hex"))]
+        data: Vec<u8>,
+        ttl: u32,
+    },
+    A { // 1
+        domain: String,
+        addr_v4: Ipv4Addr,
+        ttl: u32,
+    }, 
+    NS { // 2
+        domain: String,
+        host: String,
+        ttl: u32,
+    }, 
+    CNAME { // 5
+        domain: String,
+        host: String,
+        ttl: u32,
+    }, 
+    SOA { // 6
+        domain: String,
+        m_name: String, // primary name server
+        r_name: String, // mailbox of the person responsible, the first dot stands for @
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,   // TTL of negative answers from the zone, RFC 2308
+        ttl: u32,
+    },
+    PTR { // 12
+        domain: String,
+        host: String,
+        ttl: u32,
+    },
+    MX { // 15
+        domain: String,
+        priority: u16,
+        host: String,
+        ttl: u32,
+    }, 
+    TXT { // 16
+        domain: String,
+        data: Vec<String>,
+        ttl: u32,
+    }, 
+    CHAOS_TXT { // 16 in the CHAOS class, only ever answered here with a TTL of 0
+        domain: String,
+        data: String,
+    },
+    AAAA { // 28
+        domain: String,
+        addr: Ipv6Addr,
+        ttl: u32,
+    }, 
+    SRV { // 33 - RFC 2782
+        domain: String,
+        priority: u16,
+        weight: u16,
+        port: u16,
+        host: String, // target, never compressed
+        ttl: u32,
+    },
+    OPT { // 41 - RFC 6891, always owned by the root and carried in the additional section
+        payload_size: u16, // largest UDP message the sender accepts, sent in the class field
+        ext_rcode: u8,     // upper 8 bits of the extended response code, this and the next two are sent in the ttl field
+        version: u8,
+        flags: u16,        // DO bit and reserved flags
+        #[cfg_attr(feature = "serde", serde(with = "crate::serialization::hex"))]
+        data: Vec<u8>,     // options, still encoded
+    },
+}
+
+impl DnsRecord {
+
+    /// Time to live of the record in seconds
+    pub fn ttl(&self) -> u32 {
+        match *self {
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::PTR { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::SRV { ttl, .. } => ttl,
+            // the ttl field holds flags, the record itself is never cached
+            DnsRecord::OPT { .. } => 0,
+            DnsRecord::CHAOS_TXT { .. } => 0,
+        }
+    }
+
+    /// Change the time to live, records without one are left as they are
+    pub fn set_ttl(&mut self, ttl: u32) {
+        match self {
+            DnsRecord::UNKNOWN { ttl: x, .. }
+            | DnsRecord::A { ttl: x, .. }
+            | DnsRecord::NS { ttl: x, .. }
+            | DnsRecord::CNAME { ttl: x, .. }
+            | DnsRecord::SOA { ttl: x, .. }
+            | DnsRecord::PTR { ttl: x, .. }
+            | DnsRecord::MX { ttl: x, .. }
+            | DnsRecord::TXT { ttl: x, .. }
+            | DnsRecord::AAAA { ttl: x, .. }
+            | DnsRecord::SRV { ttl: x, .. } => *x = ttl,
+            // the ttl field holds flags
+            DnsRecord::OPT { .. } => (),
+            DnsRecord::CHAOS_TXT { .. } => (),
+        }
+    }
+
+    /// Owner name of the record
+    pub fn domain(&self) -> &str {
+        match *self {
+            DnsRecord::UNKNOWN { ref domain, .. }
+            | DnsRecord::A { ref domain, .. }
+            | DnsRecord::NS { ref domain, .. }
+            | DnsRecord::CNAME { ref domain, .. }
+            | DnsRecord::SOA { ref domain, .. }
+            | DnsRecord::PTR { ref domain, .. }
+            | DnsRecord::MX { ref domain, .. }
+            | DnsRecord::TXT { ref domain, .. }
+            | DnsRecord::CHAOS_TXT { ref domain, .. }
+            | DnsRecord::AAAA { ref domain, .. }
+            | DnsRecord::SRV { ref domain, .. } => domain,
+            DnsRecord::OPT { .. } => "",
+        }
+    }
+
+    /// Type of the record
+    pub fn query_type(&self) -> QueryType {
+        match *self {
+            DnsRecord::UNKNOWN { q_type, .. } => QueryType::from_u16(q_type),
+            DnsRecord::A { .. } => QueryType::A,
+            DnsRecord::NS { .. } => QueryType::NS,
+            DnsRecord::CNAME { .. } => QueryType::CNAME,
+            DnsRecord::SOA { .. } => QueryType::SOA,
+            DnsRecord::PTR { .. } => QueryType::PTR,
+            DnsRecord::MX { .. } => QueryType::MX,
+            DnsRecord::TXT { .. } | DnsRecord::CHAOS_TXT { .. } => QueryType::TXT,
+            DnsRecord::AAAA { .. } => QueryType::AAAA,
+            DnsRecord::SRV { .. } => QueryType::SRV,
+            DnsRecord::OPT { .. } => QueryType::OPT,
+        }
+    }
+
+    /// Whether two records have the same type and data, whatever their owners and TTLs, names in the data compared ignoring case
+    /// Records of an RRset that this holds for are duplicates
+    pub fn rdata_eq(&self, other: &DnsRecord) -> bool {
+        match self {
+            DnsRecord::UNKNOWN { q_type: t, data: x, .. } => matches!(other, DnsRecord::UNKNOWN { q_type, data, .. } if q_type == t && data == x),
+            DnsRecord::A { addr_v4: x, .. } => matches!(other, DnsRecord::A { addr_v4, .. } if addr_v4 == x),
+            DnsRecord::NS { host: x, .. } => matches!(other, DnsRecord::NS { host, .. } if dns_name::eq_ignore_case(host, x)),
+            DnsRecord::CNAME { host: x, .. } => matches!(other, DnsRecord::CNAME { host, .. } if dns_name::eq_ignore_case(host, x)),
+            DnsRecord::SOA { m_name: m, r_name: r, serial: s, refresh: f, retry: t, expire: e, minimum: n, .. } => matches!(
+                other,
+                DnsRecord::SOA { m_name, r_name, serial, refresh, retry, expire, minimum, .. }
+                    if dns_name::eq_ignore_case(m_name, m) && dns_name::eq_ignore_case(r_name, r)
+                        && (serial, refresh, retry, expire, minimum) == (s, f, t, e, n)
+            ),
+            DnsRecord::PTR { host: x, .. } => matches!(other, DnsRecord::PTR { host, .. } if dns_name::eq_ignore_case(host, x)),
+            DnsRecord::MX { priority: p, host: x, .. } => {
+                matches!(other, DnsRecord::MX { priority, host, .. } if priority == p && dns_name::eq_ignore_case(host, x))
+            }
+            DnsRecord::TXT { data: x, .. } => matches!(other, DnsRecord::TXT { data, .. } if data == x),
+            DnsRecord::CHAOS_TXT { data: x, .. } => matches!(other, DnsRecord::CHAOS_TXT { data, .. } if data == x),
+            DnsRecord::AAAA { addr: x, .. } => matches!(other, DnsRecord::AAAA { addr, .. } if addr == x),
+            DnsRecord::SRV { priority: p, weight: w, port: o, host: x, .. } => matches!(
+                other,
+                DnsRecord::SRV { priority, weight, port, host, .. } if (priority, weight, port) == (p, w, o) && dns_name::eq_ignore_case(host, x)
+            ),
+            DnsRecord::OPT { payload_size: p, ext_rcode: e, version: v, flags: f, data: x } => matches!(
+                other,
+                DnsRecord::OPT { payload_size, ext_rcode, version, flags, data } if (payload_size, ext_rcode, version, flags, data) == (p, e, v, f, x)
+            ),
+        }
+    }
+
+    /// Record data in presentation format, ex. "10 mail.example.com." for MX
+    /// Types without a presentation format here use the generic \# form from RFC 3597
+    pub fn rdata_string(&self) -> String {
+        self.rdata_with(|x| format!("{}.", x))
+    }
+
+    /// Record data with the host names written by fqdn
+    fn rdata_with(&self, fqdn: fn(&str) -> String) -> String {
+        match *self {
+            DnsRecord::A { ref addr_v4, .. } => addr_v4.to_string(),
+            DnsRecord::AAAA { ref addr, .. } => addr.to_string(),
+            DnsRecord::NS { ref host, .. }
+            | DnsRecord::CNAME { ref host, .. }
+            | DnsRecord::PTR { ref host, .. } => fqdn(host),
+            DnsRecord::SOA { ref m_name, ref r_name, serial, refresh, retry, expire, minimum, .. } => {
+                format!("{} {} {} {} {} {} {}", fqdn(m_name), fqdn(r_name), serial, refresh, retry, expire, minimum)
+            }
+            DnsRecord::MX { priority, ref host, .. } => format!("{} {}", priority, fqdn(host)),
+            DnsRecord::SRV { priority, weight, port, ref host, .. } => format!("{} {} {} {}", priority, weight, port, fqdn(host)),
+            DnsRecord::TXT { ref data, .. } => {
+                data.iter()
+                    .map(|x| quote_text(x))
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            }
+            DnsRecord::CHAOS_TXT { ref data, .. } => quote_text(data),
+            DnsRecord::UNKNOWN { ref data, .. }
+            | DnsRecord::OPT { ref data, .. } => {
+                let hex: String = data.iter().map(|x| format!("{:02x}", x)).collect();
+                if hex.is_empty() {
+                    "\\# 0".to_string()
+                } else {
+                    format!("\\# {} {}", data.len(), hex)
+                }
+            }
+        }
+    }
+
+    pub fn read(buf: &mut PacketBuffer) -> Result<DnsRecord> {
+        let mut domain = String::new();
+        buf.read_qname(&mut domain)?;
+
+        let q_type_u16 = buf.read_u16()?;
+        let q_type = QueryType::from_u16(q_type_u16);
+        let class = buf.read_u16()?;
+        let ttl = buf.read_u32()?;
+        let len = buf.read_u16()?;
+
+        match q_type {
+            QueryType::A => {
+                let raw_addr_v4 = buf.read_u32()?;
+                let addr_v4 = Ipv4Addr::new(
+                    ((raw_addr_v4 >> 24) & 0xFF) as u8,
+                    ((raw_addr_v4 >> 16) & 0xFF) as u8,
+                    ((raw_addr_v4 >> 8) & 0xFF)  as u8,
+                    ((raw_addr_v4 >> 0) & 0xFF)  as u8,
+                );
+
+                Ok(DnsRecord::A { 
+                    domain: domain, 
+                    addr_v4: addr_v4, 
+                    ttl: ttl, 
+                })
+            }
+            QueryType::AAAA => {
+                let raw_addr_1 = buf.read_u32()?;
+                let raw_addr_2 = buf.read_u32()?;
+                let raw_addr_3 = buf.read_u32()?;
+                let raw_addr_4 = buf.read_u32()?;
+                let addr_v6 = Ipv6Addr::new(
+                    ((raw_addr_1 >> 16 & 0xFFFF)) as u16,
+                    ((raw_addr_1 >> 0  & 0xFFFF)) as u16,
+                    ((raw_addr_2 >> 16 & 0xFFFF)) as u16,
+                    ((raw_addr_2 >> 0  & 0xFFFF)) as u16,
+                    ((raw_addr_3 >> 16 & 0xFFFF)) as u16,
+                    ((raw_addr_3 >> 0  & 0xFFFF)) as u16,
+                    ((raw_addr_4 >> 16 & 0xFFFF)) as u16,
+                    ((raw_addr_4 >> 0  & 0xFFFF)) as u16,
+                );
+
+                Ok(DnsRecord::AAAA { 
+                    domain: domain, 
+                    addr: addr_v6, 
+                    ttl: ttl 
+                })
+            }
+            QueryType::NS => {
+                let mut ns = String::new();
+                buf.read_qname(&mut ns)?;
+
+                Ok(DnsRecord::NS { 
+                    domain: domain, 
+                    host: ns, 
+                    ttl: ttl 
+                })
+            }
+            QueryType::CNAME => {
+                let mut cname = String::new();
+                buf.read_qname(&mut cname)?;
+
+                Ok(DnsRecord::CNAME { 
+                    domain: domain, 
+                    host: cname, 
+                    ttl: ttl 
+                })
+            }
+            QueryType::SOA => {
+                let mut m_name = String::new();
+                buf.read_qname(&mut m_name)?;
+                let mut r_name = String::new();
+                buf.read_qname(&mut r_name)?;
+
+                Ok(DnsRecord::SOA {
+                    domain: domain,
+                    m_name: m_name,
+                    r_name: r_name,
+                    serial: buf.read_u32()?,
+                    refresh: buf.read_u32()?,
+                    retry: buf.read_u32()?,
+                    expire: buf.read_u32()?,
+                    minimum: buf.read_u32()?,
+                    ttl: ttl,
+                })
+            }
+            QueryType::PTR => {
+                let mut host = String::new();
+                buf.read_qname(&mut host)?;
+
+                Ok(DnsRecord::PTR {
+                    domain: domain,
+                    host: host,
+                    ttl: ttl,
+                })
+            }
+            QueryType::SRV => {
+                let priority = buf.read_u16()?;
+                let weight = buf.read_u16()?;
+                let port = buf.read_u16()?;
+                let mut host = String::new();
+                buf.read_qname(&mut host)?;
+
+                Ok(DnsRecord::SRV {
+                    domain: domain,
+                    priority: priority,
+                    weight: weight,
+                    port: port,
+                    host: host,
+                    ttl: ttl,
+                })
+            }
+            QueryType::MX => {
+                let prio = buf.read_u16()?;
+                let mut mx = String::new();
+                buf.read_qname(&mut mx)?;
+
+                Ok(DnsRecord::MX { 
+                    domain: domain, 
+                    priority: prio, 
+                    host: mx, 
+                    ttl: ttl 
+                })
+            }
+            QueryType::TXT => {
+                let end = buf.pos() + len as usize;
+                let mut data = Vec::new();
+
+                // one or more length prefixed character strings
+                while buf.pos() < end {
+                    let start = buf.pos();
+                    let str_len = buf.get_u8(start)? as usize;
+                    let text = String::from_utf8_lossy(buf.get_range(start + 1, str_len)?).to_string();
+                    buf.step(str_len + 1)?;
+
+                    data.push(text);
+                }
+
+                Ok(DnsRecord::TXT { 
+                    domain: domain, 
+                    data: data, 
+                    ttl: ttl 
+                })
+            }
+            QueryType::OPT => {
+                let start = buf.pos();
+                let data = buf.get_range(start, len as usize)?.to_vec();
+                buf.step(len as usize)?;
+
+                Ok(DnsRecord::OPT {
+                    payload_size: class,
+                    ext_rcode: (ttl >> 24) as u8,
+                    version: (ttl >> 16) as u8,
+                    flags: ttl as u16,
+                    data: data,
+                })
+            }
+            QueryType::UNKNOWN(_) => {
+                let start = buf.pos();
+                let data = buf.get_range(start, len as usize)?.to_vec();
+                buf.step(len as usize)?;
+
+                Ok(DnsRecord::UNKNOWN { 
+                    domain: domain, 
+                    q_type: q_type_u16,
+                    len: len, 
+                    data: data,
+                    ttl: ttl 
+                })
+            }
+        }
+    }
+
+    
+    pub fn write(&self, buf: &mut PacketBuffer) -> Result<usize> {
+        let start = buf.pos;
+
+        match *self {
+            DnsRecord::A {
+                ref domain,
+                ref addr_v4,
+                ttl,
+            } => {
+                buf.write_qname(domain)?;
+                buf.write_u16(QueryType::A.to_u16())?;
+                buf.write_u16(1)?;
+                buf.write_u32(ttl)?;
+                buf.write_u16(4)?;
+
+                let octets = addr_v4.octets();
+                buf.write_u8(octets[0])?;
+                buf.write_u8(octets[1])?;
+                buf.write_u8(octets[2])?;
+                buf.write_u8(octets[3])?;
+            }
+            DnsRecord::NS { 
+                ref domain, 
+                ref host, 
+                ttl 
+            } => {
+                buf.write_qname(domain)?;
+                buf.write_u16(QueryType::NS.to_u16())?;
+                buf.write_u16(1)?;
+                buf.write_u32(ttl)?;
+
+                let pos = buf.pos();
+                buf.write_u16(0)?;
+
+                buf.write_qname(host)?;
+                let size = buf.pos() - (pos + 2);
+                buf.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::CNAME { 
+                ref domain,
+                ref host, 
+                ttl 
+            } => {
+                buf.write_qname(domain)?;
+                buf.write_u16(QueryType::CNAME.to_u16())?;
+                buf.write_u16(1)?;
+                buf.write_u32(ttl)?;
+
+                let pos = buf.pos();
+                buf.write_u16(0)?;
+
+                buf.write_qname(host)?;
+
+                let size = buf.pos() - (pos + 2);
+                buf.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::SOA {
+                ref domain,
+                ref m_name,
+                ref r_name,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ttl,
+            } => {
+                buf.write_qname(domain)?;
+                buf.write_u16(QueryType::SOA.to_u16())?;
+                buf.write_u16(1)?;
+                buf.write_u32(ttl)?;
+
+                let pos = buf.pos();
+                buf.write_u16(0)?;
+
+                buf.write_qname(m_name)?;
+                buf.write_qname(r_name)?;
+                buf.write_u32(serial)?;
+                buf.write_u32(refresh)?;
+                buf.write_u32(retry)?;
+                buf.write_u32(expire)?;
+                buf.write_u32(minimum)?;
+
+                let size = buf.pos() - (pos + 2);
+                buf.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::PTR {
+                ref domain,
+                ref host,
+                ttl,
+            } => {
+                buf.write_qname(domain)?;
+                buf.write_u16(QueryType::PTR.to_u16())?;
+                buf.write_u16(1)?;
+                buf.write_u32(ttl)?;
+
+                let pos = buf.pos();
+                buf.write_u16(0)?;
+
+                buf.write_qname(host)?;
+
+                let size = buf.pos() - (pos + 2);
+                buf.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::SRV {
+                ref domain,
+                priority,
+                weight,
+                port,
+                ref host,
+                ttl,
+            } => {
+                buf.write_qname(domain)?;
+                buf.write_u16(QueryType::SRV.to_u16())?;
+                buf.write_u16(1)?;
+                buf.write_u32(ttl)?;
+
+                let pos = buf.pos();
+                buf.write_u16(0)?;
+
+                buf.write_u16(priority)?;
+                buf.write_u16(weight)?;
+                buf.write_u16(port)?;
+                buf.write_qname(host)?;
+
+                let size = buf.pos() - (pos + 2);
+                buf.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::MX { 
+                ref domain, 
+                priority, 
+                ref host, 
+                ttl 
+            } => {
+                buf.write_qname(domain)?;
+                buf.write_u16(QueryType::MX.to_u16())?;
+                buf.write_u16(1)?;
+                buf.write_u32(ttl)?;
+
+                let pos = buf.pos();
+                buf.write_u16(0)?;
+
+                buf.write_u16(priority)?;
+                buf.write_qname(host)?;
+
+                let size = buf.pos() - (pos + 2);
+                buf.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::AAAA { 
+                ref domain,
+                ref addr,
+                ttl 
+            } => {
+                buf.write_qname(domain)?;
+                buf.write_u16(QueryType::AAAA.to_u16())?;
+                buf.write_u16(1)?;
+                buf.write_u32(ttl)?;
+                buf.write_u16(16)?;
+
+                for octet in &addr.segments() {
+                    buf.write_u16(*octet)?;
+                }
+            }        
+ 
+            DnsRecord::TXT { 
+                ref domain,
+                ref data,
+                ttl 
+            } => {
+                buf.write_qname(domain)?;
+                buf.write_u16(QueryType::TXT.to_u16())?;
+                buf.write_u16(1)?;
+                buf.write_u32(ttl)?;
+
+                let pos = buf.pos();
+                buf.write_u16(0)?;
+
+                // character strings are at most 255 bytes, longer text is split across several
+                for text in data {
+                    let bytes = text.as_bytes();
+                    if bytes.is_empty() {
+                        buf.write_u8(0)?;
+                    }
+                    for chunk in bytes.chunks(255) {
+                        buf.write_u8(chunk.len() as u8)?;
+                        buf.write_bytes(chunk)?;
+                    }
+                }
+
+                let size = buf.pos() - (pos + 2);
+                buf.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::UNKNOWN { 
+                ref domain,
+                q_type,
+                ref data,
+                ttl,
+                ..
+            } => {
+                // RFC 3597 - unknown rdata is never compressed so it can be copied as is
+                buf.write_qname(domain)?;
+                buf.write_u16(q_type)?;
+                buf.write_u16(1)?;
+                buf.write_u32(ttl)?;
+                buf.write_u16(data.len() as u16)?;
+                buf.write_bytes(data)?;
+            }                 
+            DnsRecord::CHAOS_TXT {
+                ref domain,
+                ref data,
+            } => {
+                buf.write_qname(domain)?;
+                buf.write_u16(QueryType::TXT.to_u16())?;
+                buf.write_u16(CLASS_CH)?;
+                buf.write_u32(0)?;
+
+                let pos = buf.pos();
+                buf.write_u16(0)?;
+
+                // split across character strings of at most 255 bytes as TXT is, a --version-string can be any length
+                let bytes = data.as_bytes();
+                if bytes.is_empty() {
+                    buf.write_u8(0)?;
+                }
+                for chunk in bytes.chunks(255) {
+                    buf.write_u8(chunk.len() as u8)?;
+                    buf.write_bytes(chunk)?;
+                }
+
+                let size = buf.pos() - (pos + 2);
+                buf.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::OPT {
+                payload_size,
+                ext_rcode,
+                version,
+                flags,
+                ref data,
+            } => {
+                buf.write_qname("")?;
+                buf.write_u16(QueryType::OPT.to_u16())?;
+                buf.write_u16(payload_size)?;
+                buf.write_u32(((ext_rcode as u32) << 24) | ((version as u32) << 16) | flags as u32)?;
+                buf.write_u16(data.len() as u16)?;
+                buf.write_bytes(data)?;
+            }
+        }
+
+        Ok(buf.pos() - start)
+    }
+}
+
+/// A record of an UPDATE's prerequisite or update section, its class saying what it asks for (RFC 2136 section 2.4 and 2.5)
+/// Those of class ANY and NONE may come without data, and then have no record
+#[derive(Clone, Debug)]
+pub(crate) struct UpdateRecord {
+    pub name: String,
+    pub q_type: QueryType,
+    pub class: u16,
+    pub ttl: u32,
+    pub record: Option<DnsRecord>,
+}
+
+impl UpdateRecord {
+    pub fn read(buf: &mut PacketBuffer) -> Result<UpdateRecord> {
+        let start = buf.pos();
+        let mut name = String::new();
+        buf.read_qname(&mut name)?;
+
+        let q_type = QueryType::from_u16(buf.read_u16()?);
+        let class = buf.read_u16()?;
+        let ttl = buf.read_u32()?;
+        let len = buf.read_u16()?;
+
+        let record = if len == 0 {
+            None
+        } else {
+            // read again from the start as an ordinary record
+            buf.move_to_pos(start)?;
+            Some(DnsRecord::read(buf)?)
+        };
+
+        Ok(UpdateRecord {
+            name: name,
+            q_type: q_type,
+            class: class,
+            ttl: ttl,
+            record: record,
+        })
+    }
+}
+
+/// One line in master file presentation format, ex. "example.com.\t300\tIN\tMX\t10 mail.example.com."
+/// An OPT record is shown as dig shows the EDNS pseudosection, ex. "; EDNS: version: 0, flags: do; udp: 1232"
+impl fmt::Display for DnsRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let class = match self {
+            DnsRecord::OPT { payload_size, version, flags, .. } => {
+                let flags = if flags & DO_BIT != 0 { " do" } else { "" };
+                return write!(f, "; EDNS: version: {}, flags:{}; udp: {}", version, flags, payload_size);
+            }
+            DnsRecord::CHAOS_TXT { .. } => CLASS_CH,
+            _ => CLASS_IN,
+        };
+
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}",
+            shown_name(self.domain()),
+            self.ttl(),
+            class_name(class),
+            self.query_type().mnemonic(),
+            self.rdata_with(shown_name),
+        )
+    }
+}
+
+/// Quote a TXT character string, escaping quotes, backslashes and unprintable bytes as \DDD
+fn quote_text(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+
+    for &b in text.as_bytes() {
+        match b {
+            b'"' | b'\\' => {
+                out.push('\\');
+                out.push(b as char);
+            }
+            0x20..=0x7E => out.push(b as char),
+            _ => out.push_str(&format!("\\{:03}", b)),
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+/// Upper bounds on the section counts accepted when parsing untrusted packets
+/// A 512 byte packet can claim 65535 answers; these stop us from even trying
+#[derive(Copy, Clone, Debug)]
+pub struct ParseLimits {
+    pub max_questions: u16,
+    pub max_answers: u16,
+    pub max_authorities: u16,
+    pub max_resources: u16,
+}
+
+impl Default for ParseLimits {
+    fn default() -> ParseLimits {
+        ParseLimits {
+            max_questions: 64,
+            max_answers: 256,
+            max_authorities: 256,
+            max_resources: 256,
+        }
+    }
+}
+
+impl ParseLimits {
+    /// Returns a format error if any count in the header exceeds its limit
+    fn check(&self, header: &DnsHeader) -> Result<()> {
+        let sections = [
+            ("question", header.ques_count, self.max_questions),
+            ("answer", header.ans_count, self.max_answers),
+            ("authority", header.auth_count, self.max_authorities),
+            ("additional", header.res_count, self.max_resources),
+        ];
+
+        for (name, count, max) in sections {
+            if count > max {
+                return Err(DnsError::TooManyRecords { section: name, count: count, max: max });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(remote = "Self"))]
+pub struct DnsPacket {
+    pub header: DnsHeader,
+    pub questions: Vec<DnsQuestion>,
+    pub answers: Vec<DnsRecord>,
+    pub authorities: Vec<DnsRecord>,
+    pub resources: Vec<DnsRecord>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub extended_errors: Vec<Ede>, // why it's answered the way it is, sent in the OPT record to clients that have one
+}
+
+impl DnsPacket {
+    /// Default constructor
+    pub fn new() -> DnsPacket {
+        DnsPacket {
+            header: DnsHeader::new(),
+            questions: Vec::new(),
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            resources: Vec::new(),
+            extended_errors: Vec::new(),
+        }
+    }
+
+    /// An empty response to a request, its header echoed as [`response_to`] does, to build on with the methods below
+    /// ex. DnsPacket::response_to(&req).with_rcode(ResCode::NX_DOMAIN).authority(soa)
+    /// The section counts are left to write, which always sets them from the sections
+    pub fn response_to(req: &DnsPacket) -> DnsPacket {
+        response_to(&req.header)
+    }
+
+    /// The packet with an rcode
+    pub fn with_rcode(mut self, res_code: ResCode) -> DnsPacket {
+        self.header.res_code = res_code;
+        self
+    }
+
+    /// The packet with AA set, for an answer from data the server is the authority for
+    pub fn authoritative(mut self) -> DnsPacket {
+        self.header.authoritative = true;
+        self
+    }
+
+    /// The packet with a question added
+    pub fn question(mut self, question: DnsQuestion) -> DnsPacket {
+        self.questions.push(question);
+        self
+    }
+
+    /// The packet with a record added to the answer section
+    pub fn answer(mut self, record: DnsRecord) -> DnsPacket {
+        self.answers.push(record);
+        self
+    }
+
+    /// The packet with a record added to the authority section
+    pub fn authority(mut self, record: DnsRecord) -> DnsPacket {
+        self.authorities.push(record);
+        self
+    }
+
+    /// The packet with a record added to the additional section
+    pub fn additional(mut self, record: DnsRecord) -> DnsPacket {
+        self.resources.push(record);
+        self
+    }
+
+    /// Read the contents of a PacketBuffer into a DnsPacket
+    /// Section counts are checked against the default ParseLimits
+    pub fn from_buf(buf: &mut PacketBuffer) -> Result<DnsPacket> {
+        DnsPacket::from_buf_with_limits(buf, &ParseLimits::default())
+    }
+
+    /// Read the contents of a PacketBuffer into a DnsPacket
+    /// Fails before reading any section if the header claims more entries than the limits allow
+    pub fn from_buf_with_limits(buf: &mut PacketBuffer, limits: &ParseLimits) -> Result<DnsPacket> {
+        let mut result = DnsPacket::new();
+        result.header.read(buf)?;
+
+        limits.check(&result.header)?;
+
+        for _ in 0..result.header.ques_count {
+            let mut ques = DnsQuestion::new("".to_string(), QueryType::UNKNOWN(0));
+            ques.read(buf)?;
+            result.questions.push(ques);
+        }
+
+        for _ in 0..result.header.ans_count {
+            let rec = DnsRecord::read(buf)?;
+            result.answers.push(rec);
+        }
+
+        for _ in 0..result.header.auth_count {
+            let rec = DnsRecord::read(buf)?;
+            result.authorities.push(rec);
+        }
+        
+        for _ in 0..result.header.res_count {
+            let rec = DnsRecord::read(buf)?;
+            result.resources.push(rec);
+        }
+
+        // the upper bits of an extended rcode are in the OPT record
+        if let Some(DnsRecord::OPT { ext_rcode, .. }) = result.resources.iter().find(|x| matches!(x, DnsRecord::OPT { .. })) {
+            result.header.res_code = result.header.res_code.with_ext_bits(*ext_rcode);
+        }
+
+        Ok(result)
+    }
+
+    /// Parse a DnsPacket from raw wire bytes of any length
+    /// Truncated input results in an error rather than reading padding
+    pub fn from_bytes(bytes: &[u8]) -> Result<DnsPacket> {
+        let mut buf = PacketBuffer::from_bytes(bytes);
+
+        DnsPacket::from_buf(&mut buf)
+    }
+
+    /// Borrow the question of a single question packet without parsing the rest of it
+    /// Fails for packets with more or less than one question, or a compressed question name,
+    /// in which case the caller should fall back to [`from_buf(buf)`]
+    pub fn peek_question(buf: &PacketBuffer) -> Result<QuestionRef<'_>> {
+        // Questions start right after the 12 byte header
+        let start = 12;
+        let bytes = &buf.buf;
+
+        if bytes.len() < start {
+            return Err(DnsError::BufferOverrun { pos: bytes.len(), needed: start - bytes.len() });
+        }
+        let ques_count = ((bytes[4] as u16) << 8) | (bytes[5] as u16);
+        if ques_count != 1 {
+            return Err(DnsError::QuestionCount(ques_count));
+        }
+
+        let mut pos = start;
+        loop {
+            let len = *bytes.get(pos).ok_or(DnsError::BufferOverrun { pos: pos, needed: 1 })? as usize;
+
+            if (len & 0xC0) != 0 {
+                return Err(DnsError::CompressedQuestion);
+            }
+
+            pos += 1 + len;
+
+            if len == 0 {
+                break;
+            }
+        }
+
+        // type and class follow the name
+        if pos + 4 > bytes.len() {
+            return Err(DnsError::BufferOverrun { pos: pos, needed: pos + 4 - bytes.len() });
+        }
+        let q_type = QueryType::from_u16(((bytes[pos] as u16) << 8) | (bytes[pos + 1] as u16));
+        let class = ((bytes[pos + 2] as u16) << 8) | (bytes[pos + 3] as u16);
+
+        Ok(QuestionRef {
+            raw: &bytes[start..pos + 4],
+            q_type: q_type,
+            class: class,
+        })
+    }
+
+    /// The TTL field and options of a request's OPT record, read from its bytes without parsing the rest
+    /// None if it has no OPT record or it runs past the end
+    pub fn peek_opt(req: &[u8]) -> Option<(u32, &[u8])> {
+        let pos = DnsPacket::find_opt(req)?;
+        let ttl = u32::from_be_bytes(req.get(pos + 4..pos + 8)?.try_into().ok()?);
+        let len = u16::from_be_bytes(req.get(pos + 8..pos + 10)?.try_into().ok()?) as usize;
+
+        Some((ttl, req.get(pos + 10..pos + 10 + len)?))
+    }
+
+    /// The rcode of a message read from its bytes, with the upper bits from its OPT record when it has one
+    /// The message must be at least as long as a header
+    pub fn peek_res_code(bytes: &[u8]) -> ResCode {
+        let res_code = ResCode::from_u8(bytes[3] & 0x0F);
+        match DnsPacket::find_opt(bytes).and_then(|x| bytes.get(x + 4)) {
+            Some(ext_rcode) => res_code.with_ext_bits(*ext_rcode),
+            None => res_code,
+        }
+    }
+
+    /// Where the type field of a message's OPT record is, right after its name, found without parsing the rest
+    /// None if it has no OPT record or it runs past the end
+    pub fn find_opt(bytes: &[u8]) -> Option<usize> {
+        let count = |i: usize| bytes.get(i..i + 2).map(|x| u16::from_be_bytes([x[0], x[1]]) as usize);
+        let (questions, answers, authorities, additional) = (count(4)?, count(6)?, count(8)?, count(10)?);
+
+        let mut pos = 12;
+        for _ in 0..questions {
+            pos = skip_name(bytes, pos)? + 4;
+        }
+        for _ in 0..answers + authorities {
+            pos = skip_name(bytes, pos)? + 8;
+            pos += 2 + count(pos)?;
+        }
+
+        for _ in 0..additional {
+            pos = skip_name(bytes, pos)?;
+            let r_type = count(pos)?;
+            let len = count(pos + 8)?;
+            if pos + 10 + len > bytes.len() {
+                return None;
+            }
+
+            if r_type == QueryType::OPT.to_u16() as usize {
+                return Some(pos);
+            }
+            pos += 10 + len;
+        }
+
+        None
+    }
+
+    /// Write the packet with a raw question section in place of `self.questions`
+    /// Used to copy a question taken from [`peek_question(buf)`] straight into a response
+    pub fn write_with_question(&mut self, buf: &mut PacketBuffer, question: &QuestionRef) -> Result<()> {
+        self.header.ques_count = 1;
+        self.header.ans_count = self.answers.len() as u16;
+        self.header.auth_count = self.authorities.len() as u16;
+        self.header.res_count = self.resources.len() as u16;
+        self.split_res_code();
+
+        self.header.write(buf)?;
+
+        buf.write_bytes(question.as_bytes())?;
+
+        for rec in &self.answers {
+            rec.write(buf)?;
+        }
+
+        for rec in &self.authorities {
+            rec.write(buf)?;
+        }
+
+        for rec in &self.resources {
+            rec.write(buf)?;
+        }
+
+        Ok(())
+    }
+
+    /// Serialize the packet to wire bytes, up to the largest message TCP can carry
+    /// Header counts are updated the same way as [`write(&mut self, buf)`]
+    pub fn to_bytes(&mut self) -> Result<Vec<u8>> {
+        let mut buf = PacketBuffer::with_size(MAX_TCP_MESSAGE);
+        self.write(&mut buf)?;
+
+        let mut bytes = buf.buf;
+        bytes.truncate(buf.pos);
+
+        Ok(bytes)
+    }
+
+    /// Write the contents of the packet to a PacketBuffer
+    pub fn write(&mut self, buf: &mut PacketBuffer) -> Result<()> {
+        self.header.ques_count = self.questions.len() as u16;
+        self.header.ans_count = self.answers.len() as u16;
+        self.header.auth_count = self.authorities.len() as u16;
+        self.header.res_count = self.resources.len() as u16;
+        self.split_res_code();
+
+        self.header.write(buf)?;
+
+        for ques in &self.questions {
+            ques.write(buf)?;
+        }
+        
+        for rec in &self.answers {
+            rec.write(buf)?;
+        }
+
+        for rec in &self.authorities {
+            rec.write(buf)?;
+        }
+
+        for rec in &self.resources {
+            rec.write(buf)?;
+        }
+
+        Ok(())
+    }
+
+    /// Give the OPT record the upper bits of the rcode, the header only has room for the lower 4
+    /// Without an OPT record they're lost, a client that sent none can't be told an extended rcode
+    fn split_res_code(&mut self) {
+        let ext_bits = self.header.res_code.ext_bits();
+        for rec in &mut self.resources {
+            if let DnsRecord::OPT { ext_rcode, .. } = rec {
+                *ext_rcode = ext_bits;
+            }
+        }
+    }
+
+    /// The A records of the answer section, as their owner, address and TTL
+    pub fn a_records(&self) -> impl Iterator<Item = (&str, Ipv4Addr, u32)> {
+        self.answers.iter().filter_map(|record| match record {
+            DnsRecord::A { domain, addr_v4, ttl } => Some((domain.as_str(), *addr_v4, *ttl)),
+            _ => None,
+        })
+    }
+
+    /// The AAAA records of the answer section, as their owner, address and TTL
+    pub fn aaaa_records(&self) -> impl Iterator<Item = (&str, Ipv6Addr, u32)> {
+        self.answers.iter().filter_map(|record| match record {
+            DnsRecord::AAAA { domain, addr, ttl } => Some((domain.as_str(), *addr, *ttl)),
+            _ => None,
+        })
+    }
+
+    /// The CNAME records of the answer section, as their owner, target and TTL
+    pub fn cname_records(&self) -> impl Iterator<Item = (&str, &str, u32)> {
+        self.answers.iter().filter_map(|record| match record {
+            DnsRecord::CNAME { domain, host, ttl } => Some((domain.as_str(), host.as_str(), *ttl)),
+            _ => None,
+        })
+    }
+
+    /// The NS records of the answer and authority sections, as their zone, name server and TTL
+    /// A referral has them in the authority section, an answer to an NS question in the answer section
+    pub fn ns_records(&self) -> impl Iterator<Item = (&str, &str, u32)> {
+        self.answers.iter()
+            .chain(&self.authorities)
+            .filter_map(|record| match record {
+                DnsRecord::NS { domain, host, ttl } => Some((domain.as_str(), host.as_str(), *ttl)),
+                _ => None,
+            })
+    }
+
+    /// The records of the answer section of a type
+    pub fn answers_of_type(&self, q_type: QueryType) -> impl Iterator<Item = &DnsRecord> {
+        self.answers.iter().filter(move |x| x.query_type() == q_type)
+    }
+
+    /// The A and AAAA records of the answer section, as their owner, address and TTL
+    pub fn addr_records(&self) -> impl Iterator<Item = (&str, IpAddr, u32)> {
+        addr_records(&self.answers)
+    }
+
+    /// The A and AAAA records of the answer and additional sections, as their owner, address and TTL,
+    /// where the addresses of name servers are whether they were asked for or came as glue
+    pub fn addr_records_with_additional(&self) -> impl Iterator<Item = (&str, IpAddr, u32)> {
+        addr_records(&self.answers).chain(addr_records(&self.resources))
+    }
+
+    /// Pick a random A record from a packet
+    /// Useful when many are returned as the choice is arbitrary
+    pub fn get_random_a(&self) -> Option<Ipv4Addr> {
+        let addrs: Vec<Ipv4Addr> = self.a_records().map(|(_, addr, _)| addr).collect();
+        match addrs.len() {
+            0 => None,
+            len => Some(addrs[rand::random::<usize>() % len]),
+        }
+    }
+
+    /// The first address of the answer section, IPv4 or IPv6
+    pub fn get_first_addr(&self) -> Option<IpAddr> {
+        self.addr_records().map(|(_, addr, _)| addr).next()
+    }
+
+    /// Returns an iterator of all nameservers for zones containing qname, as (zone, host)
+    fn get_ns<'a>(&'a self, qname: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
+        self.ns_records()
+            .map(|(domain, host, _)| (domain, host))
+            // Keep only authoritative entries, compared by whole labels
+            .filter(move |(domain, _)| dns_name::is_subdomain(qname, domain))
+    }
+
+    /// Addresses given for the nameservers of qname, mostly as glue in the additional section, in the order listed
+    fn get_glue<'a>(&'a self, qname: &'a str) -> impl Iterator<Item = IpAddr> + 'a {
+        self.get_ns(qname)
+            // flat_map returns an iterator for each matching element
+            .flat_map(move |(_, host)| {
+                self.addr_records_with_additional()
+                    .filter(move |(domain, _, _)| dns_name::eq_ignore_case(domain, host))
+                    .map(|(_, addr, _)| addr)
+            })
+    }
+
+    /// Every nameserver address for qname found in the additional section, IPv4 before IPv6
+    /// so another can be tried when the first doesn't respond
+    pub fn get_resolved_ns_all<'a>(&'a self, qname: &'a str) -> impl Iterator<Item = IpAddr> + 'a {
+        self.get_glue(qname).filter(|x| x.is_ipv4())
+            .chain(self.get_glue(qname).filter(|x| x.is_ipv6()))
+    }
+
+    /// Attempts to return the IP of a nameserver record given the qualified name, preferring IPv4
+    pub fn get_resolved_ns(&self, qname: &str) -> Option<IpAddr> {
+        self.get_resolved_ns_all(qname).next()
+    }
+
+    /// Returns the hostname of a nameserver
+    pub fn get_unresolved_ns<'a>(&'a self, qname: &'a str) -> Option<&'a str> {
+        self.get_ns(qname)
+            // keeps the hostnames of the entries in the authorities section
+            .map(|(_, host)| host)
+            // keep the first valid entry
+            .next()
+    }
+}
+
+/// The A and AAAA records among some, as their owner, address and TTL
+fn addr_records(records: &[DnsRecord]) -> impl Iterator<Item = (&str, IpAddr, u32)> {
+    records.iter().filter_map(|record| match record {
+        DnsRecord::A { domain, addr_v4, ttl } => Some((domain.as_str(), IpAddr::V4(*addr_v4), *ttl)),
+        DnsRecord::AAAA { domain, addr, ttl } => Some((domain.as_str(), IpAddr::V6(*addr), *ttl)),
+        _ => None,
+    })
+}
+
+/// Where a name starting at pos in a message ends, None if it runs past the end
+fn skip_name(bytes: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *bytes.get(pos)? as usize;
+        if len & 0xC0 == 0xC0 {
+            return Some(pos + 2);
+        }
+        pos += 1 + len;
+        if len == 0 {
+            return Some(pos);
+        }
+    }
+}
+
+/// An empty response echoing the id, opcode, recursion desired and checking disabled flags of a request
+pub(crate) fn response_to(req_header: &DnsHeader) -> DnsPacket {
+    let mut response = DnsPacket::new();
+    response.header.id = req_header.id;
+    response.header.query_res = true;
+    response.header.opcode = req_header.opcode;
+    response.header.rec_av = false;
+    response.header.rec_des = req_header.rec_des;
+    response.header.checking_disabled = req_header.checking_disabled;
+    response.header.res_code = ResCode::NO_ERR;
+
+    response
+}
+
+/// As from_bytes, failing on truncated or malformed packets
+///
+/// ```
+/// use pine_dns::packet::{ DnsPacket, QueryType, ResCode };
+///
+/// // a response to an A query for example.com, NXDOMAIN with RD and RA set
+/// let bytes = [
+///     0x12, 0x34, 0x81, 0x83, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+///     0x07, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, 0x00, 0x01, 0x00, 0x01,
+/// ];
+/// let packet = DnsPacket::try_from(&bytes[..]).unwrap();
+/// assert_eq!(packet.header.id, 0x1234);
+/// assert_eq!(packet.header.res_code, ResCode::NX_DOMAIN);
+/// assert_eq!(packet.questions[0].name, "example.com");
+/// assert_eq!(packet.questions[0].q_type, QueryType::A);
+///
+/// // the numbers of a type or rcode convert the same way
+/// let q_type: QueryType = 28u16.try_into().unwrap();
+/// let res_code: ResCode = 3u16.try_into().unwrap();
+/// assert_eq!((q_type, res_code), (QueryType::AAAA, ResCode::NX_DOMAIN));
+/// assert!(ResCode::try_from(1 << 12).is_err());
+///
+/// // and a packet cut short fails
+/// assert!(DnsPacket::try_from(&bytes[..20]).is_err());
+/// ```
+impl TryFrom<&[u8]> for DnsPacket {
+    type Error = DnsError;
+
+    fn try_from(bytes: &[u8]) -> Result<DnsPacket> {
+        DnsPacket::from_bytes(bytes)
+    }
+}
+
+/// As to_bytes, updating the header counts of the packet
+///
+/// ```
+/// use pine_dns::packet::{ DnsPacket, DnsQuestion, DnsRecord, QueryType };
+///
+/// // forty TXT records, too many for a 512 byte UDP message
+/// let mut packet = DnsPacket::new().question(DnsQuestion::new("example.com".to_string(), QueryType::TXT));
+/// for i in 0..40 {
+///     packet = packet.answer(DnsRecord::TXT { domain: "example.com".to_string(), data: vec![format!("record {}", i)], ttl: 60 });
+/// }
+///
+/// let bytes = Vec::try_from(&mut packet).unwrap();
+/// assert!(bytes.len() > 512);
+/// assert_eq!(packet.header.ans_count, 40);
+///
+/// let read = DnsPacket::try_from(&bytes[..]).unwrap();
+/// assert_eq!(read.answers, packet.answers);
+/// assert_eq!(Vec::try_from(read).unwrap(), bytes);
+/// ```
+impl TryFrom<&mut DnsPacket> for Vec<u8> {
+    type Error = DnsError;
+
+    fn try_from(packet: &mut DnsPacket) -> Result<Vec<u8>> {
+        packet.to_bytes()
+    }
+}
+
+/// As to_bytes, for a packet that isn't needed after it's written
+impl TryFrom<DnsPacket> for Vec<u8> {
+    type Error = DnsError;
+
+    fn try_from(mut packet: DnsPacket) -> Result<Vec<u8>> {
+        packet.to_bytes()
+    }
+}
+
+/// The packet as dig shows it: the header and flags, the EDNS pseudosection, then each section with a record per line
+/// Section counts are those of the sections, not of the header, so a packet being built shows what it holds
+impl fmt::Display for DnsPacket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let header = &self.header;
+        writeln!(f, ";; ->>HEADER<<- opcode: {}, status: {}, id: {}", header.opcode, header.res_code, header.id)?;
+
+        let flags: Vec<&str> = [
+            (header.query_res, "qr"),
+            (header.authoritative, "aa"),
+            (header.trunc, "tc"),
+            (header.rec_des, "rd"),
+            (header.rec_av, "ra"),
+            (header.auth_data, "ad"),
+            (header.checking_disabled, "cd"),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, name)| *name)
+        .collect();
+        write!(
+            f,
+            ";; flags: {}; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+            flags.join(" "),
+            self.questions.len(),
+            self.answers.len(),
+            self.authorities.len(),
+            self.resources.len(),
+        )?;
+
+        let (opt, additional): (Vec<&DnsRecord>, Vec<&DnsRecord>) = self.resources.iter()
+            .partition(|x| matches!(x, DnsRecord::OPT { .. }));
+        if !opt.is_empty() || !self.extended_errors.is_empty() {
+            write!(f, "\n\n;; OPT PSEUDOSECTION:")?;
+            for record in opt {
+                write!(f, "\n{}", record)?;
+            }
+            for ede in &self.extended_errors {
+                write!(f, "\n; EDE: {}", ede)?;
+            }
+        }
+
+        if !self.questions.is_empty() {
+            write!(f, "\n\n;; QUESTION SECTION:")?;
+            for question in &self.questions {
+                write!(f, "\n;{}\t\t{}\t{}", shown_name(&question.name), class_name(question.class), question.q_type)?;
+            }
+        }
+
+        for (title, records) in [("ANSWER", self.answers.iter().collect()), ("AUTHORITY", self.authorities.iter().collect()), ("ADDITIONAL", additional)] {
+            if records.is_empty() {
+                continue;
+            }
+            write!(f, "\n\n;; {} SECTION:", title)?;
+            for record in records {
+                write!(f, "\n{}", record)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::{ GlobalAlloc, Layout, System };
+    use std::cell::Cell;
+    use std::io::{ Read, Seek, SeekFrom };
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// The system allocator, counting the allocations made on each thread
+    struct CountingAllocator;
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            // a thread being torn down has no count left to add to
+            let _ = ALLOCATIONS.try_with(|x| x.set(x.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// How many allocations something makes on this thread, whatever other tests are doing
+    fn allocations(f: impl FnOnce()) -> usize {
+        let before = ALLOCATIONS.get();
+        f();
+        ALLOCATIONS.get() - before
+    }
+
+    #[test]
+    fn seek_before_start_fails_and_keeps_pos() {
+        let mut buf = PacketBuffer::from_bytes(&[1, 2, 3, 4]);
+        buf.seek(SeekFrom::Start(2)).unwrap();
+
+        for pos in [SeekFrom::Current(-3), SeekFrom::End(-5)] {
+            let e = buf.seek(pos).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+            assert_eq!(buf.pos, 2);
+        }
+    }
+
+    #[test]
+    fn seek_past_end_hits_end_of_buffer() {
+        let mut buf = PacketBuffer::from_bytes(&[1, 2, 3, 4]);
+        assert_eq!(buf.seek(SeekFrom::End(6)).unwrap(), 10);
+        assert_eq!(buf.pos, 10);
+
+        let mut out = [0; 2];
+        assert_eq!(buf.read(&mut out).unwrap(), 0);
+        assert_eq!(io::Write::write(&mut buf, &[5]).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert!(matches!(buf.read_u16(), Err(DnsError::UnexpectedEof)));
+        assert_eq!(buf.pos, 10);
+    }
+
+    #[test]
+    fn absurd_counts_fail_before_reading_records() {
+        let bytes = [0x12, 0x34, 0x01, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+
+        let e = DnsPacket::from_bytes(&bytes).unwrap_err();
+        assert!(matches!(e, DnsError::TooManyRecords { section: "question", count: 0xFFFF, max: 64 }), "{:?}", e);
+
+        // even with no limits the first missing question ends it
+        let unlimited = ParseLimits { max_questions: u16::MAX, max_answers: u16::MAX, max_authorities: u16::MAX, max_resources: u16::MAX };
+        let e = DnsPacket::from_buf_with_limits(&mut PacketBuffer::from_bytes(&bytes), &unlimited).unwrap_err();
+        assert!(matches!(e, DnsError::UnexpectedEof | DnsError::BufferOverrun { .. }), "{:?}", e);
+    }
+
+    #[test]
+    fn counts_are_checked_per_section() {
+        let limits = ParseLimits { max_answers: 2, ..ParseLimits::default() };
+        let mut bytes = vec![0x12, 0x34, 0x81, 0x80, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00];
+        bytes.resize(512, 0);
+
+        let e = DnsPacket::from_buf_with_limits(&mut PacketBuffer::from_bytes(&bytes), &limits).unwrap_err();
+        assert!(matches!(e, DnsError::TooManyRecords { section: "answer", count: 3, max: 2 }), "{:?}", e);
+        assert_eq!(e.res_code(), ResCode::FORM_ERR);
+    }
+
+    // www.Example.com CNAME example.com A 93.184.216.34, every name after the question compressed
+    const COMPRESSED: &[u8] = &[
+        0xab, 0xcd, 0x81, 0x80, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00,
+        0x03, b'w', b'w', b'w', 0x07, b'E', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00,
+        0x00, 0x01, 0x00, 0x01,
+        0xc0, 0x0c, 0x00, 0x05, 0x00, 0x01, 0x00, 0x00, 0x0e, 0x10, 0x00, 0x02, 0xc0, 0x10,
+        0xc0, 0x10, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x0e, 0x10, 0x00, 0x04, 93, 184, 216, 34,
+    ];
+
+    #[test]
+    fn parse_write_parse_is_idempotent() {
+        let mut first = DnsPacket::from_bytes(COMPRESSED).unwrap();
+        assert!(matches!(&first.answers[0], DnsRecord::CNAME { domain, host, .. } if domain == "www.example.com" && host == "example.com"));
+        assert!(matches!(&first.answers[1], DnsRecord::A { domain, .. } if domain == "example.com"));
+
+        let written = first.to_bytes().unwrap();
+        let mut second = DnsPacket::from_bytes(&written).unwrap();
+        assert_eq!(second.to_bytes().unwrap(), written);
+        assert_eq!(format!("{:?}", second), format!("{:?}", first));
+    }
+
+    #[test]
+    fn responses_past_512_bytes_round_trip() {
+        let mut packet = DnsPacket::new().question(DnsQuestion::new("example.com".to_string(), QueryType::TXT));
+        for i in 0..40 {
+            packet = packet.answer(DnsRecord::TXT { domain: "example.com".to_string(), data: vec![format!("record {}", i)], ttl: 60 });
+        }
+
+        let bytes = packet.to_bytes().unwrap();
+        assert!(bytes.len() > BUF_SIZE);
+
+        let read = DnsPacket::from_bytes(&bytes).unwrap();
+        assert_eq!(read.answers, packet.answers);
+        assert_eq!(Vec::try_from(read).unwrap(), bytes);
+    }
+
+    #[test]
+    fn long_txt_is_written_as_length_prefixed_chunks() {
+        let data = vec!["a".repeat(300), String::new(), "b".repeat(255)];
+        let record = DnsRecord::TXT { domain: "example.com".to_string(), data: data.clone(), ttl: 60 };
+
+        let mut buf = PacketBuffer::with_size(1024);
+        let len = record.write(&mut buf).unwrap();
+        let bytes = &buf.buf[..len];
+
+        // name, type, class and ttl before the rdata
+        let rdata_at = "example.com".len() + 2 + 10;
+        let mut rdata = vec![255];
+        rdata.extend_from_slice(&[b'a'; 255]);
+        rdata.push(45);
+        rdata.extend_from_slice(&[b'a'; 45]);
+        rdata.push(0);
+        rdata.push(255);
+        rdata.extend_from_slice(&[b'b'; 255]);
+
+        assert_eq!(&bytes[rdata_at - 2..rdata_at], &(rdata.len() as u16).to_be_bytes());
+        assert_eq!(&bytes[rdata_at..], &rdata[..]);
+
+        // read back the chunks are separate strings, the empty one kept
+        buf.pos = 0;
+        match DnsRecord::read(&mut buf).unwrap() {
+            DnsRecord::TXT { data: read, .. } => assert_eq!(read, ["a".repeat(255), "a".repeat(45), String::new(), "b".repeat(255)]),
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn header_round_trips() {
+        let mut header = DnsHeader::new();
+        header.id = 0xbeef;
+        header.opcode = Opcode::NOTIFY;
+        header.authoritative = true;
+        header.trunc = true;
+        header.rec_des = true;
+        header.auth_data = true;
+        header.checking_disabled = true;
+        header.res_code = ResCode::NX_DOMAIN;
+        header.ques_count = 1;
+        header.ans_count = 2;
+        header.auth_count = 3;
+        header.res_count = 4;
+
+        let mut buf = PacketBuffer::new();
+        header.write(&mut buf).unwrap();
+        assert_eq!(buf.pos, 12);
+        assert_eq!(&buf.buf[..12], &[0xbe, 0xef, 0xa7, 0x33, 0, 1, 0, 2, 0, 3, 0, 4]);
+
+        buf.pos = 0;
+        let mut read = DnsHeader::new();
+        read.read(&mut buf).unwrap();
+        assert_eq!(read.id, header.id);
+        assert_eq!(read.query_res, header.query_res);
+        assert_eq!(read.opcode, header.opcode);
+        assert!(read.authoritative && read.trunc && read.rec_des && !read.rec_av);
+        assert!(!read.reserved && read.auth_data && read.checking_disabled);
+        assert_eq!(read.res_code, header.res_code);
+        assert_eq!((read.ques_count, read.ans_count, read.auth_count, read.res_count), (1, 2, 3, 4));
+    }
+
+    #[test]
+    fn question_round_trips() {
+        for (name, q_type, class) in [
+            ("example.com", QueryType::A, CLASS_IN),
+            ("mail.example.com", QueryType::MX, CLASS_IN),
+            ("version.bind", QueryType::TXT, 3),
+            ("example.com", QueryType::UNKNOWN(65280), CLASS_IN),
+        ] {
+            let mut question = DnsQuestion::new(name.to_string(), q_type);
+            question.class = class;
+
+            let mut buf = PacketBuffer::new();
+            question.write(&mut buf).unwrap();
+            let len = buf.pos;
+
+            buf.pos = 0;
+            let mut read = DnsQuestion::new(String::new(), QueryType::UNKNOWN(0));
+            read.read(&mut buf).unwrap();
+            assert_eq!(buf.pos, len);
+            assert_eq!(read.name, name);
+            assert_eq!(read.q_type, q_type);
+            assert_eq!(read.class, class);
+        }
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let domain = || "example.com".to_string();
+        let answers = vec![
+            DnsRecord::A { domain: domain(), addr_v4: Ipv4Addr::new(192, 0, 2, 1), ttl: 300 },
+            DnsRecord::NS { domain: domain(), host: "ns1.example.com".to_string(), ttl: 86400 },
+            DnsRecord::CNAME { domain: "www.example.com".to_string(), host: domain(), ttl: 60 },
+            DnsRecord::PTR { domain: "1.2.0.192.in-addr.arpa".to_string(), host: domain(), ttl: 60 },
+            DnsRecord::MX { domain: domain(), priority: 10, host: "mail.example.com".to_string(), ttl: 3600 },
+            DnsRecord::TXT { domain: domain(), data: vec!["v=spf1 -all".to_string(), String::new()], ttl: 60 },
+            DnsRecord::AAAA { domain: domain(), addr: "2001:db8::1".parse().unwrap(), ttl: 300 },
+            DnsRecord::SRV { domain: "_sip._tcp.example.com".to_string(), priority: 1, weight: 5, port: 5060, host: "sip.example.com".to_string(), ttl: 60 },
+            DnsRecord::UNKNOWN { domain: domain(), q_type: 65280, len: 3, data: vec![1, 2, 3], ttl: 60 },
+        ];
+        let soa = DnsRecord::SOA {
+            domain: domain(),
+            m_name: "ns1.example.com".to_string(),
+            r_name: "hostmaster.example.com".to_string(),
+            serial: 2024010101,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum: 300,
+            ttl: 3600,
+        };
+        let opt = DnsRecord::OPT { payload_size: 1232, ext_rcode: 0, version: 0, flags: 0x8000, data: vec![0, 10, 0, 8, 1, 2, 3, 4, 5, 6, 7, 8] };
+
+        let mut packet = DnsPacket::new().question(DnsQuestion::new(domain(), QueryType::A)).authority(soa.clone()).additional(opt.clone());
+        for answer in &answers {
+            packet = packet.answer(answer.clone());
+        }
+
+        let read = DnsPacket::from_bytes(&packet.to_bytes().unwrap()).unwrap();
+
+        assert_eq!(read.questions, packet.questions);
+        assert_eq!(read.answers, answers);
+        assert_eq!(read.authorities, [soa]);
+        assert_eq!(read.resources, [opt]);
+    }
+
+    #[test]
+    fn query_types_display_and_parse() {
+        for (q_type, name) in [
+            (QueryType::A, "A"),
+            (QueryType::NS, "NS"),
+            (QueryType::CNAME, "CNAME"),
+            (QueryType::SOA, "SOA"),
+            (QueryType::PTR, "PTR"),
+            (QueryType::MX, "MX"),
+            (QueryType::TXT, "TXT"),
+            (QueryType::AAAA, "AAAA"),
+            (QueryType::SRV, "SRV"),
+            (QueryType::OPT, "OPT"),
+            (QueryType::UNKNOWN(0), "TYPE0"),
+            (QueryType::UNKNOWN(99), "TYPE99"),
+            (QueryType::UNKNOWN(65280), "TYPE65280"),
+            (QueryType::UNKNOWN(65535), "TYPE65535"),
+        ] {
+            assert_eq!(q_type.to_string(), name);
+            assert_eq!(name.parse::<QueryType>().unwrap(), q_type);
+            assert_eq!(name.to_ascii_lowercase().parse::<QueryType>().unwrap(), q_type);
+            assert_eq!(q_type.to_u16().to_string().parse::<QueryType>().unwrap(), q_type);
+        }
+
+        // a TYPE<n> name of a type with a mnemonic is that type
+        for (name, q_type) in [("TYPE1", QueryType::A), ("type28", QueryType::AAAA), ("TYPE41", QueryType::OPT)] {
+            assert_eq!(name.parse::<QueryType>().unwrap(), q_type);
+        }
+
+        for name in ["", "TYPE", "TYPE65536", "TYPE-1", "65536", "ANYTHING"] {
+            assert!(name.parse::<QueryType>().is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn res_codes_display_and_parse() {
+        for (res_code, name, debug) in [
+            (ResCode::NO_ERR, "NOERROR", "NO_ERR"),
+            (ResCode::FORM_ERR, "FORMERR", "FORM_ERR"),
+            (ResCode::SERV_FAIL, "SERVFAIL", "SERV_FAIL"),
+            (ResCode::NX_DOMAIN, "NXDOMAIN", "NX_DOMAIN"),
+            (ResCode::NOT_IMP, "NOTIMP", "NOT_IMP"),
+            (ResCode::REFUSED, "REFUSED", "REFUSED"),
+            (ResCode::YX_DOMAIN, "YXDOMAIN", "YX_DOMAIN"),
+            (ResCode::YX_RR_SET, "YXRRSET", "YX_RR_SET"),
+            (ResCode::NX_RR_SET, "NXRRSET", "NX_RR_SET"),
+            (ResCode::NOT_AUTH, "NOTAUTH", "NOT_AUTH"),
+            (ResCode::NOT_ZONE, "NOTZONE", "NOT_ZONE"),
+            (ResCode::BAD_VERS, "BADVERS", "BAD_VERS"),
+            (ResCode::BAD_COOKIE, "BADCOOKIE", "BAD_COOKIE"),
+            (ResCode::UNKNOWN(11), "RCODE11", "UNKNOWN(11)"),
+            (ResCode::UNKNOWN(4095), "RCODE4095", "UNKNOWN(4095)"),
+        ] {
+            assert_eq!(res_code.to_string(), name);
+            assert_eq!(name.parse::<ResCode>().unwrap(), res_code);
+            assert_eq!(name.to_ascii_lowercase().parse::<ResCode>().unwrap(), res_code);
+            if !matches!(res_code, ResCode::UNKNOWN(_)) {
+                assert_eq!(debug.parse::<ResCode>().unwrap(), res_code);
+            }
+        }
+
+        // an RCODE<n> name of a code with a mnemonic is that code
+        for (name, res_code) in [("RCODE0", ResCode::NO_ERR), ("rcode3", ResCode::NX_DOMAIN), ("RCODE23", ResCode::BAD_COOKIE)] {
+            assert_eq!(name.parse::<ResCode>().unwrap(), res_code);
+        }
+
+        for name in ["", "RCODE", "RCODE4096", "NXDOMAINS", "UNKNOWN(11)"] {
+            assert!(name.parse::<ResCode>().is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn built_nxdomain_has_the_expected_wire_bytes() {
+        let mut req = DnsPacket::new().question(DnsQuestion::new("nx.example".to_string(), QueryType::A));
+        req.header.id = 0x1234;
+        req.header.query_res = false;
+        req.header.rec_des = true;
+
+        let soa = DnsRecord::SOA {
+            domain: "example".to_string(),
+            m_name: "ns.example".to_string(),
+            r_name: "hm.example".to_string(),
+            serial: 1,
+            refresh: 2,
+            retry: 3,
+            expire: 4,
+            minimum: 60,
+            ttl: 300,
+        };
+        let mut res = DnsPacket::response_to(&req)
+            .authoritative()
+            .with_rcode(ResCode::NX_DOMAIN)
+            .question(req.questions[0].clone())
+            .authority(soa);
+
+        let expected = [
+            // id, QR AA RD and NXDOMAIN, one question and one authority
+            &[0x12, 0x34, 0x85, 0x03, 0, 1, 0, 0, 0, 1, 0, 0][..],
+            // nx.example A IN
+            b"\x02nx\x07example\x00\x00\x01\x00\x01",
+            // example SOA IN, ttl 300 and 44 bytes of rdata
+            b"\x07example\x00\x00\x06\x00\x01\x00\x00\x01\x2c\x00\x2c",
+            b"\x02ns\x07example\x00",
+            b"\x02hm\x07example\x00",
+            &[0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0, 60],
+        ].concat();
+
+        assert_eq!(res.to_bytes().unwrap(), expected);
+    }
+
+    #[test]
+    fn long_chaos_text_is_split_into_character_strings() {
+        let text = "x".repeat(300);
+        let mut packet = DnsPacket::new().answer(DnsRecord::CHAOS_TXT { domain: "id.server".to_string(), data: text });
+        let bytes = packet.to_bytes().unwrap();
+
+        // the header and id.server TXT CH with ttl 0, then 302 bytes of rdata: 255 bytes and the other 45
+        let rdata = &bytes[12 + 11 + 8..];
+        assert_eq!(&rdata[..2], &302u16.to_be_bytes());
+        assert_eq!(rdata[2], 255);
+        assert_eq!(rdata[2 + 256], 45);
+        assert_eq!(rdata.len(), 2 + 302);
+    }
+
+    #[test]
+    fn display_shows_each_record_type_as_dig_does() {
+        let domain = || "example.com".to_string();
+        let mut packet = DnsPacket::new()
+            .question(DnsQuestion::new(domain(), QueryType::A))
+            .answer(DnsRecord::A { domain: domain(), addr_v4: Ipv4Addr::new(192, 0, 2, 1), ttl: 300 })
+            .answer(DnsRecord::NS { domain: domain(), host: "ns1.example.com".to_string(), ttl: 86400 })
+            .answer(DnsRecord::CNAME { domain: "www.example.com".to_string(), host: domain(), ttl: 60 })
+            .answer(DnsRecord::PTR { domain: "1.2.0.192.in-addr.arpa".to_string(), host: domain(), ttl: 60 })
+            .answer(DnsRecord::MX { domain: domain(), priority: 10, host: "mail.example.com".to_string(), ttl: 3600 })
+            .answer(DnsRecord::TXT { domain: domain(), data: vec!["say \"hi\"".to_string(), "C:\\dns".to_string(), "tab\there".to_string(), String::new()], ttl: 60 })
+            .answer(DnsRecord::AAAA { domain: domain(), addr: "2001:db8:0:0:0:0:0:1".parse().unwrap(), ttl: 300 })
+            .answer(DnsRecord::SRV { domain: "_sip._tcp.example.com".to_string(), priority: 1, weight: 5, port: 5060, host: "sip.example.com".to_string(), ttl: 60 })
+            .answer(DnsRecord::UNKNOWN { domain: domain(), q_type: 65280, len: 3, data: vec![1, 2, 3], ttl: 60 })
+            .authority(DnsRecord::SOA {
+                domain: domain(),
+                m_name: "ns1.example.com".to_string(),
+                r_name: "hostmaster.example.com".to_string(),
+                serial: 2024010101,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum: 300,
+                ttl: 3600,
+            })
+            .additional(DnsRecord::OPT { payload_size: 1232, ext_rcode: 0, version: 0, flags: 0x8000, data: Vec::new() });
+        packet.header.id = 4660;
+        packet.header.query_res = true;
+        packet.header.rec_des = true;
+        packet.header.rec_av = true;
+
+        let expected = concat!(
+            ";; ->>HEADER<<- opcode: QUERY, status: NOERROR, id: 4660\n",
+            ";; flags: qr rd ra; QUERY: 1, ANSWER: 9, AUTHORITY: 1, ADDITIONAL: 1\n",
+            "\n",
+            ";; OPT PSEUDOSECTION:\n",
+            "; EDNS: version: 0, flags: do; udp: 1232\n",
+            "\n",
+            ";; QUESTION SECTION:\n",
+            ";example.com.\t\tIN\tA\n",
+            "\n",
+            ";; ANSWER SECTION:\n",
+            "example.com.\t300\tIN\tA\t192.0.2.1\n",
+            "example.com.\t86400\tIN\tNS\tns1.example.com.\n",
+            "www.example.com.\t60\tIN\tCNAME\texample.com.\n",
+            "1.2.0.192.in-addr.arpa.\t60\tIN\tPTR\texample.com.\n",
+            "example.com.\t3600\tIN\tMX\t10 mail.example.com.\n",
+            // quotes and backslashes escaped, unprintable bytes as \DDD, an AAAA address compressed
+            "example.com.\t60\tIN\tTXT\t\"say \\\"hi\\\"\" \"C:\\\\dns\" \"tab\\009here\" \"\"\n",
+            "example.com.\t300\tIN\tAAAA\t2001:db8::1\n",
+            "_sip._tcp.example.com.\t60\tIN\tSRV\t1 5 5060 sip.example.com.\n",
+            "example.com.\t60\tIN\tTYPE65280\t\\# 3 010203\n",
+            "\n",
+            ";; AUTHORITY SECTION:\n",
+            "example.com.\t3600\tIN\tSOA\tns1.example.com. hostmaster.example.com. 2024010101 7200 3600 1209600 300",
+        );
+        assert_eq!(packet.to_string(), expected);
+    }
+
+    #[test]
+    fn display_decodes_xn_labels() {
+        let record = DnsRecord::CNAME {
+            domain: "xn--bcher-kva.example".to_string(),
+            host: "www.xn--mnchen-3ya.example".to_string(),
+            ttl: 60,
+        };
+
+        assert_eq!(record.to_string(), "bücher.example.\t60\tIN\tCNAME\twww.münchen.example.");
+        // the zone and JSON form stays ASCII
+        assert_eq!(record.rdata_string(), "www.xn--mnchen-3ya.example.");
+
+        let mut packet = DnsPacket::new();
+        packet.questions.push(DnsQuestion::new("xn--bcher-kva.example".to_string(), QueryType::A));
+        let mut buf = PacketBuffer::new();
+        packet.write(&mut buf).unwrap();
+        assert_eq!(DnsPacket::peek_question(&buf).unwrap().to_string(), "bücher.example");
+        assert!(packet.to_string().contains("\n;bücher.example.\t\tIN\tA"));
+    }
+
+    #[test]
+    fn peeked_question_is_answered_with_fewer_allocations_than_a_parsed_one() {
+        let mut query = DnsPacket::new();
+        query.questions.push(DnsQuestion::new("www.bluesky.com".to_string(), QueryType::A));
+        let query = query.to_bytes().unwrap();
+        let answer = DnsRecord::A { domain: "www.bluesky.com".to_string(), addr_v4: Ipv4Addr::new(192, 0, 2, 1), ttl: 300 };
+
+        let peek_buf = PacketBuffer::from_bytes(&query);
+        let mut parse_buf = PacketBuffer::from_bytes(&query);
+        let mut fast_buf = PacketBuffer::new();
+        let mut slow_buf = PacketBuffer::new();
+        let mut fast_res = DnsPacket::new();
+        let mut slow_res = DnsPacket::new();
+        fast_res.answers.reserve(1);
+        slow_res.answers.reserve(1);
+        slow_res.questions.reserve(1);
+
+        assert_eq!(allocations(|| { DnsPacket::peek_question(&peek_buf).unwrap(); }), 0);
+
+        // the question copied into the response as it came
+        let fast = allocations(|| {
+            let question = DnsPacket::peek_question(&peek_buf).unwrap();
+            fast_res.answers.push(answer.clone());
+            fast_res.write_with_question(&mut fast_buf, &question).unwrap();
+        });
+        // the whole query parsed and its question written out again
+        let slow = allocations(|| {
+            let req = DnsPacket::from_buf(&mut parse_buf).unwrap();
+            slow_res.questions.push(req.questions[0].clone());
+            slow_res.answers.push(answer.clone());
+            slow_res.write(&mut slow_buf).unwrap();
+        });
+
+        // the same response either way, but peeking adds nothing to cloning the answer, where parsing builds the name and sections
+        assert_eq!(fast_buf.buf[..fast_buf.pos], slow_buf.buf[..slow_buf.pos]);
+        assert_eq!(fast, allocations(|| { let _ = answer.clone(); }));
+        assert!(fast * 4 <= slow, "{} allocations peeking, {} parsing", fast, slow);
+    }
+}
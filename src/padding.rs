@@ -0,0 +1,147 @@
+//! EDNS padding (RFC 7830) of messages over encrypted transports, so their size says less about what's in them
+//! Following the block-length policy of RFC 8467, queries to DoT and DoH upstreams are padded to a multiple of 128 bytes
+//! and responses over DoT, DoH and DoQ to a multiple of 468, the latter only for clients that sent an OPT record
+//! A response is never padded past the UDP payload size the client offered or the largest message a stream carries,
+//! one that's already past it goes as it is, and nothing sent over plain UDP or TCP is padded
+
+use crate::packet::{ DnsPacket, QueryType, LISTENER_PAYLOAD };
+use crate::transport::MAX_TCP_MESSAGE;
+
+// Option code of padding in an OPT record
+const OPTION_PADDING: u16 = 12;
+// Block lengths of RFC 8467 section 4.1
+#[cfg(feature = "tls")]
+const QUERY_BLOCK: usize = 128;
+const RESPONSE_BLOCK: usize = 468;
+// The smallest UDP payload size a client may offer, RFC 6891 section 6.2.5
+const MIN_PAYLOAD: usize = 512;
+
+/// A query to an upstream over an encrypted transport with padding in its OPT record, as it is if it has none
+#[cfg(feature = "tls")]
+pub fn pad_query(query: &[u8]) -> Vec<u8> {
+    let mut query = query.to_vec();
+    if let Some(opt) = DnsPacket::find_opt(&query) {
+        pad(&mut query, opt, QUERY_BLOCK, MAX_TCP_MESSAGE);
+    }
+
+    query
+}
+
+/// A response to a client over an encrypted transport padded for the query it answers, with an OPT record
+/// added for the padding if it has none, as it is if the query had no OPT record
+pub fn pad_response(query: &[u8], mut response: Vec<u8>) -> Vec<u8> {
+    // the class field of an OPT record is the UDP payload size its sender accepts
+    let Some(payload_size) = DnsPacket::find_opt(query).and_then(|x| query.get(x + 2..x + 4)) else {
+        return response;
+    };
+    let max_size = (u16::from_be_bytes([payload_size[0], payload_size[1]]) as usize).clamp(MIN_PAYLOAD, MAX_TCP_MESSAGE);
+
+    let opt = match DnsPacket::find_opt(&response) {
+        Some(x) => x,
+        None if response.len() >= 12 => {
+            // owned by the root, with no options yet
+            response.push(0);
+            let opt = response.len();
+            response.extend_from_slice(&QueryType::OPT.to_u16().to_be_bytes());
+            response.extend_from_slice(&LISTENER_PAYLOAD.to_be_bytes());
+            response.extend_from_slice(&[0; 6]);
+
+            let additional = u16::from_be_bytes([response[10], response[11]]).wrapping_add(1);
+            response[10..12].copy_from_slice(&additional.to_be_bytes());
+
+            opt
+        }
+        None => return response,
+    };
+
+    pad(&mut response, opt, RESPONSE_BLOCK, max_size);
+
+    response
+}
+
+/// Add a padding option to the end of the OPT record whose type field is at opt, making the message a multiple
+/// of the block length, or as long as it may be if that's less, left as it is if not even an empty option fits
+fn pad(message: &mut Vec<u8>, opt: usize, block: usize, max_size: usize) {
+    let unpadded = message.len() + 4;
+    let padded = (unpadded.div_ceil(block) * block).min(max_size);
+    if unpadded > padded {
+        return;
+    }
+
+    let len = padded - unpadded;
+    let data_len = u16::from_be_bytes([message[opt + 8], message[opt + 9]]) as usize;
+    let end = opt + 10 + data_len;
+
+    let mut option = Vec::with_capacity(4 + len);
+    option.extend_from_slice(&OPTION_PADDING.to_be_bytes());
+    option.extend_from_slice(&(len as u16).to_be_bytes());
+    option.resize(4 + len, 0);
+    message.splice(end..end, option);
+
+    message[opt + 8..opt + 10].copy_from_slice(&((data_len + 4 + len) as u16).to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{ DnsQuestion, DnsRecord };
+
+    fn query(payload_size: Option<u16>) -> Vec<u8> {
+        let mut query = DnsPacket::new().question(DnsQuestion::new("example.com".to_string(), QueryType::A));
+        if let Some(payload_size) = payload_size {
+            query = query.additional(DnsRecord::OPT { payload_size, ext_rcode: 0, version: 0, flags: 0, data: Vec::new() });
+        }
+        query.to_bytes().unwrap()
+    }
+
+    fn response(answers: usize) -> Vec<u8> {
+        let mut response = DnsPacket::new().question(DnsQuestion::new("example.com".to_string(), QueryType::TXT));
+        for i in 0..answers {
+            response = response.answer(DnsRecord::TXT { domain: "example.com".to_string(), data: vec![i.to_string()], ttl: 60 });
+        }
+        response.to_bytes().unwrap()
+    }
+
+    #[test]
+    fn responses_are_padded_to_the_block_length() {
+        for answers in [0, 1, 20, 40] {
+            let unpadded = response(answers);
+            let padded = pad_response(&query(Some(4096)), unpadded.clone());
+            assert!(padded.len() > unpadded.len());
+            assert!(padded.len().is_multiple_of(RESPONSE_BLOCK), "{} answers padded to {}", answers, padded.len());
+
+            let packet = DnsPacket::from_bytes(&padded).unwrap();
+            assert_eq!(packet.answers.len(), answers);
+        }
+    }
+
+    #[test]
+    fn responses_are_not_padded_past_the_payload_size() {
+        // a block past 468 but not past 512
+        let padded = pad_response(&query(Some(512)), response(17));
+        assert_eq!(padded.len(), MIN_PAYLOAD);
+
+        // already past it, only the OPT record added
+        let unpadded = response(20);
+        assert!(unpadded.len() > MIN_PAYLOAD);
+        let padded = pad_response(&query(Some(512)), unpadded.clone());
+        assert_eq!(padded.len(), unpadded.len() + 11);
+    }
+
+    #[test]
+    fn responses_to_queries_without_opt_are_not_padded() {
+        let unpadded = response(1);
+        assert_eq!(pad_response(&query(None), unpadded.clone()), unpadded);
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn queries_are_padded_to_the_block_length() {
+        let padded = pad_query(&query(Some(1232)));
+        assert_eq!(padded.len(), QUERY_BLOCK);
+        assert!(DnsPacket::from_bytes(&padded).is_ok());
+
+        let unpadded = query(None);
+        assert_eq!(pad_query(&unpadded), unpadded);
+    }
+}
@@ -0,0 +1,319 @@
+//! Packet capture, every query received and response sent written to --pcap <path> in pcap format, for Wireshark or tcpdump -r
+//! Add --pcap-upstream to capture the UDP exchanges with upstreams too
+//! Only payloads and addresses are known here, so each message is wrapped in made up Ethernet, IPv4 or IPv6 and UDP headers,
+//! whatever transport it came over. The address a query came in on isn't known where it's answered, so the server's side
+//! is the unspecified address on port 53, and the client's port is made up from the message ID so each exchange
+//! is a conversation of its own. Responses are captured as answered, before --rrl or --max-amplification hold any back
+//! Frames go to a thread of their own through a bounded queue, those that don't fit while it's full are dropped,
+//! and the file is written out every second and on shutdown
+
+use std::fs::File;
+use std::io::{ self, BufWriter, Write };
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr };
+use std::sync::atomic::{ AtomicBool, AtomicU64, Ordering };
+use std::sync::mpsc::{ self, Receiver, RecvTimeoutError, SyncSender, TrySendError };
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+use log::{ error, warn };
+
+static QUEUE: OnceLock<SyncSender<Message>> = OnceLock::new();
+static UPSTREAM: AtomicBool = AtomicBool::new(false);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+// Frames waiting to be written before new ones are dropped
+const QUEUE_SIZE: usize = 4096;
+// How often the file is written out while frames keep coming
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+// How long shutdown waits for the queue to be written out
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+// The server's side of captured client exchanges
+const SERVER_PORT: u16 = 53;
+// Client ports are made up in the dynamic range
+const CLIENT_PORTS: u16 = 49152;
+// pcap file header: microsecond timestamps, version 2.4, UTC, frames up to 65535 bytes of Ethernet
+const MAGIC: u32 = 0xA1B2_C3D4;
+const SNAP_LEN: u32 = 65535;
+const LINKTYPE_ETHERNET: u32 = 1;
+// Ethernet, IPv4 and UDP header sizes
+const ETHERNET_LEN: usize = 14;
+const IPV4_LEN: usize = 20;
+const IPV6_LEN: usize = 40;
+const UDP_LEN: usize = 8;
+const PROTO_UDP: u8 = 17;
+
+enum Message {
+    Frame { time: SystemTime, src: SocketAddr, dst: SocketAddr, payload: Vec<u8> },
+    /// Write everything queued so far and say so
+    Close(mpsc::Sender<()>),
+}
+
+/// Start capturing to a new file at path, written by a thread of its own, upstream to capture upstream exchanges too
+/// Only the first call has any effect
+pub fn open(path: &str, upstream: bool) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(&file_header())?;
+    file.flush()?;
+
+    let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+    if QUEUE.set(sender).is_err() {
+        return Ok(());
+    }
+    UPSTREAM.store(upstream, Ordering::Relaxed);
+
+    let path = path.to_string();
+    thread::spawn(move || write_frames(&path, file, receiver));
+
+    Ok(())
+}
+
+/// Whether client exchanges are captured, so nothing is copied when they aren't
+pub fn is_enabled() -> bool {
+    QUEUE.get().is_some()
+}
+
+/// Whether upstream exchanges are captured
+pub fn is_upstream_enabled() -> bool {
+    is_enabled() && UPSTREAM.load(Ordering::Relaxed)
+}
+
+/// Capture a query from a client
+pub fn query(client: IpAddr, msg: &[u8]) {
+    if is_enabled() {
+        let (client, server) = client_exchange(client, msg);
+        capture(client, server, msg);
+    }
+}
+
+/// Capture a response to a client
+pub fn response(client: IpAddr, msg: &[u8]) {
+    if is_enabled() {
+        let (client, server) = client_exchange(client, msg);
+        capture(server, client, msg);
+    }
+}
+
+/// Capture a message sent to or received from an upstream, from src to dst
+pub fn upstream(src: SocketAddr, dst: SocketAddr, msg: &[u8]) {
+    if is_upstream_enabled() {
+        capture(src, dst, msg);
+    }
+}
+
+/// Write out what's been captured, done before the server exits
+pub fn close() {
+    let queue = match QUEUE.get() {
+        Some(x) => x,
+        None => return,
+    };
+
+    let (done, wait) = mpsc::channel();
+    if queue.send(Message::Close(done)).is_ok() {
+        let _ = wait.recv_timeout(CLOSE_TIMEOUT);
+    }
+}
+
+fn capture(src: SocketAddr, dst: SocketAddr, msg: &[u8]) {
+    let queue = match QUEUE.get() {
+        Some(x) => x,
+        None => return,
+    };
+
+    let frame = Message::Frame { time: SystemTime::now(), src: src, dst: dst, payload: msg.to_vec() };
+    match queue.try_send(frame) {
+        Ok(()) => (),
+        // reported by the writer once it catches up, so a burst isn't a line per frame
+        Err(TrySendError::Full(_)) => {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(TrySendError::Disconnected(_)) => (),
+    }
+}
+
+/// The client's and server's addresses of a client exchange, the client's port made up from the message ID
+fn client_exchange(client: IpAddr, msg: &[u8]) -> (SocketAddr, SocketAddr) {
+    let id = if msg.len() >= 2 { u16::from_be_bytes([msg[0], msg[1]]) } else { 0 };
+    let port = CLIENT_PORTS + id % (u16::MAX - CLIENT_PORTS + 1);
+
+    let server = match client {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+
+    (SocketAddr::new(client, port), SocketAddr::new(server, SERVER_PORT))
+}
+
+/// The writer thread, writing frames as they come and the file out once a second or when asked
+fn write_frames(path: &str, mut file: BufWriter<File>, queue: Receiver<Message>) {
+    let mut failing = false;
+    let mut unflushed = false;
+
+    loop {
+        let message = match queue.recv_timeout(FLUSH_INTERVAL) {
+            Ok(x) => Some(x),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+
+        let written = match message {
+            Some(Message::Frame { time, src, dst, payload }) => {
+                unflushed = true;
+                file.write_all(&record(time, src, dst, &payload))
+            }
+            Some(Message::Close(done)) => {
+                let flushed = file.flush();
+                let _ = done.send(());
+                unflushed = false;
+                flushed
+            }
+            None if unflushed => {
+                unflushed = false;
+                file.flush()
+            }
+            None => Ok(()),
+        };
+
+        match written {
+            Ok(()) => failing = false,
+            Err(e) if !failing => {
+                error!("Failed to write to the capture {}, dropping packets until it works again: {}", path, e);
+                failing = true;
+            }
+            Err(_) => (),
+        }
+
+        let dropped = DROPPED.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            warn!("Dropped {} packets from the capture {}, it couldn't keep up", dropped, path);
+        }
+    }
+}
+
+fn file_header() -> Vec<u8> {
+    let mut header = Vec::with_capacity(24);
+    header.extend_from_slice(&MAGIC.to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&4u16.to_le_bytes());
+    header.extend_from_slice(&0i32.to_le_bytes()); // timezone offset
+    header.extend_from_slice(&0u32.to_le_bytes()); // timestamp accuracy
+    header.extend_from_slice(&SNAP_LEN.to_le_bytes());
+    header.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+    header
+}
+
+/// A pcap record of a UDP datagram from src to dst, its payload cut short if it won't fit in one
+/// The families of src and dst are expected to match, a mismatched dst is sent as the unspecified address of src's
+fn record(time: SystemTime, src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let ip_len = if src.is_ipv4() { IPV4_LEN } else { IPV6_LEN };
+    let payload = &payload[..payload.len().min(u16::MAX as usize - ip_len - UDP_LEN)];
+    let udp_len = (UDP_LEN + payload.len()) as u16;
+
+    let mut udp = Vec::with_capacity(udp_len as usize);
+    udp.extend_from_slice(&src.port().to_be_bytes());
+    udp.extend_from_slice(&dst.port().to_be_bytes());
+    udp.extend_from_slice(&udp_len.to_be_bytes());
+    udp.extend_from_slice(&[0, 0]); // checksum, filled in below
+    udp.extend_from_slice(payload);
+
+    let mut frame = Vec::with_capacity(ETHERNET_LEN + ip_len + udp.len());
+    frame.extend_from_slice(&mac(dst.ip()));
+    frame.extend_from_slice(&mac(src.ip()));
+
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src), dst) => {
+            let dst = match dst {
+                IpAddr::V4(x) => x,
+                IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+            };
+
+            frame.extend_from_slice(&0x0800u16.to_be_bytes());
+            let mut ip = Vec::with_capacity(IPV4_LEN);
+            ip.extend_from_slice(&[0x45, 0]); // version 4, 20 byte header, no DSCP
+            ip.extend_from_slice(&((IPV4_LEN + udp.len()) as u16).to_be_bytes());
+            ip.extend_from_slice(&[0, 0, 0x40, 0]); // ID 0, don't fragment
+            ip.extend_from_slice(&[64, PROTO_UDP, 0, 0]); // TTL, protocol, checksum filled in below
+            ip.extend_from_slice(&src.octets());
+            ip.extend_from_slice(&dst.octets());
+            let sum = checksum(&[&ip]);
+            ip[10..12].copy_from_slice(&sum.to_be_bytes());
+
+            // optional over IPv4, but Wireshark can check it
+            let pseudo = [&src.octets()[..], &dst.octets()[..], &[0, PROTO_UDP], &udp_len.to_be_bytes()].concat();
+            let sum = udp_checksum(&pseudo, &udp);
+            udp[6..8].copy_from_slice(&sum.to_be_bytes());
+
+            frame.extend_from_slice(&ip);
+        }
+        (IpAddr::V6(src), dst) => {
+            let dst = match dst {
+                IpAddr::V6(x) => x,
+                IpAddr::V4(x) => x.to_ipv6_mapped(),
+            };
+
+            frame.extend_from_slice(&0x86DDu16.to_be_bytes());
+            frame.extend_from_slice(&[0x60, 0, 0, 0]); // version 6, no traffic class or flow label
+            frame.extend_from_slice(&udp_len.to_be_bytes());
+            frame.extend_from_slice(&[PROTO_UDP, 64]); // next header, hop limit
+            frame.extend_from_slice(&src.octets());
+            frame.extend_from_slice(&dst.octets());
+
+            let pseudo = [&src.octets()[..], &dst.octets()[..], &(udp_len as u32).to_be_bytes(), &[0, 0, 0, PROTO_UDP]].concat();
+            let sum = udp_checksum(&pseudo, &udp);
+            udp[6..8].copy_from_slice(&sum.to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(&udp);
+
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut record = Vec::with_capacity(16 + frame.len());
+    record.extend_from_slice(&(since.as_secs() as u32).to_le_bytes());
+    record.extend_from_slice(&since.subsec_micros().to_le_bytes());
+    record.extend_from_slice(&(frame.len() as u32).to_le_bytes()); // captured
+    record.extend_from_slice(&(frame.len() as u32).to_le_bytes()); // on the wire
+    record.extend_from_slice(&frame);
+    record
+}
+
+/// A made up MAC address for an IP address, locally administered so it can't be mistaken for real hardware
+/// and ending in the address's last 4 bytes so each host keeps its own
+fn mac(ip: IpAddr) -> [u8; 6] {
+    let low = match ip {
+        IpAddr::V4(x) => x.octets(),
+        IpAddr::V6(x) => {
+            let x = x.octets();
+            [x[12], x[13], x[14], x[15]]
+        }
+    };
+
+    [0x02, 0, low[0], low[1], low[2], low[3]]
+}
+
+/// The UDP checksum over a pseudo header and the datagram, 0 sent as all ones since 0 means none (RFC 768)
+fn udp_checksum(pseudo: &[u8], udp: &[u8]) -> u16 {
+    match checksum(&[pseudo, udp]) {
+        0 => 0xFFFF,
+        x => x,
+    }
+}
+
+/// The internet checksum of the parts, one's complement of the one's complement sum of their 16 bit words (RFC 1071)
+/// Every part but the last is expected to be of even length
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum: u32 = 0;
+    for part in parts {
+        for word in part.chunks(2) {
+            let word = match word {
+                [a, b] => u16::from_be_bytes([*a, *b]),
+                [a] => u16::from_be_bytes([*a, 0]),
+                _ => 0,
+            };
+            sum += word as u32;
+        }
+    }
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
@@ -0,0 +1,325 @@
+//! Query log, a line for every answered query written to --query-log <path> for auditing, whatever the log level
+//! Each line has the time, client, transport, question, rcode, number of answers, where the answer came from,
+//! the upstream that gave it and how long it took, tab separated or as JSON with --query-log-format json
+//! Lines are buffered and written out every second and on shutdown, and the file is rotated once it would grow past
+//! --query-log-max-size <bytes>, the last --query-log-keep <n> of them kept as <path>.1 (the newest) to <path>.<n>
+//! A file that can't be written loses its lines until it can again, queries are answered either way
+
+use std::cell::Cell;
+use std::fs::{ self, File, OpenOptions };
+use std::io::{ self, BufWriter, Write };
+use std::net::{ IpAddr, SocketAddr };
+use std::path::PathBuf;
+use std::sync::{ Mutex, OnceLock, PoisonError };
+use std::thread;
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+use log::error;
+
+use crate::json;
+use crate::packet::ResCode;
+use crate::transport::Transport;
+
+static LOG: OnceLock<Mutex<QueryLog>> = OnceLock::new();
+
+// How often buffered lines are written out
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+thread_local! {
+    // where the answer to the query being answered on this thread came from so far
+    static NOTE: Cell<(Source, Option<SocketAddr>)> = const { Cell::new((Source::LOCAL, None)) };
+}
+
+/// How the lines of the log are written
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    TSV,
+    JSON,
+}
+
+/// Where the log goes and how much of it is kept
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub path: PathBuf,
+    pub format: Format,
+    pub max_size: u64, // bytes a file may grow to before it's rotated
+    pub keep: usize,   // rotated files kept
+}
+
+/// Where an answer came from
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// The server itself, for errors and anything not noted otherwise
+    LOCAL,
+    CACHE,
+    HOSTS,
+    ZONE,
+    BLOCKED,
+    SPECIAL,
+    CHAOS,
+    /// An upstream resolver
+    FORWARDED,
+    /// Iterating from the root servers
+    RECURSIVE,
+    /// Refused to a client outside --allow-recursion, or a transfer over UDP
+    REFUSED,
+}
+
+/// An answered query
+pub struct Entry<'a> {
+    pub client: IpAddr,
+    pub transport: Transport,
+    pub qname: &'a str,
+    pub q_type: &'a str,
+    pub res_code: ResCode,
+    pub answers: u16,
+    pub source: Source,
+    pub upstream: Option<SocketAddr>,
+    pub duration: Duration,
+}
+
+struct QueryLog {
+    config: Config,
+    file: Option<BufWriter<File>>,
+    size: u64,     // bytes in the file, written out or not
+    failing: bool, // the last write or rotation failed, so the next failure isn't reported again
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "tsv" => Some(Format::TSV),
+            "json" => Some(Format::JSON),
+            _ => None,
+        }
+    }
+}
+
+impl Source {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Source::LOCAL => "local",
+            Source::CACHE => "cache",
+            Source::HOSTS => "hosts",
+            Source::ZONE => "zone",
+            Source::BLOCKED => "blocked",
+            Source::SPECIAL => "special",
+            Source::CHAOS => "chaos",
+            Source::FORWARDED => "forwarded",
+            Source::RECURSIVE => "recursive",
+            Source::REFUSED => "refused",
+        }
+    }
+}
+
+/// Note where the answer to the query being answered on this thread came from, and the upstream that gave it
+/// A query with several questions keeps the last one noted
+pub fn note(source: Source, upstream: Option<SocketAddr>) {
+    NOTE.with(|x| x.set((source, upstream)));
+}
+
+/// Where the answer to the query answered on this thread came from, forgetting it for the next one
+pub fn take_note() -> (Source, Option<SocketAddr>) {
+    NOTE.with(|x| x.replace((Source::LOCAL, None)))
+}
+
+/// Start logging queries, appending to the file if it's there, and flushing it every second from a thread of its own
+/// Only the first call has any effect
+pub fn open(config: Config) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+    let size = file.metadata()?.len();
+
+    let log = QueryLog {
+        config: config,
+        file: Some(BufWriter::new(file)),
+        size: size,
+        failing: false,
+    };
+    if LOG.set(Mutex::new(log)).is_err() {
+        return Ok(());
+    }
+
+    thread::spawn(|| loop {
+        thread::sleep(FLUSH_INTERVAL);
+        flush();
+    });
+
+    Ok(())
+}
+
+/// Whether queries are logged, so entries are only made when they are
+pub fn is_enabled() -> bool {
+    LOG.get().is_some()
+}
+
+/// Add a query to the log, written out with the next flush
+pub fn record(entry: &Entry) {
+    let log = match LOG.get() {
+        Some(x) => x,
+        None => return,
+    };
+
+    let mut log = log.lock().unwrap_or_else(PoisonError::into_inner);
+    let line = entry.format(log.config.format);
+    log.write(&line);
+}
+
+/// Write out the buffered lines, done every second and before the server exits
+pub fn flush() {
+    if let Some(log) = LOG.get() {
+        log.lock().unwrap_or_else(PoisonError::into_inner).flush();
+    }
+}
+
+impl<'a> Entry<'a> {
+    /// The entry as a line of the log, ending in a newline
+    fn format(&self, format: Format) -> String {
+        let time = timestamp(SystemTime::now());
+        let upstream = self.upstream.map(|x| x.to_string());
+        let ms = self.duration.as_secs_f64() * 1000.0;
+
+        match format {
+            Format::TSV => format!(
+                "{}\t{}\t{}\t{}\t{}\t{:?}\t{}\t{}\t{}\t{:.3}\n",
+                time,
+                self.client,
+                self.transport.name(),
+                // a tab or newline in a name would split the line
+                self.qname.replace(|c: char| c.is_control(), "?"),
+                self.q_type,
+                self.res_code,
+                self.answers,
+                self.source.name(),
+                upstream.as_deref().unwrap_or("-"),
+                ms,
+            ),
+            Format::JSON => format!(
+                "{{\"time\":\"{}\",\"client\":\"{}\",\"transport\":\"{}\",\"qname\":{},\"qtype\":{},\"rcode\":\"{:?}\",\"answers\":{},\"source\":\"{}\",\"upstream\":{},\"ms\":{:.3}}}\n",
+                time,
+                self.client,
+                self.transport.name(),
+                json::quote(self.qname),
+                json::quote(self.q_type),
+                self.res_code,
+                self.answers,
+                self.source.name(),
+                upstream.map_or_else(|| "null".to_string(), |x| format!("\"{}\"", x)),
+                ms,
+            ),
+        }
+    }
+}
+
+impl QueryLog {
+    fn write(&mut self, line: &str) {
+        let len = line.len() as u64;
+        if self.size > 0 && self.size + len > self.config.max_size {
+            self.rotate();
+        }
+
+        let file = match &mut self.file {
+            Some(x) => x,
+            None => return,
+        };
+        match file.write_all(line.as_bytes()) {
+            Ok(()) => self.size += len,
+            Err(e) => self.fail("write to", e),
+        }
+    }
+
+    fn flush(&mut self) {
+        // a file that couldn't be opened at the last rotation is tried again
+        if self.file.is_none() {
+            match OpenOptions::new().create(true).append(true).open(&self.config.path) {
+                Ok(x) => {
+                    self.size = x.metadata().map_or(0, |x| x.len());
+                    self.file = Some(BufWriter::new(x));
+                }
+                Err(e) => return self.fail("open", e),
+            }
+        }
+
+        if let Some(file) = &mut self.file {
+            match file.flush() {
+                Ok(()) => self.failing = false,
+                Err(e) => self.fail("write to", e),
+            }
+        }
+    }
+
+    /// Move the file to <path>.1 and every older one up a number, dropping the one past --query-log-keep, and start a new file
+    fn rotate(&mut self) {
+        if let Some(mut file) = self.file.take() {
+            if let Err(e) = file.flush() {
+                self.fail("write to", e);
+            }
+        }
+
+        let path = &self.config.path;
+        let numbered = |n: usize| {
+            let mut x = path.clone().into_os_string();
+            x.push(format!(".{}", n));
+            PathBuf::from(x)
+        };
+
+        let moved = if self.config.keep == 0 {
+            fs::remove_file(path)
+        } else {
+            for n in (1..self.config.keep).rev() {
+                // the files below keep may not all be there yet
+                let _ = fs::rename(numbered(n), numbered(n + 1));
+            }
+            fs::rename(path, numbered(1))
+        };
+        if let Err(e) = moved {
+            return self.fail("rotate", e);
+        }
+
+        match File::create(path) {
+            Ok(x) => {
+                self.file = Some(BufWriter::new(x));
+                self.size = 0;
+            }
+            Err(e) => self.fail("open", e),
+        }
+    }
+
+    /// Report a failure, once until the log works again
+    fn fail(&mut self, what: &str, e: io::Error) {
+        if !self.failing {
+            error!("Failed to {} the query log {}, dropping queries until it works again: {}", what, self.config.path.display(), e);
+            self.failing = true;
+        }
+    }
+}
+
+/// A time as UTC in RFC 3339 with milliseconds, ex. 2024-05-01T12:00:00.000Z
+fn timestamp(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+
+    // days to a civil date, from Howard Hinnant's algorithm
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        since.subsec_millis(),
+    )
+}
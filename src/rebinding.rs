@@ -0,0 +1,195 @@
+//! DNS rebinding protection, so a page from the internet can't get a browser to reach devices on the LAN by its name
+//! With --rebind-protection, A and AAAA records pointing into private, loopback, link-local or otherwise reserved ranges
+//! are taken out of answers from upstreams and the root servers, once CNAMEs are chased and DNS64 has made its AAAA records
+//! An answer left without any address gets NXDOMAIN, the filtered records are logged and counted in the stats
+//! The cache keeps each answer as it came, so it's filtered every time it's given out, and a private A record
+//! can't stand in for the name's other types
+//! Names under a --forward rule's domain are left alone, those upstreams are the LAN's own, and so are names under
+//! a --rebind-allow <domain>, ex. a dynamic DNS name pointing home, the hosts file and local zones never get here
+
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr };
+use std::sync::OnceLock;
+
+use log::debug;
+
+use crate::dns_name;
+use crate::ede::{ self, Ede };
+use crate::error::DnsError;
+use crate::forwarding;
+use crate::idna;
+use crate::packet::{ DnsPacket, DnsRecord, ResCode };
+use crate::stats;
+
+type Result<T> = std::result::Result<T, DnsError>;
+
+static ALLOWED: OnceLock<Vec<String>> = OnceLock::new();
+
+/// Filter private addresses from upstream answers, except for names under the allowed domains,
+/// only the first call has any effect, fails on an allowed domain that isn't a valid name
+pub fn enable(allowed: &[String]) -> Result<()> {
+    for domain in allowed {
+        dns_name::validate(domain).map_err(|e| DnsError::Config(format!("{}: {}", domain, e)))?;
+    }
+
+    let _ = ALLOWED.set(allowed.iter().map(|x| dns_name::normalize(x)).collect());
+
+    Ok(())
+}
+
+/// Whether answers are filtered
+pub fn is_enabled() -> bool {
+    ALLOWED.get().is_some()
+}
+
+/// Take the A and AAAA records with reserved addresses out of an upstream's answer to a name,
+/// answering NXDOMAIN if it had addresses and none are left
+pub fn filter(mut res: DnsPacket, qname: &str) -> DnsPacket {
+    let allowed = match ALLOWED.get() {
+        Some(x) => x,
+        None => return res,
+    };
+    if forwarding::find(qname).is_some() || allowed.iter().any(|x| dns_name::is_subdomain(qname, x)) {
+        return res;
+    }
+
+    let addresses = res.answers.iter().filter(|x| address(x).is_some()).count();
+    let mut filtered = 0;
+    res.answers.retain(|x| {
+        // the end of a CNAME chain may be an allowed name too
+        let reserved = address(x).filter(|addr| is_reserved(*addr) && !allowed.iter().any(|y| dns_name::is_subdomain(x.domain(), y)));
+        if let Some(addr) = reserved {
+            debug!("Filtered {} {} {} from the answer for {}, a reserved address", idna::to_unicode(x.domain()), x.query_type(), addr, idna::to_unicode(qname));
+            filtered += 1;
+        }
+
+        reserved.is_none()
+    });
+
+    if filtered == 0 {
+        return res;
+    }
+    stats::count_rebinding_filtered(filtered as u64);
+
+    if filtered == addresses {
+        res.header.res_code = ResCode::NX_DOMAIN;
+        res.header.auth_data = false;
+        res.extended_errors.push(Ede::new(ede::FILTERED, "only reserved addresses"));
+        res.answers.clear();
+        res.authorities.clear();
+    }
+
+    res
+}
+
+/// The address of an A or AAAA record
+fn address(record: &DnsRecord) -> Option<IpAddr> {
+    match *record {
+        DnsRecord::A { addr_v4, .. } => Some(IpAddr::V4(addr_v4)),
+        DnsRecord::AAAA { addr, .. } => Some(IpAddr::V6(addr)),
+        _ => None,
+    }
+}
+
+/// Whether an address is one no public name should point to: private (RFC 1918), shared (RFC 6598), loopback,
+/// link-local, unspecified or broadcast, or unique local (fc00::/7), an IPv4-mapped IPv6 address by its IPv4 one
+fn is_reserved(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(x) => is_reserved_v4(x),
+        IpAddr::V6(x) => match x.to_ipv4_mapped() {
+            Some(v4) => is_reserved_v4(v4),
+            None => is_reserved_v6(x),
+        },
+    }
+}
+
+fn is_reserved_v4(addr: Ipv4Addr) -> bool {
+    let [a, b, ..] = addr.octets();
+
+    addr.is_private() || addr.is_loopback() || addr.is_link_local() || addr.is_broadcast()
+        // 0.0.0.0/8, "this network", and 100.64.0.0/10 behind carrier-grade NAT
+        || a == 0 || (a == 100 && b & 0xC0 == 64)
+}
+
+fn is_reserved_v6(addr: Ipv6Addr) -> bool {
+    let first = addr.segments()[0];
+
+    addr.is_loopback() || addr.is_unspecified()
+        // unique local fc00::/7 and link-local fe80::/10
+        || first & 0xFE00 == 0xFC00 || first & 0xFFC0 == 0xFE80
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{ SocketAddr, UdpSocket };
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::cache;
+    use crate::packet::{ DnsQuestion, QueryType };
+    use crate::resolve::{ self, Resolution };
+    use crate::transport::Transport;
+    use crate::upstreams::{ Strategy, Upstream, Upstreams };
+
+    /// An upstream answering A with a private address and AAAA with a public one until it's been idle a while,
+    /// returning how many queries it got
+    fn upstream() -> (SocketAddr, thread::JoinHandle<usize>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        let handle = thread::spawn(move || {
+            socket.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+            let mut buf = [0; 512];
+            let mut queries = 0;
+            while let Ok((size, client)) = socket.recv_from(&mut buf) {
+                queries += 1;
+                let query = DnsPacket::from_bytes(&buf[..size]).unwrap();
+                let ques = query.questions[0].clone();
+                let answer = match ques.q_type {
+                    QueryType::A => DnsRecord::A { domain: ques.name.clone(), addr_v4: Ipv4Addr::new(192, 168, 1, 10), ttl: 300 },
+                    _ => DnsRecord::AAAA { domain: ques.name.clone(), addr: "2001:db8::10".parse().unwrap(), ttl: 300 },
+                };
+                let mut res = DnsPacket::response_to(&query).question(ques).answer(answer);
+                socket.send_to(&res.to_bytes().unwrap(), client).unwrap();
+            }
+            queries
+        });
+
+        (addr, handle)
+    }
+
+    fn ask(name: &str, q_type: QueryType, resolution: &Resolution) -> DnsPacket {
+        let mut query = DnsPacket::new();
+        query.header.query_res = false;
+        query.questions.push(DnsQuestion::new(name.to_string(), q_type));
+        let req = query.to_bytes().unwrap();
+
+        let res = resolve::handle_query_bytes(&req, IpAddr::V4(Ipv4Addr::LOCALHOST), resolution, Transport::UDP).unwrap();
+        DnsPacket::from_bytes(&res).unwrap()
+    }
+
+    #[test]
+    fn filtered_answers_stay_out_of_the_cache() {
+        enable(&[]).unwrap();
+        let (addr, upstream) = upstream();
+        let upstreams = Upstreams::new(vec![Upstream::udp(addr)], Strategy::SEQUENTIAL);
+        let resolution = Resolution::Forward(Box::leak(Box::new(upstreams)));
+
+        let private = ask("home.rebinding.pine-dns.com", QueryType::A, &resolution);
+        assert_eq!(private.header.res_code, ResCode::NX_DOMAIN);
+        assert!(private.answers.is_empty());
+
+        // the name's public AAAA record still answers, the NXDOMAIN was only for the A query
+        let public = ask("home.rebinding.pine-dns.com", QueryType::AAAA, &resolution);
+        assert_eq!(public.header.res_code, ResCode::NO_ERR);
+        assert_eq!(public.get_first_addr(), Some("2001:db8::10".parse().unwrap()));
+
+        // answered from the cache, which kept the upstream's answer, and filtered again
+        let again = ask("home.rebinding.pine-dns.com", QueryType::A, &resolution);
+        assert_eq!(again.header.res_code, ResCode::NX_DOMAIN);
+        assert_eq!(upstream.join().unwrap(), 2);
+
+        let cached = cache::lookup("home.rebinding.pine-dns.com", QueryType::A, |_, _| Err(DnsError::NoResolver)).unwrap();
+        assert_eq!(cached.get_first_addr(), Some(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10))));
+    }
+}
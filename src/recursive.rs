@@ -0,0 +1,1000 @@
+//! Iterative resolution from the root name servers, RFC 1034 section 5.3.3
+//! Each name server is asked about the name with recursion desired unset, a referral names the
+//! servers for a zone closer to it and their glue says where to find them, each tried in turn until one
+//! answers, and the walk goes on down the delegations until an answer, NXDOMAIN or an authoritative empty answer
+//! With qname minimization each zone's servers are only told the name one label below the zone,
+//! so the root learns nothing more than the TLD and only the final zone's servers see the whole name
+//! A server that answers for a zone without authority, refuses or refers back up is lame for it,
+//! it's passed over for that zone for a few minutes and the zone's other servers are asked instead
+//! Records outside the zone a server was asked about, and addresses that are no glue, are dropped from its responses
+//! Each client query has a budget of referrals and queries to name servers, and a referral back to a zone
+//! already visited is a loop, either ends the walk with an error
+//! The root servers come from a named.root hints file given with --root-hints or the built-in table,
+//! until a priming query (RFC 8109) to them returns the current set, which is asked again when its TTL runs out
+
+use std::fs;
+use std::net::{ IpAddr, SocketAddr };
+use std::sync::{ Mutex, OnceLock, PoisonError, RwLock };
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+use std::thread;
+use std::time::{ Duration, Instant };
+
+use log::{ debug, info, warn };
+
+use crate::dns_name;
+use crate::error::DnsError;
+use crate::idna;
+use crate::packet::{ DnsPacket, DnsRecord, QueryType, ResCode };
+use crate::resolve;
+
+type Error = DnsError;
+type Result<T> = std::result::Result<T, Error>;
+
+static ROOT_HINTS: OnceLock<Vec<NameServer>> = OnceLock::new();
+static PRIMED: RwLock<Option<Primed>> = RwLock::new(None);
+static PREFER_IPV6: AtomicBool = AtomicBool::new(false);
+static QNAME_MINIMIZATION: AtomicBool = AtomicBool::new(true);
+static NS_ADDRS: Mutex<Vec<NsAddrs>> = Mutex::new(Vec::new());
+static LAME: Mutex<Vec<Lame>> = Mutex::new(Vec::new());
+static MAX_REFERRALS: AtomicUsize = AtomicUsize::new(16);
+static MAX_QUERIES: AtomicUsize = AtomicUsize::new(50);
+
+// Root servers for one test's thread and the port its name servers answer on, so loopback addresses can stand in for them
+#[cfg(test)]
+thread_local! {
+    static TEST_ROOTS: std::cell::RefCell<Option<(Vec<IpAddr>, u16)>> = const { std::cell::RefCell::new(None) };
+}
+
+// How long to wait before priming again after it failed, lookups start from the hints meanwhile
+const PRIME_RETRY: Duration = Duration::from_secs(60);
+
+// Name servers are looked up for at most this many levels of name servers needing name servers
+const MAX_NS_DEPTH: usize = 4;
+
+// Addresses of glueless name servers kept at most
+const MAX_NS_ADDRS: usize = 1024;
+
+// How long a name server that was lame for a zone is passed over for it
+const LAME_TTL: Duration = Duration::from_secs(300);
+
+// Lame servers kept at most
+const MAX_LAME: usize = 1024;
+
+/// Name servers are only asked on the standard port
+pub const DNS_PORT: u16 = 53;
+
+// The root servers as of https://www.internic.net/domain/named.root
+const BUILTIN_HINTS: [(&str, &str, &str); 13] = [
+    ("a.root-servers.net", "198.41.0.4", "2001:503:ba3e::2:30"),
+    ("b.root-servers.net", "170.247.170.2", "2801:1b8:10::b"),
+    ("c.root-servers.net", "192.33.4.12", "2001:500:2::c"),
+    ("d.root-servers.net", "199.7.91.13", "2001:500:2d::d"),
+    ("e.root-servers.net", "192.203.230.10", "2001:500:a8::e"),
+    ("f.root-servers.net", "192.5.5.241", "2001:500:2f::f"),
+    ("g.root-servers.net", "192.112.36.4", "2001:500:12::d0d"),
+    ("h.root-servers.net", "198.97.190.53", "2001:500:1::53"),
+    ("i.root-servers.net", "192.36.148.17", "2001:7fe::53"),
+    ("j.root-servers.net", "192.58.128.30", "2001:503:c27::2:30"),
+    ("k.root-servers.net", "193.0.14.129", "2001:7fd::1"),
+    ("l.root-servers.net", "199.7.83.42", "2001:500:9f::42"),
+    ("m.root-servers.net", "202.12.27.33", "2001:dc3::35"),
+];
+
+/// A name server and the addresses it answers on
+#[derive(Clone, Debug)]
+pub struct NameServer {
+    pub name: String,
+    pub addrs: Vec<IpAddr>,
+}
+
+/// Try name servers' IPv6 addresses before their IPv4 ones
+/// IPv4 goes first by default since the host may have no route for IPv6
+pub fn set_prefer_ipv6(prefer: bool) {
+    PREFER_IPV6.store(prefer, Ordering::Relaxed);
+}
+
+/// Ask each zone's servers only for the name one label below the zone, not the whole name
+/// On by default, some broken authoritative servers answer the shortened names wrongly
+pub fn set_qname_minimization(enabled: bool) {
+    QNAME_MINIMIZATION.store(enabled, Ordering::Relaxed);
+}
+
+/// Most referrals one client query may follow, glueless name server lookups included (default 16)
+pub fn set_max_referrals(max: usize) {
+    MAX_REFERRALS.store(max, Ordering::Relaxed);
+}
+
+/// Most queries one client query may send to name servers, glueless name server lookups included (default 50)
+/// Stops pathological zones turning one query into a storm
+pub fn set_max_queries(max: usize) {
+    MAX_QUERIES.store(max, Ordering::Relaxed);
+}
+
+/// Install root hints, only the first call has any effect
+/// Without it the built-in table is used
+pub fn set_root_hints(hints: Vec<NameServer>) {
+    let _ = ROOT_HINTS.set(hints);
+}
+
+/// Start this thread's lookups from root servers on loopback, asking every name server on a port of the test's
+#[cfg(test)]
+fn set_test_roots(roots: Vec<IpAddr>, port: u16) {
+    TEST_ROOTS.set(Some((roots, port)));
+}
+
+/// The root servers resolution starts from
+pub fn root_hints() -> &'static [NameServer] {
+    ROOT_HINTS.get_or_init(builtin_hints)
+}
+
+/// The root servers as a priming response listed them
+struct Primed {
+    servers: Vec<NameServer>,
+    expires: Instant,
+}
+
+/// Looked up addresses of a name server that came without glue
+struct NsAddrs {
+    host: String, // normalized
+    addrs: Vec<IpAddr>,
+    expires: Instant,
+}
+
+/// A name server found lame for a zone, skipped for it until it expires
+struct Lame {
+    addr: IpAddr,
+    zone: String, // normalized
+    expires: Instant,
+}
+
+/// The root servers resolution currently starts from
+#[derive(Clone, Debug)]
+pub struct RootStats {
+    pub primed: bool,                 // false while only the hints are known
+    pub servers: Vec<NameServer>,
+    pub expires_in: Option<Duration>, // until the primed set is refreshed
+}
+
+impl RootStats {
+    /// Snapshot of the working root server set
+    pub fn current() -> RootStats {
+        let primed = PRIMED.read().unwrap_or_else(PoisonError::into_inner);
+
+        match &*primed {
+            Some(x) => RootStats {
+                primed: true,
+                servers: x.servers.clone(),
+                expires_in: Some(x.expires.saturating_duration_since(Instant::now())),
+            },
+            None => RootStats {
+                primed: false,
+                servers: root_hints().to_vec(),
+                expires_in: None,
+            },
+        }
+    }
+}
+
+/// Prime the root servers from a thread of their own, now and again whenever the primed set expires
+/// Until the first priming query is answered lookups start from the hints
+pub fn start_priming() {
+    thread::spawn(|| loop {
+        let wait = match prime() {
+            Ok(ttl) => ttl,
+            Err(e) => {
+                warn!("Priming the root servers failed, using the hints and retrying in {:?}: {}", PRIME_RETRY, e);
+                PRIME_RETRY
+            }
+        };
+
+        thread::sleep(wait);
+    });
+}
+
+/// Ask the hinted root servers for the root's NS records and make the servers listed the working set
+/// Returns how long the set is good for
+fn prime() -> Result<Duration> {
+    let resp = ask("", QueryType::NS, "", &addrs(root_hints()), &mut Walk::new())?;
+    let (servers, ttl) = primed_servers(&resp)
+        .ok_or(DnsError::NoRootServers)?;
+
+    info!("Primed {} root servers, refreshing in {:?}: {}", servers.len(), ttl, servers.iter().map(|x| x.name.as_str()).collect::<Vec<_>>().join(", "));
+
+    let primed = Primed {
+        servers: servers,
+        expires: Instant::now() + ttl,
+    };
+    *PRIMED.write().unwrap_or_else(PoisonError::into_inner) = Some(primed);
+
+    Ok(ttl)
+}
+
+/// The root name servers a priming response lists with their glue, and the lowest TTL among them
+/// Servers without an address in the additional section are left out
+fn primed_servers(resp: &DnsPacket) -> Option<(Vec<NameServer>, Duration)> {
+    let mut ttl = u32::MAX;
+    let mut servers = Vec::new();
+
+    for (_, host, ns_ttl) in resp.ns_records().filter(|(domain, _, _)| dns_name::normalize(domain).is_empty()) {
+        let mut addrs = Vec::new();
+        for (_, addr, glue_ttl) in resp.addr_records_with_additional().filter(|(domain, _, _)| dns_name::eq_ignore_case(domain, host)) {
+            addrs.push(addr);
+            ttl = ttl.min(glue_ttl);
+        }
+
+        if !addrs.is_empty() {
+            ttl = ttl.min(ns_ttl);
+            servers.push(NameServer {
+                name: dns_name::normalize(host),
+                addrs: addrs,
+            });
+        }
+    }
+
+    match servers.is_empty() {
+        true => None,
+        false => Some((servers, Duration::from_secs(ttl as u64))),
+    }
+}
+
+/// The root server addresses lookups start from, the primed set while it is current, otherwise the hints
+fn root_addrs() -> Vec<IpAddr> {
+    #[cfg(test)]
+    if let Some((roots, _)) = TEST_ROOTS.with_borrow(|x| x.clone()) {
+        return roots;
+    }
+
+    let primed = PRIMED.read().unwrap_or_else(PoisonError::into_inner);
+
+    match &*primed {
+        Some(x) if Instant::now() < x.expires => addrs(&x.servers),
+        _ => addrs(root_hints()),
+    }
+}
+
+/// The port name servers are asked on
+fn port() -> u16 {
+    #[cfg(test)]
+    if let Some((_, port)) = TEST_ROOTS.with_borrow(|x| x.clone()) {
+        return port;
+    }
+
+    DNS_PORT
+}
+
+fn addrs(servers: &[NameServer]) -> Vec<IpAddr> {
+    let mut addrs: Vec<IpAddr> = servers.iter().flat_map(|x| x.addrs.iter().copied()).collect();
+    prefer_family(&mut addrs);
+
+    addrs
+}
+
+/// Put the preferred address family first, otherwise keeping the order
+fn prefer_family(addrs: &mut [IpAddr]) {
+    let v6 = PREFER_IPV6.load(Ordering::Relaxed);
+    addrs.sort_by_key(|x| x.is_ipv6() != v6);
+}
+
+fn builtin_hints() -> Vec<NameServer> {
+    BUILTIN_HINTS.iter()
+        .map(|(name, v4, v6)| NameServer {
+            name: name.to_string(),
+            addrs: vec![v4.parse().unwrap(), v6.parse().unwrap()],
+        })
+        .collect()
+}
+
+/// Read root hints in the named.root format, a zone file of the root's NS records and their addresses
+/// ex. ".  3600000  NS  A.ROOT-SERVERS.NET." and "A.ROOT-SERVERS.NET.  3600000  A  198.41.0.4"
+/// Comments start with ; and the TTL and class are optional
+pub fn load_root_hints(path: &str) -> Result<Vec<NameServer>> {
+    let text = fs::read_to_string(path).map_err(|e| DnsError::Config(format!("{}: {}", path, e)))?;
+
+    let mut names: Vec<String> = Vec::new();
+    let mut addrs: Vec<(String, IpAddr)> = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split(';').next().unwrap_or("");
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty() {
+            continue;
+        }
+
+        // the type is the first field after the owner that is one, TTL and class come before it
+        let at = fields.iter().skip(1).position(|x| matches!(x.to_ascii_uppercase().as_str(), "NS" | "A" | "AAAA"))
+            .map(|x| x + 1);
+        let (kind, value) = match at {
+            Some(at) if at + 1 < fields.len() => (fields[at].to_ascii_uppercase(), fields[at + 1]),
+            _ => return Err(DnsError::Config(format!("{} line {}: expected an NS, A or AAAA record", path, i + 1))),
+        };
+        let owner = dns_name::normalize(fields[0]);
+
+        match kind.as_str() {
+            "NS" if owner.is_empty() => names.push(dns_name::normalize(value)),
+            "NS" => return Err(DnsError::Config(format!("{} line {}: NS record for {} instead of the root", path, i + 1, fields[0]))),
+            _ => {
+                let addr = value.parse::<IpAddr>()
+                    .map_err(|_| DnsError::Config(format!("{} line {}: invalid address {}", path, i + 1, value)))?;
+                addrs.push((owner, addr));
+            }
+        }
+    }
+
+    let hints: Vec<NameServer> = names.into_iter()
+        .map(|name| NameServer {
+            addrs: addrs.iter().filter(|(owner, _)| *owner == name).map(|(_, addr)| *addr).collect(),
+            name: name,
+        })
+        .filter(|x| !x.addrs.is_empty())
+        .collect();
+
+    if hints.is_empty() {
+        return Err(DnsError::Config(format!("{} has no root name server with an address", path)));
+    }
+
+    Ok(hints)
+}
+
+/// The work done for one client query, shared by the lookups of glueless name servers it sets off
+struct Walk {
+    queries: usize,         // sent to name servers so far
+    referrals: usize,       // followed so far
+    resolving: Vec<String>, // name servers whose addresses are being looked up, outermost first
+    minimize: bool,         // qname minimization, as set when the client query came in
+    max_queries: usize,
+    max_referrals: usize,
+}
+
+impl Walk {
+    fn new() -> Walk {
+        Walk {
+            queries: 0,
+            referrals: 0,
+            resolving: Vec::new(),
+            minimize: QNAME_MINIMIZATION.load(Ordering::Relaxed),
+            max_queries: MAX_QUERIES.load(Ordering::Relaxed),
+            max_referrals: MAX_REFERRALS.load(Ordering::Relaxed),
+        }
+    }
+
+    fn out_of_budget(&self) -> bool {
+        self.queries >= self.max_queries || self.referrals > self.max_referrals
+    }
+}
+
+/// Resolve a name from the root down, following a CNAME chain to the records at its end
+pub fn resolve(qname: &str, q_type: QueryType) -> Result<DnsPacket> {
+    resolve_in(qname, q_type, &mut Walk::new())
+}
+
+fn resolve_in(qname: &str, q_type: QueryType, walk: &mut Walk) -> Result<DnsPacket> {
+    let resp = iterate(qname, q_type, walk)?;
+
+    resolve::chase_cnames(resp, qname, q_type, |x| iterate(x, q_type, walk))
+}
+
+/// Walk the delegations from the root down to the servers for a name
+/// The response of the last server asked is returned as it is: an answer, NXDOMAIN with the zone's SOA,
+/// or an empty answer when the name has no records of the type
+fn iterate(qname: &str, q_type: QueryType, walk: &mut Walk) -> Result<DnsPacket> {
+    let labels: Vec<&str> = qname.split('.').filter(|x| !x.is_empty()).collect();
+
+    let mut servers = root_addrs();
+    let mut zone = String::new();
+    // zones visited for this name, a referral back to one of them is a loop
+    let mut chain = vec![String::new()];
+    // labels of the name asked about, the zone's and one more while minimizing, RFC 9156
+    let mut asked = match walk.minimize {
+        true => 1,
+        false => labels.len(),
+    };
+
+    loop {
+        let minimized = asked < labels.len();
+        let resp = match minimized {
+            true => ask(&labels[labels.len() - asked..].join("."), QueryType::A, &zone, &servers, walk)?,
+            false => ask(qname, q_type, &zone, &servers, walk)?,
+        };
+
+        if !minimized && (!resp.answers.is_empty() || resp.header.res_code != ResCode::NO_ERR) {
+            return Ok(resp);
+        }
+
+        let next = referral_zone(&resp, qname, &zone);
+        if let Some(x) = next.as_ref().filter(|x| chain.iter().any(|z| dns_name::eq_ignore_case(z, x))) {
+            return Err(DnsError::DelegationLoop {
+                qname: display_name(qname).to_string(),
+                zone: display_name(&zone).to_string(),
+                target: display_name(x).to_string(),
+                chain: zone_chain(&chain),
+            });
+        }
+
+        // a referral must lead closer to the name, anything else ends the walk
+        let next = match next.filter(|x| dns_name::is_subdomain(x, &zone)) {
+            Some(x) => x,
+            None if !minimized => return Ok(resp),
+            // the shortened name is no delegation of its own, one label more
+            None if resp.header.res_code == ResCode::NO_ERR => {
+                asked += 1;
+                continue;
+            }
+            // some servers answer NXDOMAIN for names that only have names below them, or fail outright,
+            // so this zone gets the full name
+            None => {
+                debug!("{} answered {} for a shortened name, asking for {} in full", display_name(&zone), resp.header.res_code, display_name(qname));
+                asked = labels.len();
+                continue;
+            }
+        };
+
+        walk.referrals += 1;
+        chain.push(next.clone());
+        if walk.referrals > walk.max_referrals {
+            return Err(DnsError::TooManyReferrals { qname: display_name(qname).to_string(), referrals: walk.max_referrals, chain: zone_chain(&chain) });
+        }
+
+        let mut glue: Vec<IpAddr> = Vec::new();
+        for addr in resp.get_resolved_ns_all(qname) {
+            if !glue.contains(&addr) {
+                glue.push(addr);
+            }
+        }
+        prefer_family(&mut glue);
+
+        servers = match glue.is_empty() {
+            false => glue,
+            // no glue, the name servers are out of the zone and their addresses have to be looked up first
+            true => glueless_addrs(&resp, &next, walk)?,
+        };
+        if asked < labels.len() {
+            asked = next.split('.').filter(|x| !x.is_empty()).count() + 1;
+        }
+        zone = next;
+    }
+}
+
+/// Addresses of the first of a referral's name servers that can be resolved, with the walk for the
+/// original name paused meanwhile
+fn glueless_addrs(resp: &DnsPacket, zone: &str, walk: &mut Walk) -> Result<Vec<IpAddr>> {
+    let hosts: Vec<String> = resp.ns_records()
+        .filter(|(domain, _, _)| dns_name::eq_ignore_case(domain, zone))
+        .map(|(_, host, _)| dns_name::normalize(host))
+        .collect();
+
+    let mut failure = DnsError::NoNameServers(zone.to_string());
+    for host in hosts {
+        match ns_addrs(&host, walk) {
+            Ok(addrs) if !addrs.is_empty() => return Ok(addrs),
+            Ok(_) => failure = DnsError::NoNameServerAddress { host: host.clone(), zone: zone.to_string() },
+            // out of budget, no other name server will fare better
+            Err(e) if walk.out_of_budget() => return Err(e),
+            Err(e) => failure = e,
+        }
+    }
+
+    Err(failure)
+}
+
+/// Look up the addresses of a name server without glue, A and AAAA, remembering them for their TTL
+/// Fails when it is already being looked up further out, ex. a zone whose only name server is inside it,
+/// or when name servers of name servers nest deeper than MAX_NS_DEPTH
+fn ns_addrs(host: &str, walk: &mut Walk) -> Result<Vec<IpAddr>> {
+    if let Some(x) = cached_ns_addrs(host) {
+        return Ok(x);
+    }
+
+    if walk.resolving.iter().any(|x| x == host) {
+        return Err(DnsError::CircularDependency { host: host.to_string(), chain: walk.resolving.join(" -> ") });
+    }
+    if walk.resolving.len() >= MAX_NS_DEPTH {
+        return Err(DnsError::NameServersTooDeep { host: host.to_string(), max: MAX_NS_DEPTH, chain: walk.resolving.join(" -> ") });
+    }
+
+    debug!("Looking up the addresses of name server {}", host);
+    walk.resolving.push(host.to_string());
+    let found = ns_lookup(host, walk);
+    walk.resolving.pop();
+
+    let (mut addrs, ttl) = found?;
+    prefer_family(&mut addrs);
+    cache_ns_addrs(host, &addrs, ttl);
+
+    Ok(addrs)
+}
+
+fn ns_lookup(host: &str, walk: &mut Walk) -> Result<(Vec<IpAddr>, u32)> {
+    let mut addrs = Vec::new();
+    let mut ttl = u32::MAX;
+
+    for q_type in [QueryType::A, QueryType::AAAA] {
+        let resp = resolve_in(host, q_type, walk)?;
+
+        addrs.extend(resp.addr_records().map(|(_, addr, _)| addr));
+        // the chain leading to the addresses counts too
+        ttl = resp.answers.iter().map(|x| x.ttl()).fold(ttl, u32::min);
+    }
+
+    Ok((addrs, ttl))
+}
+
+fn cached_ns_addrs(host: &str) -> Option<Vec<IpAddr>> {
+    let cache = NS_ADDRS.lock().unwrap_or_else(PoisonError::into_inner);
+    let now = Instant::now();
+
+    cache.iter()
+        .find(|x| x.host == host && x.expires > now)
+        .map(|x| x.addrs.clone())
+}
+
+fn cache_ns_addrs(host: &str, addrs: &[IpAddr], ttl: u32) {
+    let mut cache = NS_ADDRS.lock().unwrap_or_else(PoisonError::into_inner);
+    let now = Instant::now();
+
+    cache.retain(|x| x.host != host);
+    if cache.len() >= MAX_NS_ADDRS {
+        cache.retain(|x| x.expires > now);
+    }
+    // still full of live entries, this one just isn't kept
+    if cache.len() >= MAX_NS_ADDRS || addrs.is_empty() {
+        return;
+    }
+
+    cache.push(NsAddrs {
+        host: host.to_string(),
+        addrs: addrs.to_vec(),
+        expires: now + Duration::from_secs(ttl as u64),
+    });
+}
+
+/// Ask the servers for a zone in turn until one answers for it
+/// Servers lame for the zone are passed over, each query counts against the walk's budget
+fn ask(qname: &str, q_type: QueryType, zone: &str, servers: &[IpAddr], walk: &mut Walk) -> Result<DnsPacket> {
+    let mut lame = 0;
+
+    for server in servers {
+        if is_lame(*server, zone) {
+            lame += 1;
+            continue;
+        }
+
+        if walk.queries >= walk.max_queries {
+            return Err(DnsError::TooManyQueries { qname: display_name(qname).to_string(), queries: walk.max_queries });
+        }
+        walk.queries += 1;
+
+        debug!("Asking {} about {} {} (zone {})", server, idna::to_unicode(display_name(qname)), q_type, display_name(zone));
+
+        let resp = match resolve::query_server(qname, q_type, SocketAddr::new(*server, port())) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("{} didn't answer for {}: {}", server, display_name(zone), e);
+                continue;
+            }
+        };
+
+        match lameness(&resp, qname, zone) {
+            None => return Ok(scrub(resp, zone, *server)),
+            Some(reason) => {
+                warn!("Lame delegation: {} is listed as a name server for {} but {}, skipping it for {}s", server, display_name(zone), reason, LAME_TTL.as_secs());
+                mark_lame(*server, zone);
+                lame += 1;
+            }
+        }
+    }
+
+    match lame == servers.len() {
+        true => Err(DnsError::LameServers { zone: display_name(zone).to_string(), count: lame }),
+        false => Err(DnsError::NoNameServerAnswer(display_name(zone).to_string())),
+    }
+}
+
+/// Why a server's response shows it doesn't serve the zone it was asked about, if it does
+/// Servers for a zone answer authoritatively or refer to a zone below it
+fn lameness(resp: &DnsPacket, qname: &str, zone: &str) -> Option<String> {
+    if resp.header.res_code == ResCode::REFUSED {
+        return Some("refused the query".to_string());
+    }
+
+    if resp.answers.is_empty() {
+        let upward = resp.authorities.iter().find_map(|record| match record {
+            DnsRecord::NS { domain, .. } if dns_name::is_subdomain(zone, domain) && !dns_name::eq_ignore_case(zone, domain) => Some(dns_name::normalize(domain)),
+            _ => None,
+        });
+        if let Some(x) = upward {
+            return Some(format!("referred up to {}", display_name(&x)));
+        }
+    }
+
+    let referral = referral_zone(resp, qname, zone).is_some_and(|x| dns_name::is_subdomain(&x, zone));
+    match resp.header.authoritative || referral {
+        true => None,
+        false => Some("answered without authority".to_string()),
+    }
+}
+
+/// Drop the records a zone's server has no say over, RFC 2181 section 5.4.1, so a malicious server can't
+/// slip in addresses for names in other zones: every record must be at or below the zone,
+/// and addresses in the additional section must be glue for a name server in the response
+fn scrub(mut resp: DnsPacket, zone: &str, server: IpAddr) -> DnsPacket {
+    let before = resp.answers.len() + resp.authorities.len() + resp.resources.len();
+
+    resp.answers.retain(|x| dns_name::is_subdomain(x.domain(), zone));
+    resp.authorities.retain(|x| dns_name::is_subdomain(x.domain(), zone));
+
+    let hosts: Vec<String> = resp.authorities.iter()
+        .chain(resp.answers.iter())
+        .filter_map(|record| match record {
+            DnsRecord::NS { host, .. } => Some(dns_name::normalize(host)),
+            _ => None,
+        })
+        .collect();
+    resp.resources.retain(|x| match x {
+        DnsRecord::OPT { .. } => true,
+        DnsRecord::A { domain, .. } | DnsRecord::AAAA { domain, .. } => {
+            dns_name::is_subdomain(domain, zone) && hosts.iter().any(|host| dns_name::eq_ignore_case(host, domain))
+        }
+        _ => dns_name::is_subdomain(x.domain(), zone),
+    });
+
+    let dropped = before - (resp.answers.len() + resp.authorities.len() + resp.resources.len());
+    if dropped > 0 {
+        warn!("Dropped {} records from {} that are outside {} or glue for no name server", dropped, server, display_name(zone));
+    }
+
+    resp
+}
+
+fn is_lame(addr: IpAddr, zone: &str) -> bool {
+    let cache = LAME.lock().unwrap_or_else(PoisonError::into_inner);
+    let now = Instant::now();
+
+    cache.iter().any(|x| x.addr == addr && x.zone == zone && x.expires > now)
+}
+
+fn mark_lame(addr: IpAddr, zone: &str) {
+    let mut cache = LAME.lock().unwrap_or_else(PoisonError::into_inner);
+    let now = Instant::now();
+
+    cache.retain(|x| x.expires > now && !(x.addr == addr && x.zone == zone));
+    // still full of live entries, this one just isn't kept
+    if cache.len() >= MAX_LAME {
+        return;
+    }
+
+    cache.push(Lame {
+        addr: addr,
+        zone: zone.to_string(),
+        expires: now + LAME_TTL,
+    });
+}
+
+/// The zone a response delegates to, if it names servers for a zone containing the name other than the current one
+/// Only a zone below the current one leads anywhere, the caller tells a loop back up from a stray referral
+fn referral_zone(resp: &DnsPacket, qname: &str, zone: &str) -> Option<String> {
+    let zones: Vec<String> = resp.authorities.iter()
+        .filter_map(|record| match record {
+            DnsRecord::NS { domain, .. } => Some(dns_name::normalize(domain)),
+            _ => None,
+        })
+        .filter(|x| dns_name::is_subdomain(qname, x) && !dns_name::eq_ignore_case(x, zone))
+        .collect();
+
+    zones.iter()
+        .find(|x| dns_name::is_subdomain(x, zone))
+        .or(zones.first())
+        .cloned()
+}
+
+fn zone_chain(chain: &[String]) -> String {
+    chain.iter().map(|x| display_name(x)).collect::<Vec<_>>().join(" -> ")
+}
+
+fn display_name(zone: &str) -> &str {
+    match zone.is_empty() {
+        true => ".",
+        false => zone,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{ Ipv4Addr, UdpSocket };
+
+    use crate::packet::DnsQuestion;
+    use crate::resolve::Resolution;
+    use crate::transport::Transport;
+
+    type Answer = Box<dyn Fn(&DnsPacket) -> DnsPacket + Send>;
+
+    /// The address of the n-th name server of a test, each test has a /24 of its own so what's lame
+    /// or cached for one doesn't carry over to another
+    fn server(net: u8, n: u8) -> Ipv4Addr {
+        Ipv4Addr::new(127, 0, net, n)
+    }
+
+    /// Name servers on server(net, 1), server(net, 2) and so on, all on one port, each answering with its function
+    /// The first is this thread's root server, each returns the names it was asked about once it's been idle a while
+    fn name_servers(net: u8, answers: Vec<Answer>) -> Vec<thread::JoinHandle<Vec<String>>> {
+        let mut port = 0;
+        let mut handles = Vec::new();
+
+        for (n, answer) in (1..).zip(answers) {
+            let socket = UdpSocket::bind((server(net, n), port)).unwrap();
+            port = socket.local_addr().unwrap().port();
+
+            handles.push(thread::spawn(move || {
+                socket.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+                let mut buf = [0; 512];
+                let mut asked = Vec::new();
+                while let Ok((size, client)) = socket.recv_from(&mut buf) {
+                    let query = DnsPacket::from_bytes(&buf[..size]).unwrap();
+                    asked.push(query.questions[0].name.clone());
+                    socket.send_to(&answer(&query).to_bytes().unwrap(), client).unwrap();
+                }
+                asked
+            }));
+        }
+
+        set_test_roots(vec![IpAddr::V4(server(net, 1))], port);
+        handles
+    }
+
+    /// A referral to the servers for a zone, one name server with its glue
+    fn referral(query: &DnsPacket, zone: &str, host: &str, glue: Ipv4Addr) -> DnsPacket {
+        DnsPacket::response_to(query)
+            .question(query.questions[0].clone())
+            .authority(DnsRecord::NS { domain: zone.to_string(), host: host.to_string(), ttl: 172800 })
+            .additional(DnsRecord::A { domain: host.to_string(), addr_v4: glue, ttl: 172800 })
+    }
+
+    /// An authoritative answer with an address for the name asked about
+    fn answer(query: &DnsPacket, addr: Ipv4Addr) -> DnsPacket {
+        DnsPacket::response_to(query)
+            .authoritative()
+            .question(query.questions[0].clone())
+            .answer(DnsRecord::A { domain: query.questions[0].name.clone(), addr_v4: addr, ttl: 300 })
+    }
+
+    /// The root, .com and example.com, each with one name server and glue for the next
+    fn example_com(net: u8) -> Vec<Answer> {
+        vec![
+            Box::new(move |x| referral(x, "com", "a.gtld-servers.net", server(net, 2))),
+            Box::new(move |x| referral(x, "example.com", "ns1.example.com", server(net, 3))),
+            Box::new(|x| answer(x, Ipv4Addr::new(192, 0, 2, 80))),
+        ]
+    }
+
+    #[test]
+    fn resolves_through_three_levels_of_delegation() {
+        let servers = name_servers(53, example_com(53));
+
+        let mut query = DnsPacket::new();
+        query.header.query_res = false;
+        query.header.rec_des = true;
+        query.questions.push(DnsQuestion::new("www.example.com".to_string(), QueryType::A));
+        let req = query.to_bytes().unwrap();
+
+        let res = resolve::handle_query_bytes(&req, IpAddr::V4(Ipv4Addr::LOCALHOST), &Resolution::Recursive, Transport::UDP).unwrap();
+        let res = DnsPacket::from_bytes(&res).unwrap();
+        assert_eq!(res.header.res_code, ResCode::NO_ERR);
+        assert!(res.header.rec_av);
+        assert_eq!(res.get_first_addr(), Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 80))));
+
+        // each level was asked once
+        for server in servers {
+            assert_eq!(server.join().unwrap().len(), 1);
+        }
+    }
+
+    #[test]
+    fn priming_response_overrides_the_hints() {
+        let mut query = DnsPacket::new();
+        query.questions.push(DnsQuestion::new(String::new(), QueryType::NS));
+        // a renumbered a.root-servers.net, a new server and one that came without glue
+        let res = DnsPacket::response_to(&query)
+            .authoritative()
+            .question(query.questions[0].clone())
+            .answer(DnsRecord::NS { domain: String::new(), host: "a.root-servers.net".to_string(), ttl: 518400 })
+            .answer(DnsRecord::NS { domain: String::new(), host: "n.root-servers.net".to_string(), ttl: 518400 })
+            .answer(DnsRecord::NS { domain: String::new(), host: "b.root-servers.net".to_string(), ttl: 518400 })
+            .additional(DnsRecord::A { domain: "a.root-servers.net".to_string(), addr_v4: Ipv4Addr::new(192, 0, 2, 4), ttl: 518400 })
+            .additional(DnsRecord::AAAA { domain: "a.root-servers.net".to_string(), addr: "2001:db8::4".parse().unwrap(), ttl: 518400 })
+            .additional(DnsRecord::A { domain: "n.root-servers.net".to_string(), addr_v4: Ipv4Addr::new(192, 0, 2, 14), ttl: 3600000 });
+
+        let (servers, ttl) = primed_servers(&res).unwrap();
+        let primed: Vec<(&str, &[IpAddr])> = servers.iter().map(|x| (x.name.as_str(), x.addrs.as_slice())).collect();
+        assert_eq!(primed, [
+            ("a.root-servers.net", &["192.0.2.4".parse().unwrap(), "2001:db8::4".parse().unwrap()][..]),
+            ("n.root-servers.net", &["192.0.2.14".parse().unwrap()][..]),
+        ]);
+        // the lowest TTL of the records used
+        assert_eq!(ttl, Duration::from_secs(518400));
+
+        let hinted = root_hints().iter().find(|x| x.name == "a.root-servers.net").unwrap();
+        assert!(!hinted.addrs.contains(&servers[0].addrs[0]));
+
+        // nothing to go on, the hints stay
+        assert!(primed_servers(&DnsPacket::response_to(&query).question(query.questions[0].clone())).is_none());
+    }
+
+    /// A root server's referral for www.example.com A as it's sent: two of the .com servers with names
+    /// compressed against the question and each other, then their A and AAAA glue
+    /// The IPv4 glue is the test's, servers 2 and 3 on loopback instead of 192.5.6.30 and 192.33.14.30
+    const COM_REFERRAL: [u8; 169] = [
+        0x9c, 0x1f, 0x80, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02, 0x00, 0x04,
+        // www.example.com A IN
+        0x03, 0x77, 0x77, 0x77, 0x07, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, 0x00, 0x01, 0x00, 0x01,
+        // com NS a.gtld-servers.net
+        0xc0, 0x18, 0x00, 0x02, 0x00, 0x01, 0x00, 0x02, 0xa3, 0x00, 0x00, 0x14,
+        0x01, 0x61, 0x0c, 0x67, 0x74, 0x6c, 0x64, 0x2d, 0x73, 0x65, 0x72, 0x76, 0x65, 0x72, 0x73, 0x03, 0x6e, 0x65, 0x74, 0x00,
+        // com NS b.gtld-servers.net
+        0xc0, 0x18, 0x00, 0x02, 0x00, 0x01, 0x00, 0x02, 0xa3, 0x00, 0x00, 0x04, 0x01, 0x62, 0xc0, 0x2f,
+        // a.gtld-servers.net A 127.0.84.2, b.gtld-servers.net A 127.0.84.3
+        0xc0, 0x2d, 0x00, 0x01, 0x00, 0x01, 0x00, 0x02, 0xa3, 0x00, 0x00, 0x04, 0x7f, 0x00, 0x54, 0x02,
+        0xc0, 0x4d, 0x00, 0x01, 0x00, 0x01, 0x00, 0x02, 0xa3, 0x00, 0x00, 0x04, 0x7f, 0x00, 0x54, 0x03,
+        // a.gtld-servers.net AAAA 2001:503:a83e::2:30, b.gtld-servers.net AAAA 2001:503:231d::2:30
+        0xc0, 0x2d, 0x00, 0x1c, 0x00, 0x01, 0x00, 0x02, 0xa3, 0x00, 0x00, 0x10,
+        0x20, 0x01, 0x05, 0x03, 0xa8, 0x3e, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x30,
+        0xc0, 0x4d, 0x00, 0x1c, 0x00, 0x01, 0x00, 0x02, 0xa3, 0x00, 0x00, 0x10,
+        0x20, 0x01, 0x05, 0x03, 0x23, 0x1d, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x30,
+    ];
+
+    #[test]
+    fn com_referral_glue_leads_to_the_com_servers() {
+        let captured = DnsPacket::from_bytes(&COM_REFERRAL).unwrap();
+        let glue: Vec<IpAddr> = captured.get_resolved_ns_all("www.example.com").collect();
+        assert_eq!(glue, [
+            IpAddr::V4(server(84, 2)),
+            IpAddr::V4(server(84, 3)),
+            "2001:503:a83e::2:30".parse().unwrap(),
+            "2001:503:231d::2:30".parse().unwrap(),
+        ]);
+
+        let servers = name_servers(84, vec![
+            Box::new(|x| {
+                let mut res = DnsPacket::from_bytes(&COM_REFERRAL).unwrap();
+                res.header.id = x.header.id;
+                res.questions = x.questions.clone();
+                res
+            }),
+            Box::new(|x| referral(x, "example.com", "ns1.example.com", server(84, 4))),
+            Box::new(|x| referral(x, "example.com", "ns1.example.com", server(84, 4))),
+            Box::new(|x| answer(x, Ipv4Addr::new(192, 0, 2, 84))),
+        ]);
+
+        let res = iterate("www.example.com", QueryType::A, &mut Walk::new()).unwrap();
+        assert_eq!(res.get_first_addr(), Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 84))));
+
+        // the first glue address answered, the second .com server was never needed
+        let asked: Vec<usize> = servers.into_iter().map(|x| x.join().unwrap().len()).collect();
+        assert_eq!(asked, [1, 1, 0, 1]);
+    }
+
+    #[test]
+    fn minimization_only_tells_each_zone_one_label_more() {
+        let servers = name_servers(86, example_com(86));
+        let mut walk = Walk::new();
+        walk.minimize = true;
+        iterate("www.example.com", QueryType::A, &mut walk).unwrap();
+
+        let asked: Vec<Vec<String>> = servers.into_iter().map(|x| x.join().unwrap()).collect();
+        assert_eq!(asked, [["com"], ["example.com"], ["www.example.com"]]);
+    }
+
+    #[test]
+    fn without_minimization_every_zone_sees_the_whole_name() {
+        let servers = name_servers(87, example_com(87));
+        let mut walk = Walk::new();
+        walk.minimize = false;
+        iterate("www.example.com", QueryType::A, &mut walk).unwrap();
+
+        let asked: Vec<Vec<String>> = servers.into_iter().map(|x| x.join().unwrap()).collect();
+        assert_eq!(asked, [["www.example.com"], ["www.example.com"], ["www.example.com"]]);
+    }
+
+    #[test]
+    fn nxdomain_for_a_shortened_name_asks_for_it_in_full() {
+        // the .com servers wrongly deny example.com exists when asked about it alone
+        let servers = name_servers(88, vec![
+            Box::new(|x| referral(x, "com", "a.gtld-servers.net", server(88, 2))),
+            Box::new(|x| match x.questions[0].name.as_str() {
+                "example.com" => DnsPacket::response_to(x).authoritative().question(x.questions[0].clone()).with_rcode(ResCode::NX_DOMAIN),
+                _ => referral(x, "example.com", "ns1.example.com", server(88, 3)),
+            }),
+            Box::new(|x| answer(x, Ipv4Addr::new(192, 0, 2, 80))),
+        ]);
+        let mut walk = Walk::new();
+        walk.minimize = true;
+        iterate("www.example.com", QueryType::A, &mut walk).unwrap();
+
+        let asked: Vec<Vec<String>> = servers.into_iter().map(|x| x.join().unwrap()).collect();
+        assert_eq!(asked, [&["com"][..], &["example.com", "www.example.com"], &["www.example.com"]]);
+    }
+
+    /// A server that refers every name it's asked about to itself as a zone of its own, a delegation without end
+    fn bottomless(net: u8) -> Vec<Answer> {
+        vec![Box::new(move |x| {
+            let zone = x.questions[0].name.clone();
+            referral(x, &zone, &format!("ns.{}", zone), server(net, 1))
+        })]
+    }
+
+    #[test]
+    fn referrals_past_the_budget_end_the_walk() {
+        let servers = name_servers(89, bottomless(89));
+        let mut walk = Walk::new();
+        walk.minimize = true;
+        walk.max_referrals = 3;
+
+        let err = iterate("a.b.c.d.e.f.g.pine-dns.com", QueryType::A, &mut walk).unwrap_err();
+        assert!(matches!(&err, DnsError::TooManyReferrals { referrals: 3, chain, .. } if chain == ". -> com -> pine-dns.com -> g.pine-dns.com -> f.g.pine-dns.com"), "{}", err);
+        // the fourth referral was one too many, nothing was asked after it
+        assert_eq!(servers.into_iter().next().unwrap().join().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn queries_past_the_budget_end_the_walk() {
+        let servers = name_servers(90, bottomless(90));
+        let mut walk = Walk::new();
+        walk.minimize = true;
+        walk.max_queries = 2;
+
+        let err = iterate("a.b.c.d.e.f.g.pine-dns.com", QueryType::A, &mut walk).unwrap_err();
+        assert!(matches!(err, DnsError::TooManyQueries { queries: 2, .. }), "{}", err);
+        assert_eq!(servers.into_iter().next().unwrap().join().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn name_servers_in_each_others_zones_are_a_loop() {
+        // example.com is served by ns.example.net and example.net by ns.example.com, neither with glue
+        let servers = name_servers(91, vec![
+            Box::new(|x| match x.questions[0].name.as_str() {
+                "com" => referral(x, "com", "a.gtld-servers.net", server(91, 2)),
+                _ => referral(x, "net", "a.gtld-servers.net", server(91, 3)),
+            }),
+            Box::new(|x| {
+                let mut res = referral(x, "example.com", "ns.example.net", server(91, 4));
+                res.resources.clear();
+                res
+            }),
+            Box::new(|x| {
+                let mut res = referral(x, "example.net", "ns.example.com", server(91, 4));
+                res.resources.clear();
+                res
+            }),
+        ]);
+        let mut walk = Walk::new();
+        walk.minimize = true;
+
+        let err = iterate("www.example.com", QueryType::A, &mut walk).unwrap_err();
+        assert!(matches!(&err, DnsError::CircularDependency { host, chain } if host == "ns.example.net" && chain == "ns.example.net -> ns.example.com"), "{}", err);
+
+        let asked: Vec<Vec<String>> = servers.into_iter().map(|x| x.join().unwrap()).collect();
+        assert_eq!(asked, [&["com", "net", "com"][..], &["example.com", "example.com"], &["example.net"]]);
+    }
+
+    #[test]
+    fn scrub_drops_what_the_zone_has_no_say_over() {
+        let mut query = DnsPacket::new();
+        query.questions.push(DnsQuestion::new("www.example.com".to_string(), QueryType::A));
+        let a = |domain: &str, last: u8| DnsRecord::A { domain: domain.to_string(), addr_v4: Ipv4Addr::new(192, 0, 2, last), ttl: 300 };
+        let res = DnsPacket::response_to(&query)
+            .authoritative()
+            .question(query.questions[0].clone())
+            .answer(a("www.example.com", 1))
+            .answer(a("www.bank.com", 66))
+            .authority(DnsRecord::NS { domain: "example.com".to_string(), host: "ns1.example.com".to_string(), ttl: 300 })
+            .authority(DnsRecord::NS { domain: "bank.com".to_string(), host: "ns1.example.com".to_string(), ttl: 300 })
+            .additional(a("ns1.example.com", 53))
+            .additional(a("ns1.bank.com", 66))
+            .additional(a("mail.example.com", 66));
+
+        let scrubbed = scrub(res, "example.com", IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(scrubbed.answers, [a("www.example.com", 1)]);
+        assert_eq!(scrubbed.authorities, [DnsRecord::NS { domain: "example.com".to_string(), host: "ns1.example.com".to_string(), ttl: 300 }]);
+        // only the glue for the zone's name server is left, not an address for another zone or one that's no glue
+        assert_eq!(scrubbed.resources, [a("ns1.example.com", 53)]);
+    }
+}
@@ -0,0 +1,150 @@
+//! Reloading the --zone files without a restart, on SIGHUP and, with --zone-poll <seconds>, whenever a file's modification time changes
+//! A file that parses replaces its zone in a single swap, so a query sees the old version or the new one and never a mix,
+//! one that doesn't leaves the old version served and logs the error with its line
+//! The journal of updates is replayed over the file as on a start, and a newer serial is announced to the --also-notify servers
+
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{ Duration, SystemTime };
+
+use log::{ error, info };
+
+use crate::json;
+use crate::secondary;
+use crate::update;
+use crate::xfr;
+use crate::zone::{ self, Zone };
+
+// The modification time of each zone file when it was last loaded, by path
+static MODIFIED: Mutex<Option<HashMap<String, SystemTime>>> = Mutex::new(None);
+
+/// Reload every zone file, whether or not it changed
+pub fn reload_all() {
+    for (origin, path) in files() {
+        reload(&origin, &path);
+    }
+}
+
+/// Check the zone files for changes every interval from a thread of its own, reloading those whose modification time moved
+pub fn watch(interval: Duration) {
+    for (_, path) in files() {
+        remember(&path);
+    }
+
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        reload_changed();
+    });
+}
+
+/// Reload the zone files whose modification time moved since they were last loaded
+fn reload_changed() {
+    for (origin, path) in files() {
+        let known = MODIFIED.lock().unwrap().as_ref().and_then(|x| x.get(&path).copied());
+        let time = modified(&path);
+        if time.is_some() && time != known {
+            reload(&origin, &path);
+        }
+    }
+}
+
+/// Load a zone file again and swap it in if it parses and differs from the zone served
+fn reload(origin: &str, path: &str) {
+    // an update applied meanwhile would be lost with the swap
+    let _applying = update::applying();
+    remember(path);
+
+    let loaded = match Zone::load(origin, path).and_then(update::replay) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Failed to reload zone {}, still serving the old version: {}", json::fqdn(origin), e);
+            return;
+        }
+    };
+
+    let current = zone::zones().into_iter().find(|x| x.origin == loaded.origin);
+    let old_serial = match &current {
+        Some(x) if x.to_master() == loaded.to_master() => {
+            info!("Reloaded zone {} from {}, unchanged at serial {}", json::fqdn(origin), path, x.serial());
+            return;
+        }
+        Some(x) => x.serial(),
+        None => return,
+    };
+
+    let serial = loaded.serial();
+    let count = loaded.record_count();
+    zone::replace(loaded);
+
+    if xfr::serial_newer(serial, old_serial) {
+        info!("Reloaded zone {} from {}, serial {} with {} records", json::fqdn(origin), path, serial, count);
+    } else {
+        info!("Reloaded zone {} from {}, {} records but serial {} isn't newer than {}, so secondaries won't see the change",
+            json::fqdn(origin), path, count, serial, old_serial);
+    }
+}
+
+/// The origin and path of every zone served from a file, secondary zones saved to --secondary-dir aside
+fn files() -> Vec<(String, String)> {
+    zone::zones().iter()
+        .filter(|x| !secondary::is_secondary(&x.origin))
+        .filter_map(|x| x.path.clone().map(|path| (x.origin.clone(), path)))
+        .collect()
+}
+
+/// Note a file's modification time as the one last loaded
+fn remember(path: &str) {
+    if let Some(time) = modified(path) {
+        MODIFIED.lock().unwrap().get_or_insert_with(HashMap::new).insert(path.to_string(), time);
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|x| x.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::net::{ IpAddr, Ipv4Addr };
+
+    use crate::packet::QueryType;
+
+    fn zone_file(www: &str) -> String {
+        format!("$TTL 300\n@ SOA ns hostmaster 1 3600 600 86400 60\n@ NS ns\nns A 192.0.2.53\nwww A {}\n", www)
+    }
+
+    fn www() -> Option<IpAddr> {
+        zone::find("www.reload.example").unwrap().answer("www.reload.example", QueryType::A).get_first_addr()
+    }
+
+    #[test]
+    fn edited_zone_file_is_reloaded() {
+        let path = std::env::temp_dir().join(format!("pine-dns-reload-{}.zone", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        fs::write(&path, zone_file("192.0.2.1")).unwrap();
+        zone::replace(Zone::load("reload.example", &path).unwrap());
+        remember(&path);
+
+        // nothing changed, nothing reloaded
+        reload_changed();
+        assert_eq!(www(), Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))));
+
+        // a later modification time than the one loaded, however coarse the file system's clock
+        fs::write(&path, zone_file("192.0.2.2")).unwrap();
+        File::options().write(true).open(&path).unwrap().set_modified(SystemTime::now() + Duration::from_secs(5)).unwrap();
+        reload_changed();
+        assert_eq!(www(), Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2))));
+
+        // one that doesn't parse leaves the last version served
+        fs::write(&path, "www A 192.0.2.3\n").unwrap();
+        File::options().write(true).open(&path).unwrap().set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        reload_changed();
+        assert_eq!(www(), Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2))));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,1673 @@
+//! Looking names up, through upstream resolvers or iteratively from the root servers
+//! Upstreams picks an upstream for each lookup by its Strategy and keeps each one's stats,
+//! lookup_addrs and forward_chain ask them, resolve_recursively walks down from the root servers instead
+//! A client's query is answered from the hosts file, local zones, blocklists and the cache before any upstream is asked
+
+pub use crate::dns64::Dns64;
+pub use crate::error::DnsError;
+pub use crate::recursive::{ load_root_hints, resolve as resolve_recursively, NameServer, RootStats };
+pub use crate::upstreams::{ Failure, Protocol, State, Strategy, Upstream, UpstreamStats, Upstreams };
+
+use std::io;
+use std::iter;
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket, SocketAddr };
+use std::sync::atomic::{ AtomicBool, AtomicU64, AtomicUsize, Ordering };
+use std::thread;
+use std::time::{ Duration, Instant };
+
+use log::{ debug, info, log_enabled, warn, Level };
+
+use crate::acl;
+use crate::amplification;
+use crate::blocklist;
+use crate::buffer_pool;
+use crate::cache;
+use crate::chaos;
+use crate::cookies::{ self, Cookie };
+use crate::dns64;
+use crate::dns_name;
+use crate::ede::{ self, Ede };
+use crate::forwarding;
+use crate::hosts;
+use crate::idna;
+use crate::notify;
+use crate::packet::{ response_to, DnsHeader, DnsPacket, DnsQuestion, DnsRecord, Opcode, PacketBuffer, QueryType, QuestionRef, ResCode, CLASS_CH, DO_BIT };
+#[cfg(feature = "tls")]
+use crate::padding;
+use crate::pcap;
+use crate::query_log::{ self, Source };
+use crate::rebinding;
+use crate::recursive;
+use crate::rrl;
+use crate::socks5;
+use crate::special;
+use crate::stats;
+use crate::transport::{ self, Transport };
+use crate::update;
+use crate::upstreams;
+use crate::views::{ self, View };
+use crate::xfr;
+use crate::zone;
+
+type Result<T> = std::result::Result<T, DnsError>;
+
+// Largest UDP response offered to upstreams in EDNS queries, the DNS Flag Day 2020 recommendation
+// that avoids IP fragmentation
+const UPSTREAM_PAYLOAD: usize = 1232;
+
+// CNAMEs followed for one name before giving up
+pub(crate) const MAX_CNAME_CHAIN: usize = 8;
+
+// Dumps malformed packets to stderr when set
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+/// Turn on verbose output such as hexdumps of malformed packets
+pub(crate) fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+pub(crate) fn is_verbose() -> bool {
+    VERBOSE.load(Ordering::Relaxed)
+}
+
+// Answers go out without authority and additional records when set
+static MINIMAL_RESPONSES: AtomicBool = AtomicBool::new(false);
+
+/// Answer clients with only the answer section, and the SOA of negative answers
+pub(crate) fn set_minimal_responses(minimal: bool) {
+    MINIMAL_RESPONSES.store(minimal, Ordering::Relaxed);
+}
+
+// How long each upstream attempt waits for a response, in milliseconds
+static LOOKUP_TIMEOUT_MS: AtomicU64 = AtomicU64::new(2000);
+// Attempts made after the first one times out
+static LOOKUP_RETRIES: AtomicUsize = AtomicUsize::new(2);
+
+#[cfg(test)]
+thread_local! {
+    static TEST_LOOKUP_TIMEOUT: std::cell::Cell<Option<Duration>> = const { std::cell::Cell::new(None) };
+}
+
+/// Set how long each upstream attempt waits before it is retried
+pub(crate) fn set_lookup_timeout(timeout: Duration) {
+    LOOKUP_TIMEOUT_MS.store(timeout.as_millis() as u64, Ordering::Relaxed);
+}
+
+/// Wait this long on each of this thread's upstream attempts, for tests that can't change it for the whole process
+#[cfg(test)]
+pub(crate) fn set_test_lookup_timeout(timeout: Duration) {
+    TEST_LOOKUP_TIMEOUT.set(Some(timeout));
+}
+
+/// Set how many times a timed out upstream query is resent before giving up
+pub(crate) fn set_lookup_retries(retries: usize) {
+    LOOKUP_RETRIES.store(retries, Ordering::Relaxed);
+}
+
+/// How long each upstream attempt waits for a response
+pub(crate) fn lookup_timeout() -> Duration {
+    #[cfg(test)]
+    if let Some(x) = TEST_LOOKUP_TIMEOUT.get() {
+        return x;
+    }
+
+    Duration::from_millis(LOOKUP_TIMEOUT_MS.load(Ordering::Relaxed))
+}
+
+/// How many times a timed out upstream query is resent
+pub(crate) fn lookup_retries() -> usize {
+    LOOKUP_RETRIES.load(Ordering::Relaxed)
+}
+
+/// Perform a lookup of a DnsQuestion from a remote nameserver
+/// Uses a given resolver, raced against a rival if there is one
+/// Returns the response and the address of the resolver that sent it
+fn lookup(id: u16, qname: &str, q_type: QueryType, dnssec: Dnssec, resolver: &Upstream, rival: Option<&Upstream>) -> Result<(DnsPacket, SocketAddr)> {
+    lookup_flagged(id, qname, q_type, true, dnssec, resolver, rival)
+}
+
+/// Ask a name server about a name without recursion desired, as an iterative resolver does
+/// The query gets a random id
+pub fn query_server(qname: &str, q_type: QueryType, server: SocketAddr) -> Result<DnsPacket> {
+    lookup_flagged(rand::random::<u16>(), qname, q_type, false, Dnssec::default(), &Upstream::udp(server), None).map(|(res, _)| res)
+}
+
+/// The client's DNSSEC bits go along, AD asking the upstream to say whether it validated the answer
+fn lookup_flagged(id: u16, qname: &str, q_type: QueryType, rec_des: bool, dnssec: Dnssec, resolver: &Upstream, rival: Option<&Upstream>) -> Result<(DnsPacket, SocketAddr)> {
+    let question = DnsQuestion::new(qname.to_string(), q_type);
+
+    let (mut res, answered_by) = exchange_edns(resolver, rival, |edns| {
+        let mut pak = DnsPacket::new();
+
+        pak.header.id = id;
+        pak.header.query_res = false;
+        pak.header.rec_des = rec_des;
+        pak.header.auth_data = dnssec.wants_ad();
+        pak.header.checking_disabled = dnssec.checking_disabled;
+        pak.questions.push(question.clone());
+        if edns {
+            pak.resources.push(opt_record(dnssec.dnssec_ok));
+        }
+
+        // Buffers come from the pool; on errors they are dropped instead of returned
+        let mut req_buf = buffer_pool::acquire();
+        pak.write(&mut req_buf)?;
+
+        Ok(req_buf)
+    })?;
+
+    // Responses without a question section (some errors) are let through
+    if let Some(ques) = res.questions.first() {
+        if *ques != question {
+            return Err(DnsError::MismatchedResponse(format!("Upstream answered {} instead of {}", ques.name, qname)));
+        }
+    }
+    scrub_answers(&mut res, qname, answered_by);
+
+    Ok((res, answered_by))
+}
+
+/// Perform a lookup of a borrowed question from a remote nameserver
+/// The question bytes are copied into the query as they are
+fn lookup_question(id: u16, question: &QuestionRef, dnssec: Dnssec, resolver: &Upstream, rival: Option<&Upstream>) -> Result<(DnsPacket, SocketAddr)> {
+    let (mut res, answered_by) = exchange_edns(resolver, rival, |edns| {
+        let mut header = DnsHeader::new();
+
+        header.id = id;
+        header.query_res = false;
+        header.rec_des = true;
+        header.auth_data = dnssec.wants_ad();
+        header.checking_disabled = dnssec.checking_disabled;
+        header.ques_count = 1;
+        header.res_count = edns as u16;
+
+        let mut req_buf = buffer_pool::acquire();
+        header.write(&mut req_buf)?;
+        req_buf.write_bytes(question.as_bytes())?;
+        if edns {
+            opt_record(dnssec.dnssec_ok).write(&mut req_buf)?;
+        }
+
+        Ok(req_buf)
+    })?;
+
+    if let Some(ques) = res.questions.first() {
+        if ques.q_type != question.q_type || !question.name_matches(&ques.name) {
+            return Err(DnsError::MismatchedResponse(format!("Upstream answered {} instead of {}", ques.name, question)));
+        }
+    }
+    scrub_answers(&mut res, &question.name(), answered_by);
+
+    Ok((res, answered_by))
+}
+
+/// The OPT record sent with upstream queries, offering UDP responses of up to UPSTREAM_PAYLOAD bytes
+/// and asking for DNSSEC records with dnssec_ok
+fn opt_record(dnssec_ok: bool) -> DnsRecord {
+    DnsRecord::OPT {
+        payload_size: UPSTREAM_PAYLOAD as u16,
+        ext_rcode: 0,
+        version: 0,
+        flags: if dnssec_ok { DO_BIT } else { 0 },
+        data: Vec::new(),
+    }
+}
+
+/// Send a query made by write_query with EDNS, retrying once without it when the resolver
+/// answers FORMERR or NOTIMP or doesn't answer at all, RFC 6891 section 7
+/// Resolvers that only answer without EDNS are remembered so for a while their lookups skip it
+/// The OPT record is removed from the response, clients only get the answer
+fn exchange_edns(resolver: &Upstream, rival: Option<&Upstream>, write_query: impl Fn(bool) -> Result<PacketBuffer>) -> Result<(DnsPacket, SocketAddr)> {
+    let attempts = lookup_retries() + 1;
+    let edns = upstreams::edns_enabled(resolver.addr) && rival.is_none_or(|x| upstreams::edns_enabled(x.addr));
+
+    let (mut res, answered_by) = if !edns {
+        exchange(write_query(false)?, resolver, rival, attempts)?
+    } else {
+        match exchange(write_query(true)?, resolver, rival, attempts) {
+            Ok((res, addr)) if !rejects_edns(&res) => (res, addr),
+            rejected => {
+                let reason = match rejected {
+                    Ok((res, addr)) => format!("{} answered {}", addr, res.header.res_code),
+                    Err(e) => e.to_string(),
+                };
+                warn!("EDNS query for {} failed ({}), retrying without EDNS", targets(resolver, rival), reason);
+
+                // a single attempt, a resolver that drops EDNS queries has already cost every retry
+                let (res, addr) = exchange(write_query(false)?, resolver, rival, 1)?;
+                if !rejects_edns(&res) {
+                    info!("{} only answers without EDNS, leaving it out for a while", addr);
+                    upstreams::disable_edns(addr);
+                }
+
+                (res, addr)
+            }
+        }
+    };
+
+    // the Extended DNS Errors in it are kept to pass on
+    for record in &res.resources {
+        if let DnsRecord::OPT { data, .. } = record {
+            res.extended_errors = ede::parse(data);
+        }
+    }
+    for error in &res.extended_errors {
+        warn!("{} answered {} with the extended error {}", answered_by, res.header.res_code, error);
+    }
+    res.resources.retain(|x| x.query_type() != QueryType::OPT);
+
+    Ok((res, answered_by))
+}
+
+/// Whether a response to an EDNS query says the resolver didn't understand it
+fn rejects_edns(res: &DnsPacket) -> bool {
+    matches!(res.header.res_code, ResCode::FORM_ERR | ResCode::NOT_IMP)
+}
+
+/// Forward a parsed question to an upstream, blocking until it answers or fails
+/// For callers that pick the upstream themselves
+#[cfg(feature = "async")]
+pub(crate) fn forward_lookup(id: u16, question: &DnsQuestion, resolver: &Upstream) -> Result<DnsPacket> {
+    lookup(id, &question.name, question.q_type, Dnssec::default(), resolver, None).map(|(res, _)| res)
+}
+
+/// Where the CNAME chain from qname in an answer section ends when it stops short of records of the asked type
+/// None when there's nothing to chase: no CNAME for qname, the records are there, or CNAME (or ANY) was asked for
+/// Fails on a loop, ex. a to b and back to a, or a chain longer than MAX_CNAME_CHAIN
+pub(crate) fn cname_target(answers: &[DnsRecord], qname: &str, q_type: QueryType) -> Result<Option<String>> {
+    if q_type == QueryType::CNAME || q_type == QueryType::UNKNOWN(255) {
+        return Ok(None);
+    }
+
+    let mut chain = vec![dns_name::normalize(qname)];
+    while let Some(host) = answers.iter().find_map(|x| match x {
+        DnsRecord::CNAME { domain, host, .. } if dns_name::eq_ignore_case(domain, &chain[chain.len() - 1]) => Some(dns_name::normalize(host)),
+        _ => None,
+    }) {
+        if chain.contains(&host) {
+            return Err(DnsError::CnameLoop { from: chain[chain.len() - 1].clone(), to: host });
+        }
+        if chain.len() > MAX_CNAME_CHAIN {
+            return Err(DnsError::CnameChainTooLong { qname: qname.to_string(), max: MAX_CNAME_CHAIN });
+        }
+        chain.push(host);
+    }
+
+    let target = chain.pop().unwrap_or_default();
+    let answered = answers.iter().any(|x| x.query_type() == q_type && dns_name::eq_ignore_case(x.domain(), &target));
+
+    match chain.is_empty() || answered {
+        true => Ok(None),
+        false => Ok(Some(target)),
+    }
+}
+
+/// Complete a response whose CNAME chain stops short of records of the asked type by resolving the rest,
+/// appending each further link and finally the records to the answer section in order
+/// The response takes the code and authorities of the last lookup, so a chain to a missing name is NXDOMAIN
+pub(crate) fn chase_cnames(mut res: DnsPacket, qname: &str, q_type: QueryType, mut resolve: impl FnMut(&str) -> Result<DnsPacket>) -> Result<DnsPacket> {
+    let mut asked = dns_name::normalize(qname);
+
+    while let Some(target) = cname_target(&res.answers, qname, q_type)? {
+        // the last lookup didn't get any further, the name simply has no such records
+        if target == asked || res.header.res_code != ResCode::NO_ERR {
+            break;
+        }
+
+        debug!("Following the CNAME chain from {} to {} {}", idna::to_unicode(qname), idna::to_unicode(&target), q_type);
+        let next = resolve(&target)?;
+
+        res.answers.extend(next.answers);
+        res.authorities = next.authorities;
+        res.header.res_code = next.header.res_code;
+        // the chain is only validated if every link of it is
+        res.header.auth_data &= next.header.auth_data;
+        asked = target;
+    }
+
+    Ok(res)
+}
+
+/// An address a name resolved to, with the TTL it may be kept for
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct AddrRecord {
+    pub addr: IpAddr,
+    pub ttl: u32, // the lowest TTL along the CNAME chain leading to the address
+}
+
+/// Look up the IPv4 and IPv6 addresses of a name through the upstreams, following CNAMEs to the final name
+/// The A and AAAA lookups run in parallel so both families cost one round trip
+/// A family the name has no addresses in is left out, it is only an error when both lookups fail
+pub fn lookup_addrs(name: &str, upstreams: &Upstreams) -> Result<Vec<AddrRecord>> {
+    let (v4, v6) = thread::scope(|scope| {
+        let v6 = scope.spawn(|| lookup_family(name, QueryType::AAAA, upstreams));
+        let v4 = lookup_family(name, QueryType::A, upstreams);
+
+        (v4, v6.join().unwrap_or_else(|_| Err(DnsError::TaskFailed("AAAA lookup panicked".to_string()))))
+    });
+
+    match (v4, v6) {
+        (Err(v4), Err(v6)) => Err(DnsError::AddressLookup { name: name.to_string(), v4: Box::new(v4), v6: Box::new(v6) }),
+        (v4, v6) => Ok(v4.unwrap_or_default().into_iter().chain(v6.unwrap_or_default()).collect()),
+    }
+}
+
+/// Look up the addresses of one family, A or AAAA, following CNAMEs
+/// The chain is followed through the answer section and asked for again where the upstream stopped
+fn lookup_family(name: &str, q_type: QueryType, upstreams: &Upstreams) -> Result<Vec<AddrRecord>> {
+    let mut target = name.to_string();
+    let mut chain_ttl = u32::MAX;
+    let mut followed = 0;
+
+    loop {
+        let res = upstreams.query(|resolver, rival| lookup(rand::random::<u16>(), &target, q_type, Dnssec::default(), resolver, rival))?;
+
+        match res.header.res_code {
+            ResCode::NO_ERR => {}
+            ResCode::NX_DOMAIN => return Ok(Vec::new()),
+            x => return Err(DnsError::UpstreamRefused(x)),
+        }
+
+        let asked = target.clone();
+        while let Some((_, host, ttl)) = res.cname_records().find(|(domain, _, _)| dns_name::eq_ignore_case(domain, &target)) {
+            followed += 1;
+            if followed > MAX_CNAME_CHAIN {
+                return Err(DnsError::CnameChainTooLong { qname: name.to_string(), max: MAX_CNAME_CHAIN });
+            }
+
+            target = host.to_string();
+            chain_ttl = chain_ttl.min(ttl);
+        }
+
+        let addrs: Vec<AddrRecord> = res.addr_records()
+            .filter(|(domain, addr, _)| dns_name::eq_ignore_case(domain, &target) && addr.is_ipv4() == (q_type == QueryType::A))
+            .map(|(_, addr, ttl)| AddrRecord { addr, ttl: ttl.min(chain_ttl) })
+            .collect();
+
+        // no progress through a CNAME means the name simply has no addresses of this family
+        if !addrs.is_empty() || dns_name::eq_ignore_case(&asked, &target) {
+            return Ok(addrs);
+        }
+    }
+}
+
+/// Resolve a hostname to an address through a plain DNS resolver, IPv4 when it has one
+/// Used at startup for upstreams given by name, which can't be resolved through themselves
+#[cfg(feature = "tls")]
+pub(crate) fn bootstrap(host: &str, resolver: SocketAddr) -> Result<IpAddr> {
+    let upstreams = Upstreams::new(vec![Upstream::udp(resolver)], upstreams::Strategy::SEQUENTIAL);
+    let addrs = lookup_addrs(host, &upstreams)?;
+
+    addrs.iter()
+        .find(|x| x.addr.is_ipv4())
+        .or_else(|| addrs.first())
+        .map(|x| x.addr)
+        .ok_or_else(|| DnsError::NoAddress { host: host.to_string(), resolver: resolver.to_string() })
+}
+
+/// Send a written query buffer to a remote nameserver over its protocol and parse its response
+/// With a rival both are raced over UDP and the first valid response wins
+/// UDP queries are sent up to attempts times, the stream transports retry on their own
+fn exchange(req_buf: PacketBuffer, resolver: &Upstream, rival: Option<&Upstream>, attempts: usize) -> Result<(DnsPacket, SocketAddr)> {
+    match (&resolver.protocol, rival) {
+        (Protocol::UDP, Some(Upstream { addr: rival, protocol: Protocol::UDP })) => exchange_udp(req_buf, &[resolver.addr, *rival], attempts),
+        // only plain UDP upstreams are raced, anything else goes to the first resolver alone
+        _ => exchange_one(req_buf, resolver, attempts).map(|res| (res, resolver.addr)),
+    }
+}
+
+/// Send a written query buffer to one remote nameserver and parse its response
+fn exchange_one(req_buf: PacketBuffer, resolver: &Upstream, attempts: usize) -> Result<DnsPacket> {
+    match &resolver.protocol {
+        Protocol::UDP => exchange_udp(req_buf, &[resolver.addr], attempts).map(|(res, _)| res),
+        #[cfg(feature = "tls")]
+        Protocol::HTTPS(x) => {
+            let bytes = x.exchange(&padding::pad_query(&req_buf.buf[0..req_buf.pos]), lookup_timeout())?;
+            buffer_pool::release(req_buf, 0);
+
+            DnsPacket::from_bytes(&bytes)
+        }
+        #[cfg(feature = "tls")]
+        Protocol::TLS(x) => {
+            let bytes = x.exchange(&padding::pad_query(&req_buf.buf[0..req_buf.pos]), lookup_timeout())?;
+            buffer_pool::release(req_buf, 0);
+
+            DnsPacket::from_bytes(&bytes)
+        }
+    }
+}
+
+/// Send a written query buffer over UDP to every given nameserver at once and parse the first
+/// valid response, returning it with the address it came from
+/// The query is resent each time an attempt times out, failing after the given number of attempts
+/// Packets from anywhere else or that don't answer the query are dropped and the wait goes on,
+/// so neither a spoofed nor a late response for an earlier query can take the place of the real one
+/// A nameserver the query can't be sent to is left out of the attempt, it fails only if none could be sent to
+/// Truncated responses are fetched again over TCP
+/// With a SOCKS5 proxy the datagrams are relayed by it, or if it can't relay UDP the query goes to
+/// the first resolver over TCP through it instead
+fn exchange_udp(req_buf: PacketBuffer, resolvers: &[SocketAddr], attempts: usize) -> Result<(DnsPacket, SocketAddr)> {
+    let query_bytes = &req_buf.buf[0..req_buf.pos];
+    let query = DnsPacket::from_bytes(query_bytes)?;
+    let names = resolvers.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(" or ");
+
+    // an ephemeral port per lookup, picked at random by the OS so overlapping lookups don't collide
+    // and responses can't be spoofed to a known port, on the unspecified address of the resolvers' family
+    // so IPv6 upstreams and upstreams off this host work too, a race between an IPv4 and an IPv6 resolver
+    // goes out on a dual-stack socket with the IPv4 one as its v4-mapped address
+    // the socket is closed once a response is accepted, so a race's loser never reaches a later lookup
+    if resolvers.is_empty() {
+        return Err(DnsError::NoResolver);
+    }
+    let dual_stack = resolvers.iter().any(|x| x.is_ipv4()) && resolvers.iter().any(|x| x.is_ipv6());
+    let local = match resolvers[0] {
+        SocketAddr::V4(_) if !dual_stack => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        _ => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let udp_socket = match socks5::proxy() {
+        None => LookupSocket::DIRECT(UdpSocket::bind(local)?),
+        Some(proxy) => match proxy.associate(lookup_timeout())? {
+            Some(relay) => LookupSocket::PROXIED(relay),
+            None => {
+                let resolver = resolvers[0];
+                return exchange_tcp(req_buf, &query, resolver).map(|res| (res, resolver));
+            }
+        },
+    };
+
+    // the port the lookup went out on, for the capture
+    let ours = udp_socket.local_addr().unwrap_or(local);
+    // the proxy relays to either family as the addresses are given
+    let targets: Vec<SocketAddr> = match udp_socket {
+        LookupSocket::DIRECT(_) if dual_stack => resolvers.iter().map(|x| v4_mapped(*x)).collect(),
+        _ => resolvers.to_vec(),
+    };
+
+    // responses may be as large as the OPT record offers
+    let mut res_buf = [0; UPSTREAM_PAYLOAD];
+    let mut received = None;
+
+    'attempts: for attempt in 1..=attempts {
+        if attempt > 1 {
+            stats::count_retry();
+        }
+        let mut sent = 0;
+        let mut failure = None;
+        for (resolver, target) in resolvers.iter().zip(&targets) {
+            match udp_socket.send_to(query_bytes, target) {
+                Ok(_) => {
+                    sent += 1;
+                    pcap::upstream(ours, *resolver, query_bytes);
+                }
+                Err(e) => {
+                    warn!("Couldn't send the query to {}: {}", resolver, e);
+                    failure = Some(e);
+                }
+            }
+        }
+        if let (0, Some(e)) = (sent, failure) {
+            return Err(e.into());
+        }
+
+        let deadline = Instant::now() + lookup_timeout();
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                warn!("No response from {} within {:?} (attempt {} of {})", names, lookup_timeout(), attempt, attempts);
+                stats::count_timeout();
+                continue 'attempts;
+            }
+            udp_socket.set_read_timeout(Some(left))?;
+
+            let (size, source) = match udp_socket.recv_from(&mut res_buf) {
+                Ok(x) => x,
+                // timeouts show up as WouldBlock on unix and TimedOut on windows
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => return Err(e.into()),
+            };
+            // as the resolver was given, not as the dual-stack socket saw it
+            let source = SocketAddr::new(source.ip().to_canonical(), source.port());
+            if !resolvers.contains(&source) {
+                warn!("Dropping a response from {}, the query went to {}", source, names);
+                continue;
+            }
+            pcap::upstream(source, ours, &res_buf[..size]);
+
+            // bounded to what arrived, the buffer still holds earlier packets past it
+            let res = match DnsPacket::from_bytes(&res_buf[..size]) {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("Dropping a malformed response from {}: {}", source, e);
+                    if is_verbose() {
+                        warn!("{}", PacketBuffer::from_bytes(&res_buf[..size]).hexdump(size));
+                    }
+                    continue;
+                }
+            };
+            if let Err(e) = check_response(&res, &query) {
+                warn!("Dropping a response from {}: {}", source, e);
+                if is_verbose() {
+                    warn!("{}", res);
+                }
+                continue;
+            }
+
+            received = Some((res, source));
+            break 'attempts;
+        }
+    }
+
+    let (res, source) = match received {
+        Some(x) => x,
+        None => return Err(DnsError::UpstreamTimeout { upstream: names, attempts: attempts }),
+    };
+
+    // The answer didn't fit in a UDP packet, ask again over TCP for all of it
+    if res.header.trunc {
+        debug!("Truncated response from {}, retrying over TCP", source);
+
+        return exchange_tcp(req_buf, &query, source).map(|res| (res, source));
+    }
+    buffer_pool::release(req_buf, 0);
+
+    Ok((res, source))
+}
+
+/// An address as a dual-stack IPv6 socket reaches it, IPv4 addresses as v4-mapped IPv6 ones
+fn v4_mapped(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(x) => SocketAddr::from((x.ip().to_ipv6_mapped(), x.port())),
+        SocketAddr::V6(_) => addr,
+    }
+}
+
+/// Send a written query buffer to a nameserver over TCP and parse its response, checked as the UDP ones are
+/// The buffer goes back to the pool before the exchange, which can fail
+fn exchange_tcp(req_buf: PacketBuffer, query: &DnsPacket, server: SocketAddr) -> Result<DnsPacket> {
+    let query_bytes = req_buf.buf[0..req_buf.pos].to_vec();
+    buffer_pool::release(req_buf, 0);
+
+    let res = DnsPacket::from_bytes(&transport::exchange_tcp(&query_bytes, &server, lookup_timeout())?)?;
+    check_response(&res, query)?;
+
+    Ok(res)
+}
+
+/// The socket a UDP lookup goes out on, straight to the resolvers or relayed by the SOCKS5 proxy
+#[allow(clippy::upper_case_acronyms)]
+enum LookupSocket {
+    DIRECT(UdpSocket),
+    PROXIED(socks5::UdpRelay),
+}
+
+impl LookupSocket {
+    fn send_to(&self, buf: &[u8], target: &SocketAddr) -> io::Result<usize> {
+        match self {
+            LookupSocket::DIRECT(x) => x.send_to(buf, target),
+            LookupSocket::PROXIED(x) => x.send_to(buf, target),
+        }
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self {
+            LookupSocket::DIRECT(x) => x.recv_from(buf),
+            LookupSocket::PROXIED(x) => x.recv_from(buf),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            LookupSocket::DIRECT(x) => x.set_read_timeout(timeout),
+            LookupSocket::PROXIED(x) => x.set_read_timeout(timeout),
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            LookupSocket::DIRECT(x) => x.local_addr(),
+            LookupSocket::PROXIED(x) => x.local_addr(),
+        }
+    }
+}
+
+/// Send a message to a server over UDP and parse the response that answers it,
+/// resending it each time an attempt times out
+pub(crate) fn exchange_message(message: &mut DnsPacket, server: SocketAddr, attempts: usize) -> Result<DnsPacket> {
+    let mut req_buf = buffer_pool::acquire();
+    message.write(&mut req_buf)?;
+
+    exchange_udp(req_buf, &[server], attempts).map(|(res, _)| res)
+}
+
+/// Check that a response answers a query: QR set, the same id and opcode and the same question
+/// Responses without a question section (some errors) are let through
+pub(crate) fn check_response(res: &DnsPacket, query: &DnsPacket) -> Result<()> {
+    if !res.header.query_res {
+        return Err(DnsError::MismatchedResponse("Not a response, QR is unset".to_string()));
+    }
+    if res.header.id != query.header.id {
+        return Err(DnsError::MismatchedResponse(format!("Id {} doesn't match the query's {}", res.header.id, query.header.id)));
+    }
+    if res.header.opcode != query.header.opcode {
+        return Err(DnsError::MismatchedResponse(format!("Opcode {} doesn't match the query's {}", res.header.opcode, query.header.opcode)));
+    }
+
+    match (res.questions.first(), query.questions.first()) {
+        (Some(x), Some(asked)) if x != asked => Err(DnsError::MismatchedResponse(format!("Answers {} {} instead of {} {}", x.name, x.q_type, asked.name, asked.q_type))),
+        _ => Ok(()),
+    }
+}
+
+/// Drop answers for names the question doesn't lead to, ex. an A record for some other domain slipped in
+/// by a malicious server, only records for the question's name and the names of its CNAME chain are kept
+pub(crate) fn scrub_answers(res: &mut DnsPacket, qname: &str, from: SocketAddr) {
+    let mut names = vec![dns_name::normalize(qname)];
+    while let Some((_, host, _)) = res.cname_records()
+        .find(|(domain, host, _)| names.iter().any(|x| dns_name::eq_ignore_case(x, domain)) && !names.iter().any(|x| dns_name::eq_ignore_case(x, host)))
+    {
+        names.push(dns_name::normalize(host));
+    }
+
+    let before = res.answers.len();
+    res.answers.retain(|x| names.iter().any(|name| dns_name::eq_ignore_case(name, x.domain())));
+    if res.answers.len() < before {
+        warn!("Dropped {} answers from {} for names {} doesn't lead to", before - res.answers.len(), from, qname);
+    }
+}
+
+// Types of the DNSSEC records left out of answers to clients without DO
+pub(crate) const TYPE_RRSIG: u16 = 46;
+const TYPE_NSEC: u16 = 47;
+const TYPE_NSEC3: u16 = 50;
+
+/// The DNSSEC bits of a client's query, DO from its OPT record and AD and CD from its header
+/// Upstream queries carry them on, and the response gets AD only if the upstream set it and the client understands it
+#[derive(Copy, Clone, Debug, Default)]
+pub(crate) struct Dnssec {
+    pub dnssec_ok: bool,         // wants RRSIG, NSEC and NSEC3 records (RFC 3225)
+    pub auth_data: bool,         // understands AD without asking for DNSSEC records (RFC 6840 section 5.7)
+    pub checking_disabled: bool, // wants the answer even if it fails validation, never cached for others
+}
+
+impl Dnssec {
+    /// The bits of a request from its header and bytes
+    pub fn of(req_header: &DnsHeader, req: &[u8]) -> Dnssec {
+        Dnssec {
+            dnssec_ok: DnsPacket::peek_opt(req).is_some_and(|(ttl, _)| ttl & DO_BIT as u32 != 0),
+            auth_data: req_header.auth_data,
+            checking_disabled: req_header.checking_disabled,
+        }
+    }
+
+    /// Whether the client may be told an answer was validated
+    pub fn wants_ad(&self) -> bool {
+        self.dnssec_ok || self.auth_data
+    }
+
+    /// Finish a response for the client: DNSSEC records are left out unless it set DO or asked for their type,
+    /// and DO is echoed in the OPT record if there is one
+    fn apply(&self, response: &mut DnsPacket, q_types: &[QueryType]) {
+        if !self.dnssec_ok {
+            let keep = |x: &DnsRecord| !is_dnssec(x.query_type()) || q_types.contains(&x.query_type());
+            response.answers.retain(keep);
+            response.authorities.retain(keep);
+            response.resources.retain(keep);
+        }
+
+        for record in &mut response.resources {
+            if let DnsRecord::OPT { flags, .. } = record {
+                *flags = if self.dnssec_ok { *flags | DO_BIT } else { *flags & !DO_BIT };
+            }
+        }
+    }
+}
+
+/// Whether a type is one of the records DNSSEC adds to answers, RRSIG, NSEC or NSEC3, only sent to clients setting DO
+fn is_dnssec(q_type: QueryType) -> bool {
+    matches!(q_type.to_u16(), TYPE_RRSIG | TYPE_NSEC | TYPE_NSEC3)
+}
+
+/// How queries are answered
+/// Names covered by a forwarding rule go to the rule's upstreams either way
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum Resolution {
+    /// Forward to one of the configured resolvers (ip and port, v4 or v6)
+    Forward(&'static Upstreams),
+    /// Recursively resolve from the root name servers
+    Recursive,
+}
+
+/// Whether a question is answered here rather than upstream, a CHAOS question, from the hosts file, for a name in a local zone,
+/// a blocked name, a special-use name without a --forward rule or a zone transfer
+/// The servers that forward on their own send everything else upstream, and these to handle_query_sized
+pub(crate) fn is_local(question: &DnsQuestion) -> bool {
+    question.class == CLASS_CH
+        || hosts::is_local(&question.name, question.q_type)
+        || zone::find(&question.name).is_some()
+        || blocklist::find(&question.name).is_some()
+        || (special::find(&question.name).is_some() && forwarding::find(&question.name).is_none())
+        || xfr::is_transfer(question.q_type)
+}
+
+/// Answer a packet received on a UDP socket, sending the response back to its source
+/// Responses that don't fit in 512 bytes are truncated with TC set so the client retries over TCP, and --rrl and --max-amplification may truncate more
+pub(crate) fn handle_packet(udp_socket: &UdpSocket, mut req_buf: PacketBuffer, size: usize, source: SocketAddr, resolution: &Resolution) -> Result<()> {
+    let mut res_buf = buffer_pool::acquire();
+    handle_request(&mut req_buf, size, &mut res_buf, source.ip(), resolution, Transport::UDP)?;
+
+    let len = res_buf.pos();
+    if let Some(sent) = rrl::limit(source.ip(), &mut res_buf.buf[..len]) {
+        let sent = amplification::limit(&req_buf.buf[..size], source.ip(), &mut res_buf.buf[..sent]);
+        udp_socket.send_to(res_buf.get_range(0, sent)?, source)?;
+    }
+    buffer_pool::release(req_buf, size);
+    buffer_pool::release(res_buf, len);
+
+    Ok(())
+}
+
+/// Handle a query from a client given as raw bytes, returning the raw response
+/// Used by stream transports where responses may be up to 65535 bytes
+pub(crate) fn handle_query_bytes(req: &[u8], client: IpAddr, resolution: &Resolution, transport: Transport) -> Result<Vec<u8>> {
+    handle_query_sized(req, transport::MAX_TCP_MESSAGE, client, resolution, transport)
+}
+
+/// Handle a query from a client given as raw bytes, truncating the response if it exceeds max_size
+/// Only a query over UDP is refused for its cookie
+/// Both buffers come from the pool, on errors they are dropped instead of returned
+pub(crate) fn handle_query_sized(req: &[u8], max_size: usize, client: IpAddr, resolution: &Resolution, transport: Transport) -> Result<Vec<u8>> {
+    let mut req_buf = buffer_pool::acquire_with(req);
+    let mut res_buf = buffer_pool::acquire_sized(max_size);
+
+    handle_request(&mut req_buf, req.len(), &mut res_buf, client, resolution, transport)?;
+
+    let len = res_buf.pos();
+    let bytes = res_buf.buf[..len].to_vec();
+    buffer_pool::release(req_buf, req.len());
+    buffer_pool::release(res_buf, len);
+
+    Ok(bytes)
+}
+
+/// Parse a request from a client, resolve it and write the response to res_buf
+/// The size of res_buf is the most the response may take up, transport is what the request came over
+pub(crate) fn handle_request(req_buf: &mut PacketBuffer, size: usize, res_buf: &mut PacketBuffer, client: IpAddr, resolution: &Resolution, transport: Transport) -> Result<()> {
+    let started = Instant::now();
+    query_log::take_note();
+    pcap::query(client, &req_buf.buf[..size]);
+    answer_request(req_buf, size, res_buf, client, resolution, transport == Transport::UDP)?;
+    pcap::response(client, &res_buf.buf[..res_buf.pos]);
+    log_answered(client, transport, &res_buf.buf[..res_buf.pos], started);
+
+    Ok(())
+}
+
+/// Log a query once its response is written, with the client, question, rcode, where the answer came from
+/// and microseconds it took as fields, and add it to the --query-log
+/// Nothing is read from the response unless debug messages are logged or there's a query log
+pub(crate) fn log_answered(client: IpAddr, transport: Transport, res: &[u8], started: Instant) {
+    let (source, upstream) = query_log::take_note();
+    if !(log_enabled!(Level::Debug) || query_log::is_enabled()) || res.len() < 12 {
+        return;
+    }
+
+    let res_buf = PacketBuffer::from_bytes(res);
+    let (qname, q_type) = match DnsPacket::peek_question(&res_buf) {
+        Ok(x) => (x.to_string(), x.q_type.to_string()),
+        Err(_) => ("?".to_string(), "?".to_string()),
+    };
+    let res_code = DnsPacket::peek_res_code(res);
+    let duration = started.elapsed();
+
+    debug!(
+        client:% = client, transport = transport.name(), qname = qname.as_str(), qtype = q_type.as_str(), rcode:? = res_code,
+        source = source.name(), duration_us = duration.as_micros() as u64;
+        "Answered query"
+    );
+
+    query_log::record(&query_log::Entry {
+        client: client,
+        transport: transport,
+        qname: &qname,
+        q_type: &q_type,
+        res_code: res_code,
+        answers: u16::from_be_bytes([res[6], res[7]]),
+        source: source,
+        upstream: upstream,
+        duration: duration,
+    });
+}
+
+/// Answer a request for handle_request, udp says whether it came over UDP
+fn answer_request(req_buf: &mut PacketBuffer, size: usize, res_buf: &mut PacketBuffer, client: IpAddr, resolution: &Resolution, udp: bool) -> Result<()> {
+    let mut req_header = DnsHeader::new();
+    req_header.read(req_buf)?;
+
+    // the records of an UPDATE's sections may have no data, so it's read section by section instead of as a packet
+    if req_header.opcode == Opcode::UPDATE {
+        let (mut response, signer) = update::answer(&req_header, &req_buf.buf[..size], client);
+        write_response(&mut response, res_buf, None)?;
+
+        if let Some(mut signer) = signer {
+            let signed = signer.sign(&res_buf.buf[..res_buf.pos]);
+            if signed.len() > res_buf.buf.len() {
+                return Err(DnsError::BufferOverrun { pos: res_buf.pos, needed: signed.len() - res_buf.buf.len() });
+            }
+            res_buf.buf[..signed.len()].copy_from_slice(&signed);
+            res_buf.pos = signed.len();
+        }
+
+        return Ok(());
+    }
+
+    let view = views::find(client);
+    let dnssec = Dnssec::of(&req_header, &req_buf.buf[..size]);
+    let edns = DnsPacket::peek_opt(&req_buf.buf[..size]).is_some();
+
+    let cookie = cookies::check(&req_buf.buf[..size], client);
+    if cookie == Cookie::MALFORMED {
+        warn!("Malformed query: a COOKIE option of the wrong length from {}", client);
+        let mut response = response_to(&req_header);
+        response.header.res_code = ResCode::FORM_ERR;
+
+        return write_response(&mut response, res_buf, None);
+    }
+    if udp && cookies::is_required() && !matches!(cookie, Cookie::VALID(_)) {
+        return refuse_cookie(&req_header, req_buf, res_buf, client, cookie);
+    }
+
+    // the OPT record of the response carries the client's cookie and the extended errors, DO says what DNSSEC records go in
+    let finish = |response: &mut DnsPacket, q_types: &[QueryType]| {
+        response.resources.extend(cookies::opt_record(cookie, client));
+        ede::attach(response, edns);
+        dnssec.apply(response, q_types);
+    };
+
+    // Fast path for the common single question query, forwarded without parsing the question
+    // clients in a view of their own take the slow one, which knows its zones and upstreams
+    if req_header.opcode == Opcode::QUERY && view.is_none() {
+        if let Ok(question) = DnsPacket::peek_question(req_buf) {
+            // Some(None) for a question resolved recursively, left to the slow path unless it's refused
+            let upstreams = match (zone::find_question(&question), forwarding::find_question(&question), resolution) {
+                // CHAOS questions, the hosts file, local zones, blocked and special-use names and zone transfers are answered from the parsed question
+                _ if question.class == CLASS_CH => None,
+                _ if xfr::is_transfer(question.q_type) => None,
+                _ if hosts::is_local_question(&question) => None,
+                _ if blocklist::is_blocked_question(&question) => None,
+                (Some(_), _, _) => None,
+                (None, Some(rule), _) => Some(Some(&rule.upstreams)),
+                (None, None, _) if special::find_question(&question).is_some() => None,
+                (None, None, Resolution::Forward(x)) => Some(Some(*x)),
+                (None, None, Resolution::Recursive) => Some(None),
+            };
+
+            match upstreams {
+                Some(_) if !acl::is_allowed(client) => {
+                    debug!("Received query: {} {}, refused, recursion isn't allowed for {}", question, question.q_type, client);
+                    query_log::note(Source::REFUSED, None);
+                    stats::count_refused_recursion();
+
+                    let mut response = response_to(&req_header);
+                    response.header.res_code = ResCode::REFUSED;
+                    response.extended_errors.push(Ede::new(ede::PROHIBITED, "recursion isn't allowed"));
+                    finish(&mut response, &[question.q_type]);
+
+                    return write_response(&mut response, res_buf, Some(&question));
+                }
+                Some(Some(upstreams)) => {
+                    let mut response = forward_question(&req_header, &question, upstreams, dnssec);
+                    finish(&mut response, &[question.q_type]);
+
+                    return write_response(&mut response, res_buf, Some(&question));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    req_buf.move_to_pos(0)?;
+    let req = match DnsPacket::from_buf(req_buf) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Malformed query: {}", e);
+            if is_verbose() {
+                warn!("{}", req_buf.hexdump(size));
+            }
+
+            let mut response = response_to(&req_header);
+            response.header.res_code = e.res_code();
+            finish(&mut response, &[]);
+
+            return write_response(&mut response, res_buf, None);
+        }
+    };
+
+    if is_verbose() {
+        debug!("Query from {}:\n{}", client, req);
+    }
+
+    let mut response = dispatch(req, client, resolution, view, dnssec);
+
+    let q_types: Vec<QueryType> = response.questions.iter().map(|x| x.q_type).collect();
+    finish(&mut response, &q_types);
+
+    if is_verbose() {
+        debug!("Response to {}:\n{}", client, response);
+    }
+
+    write_response(&mut response, res_buf, None)
+}
+
+/// Answer a parsed request by its opcode, the place for any opcode to be answered from
+/// UPDATE never gets here, its sections are read from the bytes before they're parsed as a packet
+fn dispatch(req: DnsPacket, client: IpAddr, resolution: &Resolution, view: Option<&'static View>, dnssec: Dnssec) -> DnsPacket {
+    match req.header.opcode {
+        Opcode::QUERY => resolve(req, client, resolution, view, dnssec),
+        Opcode::NOTIFY => notify::answer(&req, client),
+        Opcode::IQUERY | Opcode::STATUS | Opcode::UPDATE | Opcode::UNKNOWN(_) => {
+            debug!("Received a request with opcode {} from {}, not implemented", req.header.opcode, client);
+            let mut response = DnsPacket::response_to(&req).with_rcode(ResCode::NOT_IMP);
+            response.questions = req.questions;
+            response
+        }
+    }
+}
+
+/// Turn away a UDP query without a valid server cookie when cookies are required, BADCOOKIE with a fresh cookie
+/// if it has a client cookie, and an empty truncated response if not, so the client retries over TCP
+fn refuse_cookie(req_header: &DnsHeader, req_buf: &PacketBuffer, res_buf: &mut PacketBuffer, client: IpAddr, cookie: Cookie) -> Result<()> {
+    let question = DnsPacket::peek_question(req_buf).ok();
+    let shown = question.map_or_else(|| "?".to_string(), |x| format!("{} {}", x, x.q_type));
+    let mut response = response_to(req_header);
+
+    if cookie == Cookie::MISSING {
+        debug!("Received query: {}, truncated, {} sent no cookie", shown, client);
+        response.header.trunc = true;
+    } else {
+        debug!("Received query: {}, BADCOOKIE, {} sent no valid server cookie", shown, client);
+        response.header.res_code = ResCode::BAD_COOKIE;
+        response.resources.extend(cookies::opt_record(cookie, client));
+    }
+
+    write_response(&mut response, res_buf, question.as_ref())
+}
+
+/// Write a response, or a truncated one with only the question and TC set if it doesn't fit
+fn write_response(response: &mut DnsPacket, res_buf: &mut PacketBuffer, question: Option<&QuestionRef>) -> Result<()> {
+    if MINIMAL_RESPONSES.load(Ordering::Relaxed) {
+        minimize(response);
+    }
+
+    // every query that gets an answer gets it from here, once
+    match question {
+        Some(x) => stats::count_response(iter::once(x.q_type), response.header.res_code),
+        None => stats::count_response(response.questions.iter().map(|x| x.q_type), response.header.res_code),
+    }
+
+    let written = match question {
+        Some(x) => response.write_with_question(res_buf, x),
+        None => response.write(res_buf),
+    };
+
+    if written.is_ok() {
+        return Ok(());
+    }
+
+    response.answers.clear();
+    response.authorities.clear();
+    // the OPT record stays, it carries the client's cookie
+    response.resources.retain(|x| x.query_type() == QueryType::OPT);
+    response.header.trunc = true;
+
+    res_buf.buf.fill(0);
+    res_buf.move_to_pos(0)?;
+
+    match question {
+        Some(x) => response.write_with_question(res_buf, x),
+        None => response.write(res_buf),
+    }
+}
+
+/// Drop the authority and additional records a client doesn't need, for --minimal-responses
+fn minimize(response: &mut DnsPacket) {
+    // only negative answers carry a SOA, it's what lets the client cache them
+    response.authorities.retain(|x| matches!(x, DnsRecord::SOA { .. }));
+    response.resources.retain(|x| x.query_type() == QueryType::OPT);
+}
+
+/// Encode a response in at most max_size bytes, truncating it with TC set if it doesn't fit
+pub(crate) fn encode_response(response: &mut DnsPacket, max_size: usize) -> Result<Vec<u8>> {
+    let mut res_buf = PacketBuffer::with_size(max_size);
+    write_response(response, &mut res_buf, None)?;
+
+    let mut bytes = res_buf.buf;
+    bytes.truncate(res_buf.pos);
+
+    Ok(bytes)
+}
+
+/// Answer every question in a parsed query, as the clients of a view see it if there is one
+/// Questions not answered from the server itself are refused unless the client may have names resolved
+/// AD is set only if the client understands it and the upstreams validated the answer to every question
+fn resolve(req: DnsPacket, client: IpAddr, resolution: &Resolution, view: Option<&'static View>, dnssec: Dnssec) -> DnsPacket {
+    let mut response = DnsPacket::response_to(&req);
+    let shown = views::label(view);
+    let mut validated = 0;
+
+    if req.questions.is_empty() {
+        return response.with_rcode(ResCode::FORM_ERR);
+    }
+
+    for ques in req.questions {
+        if ques.class == CLASS_CH {
+            let local = chaos::answer(&ques.name, ques.q_type, client);
+            let how = if local.header.res_code == ResCode::NO_ERR { "answered" } else { "refused" };
+            debug!("Received query: {} {} CH{}, {} as a question about the server", idna::to_unicode(&ques.name), ques.q_type, shown, how);
+            query_log::note(Source::CHAOS, None);
+            if local.header.authoritative {
+                response.header.authoritative = true;
+            }
+            if local.header.res_code != ResCode::NO_ERR {
+                response.header.res_code = local.header.res_code;
+            }
+            response.answers.extend(local.answers);
+            response.questions.push(ques);
+
+            continue;
+        }
+
+        // transfers over streams never get here
+        if xfr::is_transfer(ques.q_type) {
+            debug!("Received query: {} {}{}, refused, zone transfers are only served over TCP", idna::to_unicode(&ques.name), ques.q_type, shown);
+            query_log::note(Source::REFUSED, None);
+            response.header.res_code = ResCode::REFUSED;
+            response.extended_errors.push(Ede::new(ede::NOT_SUPPORTED, "zone transfers are only served over TCP"));
+            response.questions.push(ques);
+
+            continue;
+        }
+
+        // a view's own hosts file and zones come ahead of the ones everyone sees
+        if let Some(local) = view.and_then(|x| x.hosts_answer(&ques.name, ques.q_type)).or_else(|| hosts::answer(&ques.name, ques.q_type)) {
+            debug!("Received query: {} {}{}, answered from the hosts file", idna::to_unicode(&ques.name), ques.q_type, shown);
+            query_log::note(Source::HOSTS, None);
+            response.header.authoritative = true;
+            response.answers.extend(local.answers);
+            response.questions.push(ques);
+
+            continue;
+        }
+
+        if let Some(zone) = view.and_then(|x| x.find_zone(&ques.name)).or_else(|| zone::find(&ques.name)) {
+            debug!("Received query: {} {}{}, answered from the zone {}", idna::to_unicode(&ques.name), ques.q_type, shown, zone.origin);
+            query_log::note(Source::ZONE, None);
+            let local = zone.answer(&ques.name, ques.q_type);
+
+            // an expired zone's SERVFAIL isn't authoritative
+            if local.header.authoritative {
+                response.header.authoritative = true;
+            }
+            if local.header.res_code != ResCode::NO_ERR {
+                response.header.res_code = local.header.res_code;
+            }
+            response.answers.extend(local.answers);
+            response.extended_errors.extend(local.extended_errors);
+            // one SOA is enough for several negative answers from the same zone
+            for record in local.authorities {
+                if !response.authorities.contains(&record) {
+                    response.authorities.push(record);
+                }
+            }
+            response.questions.push(ques);
+
+            continue;
+        }
+
+        if let Some(local) = blocklist::answer(&ques.name, ques.q_type) {
+            debug!("Received query: {} {}{}, blocked", idna::to_unicode(&ques.name), ques.q_type, shown);
+            query_log::note(Source::BLOCKED, None);
+            if local.header.authoritative {
+                response.header.authoritative = true;
+            }
+            if local.header.res_code != ResCode::NO_ERR {
+                response.header.res_code = local.header.res_code;
+            }
+            response.answers.extend(local.answers);
+            response.extended_errors.extend(local.extended_errors);
+            for record in local.authorities {
+                if !response.authorities.contains(&record) {
+                    response.authorities.push(record);
+                }
+            }
+            response.questions.push(ques);
+
+            continue;
+        }
+
+        // a --forward rule for a special-use domain means it's delegated somewhere
+        let special = forwarding::find(&ques.name).is_none().then(|| special::answer(&ques.name, ques.q_type)).flatten();
+        if let Some(local) = special {
+            debug!("Received query: {} {}{}, answered as a special-use name", idna::to_unicode(&ques.name), ques.q_type, shown);
+            query_log::note(Source::SPECIAL, None);
+            response.header.authoritative = true;
+            if local.header.res_code != ResCode::NO_ERR {
+                response.header.res_code = local.header.res_code;
+            }
+            response.answers.extend(local.answers);
+            for record in local.authorities {
+                if !response.authorities.contains(&record) {
+                    response.authorities.push(record);
+                }
+            }
+            response.questions.push(ques);
+
+            continue;
+        }
+
+        if !acl::is_allowed(client) {
+            debug!("Received query: {} {}{}, refused, recursion isn't allowed for {}", idna::to_unicode(&ques.name), ques.q_type, shown, client);
+            query_log::note(Source::REFUSED, None);
+            stats::count_refused_recursion();
+            response.header.res_code = ResCode::REFUSED;
+            response.extended_errors.push(Ede::new(ede::PROHIBITED, "recursion isn't allowed"));
+            response.questions.push(ques);
+
+            continue;
+        }
+
+        // a view's own upstreams may answer differently, so what they say stays out of the cache everyone shares
+        let (upstreams, shared) = match (forwarding::find(&ques.name), view.and_then(|x| x.upstreams.as_ref()), resolution) {
+            (Some(rule), _, _) => (Some(&rule.upstreams), true),
+            (None, Some(x), _) => (Some(x), false),
+            (None, None, Resolution::Forward(x)) => (Some(*x), true),
+            (None, None, Resolution::Recursive) => (None, true),
+        };
+
+        let cached = if shared && !dnssec.checking_disabled {
+            cache::lookup(&ques.name, ques.q_type, move |name, q_type| refresh(name, q_type, upstreams))
+        } else {
+            None
+        };
+        if let Some(cached) = cached {
+            debug!("Received query: {} {}{}, answered from the cache", idna::to_unicode(&ques.name), ques.q_type, shown);
+            query_log::note(Source::CACHE, None);
+            let cached = rebinding::filter(cached, &ques.name);
+            response.header.rec_av = upstreams.is_none();
+            if cached.header.res_code != ResCode::NO_ERR {
+                response.header.res_code = cached.header.res_code;
+            }
+            response.answers.extend(cached.answers);
+            response.authorities.extend(cached.authorities);
+            response.extended_errors.extend(cached.extended_errors);
+            response.questions.push(ques);
+
+            continue;
+        }
+
+        match upstreams {
+            Some(upstreams) => {
+                let mut result = upstreams.query(|resolver, rival| {
+                    debug!("Received query: {} {}{}, forwarding to {}", idna::to_unicode(&ques.name), ques.q_type, shown, targets(resolver, rival));
+                    query_log::note(Source::FORWARDED, Some(resolver.addr));
+                    lookup(req.header.id, &ques.name, ques.q_type, dnssec, resolver, rival)
+                        .inspect(|(_, answered_by)| query_log::note(Source::FORWARDED, Some(*answered_by)))
+                })
+                .and_then(|res| chase_cnames(res, &ques.name, ques.q_type, |x| forward_chain_for(x, ques.q_type, upstreams, dnssec)))
+                .and_then(|res| dns64::complete(res, &ques.name, ques.q_type, |x| forward_chain(x, QueryType::A, upstreams)));
+
+                // answers that weren't checked aren't for clients that want them checked,
+                // and the cache keeps what the upstreams said, rebinding is filtered from each answer given out
+                let result = if shared && !dnssec.checking_disabled {
+                    if let Ok(result) = &mut result {
+                        cache::store(&ques.name, ques.q_type, result);
+                    }
+                    cache::or_stale(result, &ques.name, ques.q_type, move |name, q_type| refresh(name, q_type, Some(upstreams)))
+                } else {
+                    result
+                };
+
+                match result.map(|res| rebinding::filter(res, &ques.name)) {
+                    Ok(result) => {
+                        if result.header.res_code != ResCode::NO_ERR {
+                            response.header.res_code = result.header.res_code;
+                        }
+                        if result.header.auth_data {
+                            validated += 1;
+                        }
+                        response.answers.extend(result.answers);
+                        response.authorities.extend(result.authorities);
+                        response.resources.extend(result.resources);
+                        response.extended_errors.extend(result.extended_errors);
+                    }
+                    Err(e) => {
+                        warn!("Lookup of {} failed: {}", ques.name, e);
+                        response.header.res_code = ResCode::SERV_FAIL;
+                        response.extended_errors.push(Ede::new(ede::NETWORK_ERROR, &e.to_string()));
+                    }
+                }
+            }
+            None => {
+                debug!("Received query: {} {}{}", idna::to_unicode(&ques.name), ques.q_type, shown);
+                query_log::note(Source::RECURSIVE, None);
+                response.header.rec_av = true;
+
+                let mut result = recursive::resolve(&ques.name, ques.q_type)
+                    .and_then(|res| dns64::complete(res, &ques.name, ques.q_type, |x| recursive::resolve(x, QueryType::A)));
+
+                if let Ok(result) = &mut result {
+                    cache::store(&ques.name, ques.q_type, result);
+                }
+                let result = cache::or_stale(result, &ques.name, ques.q_type, |name, q_type| refresh(name, q_type, None))
+                    .map(|res| rebinding::filter(res, &ques.name));
+
+                match result {
+                    Ok(result) => {
+                        response.answers.extend(result.answers);
+                        response.authorities.extend(result.authorities);
+                        response.extended_errors.extend(result.extended_errors);
+                        if result.header.res_code != ResCode::NO_ERR {
+                            response.header.res_code = result.header.res_code;
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Recursive lookup of {} failed: {}", ques.name, e);
+                        response.header.res_code = ResCode::SERV_FAIL;
+                        response.extended_errors.push(Ede::new(ede::NO_REACHABLE_AUTHORITY, &e.to_string()));
+                    }
+                }
+            }
+        }
+
+        response.questions.push(ques);
+    }
+
+    response.header.auth_data = dnssec.wants_ad() && validated == response.questions.len();
+
+    response
+}
+
+/// Forward a single borrowed question to one of the upstreams
+/// The original question bytes are copied into the response when it is written
+/// AD is passed on from the upstream's answer if the client understands it, answers with CD are neither cached nor taken from the cache
+fn forward_question(req_header: &DnsHeader, question: &QuestionRef, upstreams: &'static Upstreams, dnssec: Dnssec) -> DnsPacket {
+    let mut response = response_to(req_header);
+    let qname = question.name();
+
+    let cached = if dnssec.checking_disabled {
+        None
+    } else {
+        cache::lookup(&qname, question.q_type, move |name, q_type| refresh(name, q_type, Some(upstreams)))
+    };
+    if let Some(cached) = cached {
+        debug!("Received query: {} {}{}, answered from the cache", question, question.q_type, views::label(None));
+        query_log::note(Source::CACHE, None);
+        let cached = rebinding::filter(cached, &qname);
+        response.header.res_code = cached.header.res_code;
+        response.answers = cached.answers;
+        response.authorities = cached.authorities;
+        response.extended_errors = cached.extended_errors;
+
+        return response;
+    }
+
+    let mut result = upstreams.query(|resolver, rival| {
+        debug!("Received query: {} {}{}, forwarding to {}", question, question.q_type, views::label(None), targets(resolver, rival));
+        query_log::note(Source::FORWARDED, Some(resolver.addr));
+        lookup_question(req_header.id, question, dnssec, resolver, rival)
+            .inspect(|(_, answered_by)| query_log::note(Source::FORWARDED, Some(*answered_by)))
+    })
+    .and_then(|res| chase_cnames(res, &qname, question.q_type, |x| forward_chain_for(x, question.q_type, upstreams, dnssec)))
+    .and_then(|res| dns64::complete(res, &qname, question.q_type, |x| forward_chain(x, QueryType::A, upstreams)));
+
+    let result = if dnssec.checking_disabled {
+        result
+    } else {
+        if let Ok(result) = &mut result {
+            cache::store(&qname, question.q_type, result);
+        }
+        cache::or_stale(result, &qname, question.q_type, move |name, q_type| refresh(name, q_type, Some(upstreams)))
+    };
+
+    match result.map(|res| rebinding::filter(res, &qname)) {
+        Ok(result) => {
+            response.header.res_code = result.header.res_code;
+            response.header.auth_data = result.header.auth_data && dnssec.wants_ad();
+            response.answers = result.answers;
+            response.authorities = result.authorities;
+            response.resources = result.resources;
+            response.extended_errors = result.extended_errors;
+        }
+        Err(e) => {
+            warn!("Lookup of {} failed: {}", question, e);
+            response.header.res_code = ResCode::SERV_FAIL;
+            response.extended_errors.push(Ede::new(ede::NETWORK_ERROR, &e.to_string()));
+        }
+    }
+
+    response
+}
+
+/// Look up the next name of a CNAME chain through the upstreams
+pub fn forward_chain(target: &str, q_type: QueryType, upstreams: &Upstreams) -> Result<DnsPacket> {
+    forward_chain_for(target, q_type, upstreams, Dnssec::default())
+}
+
+/// Look up the next name of a CNAME chain through the upstreams for a client, with its DNSSEC bits
+fn forward_chain_for(target: &str, q_type: QueryType, upstreams: &Upstreams, dnssec: Dnssec) -> Result<DnsPacket> {
+    upstreams.query(|resolver, rival| lookup(rand::random::<u16>(), target, q_type, dnssec, resolver, rival))
+}
+
+/// Look a question up again, through the upstreams or recursively without them, chasing CNAMEs and applying DNS64
+/// For refreshing cached answers in the background, rebinding is filtered as they're given out
+pub(crate) fn refresh(qname: &str, q_type: QueryType, upstreams: Option<&Upstreams>) -> Result<DnsPacket> {
+    match upstreams {
+        Some(upstreams) => forward_chain(qname, q_type, upstreams)
+            .and_then(|res| chase_cnames(res, qname, q_type, |x| forward_chain(x, q_type, upstreams)))
+            .and_then(|res| dns64::complete(res, qname, q_type, |x| forward_chain(x, QueryType::A, upstreams))),
+        None => recursive::resolve(qname, q_type)
+            .and_then(|res| dns64::complete(res, qname, q_type, |x| recursive::resolve(x, QueryType::A))),
+    }
+}
+
+/// The upstreams a lookup went to, for the query log
+fn targets(resolver: &Upstream, rival: Option<&Upstream>) -> String {
+    match rival {
+        Some(x) => format!("{} and {}", resolver, x),
+        None => resolver.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query_for(name: &str) -> DnsPacket {
+        let mut query = DnsPacket::new();
+        query.header.id = 0x4242;
+        query.header.query_res = false;
+        query.questions.push(DnsQuestion::new(name.to_string(), QueryType::A));
+        query
+    }
+
+    /// An upstream on UDP that answers the one query it gets with each of the responses made from it, in turn
+    fn udp_upstream(responses: Vec<fn(&DnsPacket) -> DnsPacket>) -> (SocketAddr, thread::JoinHandle<()>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        let handle = thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (size, client) = socket.recv_from(&mut buf).unwrap();
+            let query = DnsPacket::from_bytes(&buf[..size]).unwrap();
+            for response in responses {
+                socket.send_to(&response(&query).to_bytes().unwrap(), client).unwrap();
+            }
+        });
+
+        (addr, handle)
+    }
+
+    fn answered(query: &DnsPacket) -> DnsPacket {
+        let addr = Ipv4Addr::new(192, 0, 2, 1);
+        DnsPacket::response_to(query)
+            .question(query.questions[0].clone())
+            .answer(DnsRecord::A { domain: query.questions[0].name.clone(), addr_v4: addr, ttl: 60 })
+    }
+
+    #[test]
+    fn mismatched_responses_are_dropped_for_the_real_one() {
+        let (addr, upstream) = udp_upstream(vec![
+            |x| {
+                let mut res = answered(x);
+                res.header.id ^= 1;
+                res.answers.clear();
+                res
+            },
+            |x| {
+                let mut res = answered(x);
+                res.questions[0].name = "other.example".to_string();
+                res.answers.clear();
+                res
+            },
+            answered,
+        ]);
+
+        let res = exchange_message(&mut query_for("example.com"), addr, 1).unwrap();
+        upstream.join().unwrap();
+
+        assert_eq!(res.header.id, 0x4242);
+        assert_eq!(res.get_first_addr(), Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))));
+    }
+
+    fn race(resolvers: &[SocketAddr]) -> Result<(DnsPacket, SocketAddr)> {
+        let mut req_buf = buffer_pool::acquire();
+        query_for("race.example").write(&mut req_buf).unwrap();
+        exchange_udp(req_buf, resolvers, 1)
+    }
+
+    #[test]
+    fn race_goes_out_to_both_address_families() {
+        // takes the query without answering it
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        silent.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let socket = UdpSocket::bind("[::1]:0").unwrap();
+        let v6 = socket.local_addr().unwrap();
+        let upstream = thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (size, client) = socket.recv_from(&mut buf).unwrap();
+            let query = DnsPacket::from_bytes(&buf[..size]).unwrap();
+            socket.send_to(&answered(&query).to_bytes().unwrap(), client).unwrap();
+        });
+
+        let (res, source) = race(&[silent.local_addr().unwrap(), v6]).unwrap();
+        upstream.join().unwrap();
+
+        assert_eq!(source, v6);
+        assert_eq!(res.get_first_addr(), Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))));
+        assert!(silent.recv_from(&mut [0; 512]).is_ok());
+    }
+
+    #[test]
+    fn failed_send_leaves_the_rest_of_the_race() {
+        let (addr, upstream) = udp_upstream(vec![answered]);
+
+        // nothing can be sent to port 0
+        let unsendable = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        let (res, source) = race(&[unsendable, addr]).unwrap();
+        upstream.join().unwrap();
+
+        assert_eq!(source, addr);
+        assert_eq!(res.get_first_addr(), Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))));
+
+        assert!(race(&[unsendable]).is_err());
+    }
+
+    #[test]
+    fn truncated_responses_are_checked_over_tcp_too() {
+        let (addr, upstream) = udp_upstream(vec![|x| {
+            let mut res = DnsPacket::response_to(x).question(x.questions[0].clone());
+            res.header.trunc = true;
+            res
+        }]);
+        let listener = std::net::TcpListener::bind(addr).unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let query = DnsPacket::from_bytes(&transport::read_tcp_message(&mut stream).unwrap()).unwrap();
+            let mut res = answered(&query);
+            res.header.id ^= 1;
+            transport::write_tcp_message(&mut stream, &res.to_bytes().unwrap()).unwrap();
+        });
+
+        let e = exchange_message(&mut query_for("example.com"), addr, 1).unwrap_err();
+        upstream.join().unwrap();
+        server.join().unwrap();
+
+        assert!(matches!(e, DnsError::MismatchedResponse(_)), "{:?}", e);
+    }
+
+    #[test]
+    fn unresponsive_upstream_gets_servfail_in_time() {
+        let timeout = Duration::from_millis(200);
+        set_test_lookup_timeout(timeout);
+
+        // bound so nothing else takes the port, but never read
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let upstreams = Upstreams::new(vec![Upstream::udp(silent.local_addr().unwrap())], upstreams::Strategy::SEQUENTIAL);
+        let resolution = Resolution::Forward(Box::leak(Box::new(upstreams)));
+
+        let started = Instant::now();
+        let req = query_for("unresponsive.example").to_bytes().unwrap();
+        let res = handle_query_bytes(&req, IpAddr::V4(Ipv4Addr::LOCALHOST), &resolution, Transport::UDP).unwrap();
+        let elapsed = started.elapsed();
+
+        let res = DnsPacket::from_bytes(&res).unwrap();
+        assert_eq!(res.header.id, 0x4242);
+        assert_eq!(res.header.res_code, ResCode::SERV_FAIL);
+
+        // the first attempt and every retry waited out, and not much more
+        let attempts = 1 + lookup_retries() as u32;
+        assert!(elapsed >= timeout * attempts, "{:?}", elapsed);
+        assert!(elapsed < timeout * attempts + Duration::from_secs(1), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn unreachable_proxy_is_servfail_naming_it() {
+        // nothing listens on the port once the listener is dropped
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let proxy = socks5::Proxy::parse(&format!("socks5://127.0.0.1:{}", port)).unwrap();
+        socks5::set_test_proxy(Box::leak(Box::new(proxy)));
+
+        let upstream = Upstream::udp("192.0.2.53:53".parse().unwrap());
+        let upstreams = Box::leak(Box::new(Upstreams::new(vec![upstream], Strategy::SEQUENTIAL)));
+        let mut req = query_for("proxied.unreachable.example");
+        req.resources.push(DnsRecord::OPT { payload_size: 1232, ext_rcode: 0, version: 0, flags: 0, data: Vec::new() });
+        let req = req.to_bytes().unwrap();
+
+        let res = handle_query_bytes(&req, IpAddr::V4(Ipv4Addr::LOCALHOST), &Resolution::Forward(upstreams), Transport::TCP).unwrap();
+        let res = DnsPacket::from_bytes(&res).unwrap();
+
+        assert_eq!(res.header.res_code, ResCode::SERV_FAIL);
+        // the extended error carries the same text as the "Lookup of ... failed" log line
+        let errors = match &res.resources[..] {
+            [DnsRecord::OPT { data, .. }] => ede::parse(data),
+            x => panic!("Expected one OPT record, got {:?}", x),
+        };
+        let text = &errors[0].text;
+        assert!(text.contains(&format!("SOCKS5 proxy socks5://127.0.0.1:{}", port)), "{}", text);
+    }
+
+    #[test]
+    fn minimal_responses_leave_out_the_authority_section() {
+        let query = query_for("www.minimal.pine-dns.com");
+        let mut full = answered(&query);
+        for n in 1..=13u8 {
+            let host = format!("ns{}.minimal.pine-dns.com", n);
+            full.authorities.push(DnsRecord::NS { domain: "minimal.pine-dns.com".to_string(), host: host.clone(), ttl: 3600 });
+            full.resources.push(DnsRecord::A { domain: host.clone(), addr_v4: Ipv4Addr::new(192, 0, 2, n), ttl: 3600 });
+            full.resources.push(DnsRecord::AAAA { domain: host, addr: format!("2001:db8::{}", n).parse().unwrap(), ttl: 3600 });
+        }
+        full.resources.push(DnsRecord::OPT { payload_size: 1232, ext_rcode: 0, version: 0, flags: 0, data: Vec::new() });
+
+        let mut minimal = full.clone();
+        minimize(&mut minimal);
+        let full = encode_response(&mut full, transport::MAX_TCP_MESSAGE).unwrap();
+        let minimal = encode_response(&mut minimal, transport::MAX_TCP_MESSAGE).unwrap();
+
+        // the answer and the OPT record are all that's left
+        let mut bare = answered(&query);
+        bare.resources.push(DnsRecord::OPT { payload_size: 1232, ext_rcode: 0, version: 0, flags: 0, data: Vec::new() });
+        assert_eq!(minimal.len(), bare.to_bytes().unwrap().len());
+        assert!(full.len() > 512 && minimal.len() < 100, "{} and {} bytes", full.len(), minimal.len());
+
+        // a negative answer keeps its SOA
+        let mut negative = DnsPacket::response_to(&query).with_rcode(ResCode::NX_DOMAIN).authority(DnsRecord::SOA {
+            domain: "pine-dns.com".to_string(),
+            m_name: "ns1.pine-dns.com".to_string(),
+            r_name: "hostmaster.pine-dns.com".to_string(),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 300,
+            ttl: 300,
+        });
+        minimize(&mut negative);
+        assert_eq!(negative.authorities.len(), 1);
+    }
+
+    // the name, DO and CD of a query
+    type Asked = (String, bool, bool);
+
+    /// An upstream answering every query with an A record and its RRSIG, validated, until it's left alone for a while
+    /// DO is echoed in its OPT record as a real one would, and what each query asked is answered once it's done
+    fn signing_upstream() -> (SocketAddr, thread::JoinHandle<Vec<Asked>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        let handle = thread::spawn(move || {
+            let mut asked = Vec::new();
+            let mut buf = [0; 512];
+            while let Ok((size, client)) = socket.recv_from(&mut buf) {
+                let query = DnsPacket::from_bytes(&buf[..size]).unwrap();
+                let dnssec_ok = query.resources.iter().any(|x| matches!(x, DnsRecord::OPT { flags, .. } if flags & DO_BIT != 0));
+                asked.push((query.questions[0].name.clone(), dnssec_ok, query.header.checking_disabled));
+
+                let mut res = answered(&query).answer(DnsRecord::UNKNOWN {
+                    domain: query.questions[0].name.clone(),
+                    q_type: TYPE_RRSIG,
+                    len: 20,
+                    // the type covered, A, and the rest of the fields left empty
+                    data: [&[0, 1][..], &[0; 18]].concat(),
+                    ttl: 60,
+                });
+                res.header.auth_data = true;
+                res.resources.push(DnsRecord::OPT { payload_size: 1232, ext_rcode: 0, version: 0, flags: if dnssec_ok { DO_BIT } else { 0 }, data: Vec::new() });
+                socket.send_to(&res.to_bytes().unwrap(), client).unwrap();
+            }
+            asked
+        });
+
+        (addr, handle)
+    }
+
+    #[test]
+    fn do_and_cd_reach_the_upstream_and_shape_the_answer() {
+        let (addr, upstream) = signing_upstream();
+        let upstreams = Upstreams::new(vec![Upstream::udp(addr)], Strategy::SEQUENTIAL);
+        let resolution = Resolution::Forward(Box::leak(Box::new(upstreams)));
+
+        let combinations = [(false, false), (false, true), (true, false), (true, true)];
+        for (dnssec_ok, checking_disabled) in combinations {
+            let name = format!("do-{}-cd-{}.dnssec.pine-dns.com", dnssec_ok, checking_disabled);
+            let mut req = query_for(&name);
+            req.header.checking_disabled = checking_disabled;
+            req.resources.push(DnsRecord::OPT { payload_size: 1232, ext_rcode: 0, version: 0, flags: if dnssec_ok { DO_BIT } else { 0 }, data: Vec::new() });
+            let req = req.to_bytes().unwrap();
+
+            // asked twice, the second time from the cache unless CD kept it out
+            for upstream in [true, checking_disabled] {
+                let res = handle_query_bytes(&req, IpAddr::V4(Ipv4Addr::LOCALHOST), &resolution, Transport::UDP).unwrap();
+                let res = DnsPacket::from_bytes(&res).unwrap();
+
+                assert_eq!(res.get_first_addr(), Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))), "{}", name);
+                // the RRSIG only goes to a client that set DO, cached or not, and DO is echoed back
+                let signed = res.answers.iter().any(|x| x.query_type().to_u16() == TYPE_RRSIG);
+                assert_eq!(signed, dnssec_ok, "{}: {:?}", name, res.answers);
+                let echoed = res.resources.iter().any(|x| matches!(x, DnsRecord::OPT { flags, .. } if flags & DO_BIT != 0));
+                assert_eq!(echoed, dnssec_ok, "{}", name);
+
+                // and so does AD, as the client didn't set AD itself, but only when the upstream just said so
+                assert_eq!(res.header.auth_data, dnssec_ok && upstream, "{}", name);
+            }
+        }
+
+        // DO and CD went along as the client set them, and only answers to CD were asked for again
+        let asked = upstream.join().unwrap();
+        for (dnssec_ok, checking_disabled) in combinations {
+            let name = format!("do-{}-cd-{}.dnssec.pine-dns.com", dnssec_ok, checking_disabled);
+            let times = asked.iter().filter(|x| x.0 == name).inspect(|x| assert_eq!((x.1, x.2), (dnssec_ok, checking_disabled), "{}", name)).count();
+            assert_eq!(times, if checking_disabled { 2 } else { 1 }, "{}", name);
+        }
+    }
+}
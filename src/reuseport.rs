@@ -0,0 +1,102 @@
+//! SO_REUSEPORT receive scaling
+//! Several UDP sockets bound to the same address let the kernel shard incoming queries between them,
+//! each socket answered by its own thread without a shared queue
+
+use std::io;
+use std::mem;
+use std::net::{ SocketAddr, UdpSocket };
+use std::os::unix::io::{ AsRawFd, FromRawFd };
+
+use log::{ error, trace };
+
+use crate::buffer_pool;
+use crate::resolve::{ self, Resolution };
+
+/// Bind a UDP socket with SO_REUSEPORT set so others can share its address
+pub fn bind_reuseport(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let domain = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+
+    let fd = unsafe { libc::socket(domain, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // owned from here on so the fd is closed if anything below fails
+    let udp_socket = unsafe { UdpSocket::from_raw_fd(fd) };
+
+    let on: libc::c_int = 1;
+    let set = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_REUSEPORT,
+            &on as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if set < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let bound = match addr {
+        SocketAddr::V4(x) => {
+            let mut raw: libc::sockaddr_in = unsafe { mem::zeroed() };
+            raw.sin_family = libc::AF_INET as libc::sa_family_t;
+            raw.sin_port = x.port().to_be();
+            raw.sin_addr = libc::in_addr { s_addr: u32::from_ne_bytes(x.ip().octets()) };
+
+            unsafe {
+                libc::bind(
+                    udp_socket.as_raw_fd(),
+                    &raw as *const libc::sockaddr_in as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                )
+            }
+        }
+        SocketAddr::V6(x) => {
+            let mut raw: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+            raw.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            raw.sin6_port = x.port().to_be();
+            raw.sin6_flowinfo = x.flowinfo();
+            raw.sin6_addr = libc::in6_addr { s6_addr: x.ip().octets() };
+            raw.sin6_scope_id = x.scope_id();
+
+            unsafe {
+                libc::bind(
+                    udp_socket.as_raw_fd(),
+                    &raw as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+                )
+            }
+        }
+    };
+    if bound < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(udp_socket)
+}
+
+/// Answer queries on a socket from the calling thread, without the worker pool
+/// Responses go out the socket the query arrived on
+pub fn serve_udp(udp_socket: UdpSocket, resolution: Resolution) {
+    loop {
+        let mut req_buf = buffer_pool::acquire();
+
+        let (size, source) = match udp_socket.recv_from(&mut req_buf.buf) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("An error occurred: {}", e);
+                continue;
+            }
+        };
+
+        trace!("Received {} bytes from {}", size, source);
+
+        if let Err(e) = resolve::handle_packet(&udp_socket, req_buf, size, source, &resolution) {
+            error!("An error occurred: {}", e);
+        }
+    }
+}
@@ -0,0 +1,304 @@
+//! Response rate limiting (RRL), so spoofed queries can't turn the server into an amplifier aimed at their victim
+//! UDP responses are counted per client prefix, the /24 of an IPv4 address or the /56 of an IPv6 one, and per kind of response:
+//! answers, empty answers (NODATA), NXDOMAIN and errors, so a flood of one kind doesn't hold back the others
+//! Each prefix and kind has a bucket of --rrl <n> responses a second, filled at that rate up to --rrl-window <seconds> worth,
+//! responses that find it empty are dropped, except every --rrl-slip <n>th that goes out truncated (TC set, only the question)
+//! so a real client behind the prefix still gets through by retrying over TCP, which isn't limited
+//! At most --rrl-table-size buckets are kept, the least recently used making room for a new prefix
+
+use std::collections::{ BTreeMap, HashMap };
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr };
+use std::sync::{ Mutex, OnceLock };
+use std::time::Instant;
+
+use log::info;
+
+use crate::packet::ResCode;
+use crate::stats;
+
+static LIMITS: OnceLock<Limits> = OnceLock::new();
+static TABLE: OnceLock<Mutex<Table>> = OnceLock::new();
+
+// Prefix lengths clients are grouped by, a network a single operator usually has
+const IPV4_PREFIX: u8 = 24;
+const IPV6_PREFIX: u8 = 56;
+
+/// How many responses each prefix and kind may get
+#[derive(Copy, Clone, Debug)]
+pub struct Limits {
+    pub rate: u32,          // responses a second
+    pub window: u32,        // seconds of responses a bucket holds, the burst allowed after a quiet spell
+    pub slip: u32,          // every slip-th limited response is sent truncated, 0 drops them all
+    pub table_size: usize,  // buckets kept at most
+}
+
+/// What a response is, limited separately
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+enum Kind {
+    ANSWER,
+    NODATA,
+    NXDOMAIN,
+    ERROR,
+}
+
+/// The buckets, with the least recently used found first in the recency index
+struct Table {
+    buckets: HashMap<(IpAddr, Kind), Bucket>,
+    recency: BTreeMap<u64, (IpAddr, Kind)>, // by generation last used
+    generation: u64,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    used: u64,     // generation it's filed under in the recency index
+    limited: u64,  // responses held back since it last ran dry, counting toward the next slip
+}
+
+impl Kind {
+    /// The kind of a response from its header
+    fn of(res: &[u8]) -> Kind {
+        let answers = u16::from_be_bytes([res[6], res[7]]);
+
+        match ResCode::from_u8(res[3] & 0x0F) {
+            ResCode::NO_ERR if answers > 0 => Kind::ANSWER,
+            ResCode::NO_ERR => Kind::NODATA,
+            ResCode::NX_DOMAIN => Kind::NXDOMAIN,
+            _ => Kind::ERROR,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::ANSWER => "answer",
+            Kind::NODATA => "nodata",
+            Kind::NXDOMAIN => "nxdomain",
+            Kind::ERROR => "error",
+        }
+    }
+}
+
+/// Limit the UDP responses sent to each client prefix from now on, only the first call has any effect
+pub fn set_limits(limits: Limits) {
+    let _ = LIMITS.set(limits);
+}
+
+/// How much of a UDP response to send to a client, all of it, just its header and question with TC set, or None to drop it
+/// The response is truncated in place when it's slipped
+pub fn limit(client: IpAddr, res: &mut [u8]) -> Option<usize> {
+    let limits = match LIMITS.get() {
+        Some(x) => x,
+        None => return Some(res.len()),
+    };
+
+    let mut table = TABLE.get_or_init(|| Mutex::new(Table::new())).lock().unwrap();
+    limit_in(&mut table, limits, client, res, Instant::now())
+}
+
+/// How much of a response to send by the buckets and limits given, as of now
+fn limit_in(table: &mut Table, limits: &Limits, client: IpAddr, res: &mut [u8], now: Instant) -> Option<usize> {
+    if res.len() < 12 {
+        return Some(res.len());
+    }
+
+    let prefix = prefix(client);
+    let kind = Kind::of(res);
+    let bucket = table.take(prefix, kind, limits, now);
+
+    let rate = limits.rate as f64;
+    let full = rate * limits.window.max(1) as f64;
+    bucket.tokens = (bucket.tokens + now.saturating_duration_since(bucket.updated).as_secs_f64() * rate).min(full);
+    bucket.updated = now;
+
+    if bucket.tokens >= 1.0 {
+        // a bucket that filled up again has been under the rate for a whole window
+        if bucket.limited > 0 && bucket.tokens >= full {
+            info!("Stopped rate limiting {} responses to {}/{} after holding back {}", kind.name(), prefix, prefix_len(prefix), bucket.limited);
+            bucket.limited = 0;
+        }
+        bucket.tokens -= 1.0;
+
+        return Some(res.len());
+    }
+
+    if bucket.limited == 0 {
+        info!("Rate limiting {} responses to {}/{}", kind.name(), prefix, prefix_len(prefix));
+    }
+    bucket.limited += 1;
+
+    // a slip of 0 has no remainder, so nothing slips
+    if bucket.limited.checked_rem(limits.slip as u64) == Some(0) {
+        stats::count_rate_limited(true);
+        return Some(slip(res));
+    }
+
+    stats::count_rate_limited(false);
+    None
+}
+
+impl Table {
+    fn new() -> Table {
+        Table {
+            buckets: HashMap::new(),
+            recency: BTreeMap::new(),
+            generation: 0,
+        }
+    }
+
+    /// The bucket of a prefix and kind marked the most recently used, a full one if it's new
+    /// A new bucket evicts the least recently used when the table is full
+    fn take(&mut self, prefix: IpAddr, kind: Kind, limits: &Limits, now: Instant) -> &mut Bucket {
+        self.generation += 1;
+        let generation = self.generation;
+
+        if !self.buckets.contains_key(&(prefix, kind)) {
+            while self.buckets.len() >= limits.table_size.max(1) {
+                let Some((_, key)) = self.recency.pop_first() else { break };
+                self.buckets.remove(&key);
+            }
+
+            self.buckets.insert((prefix, kind), Bucket {
+                tokens: limits.rate as f64 * limits.window.max(1) as f64,
+                updated: now,
+                used: generation,
+                limited: 0,
+            });
+        }
+
+        let bucket = self.buckets.get_mut(&(prefix, kind)).expect("The bucket was just inserted");
+        self.recency.remove(&bucket.used);
+        self.recency.insert(generation, (prefix, kind));
+        bucket.used = generation;
+
+        bucket
+    }
+}
+
+/// The prefix a client is limited by, an IPv4 client on a dual stack socket by its IPv4 address
+fn prefix(client: IpAddr) -> IpAddr {
+    let client = match client {
+        IpAddr::V6(x) => x.to_ipv4_mapped().map_or(client, IpAddr::V4),
+        x => x,
+    };
+
+    match client {
+        IpAddr::V4(x) => IpAddr::V4(Ipv4Addr::from(u32::from(x) & (u32::MAX << (32 - IPV4_PREFIX)))),
+        IpAddr::V6(x) => IpAddr::V6(Ipv6Addr::from(u128::from(x) & (u128::MAX << (128 - IPV6_PREFIX)))),
+    }
+}
+
+fn prefix_len(prefix: IpAddr) -> u8 {
+    if prefix.is_ipv4() { IPV4_PREFIX } else { IPV6_PREFIX }
+}
+
+/// Cut a response down to its header and question with TC set, answering the length left
+/// A response without a single readable question keeps only the header
+pub fn slip(res: &mut [u8]) -> usize {
+    res[2] |= 0x02;
+    res[6..12].fill(0);
+
+    let questions = u16::from_be_bytes([res[4], res[5]]);
+    match question_end(res) {
+        Some(end) if questions == 1 => end,
+        _ => {
+            res[4..6].fill(0);
+            12
+        }
+    }
+}
+
+/// Where the first question of a response ends, None if it runs past the end
+fn question_end(res: &[u8]) -> Option<usize> {
+    let mut pos = 12;
+
+    loop {
+        let len = *res.get(pos)? as usize;
+        if len & 0xC0 == 0xC0 {
+            pos += 2;
+            break;
+        }
+        pos += 1 + len;
+        if len == 0 {
+            break;
+        }
+    }
+
+    // followed by the type and class
+    Some(pos + 4).filter(|x| *x <= res.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use crate::packet::{ DnsPacket, DnsQuestion, DnsRecord, QueryType };
+
+    const LIMITS: Limits = Limits { rate: 5, window: 1, slip: 2, table_size: 100 };
+
+    fn response(res_code: ResCode) -> Vec<u8> {
+        let mut query = DnsPacket::new();
+        query.questions.push(DnsQuestion::new("flood.example".to_string(), QueryType::A));
+        let mut res = DnsPacket::response_to(&query).with_rcode(res_code).question(query.questions[0].clone());
+        if res_code == ResCode::NO_ERR {
+            res = res.answer(DnsRecord::A { domain: "flood.example".to_string(), addr_v4: Ipv4Addr::new(192, 0, 2, 1), ttl: 300 });
+        }
+
+        res.to_bytes().unwrap()
+    }
+
+    /// What happened to each of a flood of responses to a client: its full length, a slipped length, or None when dropped
+    fn flood(table: &mut Table, limits: &Limits, client: [u8; 4], res_code: ResCode, count: usize, now: Instant) -> Vec<Option<usize>> {
+        (0..count)
+            .map(|_| limit_in(table, limits, IpAddr::V4(client.into()), &mut response(res_code), now))
+            .collect()
+    }
+
+    #[test]
+    fn flood_is_dropped_past_the_rate_with_every_other_response_slipped() {
+        let mut table = Table::new();
+        let now = Instant::now();
+        let full = response(ResCode::NO_ERR).len();
+        // the header, then flood.example A IN
+        let slipped = 12 + 15 + 4;
+
+        let sent = flood(&mut table, &LIMITS, [192, 0, 2, 1], ResCode::NO_ERR, 11, now);
+        assert_eq!(sent, [
+            Some(full), Some(full), Some(full), Some(full), Some(full),
+            None, Some(slipped), None, Some(slipped), None, Some(slipped),
+        ]);
+
+        // the rest of the /24 shares the bucket, other prefixes and kinds of response have their own
+        assert_eq!(flood(&mut table, &LIMITS, [192, 0, 2, 200], ResCode::NO_ERR, 1, now), [None]);
+        assert_eq!(flood(&mut table, &LIMITS, [198, 51, 100, 1], ResCode::NO_ERR, 1, now), [Some(full)]);
+        assert!(flood(&mut table, &LIMITS, [192, 0, 2, 1], ResCode::NX_DOMAIN, 5, now).iter().all(Option::is_some));
+
+        // a second later the bucket has filled up again, and the count toward the next slip starts over
+        let later = now + Duration::from_secs(1);
+        assert_eq!(flood(&mut table, &LIMITS, [192, 0, 2, 1], ResCode::NO_ERR, 7, later), [
+            Some(full), Some(full), Some(full), Some(full), Some(full),
+            None, Some(slipped),
+        ]);
+    }
+
+    #[test]
+    fn slipped_response_is_its_question_with_tc_set() {
+        let mut res = response(ResCode::NO_ERR);
+        let len = slip(&mut res);
+
+        let slipped = DnsPacket::from_bytes(&res[..len]).unwrap();
+        assert!(slipped.header.trunc);
+        assert!(slipped.answers.is_empty());
+        assert_eq!(slipped.questions[0].name, "flood.example");
+    }
+
+    #[test]
+    fn slip_of_zero_drops_every_limited_response() {
+        let mut table = Table::new();
+        let limits = Limits { slip: 0, ..LIMITS };
+
+        let sent = flood(&mut table, &limits, [192, 0, 2, 1], ResCode::NO_ERR, 10, Instant::now());
+        assert_eq!(sent.iter().filter(|x| x.is_none()).count(), 5);
+    }
+}
@@ -0,0 +1,408 @@
+//! Secondary zones, transferred from a primary with --secondary <origin>:<primary-ip:port> and kept fresh on the SOA's timers
+//! Each zone has a thread that asks the primary for its SOA every refresh interval, or every retry interval after a failure,
+//! and pulls the changes with IXFR when the serial has gone up, or the whole zone with AXFR if there's no version to update
+//! Changes are applied to a copy of the zone swapped in once all of them are, a copy they don't apply to is transferred whole instead
+//! A zone that can't be refreshed keeps being served until its expire time has passed since the last success,
+//! then answers SERVFAIL until a transfer succeeds again
+//! A NOTIFY from the primary has the serial checked right away, without waiting for the refresh interval
+//! With --secondary-dir <dir> every transfer is saved there as <origin>.zone and served from on the next start
+
+use std::fs;
+use std::net::{ IpAddr, SocketAddr };
+use std::path::{ Path, PathBuf };
+use std::sync::mpsc::{ self, Sender };
+use std::sync::{ Arc, Mutex };
+use std::thread;
+use std::time::{ Duration, Instant, SystemTime };
+
+use log::{ error, info };
+
+use crate::dns_name;
+use crate::error::DnsError;
+use crate::json;
+use crate::packet::{ DnsRecord, QueryType, ResCode };
+use crate::resolve;
+use crate::xfr::{ self, Transfer };
+use crate::zone::{ self, Zone };
+
+type Result<T> = std::result::Result<T, DnsError>;
+
+// Every secondary zone, with the sender that wakes its thread to check the serial
+static SECONDARIES: Mutex<Vec<(Secondary, Sender<()>)>> = Mutex::new(Vec::new());
+
+// How long to wait before trying again while there's no SOA to take the retry interval from
+const INITIAL_RETRY: Duration = Duration::from_secs(10);
+
+/// A zone served as a secondary and the primary it's transferred from
+#[derive(Clone, Debug)]
+pub struct Secondary {
+    pub origin: String, // normalized, ex. home.lan
+    pub primary: SocketAddr,
+}
+
+/// The SOA timers of a zone
+struct Timers {
+    refresh: Duration,
+    retry: Duration,
+    expire: Duration,
+}
+
+/// Install a secondary zone and start the thread refreshing it
+/// The zone is served from its saved copy in the directory if there is one, and answers SERVFAIL until the first transfer if not
+pub fn start(secondary: Secondary, dir: Option<&str>) {
+    let path = dir.map(|x| PathBuf::from(x).join(file_name(&secondary.origin)));
+
+    let loaded = match &path {
+        Some(path) if path.exists() => match Zone::load(&secondary.origin, &path.to_string_lossy()) {
+            Ok(x) => {
+                info!("Loaded zone {} serial {} from {}", json::fqdn(&x.origin), x.serial(), path.display());
+                Some(x)
+            }
+            Err(e) => {
+                error!("Failed to load the saved zone {}: {}", json::fqdn(&secondary.origin), e);
+                None
+            }
+        },
+        _ => None,
+    };
+
+    // a saved copy is as fresh as the transfer that wrote it, and expires on the same schedule, its serial is checked right away
+    let last_success = loaded.as_ref().zip(path.as_deref()).map(|(zone, path)| {
+        let saved = saved_at(path).unwrap_or_else(Instant::now);
+        if let Some(timers) = timers(&zone.soa).filter(|x| saved.elapsed() >= x.expire) {
+            info!("Saved zone {} expired, not refreshed for {}s, answering SERVFAIL for it", json::fqdn(&zone.origin), timers.expire.as_secs());
+            zone.expire();
+        }
+
+        saved
+    });
+    zone::replace(loaded.unwrap_or_else(|| Zone::empty(&secondary.origin)));
+
+    let (wake, woken) = mpsc::channel();
+    SECONDARIES.lock().unwrap().push((secondary.clone(), wake));
+
+    thread::spawn(move || run(secondary, path, last_success, woken));
+}
+
+/// Check a zone's serial now, for a NOTIFY from an address
+/// Fails if the zone isn't a secondary zone or the address isn't its primary's
+pub fn notified(origin: &str, from: IpAddr) -> std::result::Result<(), String> {
+    let origin = dns_name::normalize(origin);
+    let secondaries = SECONDARIES.lock().unwrap();
+    let (secondary, wake) = secondaries.iter()
+        .find(|(x, _)| x.origin == origin)
+        .ok_or("it isn't a secondary zone here")?;
+
+    if secondary.primary.ip() != from {
+        return Err(format!("it isn't from the primary {}", secondary.primary));
+    }
+    let _ = wake.send(());
+
+    Ok(())
+}
+
+/// Whether a zone is served as a secondary
+pub fn is_secondary(origin: &str) -> bool {
+    let origin = dns_name::normalize(origin);
+
+    SECONDARIES.lock().unwrap().iter().any(|(x, _)| x.origin == origin)
+}
+
+/// Refresh a zone for the life of the process, waking early when notified
+fn run(secondary: Secondary, path: Option<PathBuf>, mut last_success: Option<Instant>, woken: mpsc::Receiver<()>) {
+    let origin = json::fqdn(&secondary.origin);
+
+    loop {
+        let wait = match refresh(&secondary, path.as_deref()) {
+            Ok(timers) => {
+                last_success = Some(Instant::now());
+                timers.refresh
+            }
+            Err(e) => {
+                error!("Failed to refresh zone {} from {}: {}", origin, secondary.primary, e);
+
+                let current = installed(&secondary.origin);
+                let timers = current.as_ref().and_then(|x| timers(&x.soa));
+                if let (Some(zone), Some(timers), Some(last)) = (&current, &timers, last_success) {
+                    if !zone.is_expired() && last.elapsed() >= timers.expire {
+                        info!("Zone {} expired, not refreshed for {}s, answering SERVFAIL for it", origin, timers.expire.as_secs());
+                        zone.expire();
+                    }
+                }
+
+                timers.map_or(INITIAL_RETRY, |x| x.retry)
+            }
+        };
+
+        // a NOTIFY ends the wait early, any more that came in meanwhile are covered by the same check
+        let _ = woken.recv_timeout(wait);
+        while woken.try_recv().is_ok() {}
+    }
+}
+
+/// Check the primary's serial and transfer the zone again if it's newer than the installed one's
+/// An expired zone is always transferred again, answering the timers to wait by
+fn refresh(secondary: &Secondary, path: Option<&Path>) -> Result<Timers> {
+    let current = installed(&secondary.origin).filter(|x| !x.is_expired());
+
+    if let Some(current) = &current {
+        let serial = primary_serial(secondary)?;
+        if !xfr::serial_newer(serial, current.serial()) {
+            return timers(&current.soa).ok_or_else(|| DnsError::InvalidZone("the installed zone has no SOA".to_string()));
+        }
+    }
+
+    let zone = match (xfr::pull(&secondary.origin, secondary.primary, current.as_deref())?, &current) {
+        (Transfer::Incremental(deltas), Some(current)) => match current.apply(&deltas) {
+            Ok(x) => {
+                let (deleted, added) = deltas.iter().fold((0, 0), |(d, a), x| (d + x.deleted.len(), a + x.added.len()));
+                info!("Transferred the changes to zone {} from serial {} to {} from {}, {} deleted and {} added records",
+                    json::fqdn(&x.origin), current.serial(), x.serial(), secondary.primary, deleted, added);
+                x
+            }
+            Err(e) => {
+                error!("Failed to apply the changes to zone {}, transferring it whole: {}", json::fqdn(&current.origin), e);
+                full(secondary)?
+            }
+        },
+        (Transfer::Incremental(_), None) => return Err(DnsError::Transfer("the primary sent changes without a version to apply them to".to_string())),
+        (Transfer::Current, Some(current)) => return timers(&current.soa).ok_or_else(|| DnsError::InvalidZone("the installed zone has no SOA".to_string())),
+        (Transfer::Current, None) => return Err(DnsError::Transfer("the primary sent no zone".to_string())),
+        (Transfer::Full(records), _) => whole(secondary, records)?,
+    };
+
+    if let Some(path) = path {
+        if let Err(e) = save(&zone, path) {
+            error!("Failed to save zone {} to {}: {}", json::fqdn(&zone.origin), path.display(), e);
+        }
+    }
+
+    let timers = timers(&zone.soa).ok_or_else(|| DnsError::InvalidZone("the transferred zone has no SOA".to_string()))?;
+    zone::replace(zone);
+
+    Ok(timers)
+}
+
+/// Transfer the whole zone with AXFR
+fn full(secondary: &Secondary) -> Result<Zone> {
+    match xfr::pull(&secondary.origin, secondary.primary, None)? {
+        Transfer::Full(x) => whole(secondary, x),
+        _ => Err(DnsError::Transfer("the primary didn't send the whole zone".to_string())),
+    }
+}
+
+/// Build a zone from the records of a full transfer
+fn whole(secondary: &Secondary, records: Vec<DnsRecord>) -> Result<Zone> {
+    let zone = Zone::from_records(&secondary.origin, records)?;
+    info!("Transferred zone {} serial {} from {}, {} records", json::fqdn(&zone.origin), zone.serial(), secondary.primary, zone.record_count());
+
+    Ok(zone)
+}
+
+/// The serial of the primary's SOA for the zone, asked for over UDP
+fn primary_serial(secondary: &Secondary) -> Result<u32> {
+    let res = resolve::query_server(&secondary.origin, QueryType::SOA, secondary.primary)?;
+    if res.header.res_code != ResCode::NO_ERR {
+        return Err(DnsError::Transfer(format!("{} answered the SOA query {}", secondary.primary, res.header.res_code)));
+    }
+
+    res.answers.iter()
+        .find_map(|x| match x {
+            DnsRecord::SOA { domain, serial, .. } if dns_name::normalize(domain) == secondary.origin => Some(*serial),
+            _ => None,
+        })
+        .ok_or_else(|| DnsError::Transfer(format!("{} answered the SOA query without the zone's SOA", secondary.primary)))
+}
+
+/// The installed zone with an origin, if any
+fn installed(origin: &str) -> Option<Arc<Zone>> {
+    zone::zones().into_iter().find(|x| x.origin == origin)
+}
+
+/// The timers of an SOA record, at least a second each so a zero never spins
+fn timers(soa: &DnsRecord) -> Option<Timers> {
+    let secs = |x: u32| Duration::from_secs(x.max(1) as u64);
+
+    match *soa {
+        DnsRecord::SOA { refresh, retry, expire, .. } => Some(Timers {
+            refresh: secs(refresh),
+            retry: secs(retry),
+            expire: secs(expire),
+        }),
+        _ => None,
+    }
+}
+
+/// Write a zone as a master file, through a temporary file so a failed write never leaves half a zone behind
+fn save(zone: &Zone, path: &Path) -> Result<()> {
+    let tmp = path.with_extension("zone.tmp");
+    fs::write(&tmp, zone.to_master())?;
+    fs::rename(&tmp, path)?;
+
+    Ok(())
+}
+
+/// When a saved zone was written, None if the file's modification time can't be read
+fn saved_at(path: &Path) -> Option<Instant> {
+    let modified = fs::metadata(path).and_then(|x| x.modified()).ok()?;
+
+    Instant::now().checked_sub(SystemTime::now().duration_since(modified).unwrap_or_default())
+}
+
+/// The file a zone is saved to, named after its origin
+fn file_name(origin: &str) -> String {
+    match origin {
+        "" => "root.zone".to_string(),
+        x => format!("{}.zone", x),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{ Ipv4Addr, TcpListener, UdpSocket };
+    use std::sync::atomic::{ AtomicBool, AtomicU32, Ordering };
+
+    use crate::packet::{ DnsPacket, DnsQuestion };
+    use crate::resolve::Resolution;
+    use crate::transport::{ self, Transport };
+
+    /// The records of a zone at a serial, www's address ending in the serial so each version answers differently
+    /// The SOA timers are seconds, refresh and retry 1 and expire 3
+    fn records(origin: &str, serial: u32) -> Vec<DnsRecord> {
+        vec![
+            DnsRecord::SOA {
+                domain: origin.to_string(),
+                m_name: format!("ns.{}", origin),
+                r_name: format!("hostmaster.{}", origin),
+                serial: serial,
+                refresh: 1,
+                retry: 1,
+                expire: 3,
+                minimum: 1,
+                ttl: 60,
+            },
+            DnsRecord::NS { domain: origin.to_string(), host: format!("ns.{}", origin), ttl: 60 },
+            DnsRecord::A { domain: format!("www.{}", origin), addr_v4: Ipv4Addr::new(192, 0, 2, serial as u8), ttl: 60 },
+        ]
+    }
+
+    /// A primary serving the zone at the serial given, answering SOA queries over UDP and every transfer over TCP with the whole zone
+    /// Its sockets are closed once stop is set
+    fn primary(origin: &'static str, serial: Arc<AtomicU32>, stop: Arc<AtomicBool>) -> SocketAddr {
+        let (listener, udp_socket) = loop {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            if let Ok(udp_socket) = UdpSocket::bind(listener.local_addr().unwrap()) {
+                break (listener, udp_socket);
+            }
+        };
+        let addr = listener.local_addr().unwrap();
+
+        let (udp_serial, udp_stop) = (serial.clone(), stop.clone());
+        udp_socket.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while !udp_stop.load(Ordering::Relaxed) {
+                let Ok((size, client)) = udp_socket.recv_from(&mut buf) else { continue };
+                let query = DnsPacket::from_bytes(&buf[..size]).unwrap();
+                let soa = records(origin, udp_serial.load(Ordering::Relaxed)).remove(0);
+                let mut res = DnsPacket::response_to(&query).authoritative().question(query.questions[0].clone()).answer(soa);
+                udp_socket.send_to(&res.to_bytes().unwrap(), client).unwrap();
+            }
+        });
+
+        listener.set_nonblocking(true).unwrap();
+        thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                let Ok((mut stream, _)) = listener.accept() else {
+                    thread::sleep(Duration::from_millis(20));
+                    continue;
+                };
+                stream.set_nonblocking(false).unwrap();
+                let query = DnsPacket::from_bytes(&transport::read_tcp_message(&mut stream).unwrap()).unwrap();
+
+                // the SOA, the rest of the zone, then the SOA again
+                let mut records = records(origin, serial.load(Ordering::Relaxed));
+                records.push(records[0].clone());
+                let mut res = DnsPacket::response_to(&query).authoritative().question(query.questions[0].clone());
+                res.answers = records;
+                transport::write_tcp_message(&mut stream, &res.to_bytes().unwrap()).unwrap();
+            }
+        });
+
+        addr
+    }
+
+    fn ask(name: &str) -> DnsPacket {
+        let mut query = DnsPacket::new();
+        query.questions.push(DnsQuestion::new(name.to_string(), QueryType::A));
+        let res = resolve::handle_query_bytes(&query.to_bytes().unwrap(), IpAddr::V4(Ipv4Addr::LOCALHOST), &Resolution::Recursive, Transport::UDP).unwrap();
+
+        DnsPacket::from_bytes(&res).unwrap()
+    }
+
+    /// Wait up to a deadline for the answer to a query to pass a check
+    fn wait_for(name: &str, within: Duration, check: impl Fn(&DnsPacket) -> bool) -> DnsPacket {
+        let deadline = Instant::now() + within;
+        loop {
+            let res = ask(name);
+            if check(&res) || Instant::now() >= deadline {
+                return res;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    fn www(res: &DnsPacket) -> Option<Ipv4Addr> {
+        res.a_records().map(|(_, addr, _)| addr).next()
+    }
+
+    #[test]
+    fn zone_is_transferred_again_when_its_serial_goes_up_and_expires_once_the_primary_is_gone() {
+        let origin = "transferred.pine-dns.com";
+        let serial = Arc::new(AtomicU32::new(1));
+        let stop = Arc::new(AtomicBool::new(false));
+        let addr = primary(origin, serial.clone(), stop.clone());
+
+        start(Secondary { origin: origin.to_string(), primary: addr }, None);
+        let res = wait_for("www.transferred.pine-dns.com", Duration::from_secs(5), |x| www(x).is_some());
+        assert!(res.header.authoritative);
+        assert_eq!(www(&res), Some(Ipv4Addr::new(192, 0, 2, 1)));
+
+        // picked up on the next refresh, a second later
+        serial.store(2, Ordering::Relaxed);
+        let res = wait_for("www.transferred.pine-dns.com", Duration::from_secs(5), |x| www(x) == Some(Ipv4Addr::new(192, 0, 2, 2)));
+        assert_eq!(www(&res), Some(Ipv4Addr::new(192, 0, 2, 2)));
+
+        // still served while the primary is gone, until the expire time has passed since the last refresh
+        stop.store(true, Ordering::Relaxed);
+        assert_eq!(www(&ask("www.transferred.pine-dns.com")), Some(Ipv4Addr::new(192, 0, 2, 2)));
+        let stopped = Instant::now();
+
+        let timeouts = resolve::lookup_timeout() * (resolve::lookup_retries() + 1) as u32;
+        let res = wait_for("www.transferred.pine-dns.com", timeouts + Duration::from_secs(10), |x| x.header.res_code == ResCode::SERV_FAIL);
+        assert_eq!(res.header.res_code, ResCode::SERV_FAIL);
+        assert!(stopped.elapsed() >= Duration::from_secs(2));
+    }
+
+    #[test]
+    fn saved_zone_is_as_old_as_the_file() {
+        let origin = "saved.pine-dns.com";
+        let dir = std::env::temp_dir().join(format!("pine-dns-secondary-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(file_name(origin));
+        save(&Zone::from_records(origin, records(origin, 1)).unwrap(), &path).unwrap();
+
+        // written an hour ago, long past the zone's expire time of 3s
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(3600)).unwrap();
+
+        // a primary that isn't there
+        let gone = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        start(Secondary { origin: origin.to_string(), primary: gone }, Some(&dir.to_string_lossy()));
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(installed(origin).unwrap().is_expired());
+        assert_eq!(ask("www.saved.pine-dns.com").header.res_code, ResCode::SERV_FAIL);
+    }
+}
@@ -0,0 +1,286 @@
+//! Serde support for packets, built with --features serde, so responses can be archived as JSON and picked apart with jq
+//! Types and rcodes are their names, ex. "AAAA" and "NX_DOMAIN", addresses are strings in JSON, records are tagged by type,
+//! ex. {"type":"MX","domain":"example.com","priority":10,"host":"mail.example.com","ttl":300}, and rdata of unknown types is hex
+//! Section counts and Extended DNS Errors aren't included, the counts follow the sections when a packet is written
+//! Anything deserialized is checked the way it would be written, names by their labels and length, text by its length,
+//! so a packet made from JSON can go on the wire
+
+use serde::de::{ self, Deserializer };
+use serde::{ Deserialize, Serialize, Serializer };
+
+use crate::dns_name;
+use crate::error::DnsError;
+use crate::idna;
+use crate::packet::{ DnsHeader, DnsPacket, DnsQuestion, DnsRecord, Opcode, QueryType, ResCode };
+
+type Result<T> = std::result::Result<T, DnsError>;
+
+// The most a record's data can take up on the wire
+const MAX_RDATA: usize = u16::MAX as usize;
+
+impl Serialize for QueryType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.mnemonic())
+    }
+}
+
+/// A mnemonic, ex. "AAAA", a TYPE<n> name or a number as text
+impl<'de> Deserialize<'de> for QueryType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<QueryType, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        name.parse().map_err(de::Error::custom)
+    }
+}
+
+/// Unassigned codes by their RCODE<n> name
+impl Serialize for ResCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            ResCode::UNKNOWN(_) => serializer.serialize_str(&self.mnemonic()),
+            _ => serializer.serialize_str(&format!("{:?}", self)),
+        }
+    }
+}
+
+/// A name as the stats and query log write it, ex. "NX_DOMAIN", or as dig shows it, ex. "NXDOMAIN"
+impl<'de> Deserialize<'de> for ResCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<ResCode, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        name.parse().map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for DnsHeader {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        DnsHeader::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DnsHeader {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<DnsHeader, D::Error> {
+        DnsHeader::deserialize(deserializer)
+    }
+}
+
+/// Opcodes are their number, as dig shows the unassigned ones
+impl Serialize for Opcode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_u8(self.to_u8())
+    }
+}
+
+impl<'de> Deserialize<'de> for Opcode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Opcode, D::Error> {
+        let num = u8::deserialize(deserializer)?;
+        if num > 0x0F {
+            return Err(de::Error::custom(format!("Opcode {} doesn't fit in 4 bits", num)));
+        }
+
+        Ok(Opcode::from_u8(num))
+    }
+}
+
+impl Serialize for DnsQuestion {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        DnsQuestion::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DnsQuestion {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<DnsQuestion, D::Error> {
+        let question = DnsQuestion::deserialize(deserializer)?;
+        check_name(&question.name).map_err(de::Error::custom)?;
+
+        Ok(question)
+    }
+}
+
+impl Serialize for DnsRecord {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        DnsRecord::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DnsRecord {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<DnsRecord, D::Error> {
+        let mut record = DnsRecord::deserialize(deserializer)?;
+        check_record(&record).map_err(de::Error::custom)?;
+
+        // the length read off the wire, left out of the JSON
+        if let DnsRecord::UNKNOWN { ref mut len, ref data, .. } = record {
+            *len = data.len() as u16;
+        }
+
+        Ok(record)
+    }
+}
+
+impl Serialize for DnsPacket {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        DnsPacket::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DnsPacket {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<DnsPacket, D::Error> {
+        let mut packet = DnsPacket::deserialize(deserializer)?;
+
+        let counts = [packet.questions.len(), packet.answers.len(), packet.authorities.len(), packet.resources.len()];
+        if counts.iter().any(|x| *x > u16::MAX as usize) {
+            return Err(de::Error::custom("A section has more entries than a packet can count"));
+        }
+        packet.header.ques_count = counts[0] as u16;
+        packet.header.ans_count = counts[1] as u16;
+        packet.header.auth_count = counts[2] as u16;
+        packet.header.res_count = counts[3] as u16;
+
+        Ok(packet)
+    }
+}
+
+/// Bytes as lowercase hex, ex. "0a00020f"
+pub mod hex {
+    use serde::de::{ self, Deserializer };
+    use serde::{ Deserialize, Serializer };
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        let hex: String = bytes.iter().map(|x| format!("{:02x}", x)).collect();
+        serializer.serialize_str(&hex)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        if hex.len() % 2 != 0 || !hex.is_ascii() {
+            return Err(de::Error::custom("Hex data must be an even number of hex digits"));
+        }
+
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| de::Error::custom(format!("Invalid hex {}", &hex[i..i + 2]))))
+            .collect()
+    }
+}
+
+/// Check a name as it's checked when written, internationalized names by their ASCII form
+fn check_name(name: &str) -> Result<()> {
+    if name.is_ascii() {
+        Ok(dns_name::validate(name)?)
+    } else {
+        Ok(dns_name::validate(&idna::to_ascii(name)?)?)
+    }
+}
+
+/// Check that a record can be written as it is
+fn check_record(record: &DnsRecord) -> Result<()> {
+    check_name(record.domain())?;
+
+    match record {
+        DnsRecord::NS { host, .. }
+        | DnsRecord::CNAME { host, .. }
+        | DnsRecord::PTR { host, .. }
+        | DnsRecord::MX { host, .. }
+        | DnsRecord::SRV { host, .. } => check_name(host),
+        DnsRecord::SOA { m_name, r_name, .. } => {
+            check_name(m_name)?;
+            check_name(r_name)
+        }
+        // written as strings of at most 255 bytes, each taking a length byte more
+        DnsRecord::TXT { data, .. } => {
+            let len: usize = data.iter().map(|x| x.len() + x.len().div_ceil(255).max(1)).sum();
+            if len > MAX_RDATA {
+                return Err(DnsError::InvalidRecord("TXT data is too long for a record".to_string()));
+            }
+            Ok(())
+        }
+        DnsRecord::CHAOS_TXT { data, .. } if data.len() > 255 => Err(DnsError::InvalidRecord("CHAOS TXT data is longer than 255 bytes".to_string())),
+        DnsRecord::UNKNOWN { q_type, data, .. } => {
+            if !matches!(QueryType::from_u16(*q_type), QueryType::UNKNOWN(_)) {
+                return Err(DnsError::InvalidRecord(format!("Type {} has a record of its own, it can't be given as unknown", q_type)));
+            }
+            if data.len() > MAX_RDATA {
+                return Err(DnsError::InvalidRecord("Unknown data is too long for a record".to_string()));
+            }
+            Ok(())
+        }
+        DnsRecord::OPT { data, .. } if data.len() > MAX_RDATA => Err(DnsError::InvalidRecord("OPT options are too long for a record".to_string())),
+        _ => Ok(()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response() -> DnsPacket {
+        let mut query = DnsPacket::new();
+        query.questions.push(DnsQuestion::new("example.com".to_string(), QueryType::MX));
+
+        let mut res = DnsPacket::response_to(&query)
+            .question(query.questions[0].clone())
+            .answer(DnsRecord::MX { domain: "example.com".to_string(), priority: 10, host: "mail.example.com".to_string(), ttl: 300 })
+            .answer(DnsRecord::TXT { domain: "example.com".to_string(), data: vec!["v=spf1 -all".to_string(), "".to_string()], ttl: 300 })
+            .answer(DnsRecord::UNKNOWN { domain: "example.com".to_string(), q_type: 65280, len: 3, data: vec![0x0a, 0x00, 0x02], ttl: 60 })
+            .authority(DnsRecord::SOA {
+                domain: "example.com".to_string(),
+                m_name: "ns1.example.com".to_string(),
+                r_name: "hostmaster.example.com".to_string(),
+                serial: 2024010101,
+                refresh: 3600,
+                retry: 600,
+                expire: 86400,
+                minimum: 300,
+                ttl: 3600,
+            });
+        res.header.auth_data = true;
+        res.resources.push(DnsRecord::A { domain: "mail.example.com".to_string(), addr_v4: "192.0.2.25".parse().unwrap(), ttl: 300 });
+        res.resources.push(DnsRecord::AAAA { domain: "mail.example.com".to_string(), addr: "2001:db8::25".parse().unwrap(), ttl: 300 });
+        res.resources.push(DnsRecord::OPT { payload_size: 1232, ext_rcode: 0, version: 0, flags: 0x8000, data: vec![0, 10, 0, 0] });
+
+        res
+    }
+
+    #[test]
+    fn packet_round_trips_through_json() {
+        let mut res = response();
+        let json = serde_json::to_string(&res).unwrap();
+        let mut back: DnsPacket = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(back.answers, res.answers);
+        assert_eq!(back.authorities, res.authorities);
+        assert_eq!(back.resources, res.resources);
+        assert_eq!(back.header.ans_count, 3);
+        // the same on the wire, header bits and all
+        assert_eq!(back.to_bytes().unwrap(), res.to_bytes().unwrap());
+    }
+
+    #[test]
+    fn json_reads_as_the_module_says() {
+        let res = response();
+        let json = serde_json::to_value(&res).unwrap();
+
+        assert_eq!(json["answers"][0], serde_json::json!({"type":"MX","domain":"example.com","priority":10,"host":"mail.example.com","ttl":300}));
+        assert_eq!(json["answers"][2]["data"], "0a0002");
+        assert_eq!(json["questions"][0]["q_type"], "MX");
+        assert_eq!(json["header"]["res_code"], "NO_ERR");
+        assert_eq!(json["resources"][0]["addr_v4"], "192.0.2.25");
+        assert!(json["header"].get("ans_count").is_none());
+    }
+
+    #[test]
+    fn json_that_cant_go_on_the_wire_is_rejected() {
+        let label = "a".repeat(64);
+        let bad = [
+            format!(r#"{{"type":"A","domain":"{}.example","addr_v4":"192.0.2.1","ttl":60}}"#, label),
+            r#"{"type":"UNKNOWN","domain":"example.com","q_type":1,"data":"c0000201","ttl":60}"#.to_string(),
+            r#"{"type":"UNKNOWN","domain":"example.com","q_type":65280,"data":"0a0","ttl":60}"#.to_string(),
+            r#"{"type":"CHAOS_TXT","domain":"version.bind","data":"x"}"#.replace("\"x\"", &format!("\"{}\"", "x".repeat(256))),
+        ];
+        for json in &bad {
+            assert!(serde_json::from_str::<DnsRecord>(json).is_err(), "{}", json);
+        }
+
+        assert!(serde_json::from_str::<Opcode>("16").is_err());
+        assert!(serde_json::from_str::<QueryType>(r#""TYPE65280""#).is_ok());
+    }
+}
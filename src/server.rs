@@ -0,0 +1,1163 @@
+//! The server as the binary runs it, from its command line to the sockets answering queries
+//! run reads the flags, binds the sockets and serves them until a signal stops it, ctl sends a command to a running one
+//! The stats and the cache it keeps can be read from the same process
+
+pub use crate::blocklist::BlockMode;
+pub use crate::buffer_pool::PoolStats;
+pub use crate::cache::{ dump as dump_cache, flush as flush_cache, flush_name as flush_cache_name, CacheStats, ShardStats };
+pub use crate::stats::Stats;
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpListener, UdpSocket, SocketAddr};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use log::{ error, info, warn };
+
+use crate::acl;
+use crate::amplification;
+#[cfg(feature = "async")]
+use crate::async_server;
+#[cfg(target_os = "linux")]
+use crate::batch;
+use crate::blocklist;
+use crate::cache;
+use crate::chaos;
+#[cfg(unix)]
+use crate::control;
+use crate::cookies;
+use crate::dns64::{ self, Dns64 };
+use crate::dns_name;
+use crate::doh;
+#[cfg(feature = "tls")]
+use crate::doh_client;
+#[cfg(feature = "doq")]
+use crate::doq;
+#[cfg(feature = "tls")]
+use crate::dot_client;
+#[cfg(unix)]
+use crate::event_loop;
+use crate::forwarding;
+use crate::hosts;
+use crate::logging;
+use crate::notify;
+use crate::pcap;
+use crate::query_log;
+use crate::rebinding;
+use crate::recursive;
+use crate::reload;
+use crate::resolve;
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_os = "macos"))]
+use crate::reuseport;
+use crate::rrl;
+use crate::secondary;
+#[cfg(unix)]
+use crate::signals;
+use crate::socks5;
+use crate::special;
+use crate::stats;
+#[cfg(target_os = "linux")]
+use crate::systemd;
+#[cfg(feature = "tls")]
+use crate::tls;
+use crate::transport;
+use crate::tsig;
+use crate::update;
+use crate::views;
+use crate::workers;
+use crate::xfr;
+use crate::zone;
+use crate::resolve::Resolution;
+#[cfg(feature = "tls")]
+use crate::upstreams::Protocol;
+use crate::upstreams::{ Strategy, Upstream, Upstreams };
+use crate::workers::WorkerPool;
+
+// Without --bind the IPv4 address must bind, the IPv6 one is skipped if the host has no IPv6 loopback
+const LISTEN_V4: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), 2053);
+const LISTEN_V6: SocketAddr = SocketAddr::new(std::net::IpAddr::V6(Ipv6Addr::LOCALHOST), 2053);
+
+/// Run the server as the command line says, args as std::env::args gives them, exiting if it's invalid
+/// The flags are described in main.rs
+pub fn run(args: &[String]) {
+    let log_level = match flag_value(args, "--log-level") {
+        Some(x) => logging::parse_level(x)
+            .unwrap_or_else(|| fail(&format!("Invalid value for --log-level: {} (expected error, warn, info, debug, trace or off)", x))),
+        None => log::LevelFilter::Info,
+    };
+    let log_target = match flag_value(args, "--log-target") {
+        Some(x) => logging::parse_target(x)
+            .unwrap_or_else(|| fail(&format!("Invalid value for --log-target: {} (expected stderr, syslog or file:<path>)", x))),
+        None => logging::Target::Stderr,
+    };
+    logging::init(log_level, &log_target).unwrap_or_else(|e| fail(&format!("Invalid --log-target {}: {}", flag_value(args, "--log-target").unwrap_or(""), e)));
+    stats::start();
+
+    let cache_file_max = match flag_value(args, "--cache-file-max") {
+        Some(x) => match x.parse::<usize>() {
+            Ok(n) => n,
+            _ => fail(&format!("Invalid value for --cache-file-max: {} (expected a number)", x)),
+        },
+        None => 10_000,
+    };
+    let cache_file = flag_value(args, "--cache-file").map(|x| (x.to_string(), cache_file_max));
+
+    if let Some(x) = flag_value(args, "--query-log") {
+        let format = match flag_value(args, "--query-log-format") {
+            Some(x) => query_log::Format::from_name(x)
+                .unwrap_or_else(|| fail(&format!("Invalid value for --query-log-format: {} (expected tsv or json)", x))),
+            None => query_log::Format::TSV,
+        };
+        let max_size = match flag_value(args, "--query-log-max-size").map(|x| (x, x.parse::<u64>())) {
+            Some((_, Ok(n))) if n > 0 => n,
+            Some((x, _)) => fail(&format!("Invalid value for --query-log-max-size: {} (expected a number of bytes above 0)", x)),
+            None => 100_000_000,
+        };
+        let keep = match flag_value(args, "--query-log-keep").map(|x| (x, x.parse::<usize>())) {
+            Some((_, Ok(n))) => n,
+            Some((x, _)) => fail(&format!("Invalid value for --query-log-keep: {} (expected a number)", x)),
+            None => 5,
+        };
+
+        query_log::open(query_log::Config {
+            path: x.into(),
+            format: format,
+            max_size: max_size,
+            keep: keep,
+        })
+        .unwrap_or_else(|e| fail(&format!("Invalid --query-log {}: {}", x, e)));
+        info!("Logging queries to {}, rotated at {} bytes with {} old files kept", x, max_size, keep);
+    } else if ["--query-log-format", "--query-log-max-size", "--query-log-keep"].iter().any(|x| flag_value(args, x).is_some()) {
+        fail("--query-log-format, --query-log-max-size or --query-log-keep is given without --query-log");
+    }
+
+    // before any thread starts, they all have to block the signals
+    #[cfg(unix)]
+    if let Err(e) = signals::handle_signals(cache_file.clone()) {
+        error!("Failed to set up signal handling: {}", e);
+    }
+
+    // after the signals are blocked, so the writer thread can't be the one a SIGTERM kills before the capture is written out
+    let pcap_upstream = args.iter().any(|arg| arg == "--pcap-upstream");
+    if let Some(x) = flag_value(args, "--pcap") {
+        pcap::open(x, pcap_upstream).unwrap_or_else(|e| fail(&format!("Invalid --pcap {}: {}", x, e)));
+        info!("Capturing client{} traffic to {}", if pcap_upstream { " and upstream" } else { "" }, x);
+    } else if pcap_upstream {
+        fail("--pcap-upstream is given without --pcap");
+    }
+
+    // before any upstream is parsed, bootstrapping a hostname already goes through it
+    if let Some(x) = flag_value(args, "--proxy") {
+        let proxy = socks5::Proxy::parse(x)
+            .unwrap_or_else(|e| fail(&format!("Invalid --proxy {}: {}", x, e)));
+        info!("Sending upstream lookups through {}", proxy);
+        socks5::set_proxy(proxy);
+    }
+
+    let bootstrap = flag_value(args, "--bootstrap").map(|x| parse_addr("--bootstrap", x));
+
+    let tls_fallback = flag_value(args, "--tls-fallback").map(|x| parse_addr("--tls-fallback", x));
+
+    let resolvers: Vec<Upstream> = flag_values(args, "--resolver").into_iter()
+        .map(|x| parse_upstream(x, bootstrap, tls_fallback))
+        .collect();
+
+    let strategy = match flag_value(args, "--upstream-strategy") {
+        Some(x) => Strategy::from_name(x)
+            .unwrap_or_else(|| fail(&format!("Invalid value for --upstream-strategy: {} (expected fastest, round-robin, sequential or race)", x))),
+        None => Strategy::SEQUENTIAL,
+    };
+
+    forwarding::set_rules(forward_rules(args, strategy, bootstrap, tls_fallback));
+    let no_special_use: Vec<String> = flag_values(args, "--no-special-use").into_iter().map(|x| x.to_string()).collect();
+    if let Err(e) = special::set_disabled(&no_special_use) {
+        fail(&format!("Invalid value for --no-special-use: {}", e));
+    }
+    if let Some(x) = flag_value(args, "--hosts-ttl") {
+        match x.parse::<u32>() {
+            Ok(n) => hosts::set_ttl(n),
+            _ => fail(&format!("Invalid value for --hosts-ttl: {} (expected a number of seconds)", x)),
+        }
+    }
+    if let Some(x) = flag_value(args, "--hosts") {
+        let count = hosts::load(x).unwrap_or_else(|e| fail(&format!("Invalid --hosts: {}", e)));
+        info!("Answering for {} names from {}", count, x);
+    }
+    match flag_value(args, "--version-string") {
+        Some("none") => chaos::set_version(None),
+        Some(x) => chaos::set_version(Some(x.to_string())),
+        None => {}
+    }
+    if let Some(x) = flag_value(args, "--identity") {
+        chaos::set_identity(x.to_string());
+    }
+    let sinkholes: Vec<IpAddr> = flag_values(args, "--sinkhole").into_iter()
+        .map(|x| x.parse().unwrap_or_else(|_| fail(&format!("Invalid value for --sinkhole: {} (expected an IPv4 or IPv6 address)", x))))
+        .collect();
+    let block_mode = match flag_value(args, "--block-mode") {
+        Some(x) => BlockMode::from_name(x)
+            .unwrap_or_else(|| fail(&format!("Invalid value for --block-mode: {} (expected nxdomain, null, refused or sinkhole)", x))),
+        None => BlockMode::NXDOMAIN,
+    };
+    let sinkhole_v4 = sinkholes.iter().find_map(|x| match x { IpAddr::V4(x) => Some(*x), _ => None });
+    let sinkhole_v6 = sinkholes.iter().find_map(|x| match x { IpAddr::V6(x) => Some(*x), _ => None });
+    if sinkholes.len() > sinkhole_v4.iter().count() + sinkhole_v6.iter().count() {
+        fail("--sinkhole takes at most one IPv4 and one IPv6 address");
+    }
+    if block_mode == BlockMode::SINKHOLE && sinkholes.is_empty() {
+        fail("--block-mode sinkhole needs a --sinkhole address");
+    }
+    if block_mode != BlockMode::SINKHOLE && !sinkholes.is_empty() {
+        fail("--sinkhole is given without --block-mode sinkhole");
+    }
+    blocklist::set_mode(block_mode, sinkhole_v4, sinkhole_v6);
+    if let Some(x) = flag_value(args, "--block-ttl") {
+        match x.parse::<u32>() {
+            Ok(n) => blocklist::set_ttl(n),
+            _ => fail(&format!("Invalid value for --block-ttl: {} (expected a number of seconds)", x)),
+        }
+    }
+    let blocklists: Vec<String> = flag_values(args, "--blocklist").into_iter().map(|x| x.to_string()).collect();
+    let allowlists: Vec<String> = flag_values(args, "--allowlist").into_iter().map(|x| x.to_string()).collect();
+    let allowed: Vec<String> = flag_values(args, "--allow").into_iter().map(|x| x.to_string()).collect();
+    if blocklists.is_empty() && !(allowlists.is_empty() && allowed.is_empty()) {
+        fail("--allowlist or --allow is given without any --blocklist");
+    }
+    if !blocklists.is_empty() {
+        let (blocked, allowed) = blocklist::load(&blocklists, &allowlists, &allowed).unwrap_or_else(|e| fail(&format!("Invalid --blocklist, --allowlist or --allow: {}", e)));
+        info!("Blocking {} unique domains from {} except {} allowed, answered with {}", blocked, blocklists.join(", "), allowed, block_mode.name());
+    }
+    zone::set_zones(zones(args));
+    views::set_views(views(args, strategy, bootstrap, tls_fallback));
+    for view in views::views() {
+        info!("View {}: {}", view.name, view.summary());
+    }
+    notify::set_targets(flag_values(args, "--also-notify").into_iter()
+        .map(|x| parse_addr("--also-notify", x))
+        .collect());
+    let secondaries = secondaries(args);
+    let secondary_dir = flag_value(args, "--secondary-dir");
+    for secondary in &secondaries {
+        secondary::start(secondary.clone(), secondary_dir);
+    }
+    if let Some(x) = flag_value(args, "--zone-poll") {
+        match x.parse::<u64>() {
+            Ok(n) if n > 0 => {
+                info!("Checking the zone files for changes every {}s", n);
+                reload::watch(Duration::from_secs(n));
+            }
+            _ => fail(&format!("Invalid value for --zone-poll: {} (expected a number of seconds above 0)", x)),
+        }
+    }
+    xfr::set_allowed(flag_values(args, "--allow-transfer").into_iter()
+        .map(|x| xfr::Network::parse(x).unwrap_or_else(|e| fail(&format!("Invalid value for --allow-transfer: {} ({})", x, e))))
+        .collect());
+    let recursion: Vec<xfr::Network> = flag_values(args, "--allow-recursion").into_iter()
+        .map(|x| xfr::Network::parse(x).unwrap_or_else(|e| fail(&format!("Invalid value for --allow-recursion: {} ({})", x, e))))
+        .collect();
+    if !recursion.is_empty() {
+        acl::set_allowed(recursion);
+    }
+    info!("Resolving for {}", acl::allowed().iter().map(|x| x.to_string()).collect::<Vec<_>>().join(", "));
+    if let Some(x) = flag_value(args, "--rrl") {
+        let rate = match x.parse::<u32>() {
+            Ok(n) if n > 0 => n,
+            _ => fail(&format!("Invalid value for --rrl: {} (expected a number of responses a second above 0)", x)),
+        };
+        let window = match flag_value(args, "--rrl-window").map(|x| (x, x.parse::<u32>())) {
+            Some((_, Ok(n))) if n > 0 => n,
+            Some((x, _)) => fail(&format!("Invalid value for --rrl-window: {} (expected a number of seconds above 0)", x)),
+            None => 5,
+        };
+        let slip = match flag_value(args, "--rrl-slip").map(|x| (x, x.parse::<u32>())) {
+            Some((_, Ok(n))) => n,
+            Some((x, _)) => fail(&format!("Invalid value for --rrl-slip: {} (expected a number)", x)),
+            None => 2,
+        };
+        let table_size = match flag_value(args, "--rrl-table-size").map(|x| (x, x.parse::<usize>())) {
+            Some((_, Ok(n))) if n > 0 => n,
+            Some((x, _)) => fail(&format!("Invalid value for --rrl-table-size: {} (expected a number above 0)", x)),
+            None => 10_000,
+        };
+
+        rrl::set_limits(rrl::Limits {
+            rate: rate,
+            window: window,
+            slip: slip,
+            table_size: table_size,
+        });
+        let slipped = match slip {
+            0 => "the rest dropped".to_string(),
+            n => format!("1 in {} of the rest sent truncated", n),
+        };
+        info!("Rate limiting UDP responses to {} a second per prefix and kind in bursts of up to {}s, {}", rate, window, slipped);
+    } else if ["--rrl-window", "--rrl-slip", "--rrl-table-size"].iter().any(|x| flag_value(args, x).is_some()) {
+        fail("--rrl-window, --rrl-slip or --rrl-table-size is given without --rrl");
+    }
+    if let Some(x) = flag_value(args, "--max-amplification") {
+        let factor = match x.parse::<f64>() {
+            Ok(n) if n >= 1.0 && n.is_finite() => n,
+            _ => fail(&format!("Invalid value for --max-amplification: {} (expected a factor of at least 1)", x)),
+        };
+
+        amplification::set_factor(factor);
+        info!("Truncating UDP responses more than {} times the size of their query for clients without a valid cookie", factor);
+    }
+    let required = args.iter().any(|arg| arg == "--require-cookies");
+    if required || args.iter().any(|arg| arg == "--cookies") {
+        let rotation = match flag_value(args, "--cookie-rotation").map(|x| (x, x.parse::<u64>())) {
+            Some((_, Ok(n))) if n > 0 => n,
+            Some((x, _)) => fail(&format!("Invalid value for --cookie-rotation: {} (expected a number of seconds above 0)", x)),
+            None => 3600,
+        };
+        let grace = match flag_value(args, "--cookie-grace").map(|x| (x, x.parse::<u64>())) {
+            Some((_, Ok(n))) => n,
+            Some((x, _)) => fail(&format!("Invalid value for --cookie-grace: {} (expected a number of seconds)", x)),
+            None => 300,
+        };
+
+        cookies::enable(Duration::from_secs(rotation), Duration::from_secs(grace), required);
+        info!("Giving out DNS cookies, the secret replaced every {}s, {}", rotation, if required { "required over UDP" } else { "not required" });
+    } else if flag_value(args, "--cookie-rotation").is_some() || flag_value(args, "--cookie-grace").is_some() {
+        fail("--cookie-rotation or --cookie-grace is given without --cookies");
+    }
+    update::set_allowed(flag_values(args, "--allow-update").into_iter()
+        .map(|x| xfr::Network::parse(x).unwrap_or_else(|e| fail(&format!("Invalid value for --allow-update: {} ({})", x, e))))
+        .collect());
+    tsig::set_keys(tsig_keys(args));
+    tsig::set_required(flag_values(args, "--require-tsig").into_iter()
+        .map(|x| tsig::Operation::parse(x).unwrap_or_else(|e| fail(&format!("Invalid value for --require-tsig: {}", e))))
+        .collect());
+    if tsig::keys().is_empty() && !tsig::required().is_empty() {
+        fail("--require-tsig is given without any --tsig-key");
+    }
+
+    // shared by every thread for the life of the process
+    let resolution = if resolvers.is_empty() {
+        Resolution::Recursive
+    } else {
+        Resolution::Forward(Box::leak(Box::new(Upstreams::new(resolvers, strategy))))
+    };
+
+    recursive::set_prefer_ipv6(args.iter().any(|arg| arg == "--prefer-ipv6"));
+    recursive::set_qname_minimization(!args.iter().any(|arg| arg == "--no-qname-minimization"));
+
+    if let Some(x) = flag_value(args, "--max-referrals") {
+        match x.parse::<usize>() {
+            Ok(n) => recursive::set_max_referrals(n),
+            _ => fail(&format!("Invalid value for --max-referrals: {} (expected a number)", x)),
+        }
+    }
+
+    if let Some(x) = flag_value(args, "--max-queries") {
+        match x.parse::<usize>() {
+            Ok(n) if n > 0 => recursive::set_max_queries(n),
+            _ => fail(&format!("Invalid value for --max-queries: {} (expected a number above 0)", x)),
+        }
+    }
+
+    if let Some(x) = flag_value(args, "--root-hints") {
+        let hints = recursive::load_root_hints(x)
+            .unwrap_or_else(|e| fail(&format!("Invalid --root-hints: {}", e)));
+        info!("Root hints from {}: {}", x, hints.iter().map(|x| x.name.as_str()).collect::<Vec<_>>().join(", "));
+        recursive::set_root_hints(hints);
+    }
+
+    if let Some(arg) = args.iter().find(|arg| *arg == "--dns64" || arg.starts_with("--dns64=")) {
+        let prefix = match arg.split_once('=') {
+            Some((_, x)) => dns64::parse_prefix(x).unwrap_or_else(|e| fail(&format!("Invalid --dns64 prefix: {}", e))),
+            None => dns64::WELL_KNOWN_PREFIX,
+        };
+        info!("Synthesizing AAAA records in {}/96", prefix);
+        dns64::set_dns64(Dns64 {
+            prefix: prefix,
+            allow_private: args.iter().any(|arg| arg == "--dns64-allow-private"),
+        });
+    }
+
+    let rebind_allowed: Vec<String> = flag_values(args, "--rebind-allow").into_iter().map(|x| x.to_string()).collect();
+    if args.iter().any(|arg| arg == "--rebind-protection") {
+        rebinding::enable(&rebind_allowed).unwrap_or_else(|e| fail(&format!("Invalid value for --rebind-allow: {}", e)));
+        info!("Filtering reserved addresses from upstream answers{}", if rebind_allowed.is_empty() { String::new() } else { format!(" except for {}", rebind_allowed.join(", ")) });
+    } else if !rebind_allowed.is_empty() {
+        fail("--rebind-allow is given without --rebind-protection");
+    }
+
+    resolve::set_verbose(args.iter().any(|arg| arg == "--verbose"));
+    resolve::set_minimal_responses(args.iter().any(|arg| arg == "--minimal-responses"));
+
+    if let Some(x) = flag_value(args, "--lookup-timeout") {
+        match x.parse::<u64>() {
+            Ok(ms) if ms > 0 => resolve::set_lookup_timeout(Duration::from_millis(ms)),
+            _ => fail(&format!("Invalid value for --lookup-timeout: {} (expected milliseconds)", x)),
+        }
+    }
+
+    if let Some(x) = flag_value(args, "--cache-size") {
+        match x.parse::<usize>() {
+            Ok(n) => cache::set_capacity(n),
+            _ => fail(&format!("Invalid value for --cache-size: {} (expected a number)", x)),
+        }
+    }
+
+    if let Some(x) = flag_value(args, "--negative-ttl") {
+        match x.parse::<u32>() {
+            Ok(secs) => cache::set_negative_ttl(secs),
+            _ => fail(&format!("Invalid value for --negative-ttl: {} (expected seconds)", x)),
+        }
+    }
+
+    let min_ttl = match flag_value(args, "--min-ttl") {
+        Some(x) => match x.parse::<u32>() {
+            Ok(secs) => secs,
+            _ => fail(&format!("Invalid value for --min-ttl: {} (expected seconds)", x)),
+        },
+        None => 0,
+    };
+    let max_ttl = match flag_value(args, "--max-ttl") {
+        Some(x) => match x.parse::<u32>() {
+            Ok(secs) if secs >= min_ttl => secs,
+            _ => fail(&format!("Invalid value for --max-ttl: {} (expected seconds, at least --min-ttl)", x)),
+        },
+        None => 86400.max(min_ttl),
+    };
+    cache::set_ttl_limits(min_ttl, max_ttl);
+
+    if let Some(x) = flag_value(args, "--min-served-ttl") {
+        match x.parse::<u32>() {
+            Ok(ttl) if ttl <= 1 => cache::set_min_served_ttl(ttl),
+            _ => fail(&format!("Invalid value for --min-served-ttl: {} (expected 0 or 1)", x)),
+        }
+    }
+
+    if let Some(x) = flag_value(args, "--prefetch-hits") {
+        match x.parse::<u32>() {
+            Ok(n) => cache::set_prefetch_hits(n),
+            _ => fail(&format!("Invalid value for --prefetch-hits: {} (expected a number)", x)),
+        }
+    }
+
+    // after the cache is configured, it's sized when the first entry goes in
+    match &cache_file {
+        Some((path, _)) if !args.iter().any(|arg| arg == "--flush-on-start") => match cache::load(path) {
+            Ok(n) => info!("Loaded {} cache entries from {}", n, path),
+            Err(e) => warn!("Ignoring the saved cache in {}: {}", path, e),
+        },
+        _ => (),
+    }
+
+    if args.iter().any(|arg| arg == "--serve-stale") {
+        let retention = match flag_value(args, "--stale-retention") {
+            Some(x) => match x.parse::<u64>() {
+                Ok(secs) => secs,
+                _ => fail(&format!("Invalid value for --stale-retention: {} (expected seconds)", x)),
+            },
+            None => 3600,
+        };
+        info!("Serving stale records up to {}s past their TTL", retention);
+        cache::set_serve_stale(true, Duration::from_secs(retention));
+    }
+
+    if let Some(x) = flag_value(args, "--lookup-retries") {
+        match x.parse::<usize>() {
+            Ok(n) => resolve::set_lookup_retries(n),
+            _ => fail(&format!("Invalid value for --lookup-retries: {} (expected a number)", x)),
+        }
+    }
+
+    let binds: Vec<SocketAddr> = flag_values(args, "--bind").into_iter()
+        .map(|x| parse_addr("--bind", x))
+        .collect();
+
+    let reuseport = match flag_value(args, "--reuseport") {
+        Some(x) => match x.parse::<usize>() {
+            Ok(n) if n > 0 => n,
+            _ => fail(&format!("Invalid value for --reuseport: {} (expected a positive number)", x)),
+        },
+        None => 0,
+    };
+
+    let (udp_sockets, tcp_listeners) = match activated_sockets() {
+        Some(x) => x,
+        None => bind_all(binds, reuseport),
+    };
+
+    let certs = match (flag_value(args, "--tls-cert"), flag_value(args, "--tls-key")) {
+        (Some(cert), Some(key)) => Some((cert, key)),
+        (None, None) => None,
+        _ => fail("--tls-cert and --tls-key must be given together"),
+    };
+
+    if let Some((cert, key)) = certs {
+        start_tls(args, cert, key, resolution);
+    }
+
+    if args.iter().any(|arg| arg == "--doq") {
+        match certs {
+            Some((cert, key)) => start_quic(args, cert, key, resolution),
+            None => fail("--doq requires --tls-cert and --tls-key"),
+        }
+    }
+
+    if let Some(bind) = flag_value(args, "--doh-bind") {
+        let bind = parse_addr("--doh-bind", bind);
+        let doh_listener = TcpListener::bind(bind).unwrap_or_else(|e| fail(&bind_error(bind, e)));
+
+        match certs {
+            Some((cert, key)) => start_https(doh_listener, cert, key, resolution),
+            None => {
+                info!("Serving DNS over HTTP on {}{}", bind, doh::DOH_PATH);
+                thread::spawn(move || doh::serve_http(doh_listener, resolution));
+            }
+        }
+    }
+
+    for zone in zone::zones().iter().filter(|x| !secondaries.iter().any(|s| s.origin == x.origin)) {
+        info!("Serving zone {} with {} records", if zone.origin.is_empty() { "." } else { &zone.origin }, zone.record_count());
+    }
+    for secondary in &secondaries {
+        info!("Serving zone {} as a secondary of {}", if secondary.origin.is_empty() { "." } else { &secondary.origin }, secondary.primary);
+    }
+    for network in xfr::allowed() {
+        info!("Allowing zone transfers to {}", network);
+    }
+    for network in update::allowed() {
+        info!("Allowing zone updates from {}", network);
+    }
+    for key in tsig::keys() {
+        info!("Accepting transfers and updates signed with the TSIG key {} ({})", key.name, key.algorithm);
+    }
+    for operation in tsig::required() {
+        info!("Refusing every {:?} that isn't signed", operation);
+    }
+    for target in notify::targets() {
+        info!("Notifying {} of zone changes", target);
+    }
+
+    for rule in forwarding::rules() {
+        for upstream in rule.upstreams.all() {
+            info!("Forwarding {} to {}", rule.domain, upstream);
+        }
+    }
+
+    match resolution {
+        Resolution::Recursive => {
+            info!("Resolving Recursively from {} root servers", recursive::root_hints().len());
+            recursive::start_priming();
+        }
+        Resolution::Forward(upstreams) => {
+            for upstream in upstreams.all() {
+                info!("Resolver: {}", upstream);
+            }
+            if upstreams.all().len() > 1 {
+                info!("Picking resolvers with the {:?} strategy", upstreams.strategy());
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    if let Some(x) = flag_value(args, "--control") {
+        control::serve(x).unwrap_or_else(|e| fail(&format!("Invalid --control {}: {}", x, e)));
+        info!("Taking control commands on {}", x);
+    }
+
+    serve(args, udp_sockets, tcp_listeners, resolution);
+}
+
+/// pine-dns ctl [--control <path>] <command>, send a command to a running server's control socket and print the answer
+/// Exits non-zero when the server can't be reached or answers with an error
+#[cfg(unix)]
+pub fn ctl(args: &[String]) -> ! {
+    let path = flag_value(args, "--control").unwrap_or(control::DEFAULT_PATH);
+
+    let mut words = Vec::new();
+    let mut rest = args[2..].iter();
+    while let Some(x) = rest.next() {
+        if x == "--control" {
+            rest.next();
+        } else {
+            words.push(x.as_str());
+        }
+    }
+    if words.is_empty() {
+        fail("Usage: pine-dns ctl [--control <path>] stats|upstreams|flush-cache|flush-name <domain>|reload-zones|reload-blocklists|reload|quit");
+    }
+
+    match control::send(path, &words.join(" ")) {
+        Ok(x) if x.starts_with("{\"error\"") => fail(&x),
+        Ok(x) => {
+            println!("{}", x);
+            process::exit(0);
+        }
+        Err(e) => fail(&format!("Failed to reach the server on {}: {}", path, e)),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn ctl(_args: &[String]) -> ! {
+    fail("pine-dns ctl needs a unix socket, which this platform doesn't have")
+}
+
+/// Bind the --bind addresses, or the default ones without any, exiting if any fail
+fn bind_all(binds: Vec<SocketAddr>, reuseport: usize) -> (Vec<UdpSocket>, Vec<TcpListener>) {
+    let mut udp_sockets = Vec::new();
+    let mut tcp_listeners = Vec::new();
+
+    if binds.is_empty() {
+        let (udp, tcp_listener) = listen(LISTEN_V4, reuseport).unwrap_or_else(|e| fail(&bind_error(LISTEN_V4, e)));
+        udp_sockets.extend(udp);
+        tcp_listeners.push(tcp_listener);
+
+        // Dual stack by default, skipping IPv6 if it isn't there
+        match listen(LISTEN_V6, reuseport) {
+            Ok((udp, tcp_listener)) => {
+                udp_sockets.extend(udp);
+                tcp_listeners.push(tcp_listener);
+            }
+            Err(e) => warn!("Not listening on {}: {}", LISTEN_V6, e),
+        }
+    } else {
+        // Bind everything before giving up so every failing address is reported
+        let mut failures = Vec::new();
+
+        for addr in binds {
+            match listen(addr, reuseport) {
+                Ok((udp, tcp_listener)) => {
+                    udp_sockets.extend(udp);
+                    tcp_listeners.push(tcp_listener);
+                }
+                Err(e) => failures.push(bind_error(addr, e)),
+            }
+        }
+
+        if !failures.is_empty() {
+            fail(&failures.join("\n"));
+        }
+    }
+
+    (udp_sockets, tcp_listeners)
+}
+
+/// Sockets passed in by systemd, exiting if they can't be served
+#[cfg(target_os = "linux")]
+fn activated_sockets() -> Option<(Vec<UdpSocket>, Vec<TcpListener>)> {
+    let listeners = systemd::listen_fds().unwrap_or_else(|e| fail(&e.to_string()))?;
+
+    for udp_socket in &listeners.udp_sockets {
+        if let Ok(addr) = udp_socket.local_addr() {
+            info!("Listening on {} (UDP from systemd)", addr);
+        }
+    }
+    for tcp_listener in &listeners.tcp_listeners {
+        if let Ok(addr) = tcp_listener.local_addr() {
+            info!("Listening on {} (TCP from systemd)", addr);
+        }
+    }
+
+    Some((listeners.udp_sockets, listeners.tcp_listeners))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn activated_sockets() -> Option<(Vec<UdpSocket>, Vec<TcpListener>)> {
+    None
+}
+
+/// Let systemd know startup is done, only matters for Type=notify units
+#[cfg(target_os = "linux")]
+fn notify_ready() {
+    if let Err(e) = systemd::notify_ready() {
+        error!("Failed to notify systemd: {}", e);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn notify_ready() {}
+
+/// Serve the bound sockets with the poll loop, on the tokio runtime, or on threads
+fn serve(args: &[String], udp_sockets: Vec<UdpSocket>, tcp_listeners: Vec<TcpListener>, resolution: Resolution) {
+    // every socket is bound, anything arriving from here on queues until its loop picks it up
+    notify_ready();
+
+    // only the threaded server answers in a client's view, checks cookies or filters rebinding, the others go straight to the default upstreams
+    let event_loop = args.iter().any(|arg| arg == "--event-loop");
+    let use_async = cfg!(feature = "async") && !args.iter().any(|arg| arg == "--sync");
+    let threaded = !event_loop && !use_async;
+    if !views::views().is_empty() && !threaded {
+        fail("--view can't be used with --event-loop or the async server, add --sync to a build with the async feature");
+    }
+    if cookies::is_enabled() && !threaded {
+        fail("--cookies can't be used with --event-loop or the async server, add --sync to a build with the async feature");
+    }
+    if rebinding::is_enabled() && !threaded {
+        fail("--rebind-protection can't be used with --event-loop or the async server, add --sync to a build with the async feature");
+    }
+
+    if event_loop {
+        serve_event_loop(udp_sockets, tcp_listeners, resolution);
+    } else if use_async {
+        serve_async(udp_sockets, tcp_listeners, resolution);
+    } else {
+        serve_threaded(args, udp_sockets, tcp_listeners, resolution);
+    }
+}
+
+#[cfg(feature = "async")]
+fn serve_async(udp_sockets: Vec<UdpSocket>, tcp_listeners: Vec<TcpListener>, resolution: Resolution) {
+    info!("Answering asynchronously");
+    if let Err(e) = async_server::run(udp_sockets, tcp_listeners, resolution) {
+        fail(&format!("Async server stopped: {}", e));
+    }
+}
+
+#[cfg(not(feature = "async"))]
+fn serve_async(_udp_sockets: Vec<UdpSocket>, _tcp_listeners: Vec<TcpListener>, _resolution: Resolution) {
+    unreachable!("Built without the async feature");
+}
+
+/// Serve UDP from a single threaded poll loop
+/// TCP is rare enough that it keeps a thread per listener
+#[cfg(unix)]
+fn serve_event_loop(udp_sockets: Vec<UdpSocket>, tcp_listeners: Vec<TcpListener>, resolution: Resolution) {
+    for tcp_listener in tcp_listeners {
+        thread::spawn(move || transport::serve_tcp(tcp_listener, resolution));
+    }
+
+    info!("Answering from the event loop");
+    if let Err(e) = event_loop::run(udp_sockets, resolution) {
+        fail(&format!("Event loop stopped: {}", e));
+    }
+}
+
+#[cfg(not(unix))]
+fn serve_event_loop(_udp_sockets: Vec<UdpSocket>, _tcp_listeners: Vec<TcpListener>, _resolution: Resolution) {
+    fail("--event-loop needs poll(2), which this platform doesn't have");
+}
+
+/// Serve TCP with a thread per listener and UDP with the worker pool
+fn serve_threaded(args: &[String], mut udp_sockets: Vec<UdpSocket>, tcp_listeners: Vec<TcpListener>, resolution: Resolution) {
+    for tcp_listener in tcp_listeners {
+        thread::spawn(move || transport::serve_tcp(tcp_listener, resolution));
+    }
+
+    if args.iter().any(|arg| arg == "--batch") {
+        if cfg!(target_os = "linux") {
+            // a batch is answered in turn on the socket's thread, there's no pool for --workers to size
+            if flag_value(args, "--workers").is_some() {
+                fail("--batch answers each socket's queries on a single thread, drop --workers or --batch");
+            }
+            serve_batched(udp_sockets, resolution);
+            return;
+        }
+        warn!("--batch needs recvmmsg, which only Linux has, answering one packet at a time");
+    }
+
+    // The kernel already spreads queries over SO_REUSEPORT sockets, so each gets a thread and no pool
+    if flag_value(args, "--reuseport").is_some() {
+        serve_reuseport(udp_sockets, resolution);
+        return;
+    }
+
+    let workers = match flag_value(args, "--workers") {
+        Some(x) => match x.parse::<usize>() {
+            Ok(n) if n > 0 => n,
+            _ => fail(&format!("Invalid value for --workers: {} (expected a positive number)", x)),
+        },
+        None => workers::default_workers(),
+    };
+    info!("Answering with {} workers", workers);
+
+    // Each socket gets its own receive loop feeding the shared pool
+    let pool = WorkerPool::new(workers, resolution);
+    let last = udp_sockets.pop().expect("At least one socket is bound");
+    for udp_socket in udp_sockets {
+        let pool = pool.clone();
+        thread::spawn(move || pool.serve_udp(udp_socket));
+    }
+
+    pool.serve_udp(last);
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_os = "macos"))]
+fn serve_reuseport(mut udp_sockets: Vec<UdpSocket>, resolution: Resolution) {
+    info!("Answering with a thread for each of {} sockets", udp_sockets.len());
+
+    let last = udp_sockets.pop().expect("At least one socket is bound");
+    for udp_socket in udp_sockets {
+        thread::spawn(move || reuseport::serve_udp(udp_socket, resolution));
+    }
+
+    reuseport::serve_udp(last, resolution);
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_os = "macos")))]
+fn serve_reuseport(_udp_sockets: Vec<UdpSocket>, _resolution: Resolution) {
+    unreachable!("SO_REUSEPORT sockets can't be bound on this platform");
+}
+
+#[cfg(target_os = "linux")]
+fn serve_batched(mut udp_sockets: Vec<UdpSocket>, resolution: Resolution) {
+    info!("Answering in batches with a thread for each of {} sockets", udp_sockets.len());
+
+    let last = udp_sockets.pop().expect("At least one socket is bound");
+    for udp_socket in udp_sockets {
+        thread::spawn(move || batch::serve_udp(udp_socket, resolution));
+    }
+
+    batch::serve_udp(last, resolution);
+}
+
+#[cfg(not(target_os = "linux"))]
+fn serve_batched(_udp_sockets: Vec<UdpSocket>, _resolution: Resolution) {
+    unreachable!("recvmmsg is only available on Linux");
+}
+
+/// Bind UDP and TCP on an address
+/// With reuseport set, that many UDP sockets share the address
+fn listen(addr: SocketAddr, reuseport: usize) -> io::Result<(Vec<UdpSocket>, TcpListener)> {
+    let udp_sockets = if reuseport > 0 {
+        (0..reuseport).map(|_| bind_reuseport(addr)).collect::<io::Result<Vec<UdpSocket>>>()?
+    } else {
+        vec![UdpSocket::bind(addr)?]
+    };
+    let tcp_listener = TcpListener::bind(addr)?;
+
+    info!("Listening on {}", addr);
+
+    Ok((udp_sockets, tcp_listener))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_os = "macos"))]
+fn bind_reuseport(addr: SocketAddr) -> io::Result<UdpSocket> {
+    reuseport::bind_reuseport(addr)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_os = "macos")))]
+fn bind_reuseport(_addr: SocketAddr) -> io::Result<UdpSocket> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "SO_REUSEPORT isn't available on this platform, run without --reuseport"))
+}
+
+/// Explain a failed bind, with a hint for the common causes
+fn bind_error(addr: SocketAddr, e: io::Error) -> String {
+    let hint = match e.kind() {
+        io::ErrorKind::PermissionDenied => " (ports below 1024 need root or CAP_NET_BIND_SERVICE)",
+        io::ErrorKind::AddrInUse => " (is another DNS server running?)",
+        io::ErrorKind::AddrNotAvailable => " (no interface has that address)",
+        _ => "",
+    };
+
+    format!("Failed to bind {}: {}{}", addr, e, hint)
+}
+
+/// Parse the --forward <domain>=<resolver> values, or exit
+/// Resolvers given for the same domain are grouped into one rule
+fn forward_rules(args: &[String], strategy: Strategy, bootstrap: Option<SocketAddr>, tls_fallback: Option<SocketAddr>) -> Vec<forwarding::ForwardRule> {
+    let mut rules: Vec<(String, Vec<Upstream>)> = Vec::new();
+
+    for value in flag_values(args, "--forward") {
+        let (domain, resolver) = value.split_once('=')
+            .unwrap_or_else(|| fail(&format!("Invalid value for --forward: {} (expected domain=resolver)", value)));
+
+        if let Err(e) = dns_name::validate(domain) {
+            fail(&format!("Invalid domain in --forward {}: {}", value, e));
+        }
+        let domain = dns_name::normalize(domain);
+        if domain.is_empty() {
+            fail("--forward needs a domain, use --resolver to forward everything");
+        }
+
+        let upstream = parse_upstream(resolver, bootstrap, tls_fallback);
+        match rules.iter_mut().find(|(x, _)| *x == domain) {
+            Some((_, upstreams)) => upstreams.push(upstream),
+            None => rules.push((domain, vec![upstream])),
+        }
+    }
+
+    rules.into_iter()
+        .map(|(domain, upstreams)| forwarding::ForwardRule {
+            domain: domain,
+            upstreams: Upstreams::new(upstreams, strategy),
+        })
+        .collect()
+}
+
+/// Load the zones of the --zone <origin>:<path> values, or exit
+fn zones(args: &[String]) -> Vec<zone::Zone> {
+    let mut zones: Vec<zone::Zone> = Vec::new();
+
+    for value in flag_values(args, "--zone") {
+        let (origin, path) = value.split_once(':')
+            .unwrap_or_else(|| fail(&format!("Invalid value for --zone: {} (expected origin:path)", value)));
+
+        if let Err(e) = dns_name::validate(origin) {
+            fail(&format!("Invalid origin in --zone {}: {}", value, e));
+        }
+        let zone = zone::Zone::load(origin, path)
+            .and_then(update::replay)
+            .unwrap_or_else(|e| fail(&format!("Invalid --zone {}: {}", value, e)));
+        if zones.iter().any(|x| x.origin == zone.origin) {
+            fail(&format!("--zone {} given twice", origin));
+        }
+
+        zones.push(zone);
+    }
+
+    zones
+}
+
+/// Set up the views of the --view <name>=<network>[,<network>...] values with their --view-zone, --view-hosts and --view-resolver values, or exit
+fn views(args: &[String], strategy: Strategy, bootstrap: Option<SocketAddr>, tls_fallback: Option<SocketAddr>) -> Vec<views::View> {
+    let mut networks: Vec<(String, Vec<xfr::Network>)> = Vec::new();
+
+    for value in flag_values(args, "--view") {
+        let (name, list) = value.split_once('=')
+            .unwrap_or_else(|| fail(&format!("Invalid value for --view: {} (expected name=network[,network...])", value)));
+
+        if name.is_empty() || name == "default" || name.contains(char::is_whitespace) {
+            fail(&format!("Invalid name in --view {}: it can't be empty, default or have spaces", value));
+        }
+        if networks.iter().any(|(x, _)| x == name) {
+            fail(&format!("--view {} given twice", name));
+        }
+
+        let list = list.split(',')
+            .map(|x| xfr::Network::parse(x).unwrap_or_else(|e| fail(&format!("Invalid network in --view {}: {}", value, e))))
+            .collect();
+        networks.push((name.to_string(), list));
+    }
+
+    // the values of the other flags are name=rest, for a view given with --view
+    let of_view = |flag: &str| -> Vec<(&str, &str)> {
+        flag_values(args, flag).into_iter()
+            .map(|value| {
+                let (name, rest) = value.split_once('=')
+                    .unwrap_or_else(|| fail(&format!("Invalid value for {}: {} (expected view=...)", flag, value)));
+                if !networks.iter().any(|(x, _)| x == name) {
+                    fail(&format!("Invalid value for {}: {} (no --view {})", flag, value, name));
+                }
+                (name, rest)
+            })
+            .collect()
+    };
+    let zone_values = of_view("--view-zone");
+    let hosts_values = of_view("--view-hosts");
+    let resolver_values = of_view("--view-resolver");
+
+    networks.into_iter()
+        .map(|(name, networks)| {
+            let mut zones: Vec<zone::Zone> = Vec::new();
+            for (_, value) in zone_values.iter().filter(|(x, _)| *x == name) {
+                let (origin, path) = value.split_once(':')
+                    .unwrap_or_else(|| fail(&format!("Invalid value for --view-zone: {}={} (expected view=origin:path)", name, value)));
+
+                if let Err(e) = dns_name::validate(origin) {
+                    fail(&format!("Invalid origin in --view-zone {}={}: {}", name, value, e));
+                }
+                let zone = zone::Zone::load(origin, path)
+                    .unwrap_or_else(|e| fail(&format!("Invalid --view-zone {}={}: {}", name, value, e)));
+                if zones.iter().any(|x| x.origin == zone.origin) {
+                    fail(&format!("--view-zone {}={} given twice", name, origin));
+                }
+
+                zones.push(zone);
+            }
+
+            let mut hosts = hosts_values.iter().filter(|(x, _)| *x == name).map(|(_, path)| path.to_string());
+            let hosts_path = hosts.next();
+            if hosts.next().is_some() {
+                fail(&format!("--view-hosts given twice for view {}", name));
+            }
+
+            let resolvers: Vec<Upstream> = resolver_values.iter()
+                .filter(|(x, _)| *x == name)
+                .map(|(_, resolver)| parse_upstream(resolver, bootstrap, tls_fallback))
+                .collect();
+            let upstreams = (!resolvers.is_empty()).then(|| Upstreams::new(resolvers, strategy));
+
+            views::View::new(&name, networks, zones, hosts_path, upstreams)
+                .unwrap_or_else(|e| fail(&format!("Invalid --view-hosts for view {}: {}", name, e)))
+        })
+        .collect()
+}
+
+/// Parse the --tsig-key <name>:<algorithm>:<base64 secret> values, or exit without showing the secrets
+fn tsig_keys(args: &[String]) -> Vec<tsig::Key> {
+    let mut keys: Vec<tsig::Key> = Vec::new();
+
+    for value in flag_values(args, "--tsig-key") {
+        let key = tsig::Key::parse(value).unwrap_or_else(|e| fail(&format!("Invalid value for --tsig-key: {}", e)));
+        if keys.iter().any(|x| x.name == key.name) {
+            fail(&format!("--tsig-key {} given twice", key.name));
+        }
+
+        keys.push(key);
+    }
+
+    keys
+}
+
+/// Parse the --secondary <origin>:<primary-ip:port> values, or exit
+/// A zone can't be both a --zone and a --secondary
+fn secondaries(args: &[String]) -> Vec<secondary::Secondary> {
+    let mut secondaries: Vec<secondary::Secondary> = Vec::new();
+
+    for value in flag_values(args, "--secondary") {
+        let (origin, primary) = value.split_once(':')
+            .unwrap_or_else(|| fail(&format!("Invalid value for --secondary: {} (expected origin:primary-ip:port)", value)));
+
+        if let Err(e) = dns_name::validate(origin) {
+            fail(&format!("Invalid origin in --secondary {}: {}", value, e));
+        }
+        let origin = dns_name::normalize(origin);
+        if secondaries.iter().any(|x| x.origin == origin) {
+            fail(&format!("--secondary {} given twice", value));
+        }
+        if zone::zones().iter().any(|x| x.origin == origin) {
+            fail(&format!("--secondary {} is also given with --zone", value));
+        }
+
+        secondaries.push(secondary::Secondary {
+            origin: origin,
+            primary: parse_addr("--secondary", primary),
+        });
+    }
+
+    secondaries
+}
+
+/// Parse a --resolver value, an ip:port, an https:// URL or a tls:// address, or exit
+fn parse_upstream(value: &str, bootstrap: Option<SocketAddr>, tls_fallback: Option<SocketAddr>) -> Upstream {
+    if value.starts_with("https://") {
+        return https_upstream(value, bootstrap);
+    }
+    if value.starts_with("tls://") {
+        return tls_upstream(value, bootstrap, tls_fallback);
+    }
+
+    Upstream::udp(parse_addr("--resolver", value))
+}
+
+#[cfg(feature = "tls")]
+fn https_upstream(url: &str, bootstrap: Option<SocketAddr>) -> Upstream {
+    let upstream = doh_client::HttpsUpstream::parse(url, bootstrap)
+        .unwrap_or_else(|e| fail(&format!("Invalid --resolver {}: {}", url, e)));
+
+    Upstream {
+        addr: upstream.addr(),
+        protocol: Protocol::HTTPS(upstream),
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+fn https_upstream(url: &str, _bootstrap: Option<SocketAddr>) -> Upstream {
+    fail(&format!("DNS over HTTPS upstreams such as {} need the tls feature", url))
+}
+
+#[cfg(feature = "tls")]
+fn tls_upstream(value: &str, bootstrap: Option<SocketAddr>, fallback: Option<SocketAddr>) -> Upstream {
+    let upstream = dot_client::TlsUpstream::parse(value, bootstrap, fallback)
+        .unwrap_or_else(|e| fail(&format!("Invalid --resolver {}: {}", value, e)));
+
+    Upstream {
+        addr: upstream.addr(),
+        protocol: Protocol::TLS(upstream),
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+fn tls_upstream(value: &str, _bootstrap: Option<SocketAddr>, _fallback: Option<SocketAddr>) -> Upstream {
+    fail(&format!("DNS over TLS upstreams such as {} need the tls feature", value))
+}
+
+/// Parse an ip:port flag value or exit
+fn parse_addr(flag: &str, value: &str) -> SocketAddr {
+    value.parse::<SocketAddr>()
+        .unwrap_or_else(|_| fail(&format!("Invalid address for {}: {} (expected ip:port, ex. 127.0.0.1:53 or [::1]:53)", flag, value)))
+}
+
+/// Print an error and exit with a non-zero status
+fn fail(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    process::exit(1);
+}
+
+/// Value following a flag, if the flag is present
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    match args.iter().position(|arg| arg == flag) {
+        Some(i) if i + 1 < args.len() => Some(args[i + 1].as_str()),
+        _ => None,
+    }
+}
+
+/// Values following every occurrence of a repeatable flag
+fn flag_values<'a>(args: &'a [String], flag: &str) -> Vec<&'a str> {
+    args.windows(2)
+        .filter(|x| x[0] == flag)
+        .map(|x| x[1].as_str())
+        .collect()
+}
+
+/// Serve DNS over TLS from its own thread
+#[cfg(feature = "tls")]
+fn start_tls(args: &[String], cert: &str, key: &str, resolution: Resolution) {
+    let config = tls::load_config(cert, key, tls::DOT_ALPN)
+        .unwrap_or_else(|e| fail(&format!("Failed to load the TLS certificate and key: {}", e)));
+
+    let bind = flag_value(args, "--tls-bind")
+        .map(|x| parse_addr("--tls-bind", x))
+        .unwrap_or_else(|| SocketAddr::from(([127, 0, 0, 1], tls::DOT_PORT)));
+    let tls_listener = TcpListener::bind(bind).unwrap_or_else(|e| fail(&bind_error(bind, e)));
+
+    info!("Serving DNS over TLS on {}", bind);
+    thread::spawn(move || {
+        tls::serve_tls(tls_listener, config, move |stream, peer| transport::serve_stream(stream, peer, &resolution, transport::Transport::TLS))
+    });
+}
+
+/// Serve DNS over HTTPS from its own thread
+#[cfg(feature = "tls")]
+fn start_https(listener: TcpListener, cert: &str, key: &str, resolution: Resolution) {
+    let config = tls::load_config(cert, key, tls::HTTP_ALPN)
+        .unwrap_or_else(|e| fail(&format!("Failed to load the TLS certificate and key: {}", e)));
+
+    if let Ok(addr) = listener.local_addr() {
+        info!("Serving DNS over HTTPS on {}{}", addr, doh::DOH_PATH);
+    }
+    thread::spawn(move || {
+        tls::serve_tls(listener, config, move |stream, peer| doh::serve_connection(stream, peer, &resolution))
+    });
+}
+
+/// Serve DNS over QUIC from its own thread
+#[cfg(feature = "doq")]
+fn start_quic(args: &[String], cert: &str, key: &str, resolution: Resolution) {
+    let config = tls::load_config(cert, key, doq::DOQ_ALPN)
+        .unwrap_or_else(|e| fail(&format!("Failed to load the TLS certificate and key: {}", e)));
+
+    let bind = flag_value(args, "--doq-bind")
+        .map(|x| parse_addr("--doq-bind", x))
+        .unwrap_or_else(|| SocketAddr::from(([127, 0, 0, 1], tls::DOT_PORT)));
+    let doq_socket = UdpSocket::bind(bind).unwrap_or_else(|e| fail(&bind_error(bind, e)));
+
+    info!("Serving DNS over QUIC on {}", bind);
+    thread::spawn(move || {
+        if let Err(e) = doq::serve_quic(doq_socket, config, resolution) {
+            error!("DNS over QUIC listener stopped: {}", e);
+        }
+    });
+}
+
+#[cfg(not(feature = "doq"))]
+fn start_quic(_args: &[String], _cert: &str, _key: &str, _resolution: Resolution) {
+    fail("DNS over QUIC requires building with --features doq");
+}
+
+#[cfg(not(feature = "tls"))]
+fn start_tls(_args: &[String], _cert: &str, _key: &str, _resolution: Resolution) {
+    fail("DNS over TLS requires building with --features tls");
+}
+
+#[cfg(not(feature = "tls"))]
+fn start_https(_listener: TcpListener, _cert: &str, _key: &str, _resolution: Resolution) {
+    fail("DNS over HTTPS requires building with --features tls");
+}
@@ -0,0 +1,78 @@
+//! Cache and zone maintenance on signals: SIGUSR1 flushes the cache, SIGUSR2 logs the stats and dumps the cache as JSON lines
+//! SIGHUP reloads the zone files, the hosts files, the views' too, and the blocklists
+//! SIGTERM and SIGINT log the stats, write out the query log and save the cache to the cache file, if there is one, before exiting
+//! The signals are blocked in every thread and taken by one thread with sigwait,
+//! so flushing and reloading run as ordinary code under their locks rather than in a signal handler
+
+use std::io;
+use std::mem;
+use std::process;
+use std::ptr;
+use std::thread;
+
+use log::{ error, info };
+
+use crate::blocklist;
+use crate::cache;
+use crate::hosts;
+use crate::pcap;
+use crate::query_log;
+use crate::reload;
+use crate::stats::Stats;
+use crate::views;
+
+// Names listed by hits in a dump's summary
+const DUMP_TOP: usize = 10;
+
+/// Block SIGUSR1, SIGUSR2, SIGHUP, SIGTERM and SIGINT and start the thread that handles them,
+/// saving the cache on exit when there's a cache file (its path and most entries) to save to
+/// Threads inherit the blocked signals, so this has to run before any other thread starts
+pub fn handle_signals(cache_file: Option<(String, usize)>) -> io::Result<()> {
+    let set = unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGUSR1);
+        libc::sigaddset(&mut set, libc::SIGUSR2);
+        libc::sigaddset(&mut set, libc::SIGHUP);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        set
+    };
+
+    let err = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) };
+    if err != 0 {
+        return Err(io::Error::from_raw_os_error(err));
+    }
+
+    thread::spawn(move || loop {
+        let mut signal = 0;
+        if unsafe { libc::sigwait(&set, &mut signal) } != 0 {
+            continue;
+        }
+
+        match signal {
+            libc::SIGUSR1 => info!("Flushed {} cache entries on SIGUSR1", cache::flush()),
+            libc::SIGUSR2 => info!("{}\n{}", Stats::current().to_json(), cache::dump(DUMP_TOP).trim_end()),
+            libc::SIGHUP => {
+                reload::reload_all();
+                hosts::reload();
+                views::reload();
+                blocklist::reload();
+            }
+            _ => {
+                info!("{}", Stats::current().to_json());
+                if let Some((path, max)) = &cache_file {
+                    match cache::save(path, *max) {
+                        Ok(n) => info!("Saved {} cache entries to {}", n, path),
+                        Err(e) => error!("Failed to save the cache to {}: {}", path, e),
+                    }
+                }
+                query_log::flush();
+                pcap::close();
+                process::exit(0);
+            }
+        }
+    });
+
+    Ok(())
+}
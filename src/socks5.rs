@@ -0,0 +1,382 @@
+//! SOCKS5 proxy client, RFC 1928 with username/password authentication from RFC 1929
+//! With --proxy set every upstream connection goes through the proxy: TCP and TLS with CONNECT,
+//! UDP with UDP ASSOCIATE, or over TCP with CONNECT when the proxy doesn't relay UDP
+//! Every step is bounded by the lookup timeout so a dead proxy fails the lookup instead of hanging it
+
+use std::fmt;
+use std::io::{ self, Read, Write };
+use std::net::{ IpAddr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket };
+use std::sync::OnceLock;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::time::Duration;
+
+use log::info;
+
+use crate::error::DnsError;
+
+type Result<T> = std::result::Result<T, DnsError>;
+
+static PROXY: OnceLock<Proxy> = OnceLock::new();
+
+// A proxy for the lookups of one test's thread, the other tests still go direct
+#[cfg(test)]
+thread_local! {
+    static TEST_PROXY: std::cell::Cell<Option<&'static Proxy>> = const { std::cell::Cell::new(None) };
+}
+
+const VERSION: u8 = 5;
+
+// Authentication methods, RFC 1928 section 3
+const NO_AUTH: u8 = 0x00;
+const USER_PASS: u8 = 0x02;
+const NO_ACCEPTABLE: u8 = 0xFF;
+
+// Commands, RFC 1928 section 4
+const CONNECT: u8 = 0x01;
+const UDP_ASSOCIATE: u8 = 0x03;
+
+// Address types
+const ATYP_V4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_V6: u8 = 0x04;
+
+// Reply code for a command the proxy doesn't implement
+const COMMAND_NOT_SUPPORTED: u8 = 0x07;
+
+/// A SOCKS5 proxy upstream connections are tunnelled through
+#[derive(Debug)]
+pub struct Proxy {
+    pub addr: SocketAddr,
+    auth: Option<(String, String)>,
+    // set once the proxy has refused UDP ASSOCIATE, UDP lookups go over TCP from then on
+    no_udp: AtomicBool,
+}
+
+/// Install the proxy, only the first call has any effect
+pub fn set_proxy(proxy: Proxy) {
+    let _ = PROXY.set(proxy);
+}
+
+/// The proxy upstream connections go through, if any
+pub fn proxy() -> Option<&'static Proxy> {
+    #[cfg(test)]
+    if let Some(x) = TEST_PROXY.get() {
+        return Some(x);
+    }
+
+    PROXY.get()
+}
+
+/// Send this thread's lookups through the proxy, for tests that can't install one for the whole process
+#[cfg(test)]
+pub fn set_test_proxy(proxy: &'static Proxy) {
+    TEST_PROXY.set(Some(proxy));
+}
+
+impl Proxy {
+    /// Parse socks5://[user:password@]host:port
+    /// A hostname is resolved once here with the system resolver
+    pub fn parse(url: &str) -> Result<Proxy> {
+        let rest = url.strip_prefix("socks5://")
+            .ok_or_else(|| DnsError::Config("Expected a socks5:// URL".to_string()))?;
+        let rest = rest.trim_end_matches('/');
+
+        let (auth, authority) = match rest.rsplit_once('@') {
+            Some((userinfo, authority)) => {
+                let (user, pass) = userinfo.split_once(':')
+                    .ok_or_else(|| DnsError::Config("Expected user:password before @".to_string()))?;
+                // RFC 1929 gives each a single length byte
+                if user.is_empty() || user.len() > 255 || pass.len() > 255 {
+                    return Err(DnsError::Config("The user name must be 1 to 255 bytes and the password at most 255".to_string()));
+                }
+                (Some((user.to_string(), pass.to_string())), authority)
+            }
+            None => (None, rest),
+        };
+
+        let addr = match authority.parse::<SocketAddr>() {
+            Ok(x) => x,
+            Err(_) => {
+                let (host, port) = authority.rsplit_once(':')
+                    .ok_or_else(|| DnsError::Config("Expected host:port".to_string()))?;
+                let port = port.parse::<u16>()
+                    .map_err(|_| DnsError::Config(format!("Invalid port: {}", port)))?;
+
+                (host, port).to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| DnsError::Config(format!("{} has no address", host)))?
+            }
+        };
+
+        Ok(Proxy {
+            addr: addr,
+            auth: auth,
+            no_udp: AtomicBool::new(false),
+        })
+    }
+
+    /// Open a TCP connection to target through the proxy
+    /// Connecting, reads and writes each give up after the timeout
+    pub fn connect(&self, target: &SocketAddr, timeout: Duration) -> Result<TcpStream> {
+        self.request(CONNECT, target, timeout)
+            .map(|(stream, _)| stream)
+            .map_err(|e| DnsError::Proxy { proxy: self.to_string(), failure: format!("CONNECT to {} failed: {}", target, e) })
+    }
+
+    /// Set up a UDP relay through the proxy, None if the proxy doesn't relay UDP
+    /// The relay lasts as long as the returned value, which holds the association's TCP connection
+    pub fn associate(&self, timeout: Duration) -> Result<Option<UdpRelay>> {
+        if self.no_udp.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+
+        match self.try_associate(timeout) {
+            Ok(x) => Ok(Some(x)),
+            Err(Refused::UNSUPPORTED) => {
+                info!("SOCKS5 proxy {} doesn't relay UDP, sending UDP lookups over TCP through it", self);
+                self.no_udp.store(true, Ordering::Relaxed);
+                Ok(None)
+            }
+            Err(Refused::FAILED(e)) => Err(DnsError::Proxy { proxy: self.to_string(), failure: format!("UDP ASSOCIATE failed: {}", e) }),
+        }
+    }
+
+    fn try_associate(&self, timeout: Duration) -> std::result::Result<UdpRelay, Refused> {
+        // bind first so the proxy can be told where datagrams will come from
+        let control = TcpStream::connect_timeout(&self.addr, timeout)?;
+        let local = SocketAddr::new(control.local_addr()?.ip(), 0);
+        let socket = UdpSocket::bind(local)?;
+
+        let (control, relay) = self.handshake(control, UDP_ASSOCIATE, &socket.local_addr()?, timeout)?;
+
+        // an unspecified relay address means the proxy's own
+        let relay = match relay.ip().is_unspecified() {
+            true => SocketAddr::new(self.addr.ip(), relay.port()),
+            false => relay,
+        };
+
+        Ok(UdpRelay {
+            socket: socket,
+            relay: relay,
+            _control: control,
+        })
+    }
+
+    fn request(&self, command: u8, target: &SocketAddr, timeout: Duration) -> std::result::Result<(TcpStream, SocketAddr), Refused> {
+        let stream = TcpStream::connect_timeout(&self.addr, timeout)?;
+        self.handshake(stream, command, target, timeout)
+    }
+
+    /// Negotiate authentication on a fresh connection to the proxy then send one command,
+    /// returning the connection and the address the proxy bound for it
+    fn handshake(&self, mut stream: TcpStream, command: u8, target: &SocketAddr, timeout: Duration) -> std::result::Result<(TcpStream, SocketAddr), Refused> {
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        stream.set_nodelay(true)?;
+
+        let method = match self.auth {
+            Some(_) => USER_PASS,
+            None => NO_AUTH,
+        };
+        stream.write_all(&[VERSION, 1, method])?;
+
+        let mut reply = [0; 2];
+        stream.read_exact(&mut reply)?;
+        if reply[0] != VERSION {
+            return Err(Refused::failed("Not a SOCKS5 proxy"));
+        }
+        match reply[1] {
+            x if x == method => (),
+            NO_ACCEPTABLE if method == USER_PASS => return Err(Refused::failed("Username and password authentication isn't accepted")),
+            NO_ACCEPTABLE => return Err(Refused::failed("The proxy requires authentication, give user:password@ in --proxy")),
+            x => return Err(Refused::failed(&format!("The proxy chose an unoffered authentication method {}", x))),
+        }
+
+        if let Some((user, pass)) = &self.auth {
+            // RFC 1929 section 2
+            let mut msg = vec![0x01, user.len() as u8];
+            msg.extend_from_slice(user.as_bytes());
+            msg.push(pass.len() as u8);
+            msg.extend_from_slice(pass.as_bytes());
+            stream.write_all(&msg)?;
+
+            stream.read_exact(&mut reply)?;
+            if reply[1] != 0 {
+                return Err(Refused::failed("Username and password were rejected"));
+            }
+        }
+
+        let mut msg = vec![VERSION, command, 0];
+        write_addr(&mut msg, target);
+        stream.write_all(&msg)?;
+
+        let mut head = [0; 3];
+        stream.read_exact(&mut head)?;
+        match head[1] {
+            0 => (),
+            COMMAND_NOT_SUPPORTED if command == UDP_ASSOCIATE => return Err(Refused::UNSUPPORTED),
+            x => return Err(Refused::failed(reply_message(x))),
+        }
+        let bound = read_addr(&mut stream)?;
+
+        Ok((stream, bound))
+    }
+}
+
+impl fmt::Display for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "socks5://{}", self.addr)
+    }
+}
+
+/// Why a request to the proxy wasn't granted
+#[allow(clippy::upper_case_acronyms)]
+enum Refused {
+    UNSUPPORTED,
+    FAILED(String),
+}
+
+impl Refused {
+    fn failed(msg: &str) -> Refused {
+        Refused::FAILED(msg.to_string())
+    }
+}
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Refused::UNSUPPORTED => write!(f, "{}", reply_message(COMMAND_NOT_SUPPORTED)),
+            Refused::FAILED(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for Refused {
+    fn from(e: io::Error) -> Refused {
+        match e.kind() {
+            // timeouts show up as WouldBlock on unix and TimedOut on windows
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Refused::failed("Timed out waiting for the proxy"),
+            _ => Refused::FAILED(e.to_string()),
+        }
+    }
+}
+
+/// A UDP socket whose datagrams the proxy relays, RFC 1928 section 7
+/// Datagrams are wrapped in a header naming the remote address on the way out and unwrapped on the way in
+#[derive(Debug)]
+pub struct UdpRelay {
+    socket: UdpSocket,
+    relay: SocketAddr,
+    // the association ends when this connection closes
+    _control: TcpStream,
+}
+
+impl UdpRelay {
+    /// Have the proxy send a datagram to target
+    pub fn send_to(&self, buf: &[u8], target: &SocketAddr) -> io::Result<usize> {
+        let mut msg = vec![0, 0, 0];
+        write_addr(&mut msg, target);
+        msg.extend_from_slice(buf);
+
+        self.socket.send_to(&msg, self.relay)?;
+        Ok(buf.len())
+    }
+
+    /// Receive a relayed datagram into buf, returning its size and the remote address it came from
+    /// Datagrams from anywhere but the relay and fragments are skipped
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut msg = [0; 1500];
+        loop {
+            let (size, source) = self.socket.recv_from(&mut msg)?;
+            if source != self.relay {
+                continue;
+            }
+
+            let mut cursor = io::Cursor::new(&msg[..size]);
+            let mut head = [0; 3];
+            if cursor.read_exact(&mut head).is_err() || head[2] != 0 {
+                continue;
+            }
+            let from = match read_addr(&mut cursor) {
+                Ok(x) => x,
+                Err(_) => continue,
+            };
+
+            let payload = &msg[cursor.position() as usize..size];
+            let len = payload.len().min(buf.len());
+            buf[..len].copy_from_slice(&payload[..len]);
+
+            return Ok((len, from));
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+fn write_addr(msg: &mut Vec<u8>, addr: &SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(x) => {
+            msg.push(ATYP_V4);
+            msg.extend_from_slice(&x.octets());
+        }
+        IpAddr::V6(x) => {
+            msg.push(ATYP_V6);
+            msg.extend_from_slice(&x.octets());
+        }
+    }
+    msg.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// Read an address and port as the proxy sends them
+/// A domain can't be used as a socket address and is read as the unspecified address
+fn read_addr(stream: &mut impl Read) -> io::Result<SocketAddr> {
+    let mut atyp = [0; 1];
+    stream.read_exact(&mut atyp)?;
+
+    let ip = match atyp[0] {
+        ATYP_V4 => {
+            let mut x = [0; 4];
+            stream.read_exact(&mut x)?;
+            IpAddr::from(x)
+        }
+        ATYP_V6 => {
+            let mut x = [0; 16];
+            stream.read_exact(&mut x)?;
+            IpAddr::from(x)
+        }
+        ATYP_DOMAIN => {
+            let mut len = [0; 1];
+            stream.read_exact(&mut len)?;
+            let mut name = vec![0; len[0] as usize];
+            stream.read_exact(&mut name)?;
+            IpAddr::from([0, 0, 0, 0])
+        }
+        x => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown address type {}", x))),
+    };
+
+    let mut port = [0; 2];
+    stream.read_exact(&mut port)?;
+
+    Ok(SocketAddr::new(ip, u16::from_be_bytes(port)))
+}
+
+/// Reply codes, RFC 1928 section 6
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        0x01 => "General SOCKS server failure",
+        0x02 => "Connection not allowed by ruleset",
+        0x03 => "Network unreachable",
+        0x04 => "Host unreachable",
+        0x05 => "Connection refused",
+        0x06 => "TTL expired",
+        0x07 => "Command not supported",
+        0x08 => "Address type not supported",
+        _ => "Unknown failure",
+    }
+}
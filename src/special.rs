@@ -0,0 +1,153 @@
+//! Special-use domains (RFC 6761) answered here and never sent upstream, where they'd only leak names and waste queries
+//! localhost and every name under it is the loopback address, invalid, test and onion (RFC 7686) don't exist,
+//! and neither do the reverse names of private addresses, 10.in-addr.arpa, 16.172.in-addr.arpa to 31.172.in-addr.arpa,
+//! 168.192.in-addr.arpa and the IPv6 unique local d.f.ip6.arpa
+//! Each is answered like an empty zone with an SOA of its own, so negative answers get cached
+//! The hosts file, local zones and --forward rules come first, and --no-special-use <domain> turns one off for those who delegate it
+
+use std::net::{ Ipv4Addr, Ipv6Addr };
+use std::sync::OnceLock;
+
+use crate::dns_name;
+use crate::error::DnsError;
+use crate::packet::{ DnsPacket, DnsRecord, QueryType, QuestionRef, ResCode };
+
+type Result<T> = std::result::Result<T, DnsError>;
+
+static DOMAINS: OnceLock<Vec<String>> = OnceLock::new();
+
+const LOCALHOST: &str = "localhost";
+// TTL of every answer, and of the negative ones through the SOA's minimum
+const TTL: u32 = 3600;
+
+/// Leave the domains given with --no-special-use to be resolved as usual, only the first call has any effect
+/// Fails on a domain that isn't one handled here
+pub fn set_disabled(disabled: &[String]) -> Result<()> {
+    let all = all();
+    if let Some(x) = disabled.iter().find(|x| !all.contains(&dns_name::normalize(x))) {
+        return Err(DnsError::Config(format!("{} isn't a special-use domain handled here, those are {}", x, all.join(", "))));
+    }
+
+    let _ = DOMAINS.set(all.into_iter().filter(|x| !disabled.iter().any(|y| dns_name::normalize(y) == *x)).collect());
+
+    Ok(())
+}
+
+/// The special-use domain a name is in, None if it isn't in one or the one it's in is turned off
+pub fn find(name: &str) -> Option<&'static str> {
+    domains().iter().find(|x| dns_name::is_subdomain(name, x)).map(|x| x.as_str())
+}
+
+/// The special-use domain a question seen before parsing is in
+pub fn find_question(question: &QuestionRef) -> Option<&'static str> {
+    domains().iter().find(|x| question.is_within(x)).map(|x| x.as_str())
+}
+
+/// The answer to a question for a name in a special-use domain, None if it isn't in one
+pub fn answer(name: &str, q_type: QueryType) -> Option<DnsPacket> {
+    let domain = find(name)?;
+    let apex = dns_name::normalize(name) == domain;
+
+    let res = DnsPacket::new().authoritative();
+
+    Some(match q_type {
+        QueryType::A if domain == LOCALHOST => res.answer(DnsRecord::A { domain: name.to_string(), addr_v4: Ipv4Addr::LOCALHOST, ttl: TTL }),
+        QueryType::AAAA if domain == LOCALHOST => res.answer(DnsRecord::AAAA { domain: name.to_string(), addr: Ipv6Addr::LOCALHOST, ttl: TTL }),
+        QueryType::SOA if apex => res.answer(soa(domain)),
+        // every name under localhost exists, under the others only the domain itself
+        _ if apex || domain == LOCALHOST => res.authority(soa(domain)),
+        _ => res.with_rcode(ResCode::NX_DOMAIN).authority(soa(domain)),
+    })
+}
+
+/// Every special-use domain handled here
+fn all() -> Vec<String> {
+    let mut all: Vec<String> = [LOCALHOST, "invalid", "test", "onion", "10.in-addr.arpa", "168.192.in-addr.arpa", "d.f.ip6.arpa"].iter()
+        .map(|x| x.to_string())
+        .collect();
+    all.extend((16..=31).map(|x| format!("{}.172.in-addr.arpa", x)));
+
+    all
+}
+
+/// The special-use domains answered here, all of them unless some were turned off
+fn domains() -> &'static [String] {
+    DOMAINS.get_or_init(all)
+}
+
+/// The SOA a special-use domain is answered with, as if it were an empty zone served here
+fn soa(domain: &str) -> DnsRecord {
+    DnsRecord::SOA {
+        domain: domain.to_string(),
+        m_name: LOCALHOST.to_string(),
+        r_name: "nobody.invalid".to_string(),
+        serial: 1,
+        refresh: TTL,
+        retry: TTL,
+        expire: TTL,
+        minimum: TTL,
+        ttl: TTL,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{ IpAddr, UdpSocket };
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::packet::DnsQuestion;
+    use crate::resolve::{ self, Resolution };
+    use crate::transport::Transport;
+    use crate::upstreams::{ Strategy, Upstream, Upstreams };
+
+    #[test]
+    fn special_use_names_send_nothing_upstream() {
+        // answers until it's been idle a while, returning the names it was asked about
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let upstream = thread::spawn(move || {
+            socket.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+            let mut buf = [0; 512];
+            let mut asked = Vec::new();
+            while let Ok((size, client)) = socket.recv_from(&mut buf) {
+                let query = DnsPacket::from_bytes(&buf[..size]).unwrap();
+                asked.push(query.questions[0].name.clone());
+                let mut res = DnsPacket::response_to(&query).question(query.questions[0].clone());
+                socket.send_to(&res.to_bytes().unwrap(), client).unwrap();
+            }
+            asked
+        });
+
+        let upstreams = Upstreams::new(vec![Upstream::udp(addr)], Strategy::SEQUENTIAL);
+        let resolution = Resolution::Forward(Box::leak(Box::new(upstreams)));
+        let ask = |name: &str, q_type: QueryType| {
+            let mut query = DnsPacket::new();
+            query.header.query_res = false;
+            query.questions.push(DnsQuestion::new(name.to_string(), q_type));
+            let res = resolve::handle_query_bytes(&query.to_bytes().unwrap(), IpAddr::V4(Ipv4Addr::LOCALHOST), &resolution, Transport::UDP).unwrap();
+            DnsPacket::from_bytes(&res).unwrap()
+        };
+
+        let res = ask("app.localhost", QueryType::A);
+        assert_eq!(res.get_first_addr(), Some(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        for (name, q_type) in [
+            ("nothing.invalid", QueryType::A),
+            ("printer.test", QueryType::AAAA),
+            ("hidden.onion", QueryType::A),
+            ("1.0.0.10.in-addr.arpa", QueryType::PTR),
+            ("1.0.20.172.in-addr.arpa", QueryType::PTR),
+            ("1.1.168.192.in-addr.arpa", QueryType::PTR),
+            ("1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.d.f.ip6.arpa", QueryType::PTR),
+        ] {
+            let res = ask(name, q_type);
+            assert_eq!(res.header.res_code, ResCode::NX_DOMAIN, "{}", name);
+            assert!(res.header.authoritative, "{}", name);
+        }
+        // the upstream is there for any other name
+        ask("not-special.pine-dns.com", QueryType::A);
+
+        assert_eq!(upstream.join().unwrap(), ["not-special.pine-dns.com"]);
+    }
+}
@@ -0,0 +1,307 @@
+//! Server wide counters: queries by type, responses by rcode, upstream timeouts and retries, blocked names by --block-mode
+//! questions refused to clients outside --allow-recursion, responses held back by --rrl and truncated by --max-amplification,
+//! and log messages syslog couldn't take, read together with the cache's and each upstream's as one snapshot, with how long the server has been up
+//! Every counter is an atomic bumped on the query path, so counting never waits on a lock
+
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use crate::blocklist::BlockMode;
+use crate::cache::CacheStats;
+use crate::packet::{ QueryType, ResCode };
+use crate::upstreams::{ self, UpstreamStats };
+
+// Types numbered past this share the last counter
+const COUNTED_TYPES: usize = 256;
+// The codes with a name, in order, unassigned ones share a counter after them
+const RES_CODES: [ResCode; 13] = [
+    ResCode::NO_ERR,
+    ResCode::FORM_ERR,
+    ResCode::SERV_FAIL,
+    ResCode::NX_DOMAIN,
+    ResCode::NOT_IMP,
+    ResCode::REFUSED,
+    ResCode::YX_DOMAIN,
+    ResCode::YX_RR_SET,
+    ResCode::NX_RR_SET,
+    ResCode::NOT_AUTH,
+    ResCode::NOT_ZONE,
+    ResCode::BAD_VERS,
+    ResCode::BAD_COOKIE,
+];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+
+static STARTED: OnceLock<Instant> = OnceLock::new();
+static QUERIES: AtomicU64 = AtomicU64::new(0);
+static BY_TYPE: [AtomicU64; COUNTED_TYPES + 1] = [ZERO; COUNTED_TYPES + 1];
+static BY_RES_CODE: [AtomicU64; RES_CODES.len() + 1] = [ZERO; RES_CODES.len() + 1];
+static TIMEOUTS: AtomicU64 = AtomicU64::new(0);
+static RETRIES: AtomicU64 = AtomicU64::new(0);
+static REFUSED_RECURSION: AtomicU64 = AtomicU64::new(0);
+static RATE_LIMITED_DROPPED: AtomicU64 = AtomicU64::new(0);
+static RATE_LIMITED_SLIPPED: AtomicU64 = AtomicU64::new(0);
+static REBINDING_FILTERED: AtomicU64 = AtomicU64::new(0);
+static TC_FORCED: AtomicU64 = AtomicU64::new(0);
+static LOG_DROPPED: AtomicU64 = AtomicU64::new(0);
+static BLOCKED: [AtomicU64; BlockMode::ALL.len()] = [ZERO; BlockMode::ALL.len()];
+
+/// Snapshot of every counter
+#[derive(Clone, Debug)]
+pub struct Stats {
+    pub uptime: u64,                   // seconds since the server started
+    pub queries: u64,                  // answered, each counted once its response is written
+    pub by_type: Vec<(QueryType, u64)>, // questions asked per type, those never asked left out
+    pub other_types: u64,              // questions for types numbered 256 and up
+    pub by_res_code: Vec<(ResCode, u64)>,
+    pub other_res_codes: u64,          // responses with unassigned rcodes, forwarded as upstreams sent them
+    pub timeouts: u64, // upstream attempts that went unanswered
+    pub retries: u64,  // queries resent upstream after a timeout
+    pub blocked: Vec<(BlockMode, u64)>, // questions for blocked names, by the mode they were answered in
+    pub refused_recursion: u64,         // questions refused to clients that may not have names resolved
+    pub rate_limited_dropped: u64,      // UDP responses dropped by --rrl, counted as answered too
+    pub rate_limited_slipped: u64,      // UDP responses sent truncated by --rrl instead
+    pub rebinding_filtered: u64,        // A and AAAA records with reserved addresses taken out of upstream answers
+    pub tc_forced: u64,                 // UDP responses sent truncated by --max-amplification, too large for their query
+    pub log_dropped: u64,               // log messages dropped while syslog was backed up
+    pub cache: CacheStats,
+    pub upstreams: Vec<UpstreamStats>, // each configured upstream, empty when resolving recursively
+}
+
+/// Count a query about to be answered, with the types of its questions and the rcode of its response
+pub fn count_response(q_types: impl Iterator<Item = QueryType>, res_code: ResCode) {
+    QUERIES.fetch_add(1, Ordering::Relaxed);
+    for q_type in q_types {
+        BY_TYPE[(q_type.to_u16() as usize).min(COUNTED_TYPES)].fetch_add(1, Ordering::Relaxed);
+    }
+    BY_RES_CODE[RES_CODES.iter().position(|x| *x == res_code).unwrap_or(RES_CODES.len())].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count an upstream attempt that timed out
+pub fn count_timeout() {
+    TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a question for a blocked name answered in a mode
+pub fn count_blocked(mode: BlockMode) {
+    BLOCKED[mode as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a question refused to a client outside --allow-recursion
+pub fn count_refused_recursion() {
+    REFUSED_RECURSION.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a UDP response held back by --rrl, sent truncated or dropped
+pub fn count_rate_limited(slipped: bool) {
+    if slipped {
+        RATE_LIMITED_SLIPPED.fetch_add(1, Ordering::Relaxed);
+    } else {
+        RATE_LIMITED_DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Count the records with reserved addresses --rebind-protection took out of an answer
+pub fn count_rebinding_filtered(records: u64) {
+    REBINDING_FILTERED.fetch_add(records, Ordering::Relaxed);
+}
+
+/// Start the uptime clock, only the first call has any effect
+pub fn start() {
+    STARTED.get_or_init(Instant::now);
+}
+
+/// Count a UDP response --max-amplification sent truncated
+pub fn count_tc_forced() {
+    TC_FORCED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a log message dropped because syslog couldn't take it
+pub fn count_log_dropped() {
+    LOG_DROPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a query resent upstream
+pub fn count_retry() {
+    RETRIES.fetch_add(1, Ordering::Relaxed);
+}
+
+impl Stats {
+    /// Snapshot of the counters
+    /// Each is read on its own, so under load they may be a few queries apart
+    pub fn current() -> Stats {
+        let by_type = BY_TYPE[..COUNTED_TYPES].iter()
+            .enumerate()
+            .map(|(q_type, count)| (QueryType::from_u16(q_type as u16), count.load(Ordering::Relaxed)))
+            .filter(|(_, count)| *count > 0)
+            .collect();
+
+        let by_res_code = RES_CODES.iter()
+            .zip(&BY_RES_CODE)
+            .map(|(x, count)| (*x, count.load(Ordering::Relaxed)))
+            .collect();
+
+        Stats {
+            uptime: STARTED.get().map_or(0, |x| x.elapsed().as_secs()),
+            queries: QUERIES.load(Ordering::Relaxed),
+            by_type: by_type,
+            other_types: BY_TYPE[COUNTED_TYPES].load(Ordering::Relaxed),
+            by_res_code: by_res_code,
+            other_res_codes: BY_RES_CODE[RES_CODES.len()].load(Ordering::Relaxed),
+            timeouts: TIMEOUTS.load(Ordering::Relaxed),
+            retries: RETRIES.load(Ordering::Relaxed),
+            blocked: BlockMode::ALL.iter().map(|x| (*x, BLOCKED[*x as usize].load(Ordering::Relaxed))).collect(),
+            refused_recursion: REFUSED_RECURSION.load(Ordering::Relaxed),
+            rate_limited_dropped: RATE_LIMITED_DROPPED.load(Ordering::Relaxed),
+            rate_limited_slipped: RATE_LIMITED_SLIPPED.load(Ordering::Relaxed),
+            rebinding_filtered: REBINDING_FILTERED.load(Ordering::Relaxed),
+            tc_forced: TC_FORCED.load(Ordering::Relaxed),
+            log_dropped: LOG_DROPPED.load(Ordering::Relaxed),
+            cache: CacheStats::current(),
+            upstreams: upstreams::all_stats(),
+        }
+    }
+
+    /// The snapshot as one line of JSON, types and rcodes keyed by name
+    /// ex. {"uptime":60,"queries":3,"types":{"A":2,"AAAA":1},"rcodes":{"NO_ERR":3,...},"timeouts":0,"retries":0,"blocked":{"nxdomain":1,...},"refused_recursion":0,"rate_limited":{"dropped":0,"slipped":0},"rebinding_filtered":0,"tc_forced":0,"log_dropped":0,"cache":{...},"upstreams":[...]}
+    pub fn to_json(&self) -> String {
+        let mut types: Vec<String> = self.by_type.iter()
+            .map(|(q_type, count)| format!("\"{}\":{}", q_type.mnemonic(), count))
+            .collect();
+        if self.other_types > 0 {
+            types.push(format!("\"other\":{}", self.other_types));
+        }
+
+        let mut res_codes: Vec<String> = self.by_res_code.iter()
+            .map(|(res_code, count)| format!("\"{:?}\":{}", res_code, count))
+            .collect();
+        if self.other_res_codes > 0 {
+            res_codes.push(format!("\"other\":{}", self.other_res_codes));
+        }
+
+        let blocked: Vec<String> = self.blocked.iter()
+            .map(|(mode, count)| format!("\"{}\":{}", mode.name(), count))
+            .collect();
+
+        let cache = &self.cache;
+        let upstreams: Vec<String> = self.upstreams.iter().map(|x| x.to_json()).collect();
+
+        format!(
+            "{{\"uptime\":{},\"queries\":{},\"types\":{{{}}},\"rcodes\":{{{}}},\"timeouts\":{},\"retries\":{},\"blocked\":{{{}}},\"refused_recursion\":{},\
+             \"rate_limited\":{{\"dropped\":{},\"slipped\":{}}},\"rebinding_filtered\":{},\"tc_forced\":{},\"log_dropped\":{},\"cache\":{{\"hits\":{},\"misses\":{},\"evictions\":{},\"stale\":{},\"prefetches\":{},\"entries\":{}}},\"upstreams\":[{}]}}",
+            self.uptime,
+            self.queries,
+            types.join(","),
+            res_codes.join(","),
+            self.timeouts,
+            self.retries,
+            blocked.join(","),
+            self.refused_recursion,
+            self.rate_limited_dropped,
+            self.rate_limited_slipped,
+            self.rebinding_filtered,
+            self.tc_forced,
+            self.log_dropped,
+            cache.hits,
+            cache.misses,
+            cache.evictions,
+            cache.stale,
+            cache.prefetches,
+            cache.rrsets + cache.negative,
+            upstreams.join(","),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{ IpAddr, Ipv4Addr };
+
+    use crate::packet::{ DnsPacket, DnsQuestion };
+    use crate::resolve::{ self, Resolution };
+    use crate::transport::Transport;
+    use crate::upstreams::{ Strategy, Upstream, Upstreams };
+
+    // the counters are shared with every other test, which may count while this one runs
+
+    fn by_type(stats: &Stats, q_type: QueryType) -> u64 {
+        stats.by_type.iter().find(|(x, _)| *x == q_type).map_or(0, |(_, count)| *count)
+    }
+
+    fn by_res_code(stats: &Stats, res_code: ResCode) -> u64 {
+        stats.by_res_code.iter().find(|(x, _)| *x == res_code).map_or(0, |(_, count)| *count)
+    }
+
+    #[test]
+    fn answered_queries_are_counted_by_type_and_rcode() {
+        // the names are special-use, answered without asking the upstream
+        let upstreams = Upstreams::new(vec![Upstream::udp("192.0.2.53:53".parse().unwrap())], Strategy::SEQUENTIAL);
+        let resolution = Resolution::Forward(Box::leak(Box::new(upstreams)));
+        let ask = |name: &str, q_type: QueryType| {
+            let mut query = DnsPacket::new();
+            query.header.query_res = false;
+            query.questions.push(DnsQuestion::new(name.to_string(), q_type));
+            let res = resolve::handle_query_bytes(&query.to_bytes().unwrap(), IpAddr::V4(Ipv4Addr::LOCALHOST), &resolution, Transport::UDP).unwrap();
+            DnsPacket::from_bytes(&res).unwrap().header.res_code
+        };
+
+        let before = Stats::current();
+        assert_eq!(ask("counted.invalid", QueryType::MX), ResCode::NX_DOMAIN);
+        assert_eq!(ask("localhost", QueryType::AAAA), ResCode::NO_ERR);
+        let after = Stats::current();
+
+        assert!(after.queries >= before.queries + 2);
+        assert!(by_type(&after, QueryType::MX) > by_type(&before, QueryType::MX));
+        assert!(by_type(&after, QueryType::AAAA) > by_type(&before, QueryType::AAAA));
+        assert!(by_res_code(&after, ResCode::NX_DOMAIN) > by_res_code(&before, ResCode::NX_DOMAIN));
+        assert!(by_res_code(&after, ResCode::NO_ERR) > by_res_code(&before, ResCode::NO_ERR));
+    }
+
+    #[test]
+    fn each_event_counts_in_its_own_counter() {
+        let before = Stats::current();
+        count_timeout();
+        count_retry();
+        count_rate_limited(true);
+        count_rate_limited(false);
+        count_refused_recursion();
+        count_tc_forced();
+        count_response([QueryType::UNKNOWN(300)].into_iter(), ResCode::NO_ERR);
+        let after = Stats::current();
+
+        assert!(after.timeouts > before.timeouts);
+        assert!(after.retries > before.retries);
+        assert!(after.rate_limited_slipped > before.rate_limited_slipped);
+        assert!(after.rate_limited_dropped > before.rate_limited_dropped);
+        assert!(after.refused_recursion > before.refused_recursion);
+        assert!(after.tc_forced > before.tc_forced);
+        // types past 255 share a counter
+        assert!(after.other_types > before.other_types);
+    }
+
+    #[test]
+    fn counts_from_many_threads_at_once_are_all_kept() {
+        const THREADS: u64 = 8;
+        const EACH: u64 = 10000;
+
+        let before = Stats::current();
+        let threads: Vec<_> = (0..THREADS).map(|_| std::thread::spawn(|| {
+            for _ in 0..EACH {
+                count_response([QueryType::A].into_iter(), ResCode::NO_ERR);
+                count_timeout();
+            }
+        })).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let after = Stats::current();
+
+        assert!(after.queries >= before.queries + THREADS * EACH);
+        assert!(by_type(&after, QueryType::A) >= by_type(&before, QueryType::A) + THREADS * EACH);
+        assert!(by_res_code(&after, ResCode::NO_ERR) >= by_res_code(&before, ResCode::NO_ERR) + THREADS * EACH);
+        assert!(after.timeouts >= before.timeouts + THREADS * EACH);
+    }
+}
@@ -0,0 +1,104 @@
+//! Syslog output for --log-target syslog, each message a datagram to the local /dev/log with facility daemon
+//! Messages are framed as in RFC 3164 without the timestamp and hostname, which the local syslog daemon adds,
+//! ex. <30>pine-dns[1234]: Listening on 127.0.0.1:53, fields following the message as key=value the way they do on stderr
+//! The socket never blocks: a message that doesn't fit while syslog is backed up is dropped and counted in the stats,
+//! and one that fails because syslog restarted is sent again once over a new connection
+
+use std::fmt::Write as _;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use std::sync::{ Mutex, PoisonError };
+
+use log::kv::{ self, VisitSource };
+use log::{ Level, LevelFilter, Log, Metadata, Record };
+
+use crate::stats;
+
+/// Where the local syslog daemon listens
+pub const PATH: &str = "/dev/log";
+
+// Facility daemon, RFC 3164 section 4.1.1
+const FACILITY_DAEMON: u8 = 3;
+// The longest message sent, the rest is cut off as many syslog daemons would
+const MAX_MESSAGE: usize = 8192;
+
+pub struct Syslog {
+    socket: Mutex<UnixDatagram>,
+    level: LevelFilter,
+}
+
+impl Syslog {
+    /// Connect to the syslog daemon, failing if it isn't listening
+    pub fn connect(level: LevelFilter) -> io::Result<Syslog> {
+        Ok(Syslog {
+            socket: Mutex::new(open()?),
+            level: level,
+        })
+    }
+}
+
+impl Log for Syslog {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut line = format!("<{}>pine-dns[{}]: {}", FACILITY_DAEMON * 8 + severity(record.level()), process::id(), record.args());
+        let _ = record.key_values().visit(&mut Fields(&mut line));
+        if line.len() > MAX_MESSAGE {
+            let mut end = MAX_MESSAGE;
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            line.truncate(end);
+        }
+
+        let mut socket = self.socket.lock().unwrap_or_else(PoisonError::into_inner);
+        match socket.send(line.as_bytes()) {
+            Ok(_) => (),
+            // the daemon restarted and its socket with it
+            Err(e) if matches!(e.kind(), io::ErrorKind::ConnectionRefused | io::ErrorKind::NotConnected) => {
+                match open().and_then(|x| x.send(line.as_bytes()).map(|_| x)) {
+                    Ok(x) => *socket = x,
+                    Err(_) => stats::count_log_dropped(),
+                }
+            }
+            Err(_) => stats::count_log_dropped(),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn open() -> io::Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(PATH)?;
+    socket.set_nonblocking(true)?;
+
+    Ok(socket)
+}
+
+/// The syslog severity of a level, RFC 3164 section 4.1.1
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Writes the fields of a message after it as key=value
+struct Fields<'a>(&'a mut String);
+
+impl<'a, 'kvs> VisitSource<'kvs> for Fields<'a> {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let _ = write!(self.0, " {}={}", key, value);
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+//! systemd socket activation and readiness notification
+//! With a .socket unit, systemd binds the listening sockets (port 53 included) and passes them
+//! starting at fd 3, described by LISTEN_PID and LISTEN_FDS, so the server needs no privileges
+//! READY=1 is sent to NOTIFY_SOCKET once serving so Type=notify units know startup is done
+
+use std::env;
+use std::io;
+use std::mem;
+use std::net::{ TcpListener, UdpSocket };
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{ AsRawFd, FromRawFd, RawFd };
+use std::os::unix::net::UnixDatagram;
+
+use crate::error::DnsError;
+
+type Result<T> = std::result::Result<T, DnsError>;
+
+// First fd passed by systemd, after stdin, stdout and stderr
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Sockets passed in by systemd
+pub struct Listeners {
+    pub udp_sockets: Vec<UdpSocket>,
+    pub tcp_listeners: Vec<TcpListener>,
+}
+
+/// Adopt the sockets systemd passed in, None when the process wasn't socket activated
+/// Datagram sockets are served as UDP and listening stream sockets as TCP, anything else is an error
+pub fn listen_fds() -> Result<Option<Listeners>> {
+    let pid = match env::var("LISTEN_PID") {
+        Ok(x) => x,
+        Err(_) => return Ok(None),
+    };
+    // the variables are inherited by children, only the process they were meant for uses them
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        return Ok(None);
+    }
+
+    let count = env::var("LISTEN_FDS").unwrap_or_default();
+    let count = count.parse::<RawFd>()
+        .map_err(|_| DnsError::Config(format!("Invalid LISTEN_FDS from systemd: {:?}", count)))?;
+
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let mut listeners = Listeners {
+        udp_sockets: Vec::new(),
+        tcp_listeners: Vec::new(),
+    };
+
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        // keep the sockets from leaking into anything we spawn
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+
+        let family = sock_opt(fd, libc::SO_DOMAIN)
+            .map_err(|e| DnsError::Config(format!("Socket activation fd {} isn't a socket: {}", fd, e)))?;
+        if family != libc::AF_INET && family != libc::AF_INET6 {
+            return Err(DnsError::Config(format!("Socket activation fd {} isn't an IPv4 or IPv6 socket, use ListenDatagram= or ListenStream= with an address", fd)));
+        }
+
+        match sock_opt(fd, libc::SO_TYPE)? {
+            libc::SOCK_DGRAM => {
+                listeners.udp_sockets.push(unsafe { UdpSocket::from_raw_fd(fd) });
+            }
+            libc::SOCK_STREAM => {
+                if sock_opt(fd, libc::SO_ACCEPTCONN)? == 0 {
+                    return Err(DnsError::Config(format!("Socket activation fd {} is a TCP socket that isn't listening, set Accept=no", fd)));
+                }
+                listeners.tcp_listeners.push(unsafe { TcpListener::from_raw_fd(fd) });
+            }
+            x => return Err(DnsError::Config(format!("Socket activation fd {} has unsupported socket type {}, expected UDP or TCP", fd, x))),
+        }
+    }
+
+    if listeners.udp_sockets.is_empty() {
+        return Err(DnsError::Config("systemd passed no UDP socket, add a ListenDatagram= line to the socket unit".to_string()));
+    }
+
+    Ok(Some(listeners))
+}
+
+/// Tell systemd startup is finished, doing nothing when not run as a Type=notify service
+pub fn notify_ready() -> Result<()> {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    let path = path.as_bytes();
+
+    // paths starting with @ are in the abstract namespace, which starts with a nul byte instead
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    if path.is_empty() || path.len() >= addr.sun_path.len() {
+        return Err(DnsError::Config(format!("Invalid NOTIFY_SOCKET: {:?}", String::from_utf8_lossy(path))));
+    }
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    for (i, b) in path.iter().enumerate() {
+        addr.sun_path[i] = if i == 0 && *b == b'@' { 0 } else { *b as libc::c_char };
+    }
+
+    let socket = UnixDatagram::unbound()?;
+    let msg = b"READY=1";
+    let addr_len = mem::size_of::<libc::sa_family_t>() + path.len();
+
+    let sent = unsafe {
+        libc::sendto(
+            socket.as_raw_fd(),
+            msg.as_ptr() as *const libc::c_void,
+            msg.len(),
+            0,
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            addr_len as libc::socklen_t,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+/// Read an integer socket option
+fn sock_opt(fd: RawFd, opt: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+    let got = unsafe {
+        libc::getsockopt(fd, libc::SOL_SOCKET, opt, &mut value as *mut libc::c_int as *mut libc::c_void, &mut len)
+    };
+    if got < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(value)
+}
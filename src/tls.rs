@@ -0,0 +1,197 @@
+//! TLS listeners and client connections
+//! DNS over TLS (RFC 7858) wraps connections in TLS and then speaks the same length prefixed protocol as TCP
+//! DNS over HTTPS uses the same accept loop with an HTTP handler
+//! Client connections to encrypted upstreams verify the server against the bundled web PKI roots
+//! Only built with the "tls" feature
+
+use std::fs::File;
+use std::io::BufReader;
+use std::net::{ IpAddr, SocketAddr, TcpListener, TcpStream };
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::error;
+use rustls::{ Certificate, ClientConfig, ClientConnection, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerConfig, ServerConnection, ServerName, StreamOwned };
+
+use crate::error::DnsError;
+use crate::resolve;
+use crate::transport;
+
+type Result<T> = std::result::Result<T, DnsError>;
+
+/// Standard DoT port
+pub const DOT_PORT: u16 = 853;
+
+/// ALPN protocol id registered for DoT
+pub const DOT_ALPN: &[u8] = b"dot";
+
+/// ALPN protocol id for DoH, only HTTP/1.1 is spoken
+pub const HTTP_ALPN: &[u8] = b"http/1.1";
+
+/// A server side TLS connection
+pub type TlsStream = StreamOwned<ServerConnection, TcpStream>;
+
+/// A client side TLS connection
+pub type ClientStream = StreamOwned<ClientConnection, TcpStream>;
+
+/// Build the server side TLS config from PEM encoded certificate chain and private key files
+/// advertising a single ALPN protocol
+pub fn load_config(cert_path: &str, key_path: &str, alpn: &[u8]) -> Result<Arc<ServerConfig>> {
+    let certs = load_certs(cert_path)?;
+    let key = load_key(key_path)?;
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| DnsError::Config(format!("Invalid certificate or key: {}", e)))?;
+    config.alpn_protocols = vec![alpn.to_vec()];
+
+    Ok(Arc::new(config))
+}
+
+/// Build the client side TLS config trusting the web PKI roots, advertising a single ALPN protocol
+pub fn client_config(alpn: &[u8]) -> Arc<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(ta.subject, ta.spki, ta.name_constraints)
+    }));
+
+    let mut config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![alpn.to_vec()];
+
+    Arc::new(config)
+}
+
+/// Connect to a TLS server, verifying its certificate is valid for the given name
+/// Connecting, reads and writes each give up after the timeout, the SOCKS5 proxy is used if one is set
+pub fn connect(addr: &SocketAddr, server_name: &ServerName, config: Arc<ClientConfig>, timeout: Duration) -> Result<ClientStream> {
+    let stream = transport::connect(addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    stream.set_nodelay(true)?;
+
+    let conn = ClientConnection::new(config, server_name.clone())?;
+    let mut tls = StreamOwned::new(conn, stream);
+
+    // finish the handshake up front so a bad certificate is reported as such
+    while tls.conn.is_handshaking() {
+        tls.conn.complete_io(&mut tls.sock)?;
+    }
+
+    Ok(tls)
+}
+
+/// Split host[:port] or [v6][:port] from an upstream address into the host and port
+pub fn split_authority(authority: &str, default_port: u16) -> Result<(&str, u16)> {
+    let (host, port) = if let Some(rest) = authority.strip_prefix('[') {
+        let end = rest.find(']').ok_or_else(|| DnsError::Config("Unclosed [ in address".to_string()))?;
+        (&rest[..end], rest[end + 1..].strip_prefix(':'))
+    } else {
+        match authority.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        }
+    };
+
+    if host.is_empty() {
+        return Err(DnsError::Config("Address has no host".to_string()));
+    }
+
+    let port = match port {
+        Some(x) => x.parse::<u16>().map_err(|_| DnsError::Config(format!("Invalid port in address: {}", x)))?,
+        None => default_port,
+    };
+
+    Ok((host, port))
+}
+
+/// Address of an encrypted upstream, a hostname is looked up once through the bootstrap resolver
+pub fn resolve_upstream(host: &str, port: u16, bootstrap: Option<SocketAddr>) -> Result<SocketAddr> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, port));
+    }
+
+    // the upstream can't be asked for its own address, that needs another resolver
+    let bootstrap = bootstrap.ok_or_else(|| {
+        DnsError::Config(format!("Resolving {} needs --bootstrap <ip:port>, or give its IP address instead", host))
+    })?;
+
+    Ok(SocketAddr::new(resolve::bootstrap(host, bootstrap)?, port))
+}
+
+/// Accept TLS connections, running the handler for each on its own thread
+/// The handshake runs on the connection's thread so a slow or broken client can't stall the listener
+pub fn serve_tls<F>(listener: TcpListener, config: Arc<ServerConfig>, handler: F)
+where
+    F: Fn(&mut TlsStream, SocketAddr) -> Result<()> + Copy + Send + 'static,
+{
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Failed to accept TLS connection: {}", e);
+                continue;
+            }
+        };
+
+        let config = config.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_tls_connection(stream, config, handler) {
+                error!("An error occurred on a TLS connection: {}", e);
+            }
+        });
+    }
+}
+
+/// Run the handler on a single TLS connection
+/// The handshake completes on the first read, so handshake failures surface as errors here
+fn handle_tls_connection<F>(stream: TcpStream, config: Arc<ServerConfig>, handler: F) -> Result<()>
+where
+    F: Fn(&mut TlsStream, SocketAddr) -> Result<()>,
+{
+    let peer = stream.peer_addr()?;
+    transport::set_idle_timeout(&stream)?;
+
+    let conn = ServerConnection::new(config)?;
+    let mut tls = StreamOwned::new(conn, stream);
+
+    handler(&mut tls, peer)?;
+
+    // let the client know no more responses are coming
+    tls.conn.send_close_notify();
+    let _ = tls.conn.complete_io(&mut tls.sock);
+
+    Ok(())
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>> {
+    let file = File::open(path).map_err(|e| DnsError::Config(format!("Failed to open certificate {}: {}", path, e)))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))?;
+
+    if certs.is_empty() {
+        return Err(DnsError::Config(format!("No certificates found in {}", path)));
+    }
+
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &str) -> Result<PrivateKey> {
+    let file = File::open(path).map_err(|e| DnsError::Config(format!("Failed to open private key {}: {}", path, e)))?;
+    let mut reader = BufReader::new(file);
+
+    // take the first key in the file, whichever format it is in
+    loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => return Err(DnsError::Config(format!("No private key found in {}", path))),
+        }
+    }
+}
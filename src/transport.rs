@@ -0,0 +1,267 @@
+//! Stream transports
+//! DNS over TCP (and TLS) prefixes every message with its length as a u16, RFC 1035 section 4.2.2
+//! The listener here answers those messages with the same handler the UDP socket uses
+
+use std::io::{ self, Read, Write };
+use std::net::{ SocketAddr, TcpListener, TcpStream };
+use std::thread;
+use std::time::Duration;
+
+use log::{ error, trace };
+
+use crate::error::DnsError;
+use crate::padding;
+use crate::resolve::{ self, Resolution };
+use crate::socks5;
+use crate::xfr;
+
+type Result<T> = std::result::Result<T, DnsError>;
+
+/// Largest message the two byte length prefix can describe
+pub const MAX_TCP_MESSAGE: usize = 65535;
+
+// Connections with no new query for this long are closed
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a query from a client arrived over
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Transport {
+    UDP,
+    TCP,
+    /// DNS over TLS
+    #[cfg(feature = "tls")]
+    TLS,
+    /// DNS over HTTPS, or plain HTTP behind a TLS terminating proxy
+    HTTPS,
+    /// DNS over QUIC
+    #[cfg(feature = "doq")]
+    QUIC,
+}
+
+impl Transport {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Transport::UDP => "udp",
+            Transport::TCP => "tcp",
+            #[cfg(feature = "tls")]
+            Transport::TLS => "tls",
+            Transport::HTTPS => "https",
+            #[cfg(feature = "doq")]
+            Transport::QUIC => "quic",
+        }
+    }
+}
+
+/// Read one length prefixed message from a stream
+/// Short reads are retried until the whole message has arrived
+/// Fails on a zero length frame or if the stream ends mid message
+pub fn read_tcp_message(stream: &mut impl Read) -> Result<Vec<u8>> {
+    let mut prefix = [0; 2];
+    stream.read_exact(&mut prefix)?;
+
+    let len = ((prefix[0] as usize) << 8) | (prefix[1] as usize);
+    if len == 0 {
+        return Err(DnsError::EmptyMessage);
+    }
+
+    let mut msg = vec![0; len];
+    stream.read_exact(&mut msg)?;
+
+    Ok(msg)
+}
+
+/// Write one message to a stream with its length prefix
+/// The prefix and message go out in a single write so they aren't split into separate segments
+pub fn write_tcp_message(stream: &mut impl Write, bytes: &[u8]) -> Result<()> {
+    let framed = frame_message(bytes)?;
+
+    stream.write_all(&framed)?;
+    stream.flush()?;
+
+    Ok(())
+}
+
+/// Copy a message behind its two byte length prefix
+pub fn frame_message(bytes: &[u8]) -> Result<Vec<u8>> {
+    if bytes.is_empty() {
+        return Err(DnsError::EmptyMessage);
+    }
+    if bytes.len() > MAX_TCP_MESSAGE {
+        return Err(DnsError::MessageTooLarge { size: bytes.len(), max: MAX_TCP_MESSAGE });
+    }
+
+    let mut framed = Vec::with_capacity(bytes.len() + 2);
+    framed.push((bytes.len() >> 8) as u8);
+    framed.push((bytes.len() & 0xFF) as u8);
+    framed.extend_from_slice(bytes);
+
+    Ok(framed)
+}
+
+/// Send one query to a server over a new TCP connection and read back its response
+/// Connecting, sending and receiving each give up after the timeout
+/// The connection goes through the SOCKS5 proxy if one is set
+pub fn exchange_tcp(query: &[u8], server: &SocketAddr, timeout: Duration) -> Result<Vec<u8>> {
+    let mut stream = connect(server, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    write_tcp_message(&mut stream, query)?;
+    read_tcp_message(&mut stream)
+}
+
+/// Open a TCP connection to an upstream, through the SOCKS5 proxy if one is set
+pub fn connect(server: &SocketAddr, timeout: Duration) -> Result<TcpStream> {
+    match socks5::proxy() {
+        Some(proxy) => proxy.connect(server, timeout),
+        None => Ok(TcpStream::connect_timeout(server, timeout)?),
+    }
+}
+
+/// Accept DNS over TCP connections, serving each on its own thread
+pub fn serve_tcp(listener: TcpListener, resolution: Resolution) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                thread::spawn(move || {
+                    if let Err(e) = handle_tcp_connection(stream, &resolution) {
+                        error!("An error occurred on a TCP connection: {}", e);
+                    }
+                });
+            }
+            Err(e) => error!("Failed to accept TCP connection: {}", e),
+        }
+    }
+}
+
+/// Answer queries on a single connection until the client closes it or goes idle
+fn handle_tcp_connection(mut stream: TcpStream, resolution: &Resolution) -> Result<()> {
+    let peer = stream.peer_addr()?;
+    set_idle_timeout(&stream)?;
+
+    serve_stream(&mut stream, peer, resolution, Transport::TCP)
+}
+
+/// Answer length prefixed queries on any stream, plain TCP or wrapped in TLS
+/// A connection can carry any number of sequential queries, their responses padded if it's encrypted
+pub fn serve_stream<S: Read + Write>(stream: &mut S, peer: SocketAddr, resolution: &Resolution, transport: Transport) -> Result<()> {
+    loop {
+        let req = match read_tcp_message(stream) {
+            Ok(x) => x,
+            // the client closing the connection or going idle between messages ends it quietly
+            Err(e) if is_closed(&e) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        trace!("Received {} bytes over a stream from {}", req.len(), peer);
+
+        // zone transfers take a run of messages, anything else gets one
+        let messages = match xfr::answer(&req, peer.ip()) {
+            Some(x) => x?,
+            None if transport != Transport::TCP => vec![padding::pad_response(&req, resolve::handle_query_bytes(&req, peer.ip(), resolution, transport)?)],
+            None => vec![resolve::handle_query_bytes(&req, peer.ip(), resolution, transport)?],
+        };
+        for res in messages {
+            write_tcp_message(stream, &res)?;
+        }
+    }
+}
+
+/// Close accepted connections that go quiet between queries
+pub fn set_idle_timeout(stream: &TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT))
+}
+
+/// True if an error just means the other end went away or timed out
+pub fn is_closed(e: &DnsError) -> bool {
+    match e {
+        DnsError::UnexpectedEof => true,
+        DnsError::Io(x) => matches!(x.kind(),
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::ConnectionReset),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{ DnsPacket, DnsQuestion, QueryType };
+
+    /// Hands out what it holds a few bytes at a time, interrupted now and then, as a slow socket would
+    struct Fragmented {
+        bytes: Vec<u8>,
+        pos: usize,
+        reads: usize,
+    }
+
+    impl Read for Fragmented {
+        fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+            if self.reads.is_multiple_of(4) {
+                return Err(io::Error::from(io::ErrorKind::Interrupted));
+            }
+
+            let len = out.len().min(self.reads % 3 + 1).min(self.bytes.len() - self.pos);
+            out[..len].copy_from_slice(&self.bytes[self.pos..self.pos + len]);
+            self.pos += len;
+
+            Ok(len)
+        }
+    }
+
+    fn fragmented(bytes: Vec<u8>) -> Fragmented {
+        Fragmented { bytes, pos: 0, reads: 0 }
+    }
+
+    fn query() -> Vec<u8> {
+        let mut packet = DnsPacket::new();
+        packet.questions.push(DnsQuestion::new("www.example.com".to_string(), QueryType::AAAA));
+        packet.to_bytes().unwrap()
+    }
+
+    #[test]
+    fn reads_a_message_split_across_short_reads() {
+        let msg = query();
+        let mut framed = frame_message(&msg).unwrap();
+        framed.extend(frame_message(&msg).unwrap());
+        let mut stream = fragmented(framed);
+
+        for _ in 0..2 {
+            let read = read_tcp_message(&mut stream).unwrap();
+            assert_eq!(read, msg);
+            assert_eq!(DnsPacket::from_bytes(&read).unwrap().questions[0].name, "www.example.com");
+        }
+        assert!(stream.reads > 2 * msg.len() / 3);
+    }
+
+    #[test]
+    fn truncated_message_is_unexpected_eof() {
+        let mut framed = frame_message(&query()).unwrap();
+        framed.pop();
+
+        assert!(matches!(read_tcp_message(&mut fragmented(framed)), Err(DnsError::UnexpectedEof)));
+        assert!(matches!(read_tcp_message(&mut fragmented(vec![0])), Err(DnsError::UnexpectedEof)));
+    }
+
+    #[test]
+    fn zero_length_and_oversized_frames_fail() {
+        assert!(matches!(read_tcp_message(&mut fragmented(vec![0, 0])), Err(DnsError::EmptyMessage)));
+        assert!(matches!(frame_message(&[]), Err(DnsError::EmptyMessage)));
+        assert!(matches!(frame_message(&vec![0; MAX_TCP_MESSAGE + 1]), Err(DnsError::MessageTooLarge { size, .. }) if size == MAX_TCP_MESSAGE + 1));
+        assert_eq!(frame_message(&vec![7; MAX_TCP_MESSAGE]).unwrap()[..3], [0xFF, 0xFF, 7]);
+    }
+
+    #[test]
+    fn write_then_read_round_trips() {
+        let msg = query();
+        let mut out = Vec::new();
+        write_tcp_message(&mut out, &msg).unwrap();
+
+        assert_eq!(out.len(), msg.len() + 2);
+        assert_eq!(read_tcp_message(&mut fragmented(out)).unwrap(), msg);
+    }
+}
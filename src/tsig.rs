@@ -0,0 +1,481 @@
+//! Transaction signatures, TSIG (RFC 8945), on zone transfers and updates
+//! Keys are secrets shared with other servers, given with --tsig-key <name>:<algorithm>:<base64 secret>, hmac-sha256 the one algorithm supported
+//! A request signed with a known key and a good MAC, within the fudge of the time it was signed, is let through from any address,
+//! and every message of the response is signed in turn, each MAC covering the one before it
+//! A request with an unknown key, a bad MAC or a time too far off gets NOTAUTH, with the TSIG error saying which
+//! With --require-tsig transfer|update an unsigned request for that operation gets REFUSED, whatever its address
+//! Secrets never show up in the logs, only the names of their keys
+
+use std::sync::OnceLock;
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+use crate::base64;
+use crate::dns_name;
+use crate::error::DnsError;
+use crate::hmac;
+use crate::packet::{ DnsHeader, DnsQuestion, DnsRecord, PacketBuffer, QueryType, UpdateRecord };
+
+type Result<T> = std::result::Result<T, DnsError>;
+
+static KEYS: OnceLock<Vec<Key>> = OnceLock::new();
+static REQUIRED: OnceLock<Vec<Operation>> = OnceLock::new();
+
+/// Transaction signature, the last record of a signed message
+pub const TSIG: QueryType = QueryType::UNKNOWN(250);
+
+const CLASS_ANY: u16 = 255;
+const HMAC_SHA256: &str = "hmac-sha256";
+// Seconds the clock of whoever checks a response's TSIG may be off from ours
+const FUDGE: u16 = 300;
+
+// TSIG errors (RFC 8945 section 3), the response's rcode being NOTAUTH
+const NO_ERROR: u16 = 0;
+const BADSIG: u16 = 16;
+const BADKEY: u16 = 17;
+const BADTIME: u16 = 18;
+const BADTRUNC: u16 = 22;
+
+/// A secret shared with another server, without Debug so it can't end up in a log
+pub struct Key {
+    pub name: String, // normalized, ex. transfer.home.lan
+    pub algorithm: String,
+    secret: Vec<u8>,
+}
+
+/// An operation that may be required to be signed
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Operation {
+    TRANSFER,
+    UPDATE,
+}
+
+/// The TSIG a request came with, and what checking it found
+pub struct Signed {
+    pub key_name: String,
+    key: Option<&'static Key>,
+    algorithm: String,
+    mac: Vec<u8>,
+    time: u64,
+    original_id: u16,
+    error: u16,
+}
+
+/// Signs the messages of the response to a signed request, in the order they're sent
+pub struct Signer {
+    key_name: String,
+    key: Option<&'static Key>, // none when the key or the MAC didn't check out, the response then goes unsigned
+    algorithm: String,
+    prior_mac: Vec<u8>, // the request's, then each message's
+    first: bool,
+    request_time: u64,
+    original_id: u16,
+    error: u16,
+}
+
+impl Key {
+    /// Parse a key written as <name>:<algorithm>:<base64 secret>, ex. transfer.home.lan:hmac-sha256:c2VjcmV0
+    /// The errors never repeat the secret
+    pub fn parse(text: &str) -> Result<Key> {
+        let mut parts = text.splitn(3, ':');
+        let (name, algorithm, secret) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(name), Some(algorithm), Some(secret)) => (name, algorithm, secret),
+            _ => return Err(DnsError::Config("expected name:algorithm:secret".to_string())),
+        };
+
+        dns_name::validate(name).map_err(|e| DnsError::Config(format!("invalid key name {}: {}", name, e)))?;
+        let algorithm = dns_name::normalize(algorithm);
+        if algorithm != HMAC_SHA256 {
+            return Err(DnsError::Config(format!("unsupported algorithm {} for key {}, only {} is", algorithm, name, HMAC_SHA256)));
+        }
+        let secret = base64::decode(secret).map_err(|_| DnsError::Config(format!("the secret of key {} isn't valid base64", name)))?;
+        if secret.is_empty() {
+            return Err(DnsError::Config(format!("the secret of key {} is empty", name)));
+        }
+
+        Ok(Key {
+            name: dns_name::normalize(name),
+            algorithm: algorithm,
+            secret: secret,
+        })
+    }
+}
+
+impl Operation {
+    pub fn parse(text: &str) -> Result<Operation> {
+        match text {
+            "transfer" => Ok(Operation::TRANSFER),
+            "update" => Ok(Operation::UPDATE),
+            _ => Err(DnsError::Config(format!("unknown operation {} (expected transfer or update)", text))),
+        }
+    }
+}
+
+impl Signed {
+    /// Whether the request was signed with a known key and a good MAC, in time
+    pub fn is_valid(&self) -> bool {
+        self.error == NO_ERROR
+    }
+
+    /// The TSIG error of the request, for the logs
+    pub fn error(&self) -> &'static str {
+        match self.error {
+            NO_ERROR => "NOERROR",
+            BADSIG => "BADSIG",
+            BADKEY => "BADKEY",
+            BADTIME => "BADTIME",
+            BADTRUNC => "BADTRUNC",
+            _ => "an unknown TSIG error",
+        }
+    }
+
+    /// A signer for the response, which carries the request's error and is signed unless the key or the MAC was bad
+    pub fn signer(&self) -> Signer {
+        let signs = self.error == NO_ERROR || self.error == BADTIME;
+
+        Signer {
+            key_name: self.key_name.clone(),
+            key: self.key.filter(|_| signs),
+            algorithm: self.algorithm.clone(),
+            prior_mac: self.mac.clone(),
+            first: true,
+            request_time: self.time,
+            original_id: self.original_id,
+            error: self.error,
+        }
+    }
+}
+
+impl Signer {
+    /// Append a TSIG to a message, counted in its additional section
+    /// The first message's MAC covers the request's MAC, the message and every TSIG field,
+    /// any later one's only the MAC before it, the message and the time (RFC 8945 section 5.3.1)
+    pub fn sign(&mut self, message: &[u8]) -> Vec<u8> {
+        let now = unix_time();
+        // a BADTIME response is signed at the request's time, with ours in the other data
+        let (time, other) = match self.error {
+            BADTIME => (self.request_time, now.to_be_bytes()[2..].to_vec()),
+            _ => (now, Vec::new()),
+        };
+
+        let mac = match self.key {
+            Some(key) => {
+                let mut digest = prefixed(&self.prior_mac);
+                digest.extend_from_slice(message);
+                if self.first {
+                    digest.extend(variables(&self.key_name, &self.algorithm, time, FUDGE, self.error, &other));
+                } else {
+                    digest.extend(timers(time, FUDGE));
+                }
+                hmac::hmac_sha256(&key.secret, &digest).to_vec()
+            }
+            None => Vec::new(),
+        };
+
+        let mut rdata = wire_name(&self.algorithm);
+        rdata.extend(timers(time, FUDGE));
+        rdata.extend(prefixed(&mac));
+        rdata.extend_from_slice(&self.original_id.to_be_bytes());
+        rdata.extend_from_slice(&self.error.to_be_bytes());
+        rdata.extend(prefixed(&other));
+
+        let mut signed = message.to_vec();
+        signed.extend(wire_name(&self.key_name));
+        signed.extend_from_slice(&TSIG.to_u16().to_be_bytes());
+        signed.extend_from_slice(&CLASS_ANY.to_be_bytes());
+        signed.extend_from_slice(&0u32.to_be_bytes());
+        signed.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        signed.extend(rdata);
+
+        let res_count = u16::from_be_bytes([signed[10], signed[11]]).wrapping_add(1);
+        signed[10..12].copy_from_slice(&res_count.to_be_bytes());
+
+        self.prior_mac = mac;
+        self.first = false;
+
+        signed
+    }
+}
+
+/// Set the keys requests may be signed with, only the first call has any effect
+pub fn set_keys(keys: Vec<Key>) {
+    let _ = KEYS.set(keys);
+}
+
+/// Keys requests may be signed with, none unless --tsig-key is given
+pub fn keys() -> &'static [Key] {
+    KEYS.get().map_or(&[], |x| x.as_slice())
+}
+
+/// Set the operations that have to be signed, only the first call has any effect
+pub fn set_required(operations: Vec<Operation>) {
+    let _ = REQUIRED.set(operations);
+}
+
+/// Operations that have to be signed, none unless --require-tsig is given
+pub fn required() -> &'static [Operation] {
+    REQUIRED.get().map_or(&[], |x| x.as_slice())
+}
+
+/// Whether an operation has to be signed
+pub fn is_required(operation: Operation) -> bool {
+    required().contains(&operation)
+}
+
+/// Read and check the TSIG a request ends with, None if it isn't signed
+/// Fails if the TSIG is malformed or isn't the last record, which gets FORMERR
+pub fn read(req: &[u8]) -> Result<Option<Signed>> {
+    let mut buf = PacketBuffer::from_bytes(req);
+    let mut header = DnsHeader::new();
+    header.read(&mut buf)?;
+
+    for _ in 0..header.ques_count {
+        DnsQuestion::new(String::new(), QueryType::UNKNOWN(0)).read(&mut buf)?;
+    }
+
+    // the records are only walked past, any of them may be an UPDATE's without data
+    let count = header.ans_count as usize + header.auth_count as usize + header.res_count as usize;
+    let mut found = None;
+    for i in 0..count {
+        let start = buf.pos;
+        let record = UpdateRecord::read(&mut buf)?;
+        if record.q_type == TSIG {
+            if i + 1 != count || header.res_count == 0 {
+                return Err(DnsError::MalformedTsig("not the last additional record"));
+            }
+            found = Some((start, record));
+        }
+    }
+    let (start, record) = match found {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+
+    let mut data = match &record.record {
+        Some(DnsRecord::UNKNOWN { data, .. }) => data.as_slice(),
+        _ => return Err(DnsError::MalformedTsig("no data")),
+    };
+    let algorithm = read_name(&mut data)?;
+    let time = take(&mut data, 6)?.iter().fold(0, |acc, x| (acc << 8) | *x as u64);
+    let fudge = read_u16(&mut data)?;
+    let mac_len = read_u16(&mut data)? as usize;
+    let mac = take(&mut data, mac_len)?.to_vec();
+    let original_id = read_u16(&mut data)?;
+    let error = read_u16(&mut data)?;
+    let other_len = read_u16(&mut data)? as usize;
+    let other = take(&mut data, other_len)?.to_vec();
+
+    let mut signed = Signed {
+        key_name: dns_name::normalize(&record.name),
+        key: keys().iter().find(|x| x.name == dns_name::normalize(&record.name) && x.algorithm == algorithm),
+        algorithm: algorithm,
+        mac: mac,
+        time: time,
+        original_id: original_id,
+        error: NO_ERROR,
+    };
+
+    let key = match signed.key {
+        Some(x) => x,
+        None => {
+            signed.error = BADKEY;
+            return Ok(Some(signed));
+        }
+    };
+
+    // the MAC covers the request as it was before the TSIG was added, with its original id
+    let mut digest = req[..start].to_vec();
+    digest[0..2].copy_from_slice(&original_id.to_be_bytes());
+    digest[10..12].copy_from_slice(&(header.res_count - 1).to_be_bytes());
+    digest.extend(variables(&signed.key_name, &signed.algorithm, time, fudge, error, &other));
+    let expected = hmac::hmac_sha256(&key.secret, &digest);
+
+    // truncated MACs (RFC 8945 section 5.2.2.1) aren't accepted, whether or not they match
+    signed.error = if signed.mac.len() < expected.len() && same_mac(&signed.mac, &expected[..signed.mac.len()]) {
+        BADTRUNC
+    } else if !same_mac(&signed.mac, &expected) {
+        BADSIG
+    } else if unix_time().abs_diff(time) > fudge as u64 {
+        BADTIME
+    } else {
+        NO_ERROR
+    };
+
+    Ok(Some(signed))
+}
+
+/// The TSIG fields a first MAC covers besides the message (RFC 8945 section 4.3.3)
+fn variables(key_name: &str, algorithm: &str, time: u64, fudge: u16, error: u16, other: &[u8]) -> Vec<u8> {
+    let mut out = wire_name(key_name);
+    out.extend_from_slice(&CLASS_ANY.to_be_bytes());
+    out.extend_from_slice(&0u32.to_be_bytes());
+    out.extend(wire_name(algorithm));
+    out.extend(timers(time, fudge));
+    out.extend_from_slice(&error.to_be_bytes());
+    out.extend(prefixed(other));
+
+    out
+}
+
+/// The 48 bit time signed and the fudge
+fn timers(time: u64, fudge: u16) -> Vec<u8> {
+    let mut out = time.to_be_bytes()[2..].to_vec();
+    out.extend_from_slice(&fudge.to_be_bytes());
+
+    out
+}
+
+/// Bytes preceded by their length
+fn prefixed(bytes: &[u8]) -> Vec<u8> {
+    let mut out = (bytes.len() as u16).to_be_bytes().to_vec();
+    out.extend_from_slice(bytes);
+
+    out
+}
+
+/// A name in canonical wire format, lowercase and uncompressed
+fn wire_name(name: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    for label in dns_name::normalize(name).split('.').filter(|x| !x.is_empty()) {
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+
+    out
+}
+
+/// Read an uncompressed name off the front of some bytes, normalized
+fn read_name(data: &mut &[u8]) -> Result<String> {
+    let mut labels = Vec::new();
+    loop {
+        let len = take(data, 1)?[0] as usize;
+        if len == 0 {
+            break;
+        }
+        if len > 63 {
+            return Err(DnsError::MalformedTsig("a compressed name"));
+        }
+        labels.push(String::from_utf8_lossy(take(data, len)?).to_string());
+    }
+
+    Ok(dns_name::normalize(&labels.join(".")))
+}
+
+fn read_u16(data: &mut &[u8]) -> Result<u16> {
+    let x = take(data, 2)?;
+
+    Ok(u16::from_be_bytes([x[0], x[1]]))
+}
+
+/// Take some bytes off the front, failing if there aren't that many
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if data.len() < len {
+        return Err(DnsError::MalformedTsig("cut short"));
+    }
+    let (taken, rest) = data.split_at(len);
+    *data = rest;
+
+    Ok(taken)
+}
+
+/// Compare MACs in time that doesn't depend on where they differ
+fn same_mac(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Seconds since the Unix epoch
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |x| x.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::DnsPacket;
+
+    const KEY_NAME: &str = "transfer.home.lan";
+    const SECRET: &[u8] = b"secret";
+
+    // a change made to a MAC before it's sent
+    type Edit = fn(&mut Vec<u8>);
+
+    /// A query signed as a client signs it, the MAC changed by edit before it's added
+    fn signed_query(key_name: &str, time: u64, edit: impl FnOnce(&mut Vec<u8>)) -> Vec<u8> {
+        set_keys(vec![Key::parse(&format!("{}:hmac-sha256:c2VjcmV0", KEY_NAME)).unwrap()]);
+
+        let mut query = DnsPacket::new().question(DnsQuestion::new("home.lan".to_string(), QueryType::SOA));
+        query.header.id = 0x4242;
+        query.header.query_res = false;
+        let mut message = query.to_bytes().unwrap();
+
+        let mut digest = message.clone();
+        digest.extend(variables(key_name, HMAC_SHA256, time, FUDGE, NO_ERROR, &[]));
+        let mut mac = hmac::hmac_sha256(SECRET, &digest).to_vec();
+        edit(&mut mac);
+
+        let mut rdata = wire_name(HMAC_SHA256);
+        rdata.extend(timers(time, FUDGE));
+        rdata.extend(prefixed(&mac));
+        rdata.extend_from_slice(&0x4242u16.to_be_bytes());
+        rdata.extend_from_slice(&NO_ERROR.to_be_bytes());
+        rdata.extend(prefixed(&[]));
+
+        message.extend(wire_name(key_name));
+        message.extend_from_slice(&TSIG.to_u16().to_be_bytes());
+        message.extend_from_slice(&CLASS_ANY.to_be_bytes());
+        message.extend_from_slice(&0u32.to_be_bytes());
+        message.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        message.extend(rdata);
+        message[11] = 1;
+
+        message
+    }
+
+    #[test]
+    fn good_mac_passes() {
+        let signed = read(&signed_query(KEY_NAME, unix_time(), |_| ())).unwrap().unwrap();
+        assert!(signed.is_valid());
+        assert_eq!(signed.error(), "NOERROR");
+        assert_eq!(signed.key_name, KEY_NAME);
+    }
+
+    #[test]
+    fn unsigned_request_has_no_tsig() {
+        let mut query = DnsPacket::new().question(DnsQuestion::new("home.lan".to_string(), QueryType::SOA));
+        assert!(read(&query.to_bytes().unwrap()).unwrap().is_none());
+    }
+
+    #[test]
+    fn bad_requests_get_their_tsig_error() {
+        let now = unix_time();
+        let cases: [(&str, u64, Edit, &str); 5] = [
+            (KEY_NAME, now, |mac| mac[7] ^= 1, "BADSIG"),
+            (KEY_NAME, now, |mac| mac.truncate(16), "BADTRUNC"),
+            (KEY_NAME, now, |mac| mac.push(0), "BADSIG"),
+            ("other.home.lan", now, |_| (), "BADKEY"),
+            (KEY_NAME, now - FUDGE as u64 - 60, |_| (), "BADTIME"),
+        ];
+
+        for (key_name, time, edit, error) in cases {
+            let signed = read(&signed_query(key_name, time, edit)).unwrap().unwrap();
+            assert!(!signed.is_valid());
+            assert_eq!(signed.error(), error);
+        }
+    }
+
+    #[test]
+    fn time_within_the_fudge_passes() {
+        let signed = read(&signed_query(KEY_NAME, unix_time() - FUDGE as u64 + 10, |_| ())).unwrap().unwrap();
+        assert!(signed.is_valid());
+    }
+
+    #[test]
+    fn tsig_not_last_is_malformed() {
+        let mut query = signed_query(KEY_NAME, unix_time(), |_| ());
+        // an empty root A record after it
+        query.extend_from_slice(&[0, 0, 1, 0, 1, 0, 0, 0, 0, 0, 0]);
+        query[11] = 2;
+        assert!(read(&query).is_err());
+    }
+}
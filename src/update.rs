@@ -0,0 +1,366 @@
+//! Dynamic updates (RFC 2136) of the local zones, from the clients allowed with --allow-update or signed with a --tsig-key
+//! An update names a zone, the prerequisites that have to hold and the records to add and delete,
+//! and is applied whole or not at all, bumping the SOA serial when anything changed
+//! Every change is appended to a journal next to the zone file, <path>.jnl, and replayed over the file on the next start
+//! Zones that aren't served here and bad signatures get NOTAUTH, secondary zones and clients that aren't allowed REFUSED
+
+use std::fs::{ self, OpenOptions };
+use std::io::{ self, Write };
+use std::iter;
+use std::net::IpAddr;
+use std::sync::{ Mutex, MutexGuard, OnceLock };
+
+use log::{ info, warn };
+
+use crate::dns_name;
+use crate::error::DnsError;
+use crate::idna;
+use crate::packet::{ self, DnsHeader, DnsPacket, DnsQuestion, DnsRecord, PacketBuffer, QueryType, ResCode, UpdateRecord };
+use crate::secondary;
+use crate::tsig::{ self, Operation, Signed, Signer };
+use crate::xfr::{ self, Network };
+use crate::zone::{ self, Delta, Zone };
+
+type Result<T> = std::result::Result<T, DnsError>;
+
+static ALLOWED: OnceLock<Vec<Network>> = OnceLock::new();
+// Updates are applied one at a time, each to the zone the one before left
+static APPLYING: Mutex<()> = Mutex::new(());
+
+const CLASS_IN: u16 = 1;
+const CLASS_NONE: u16 = 254;
+const CLASS_ANY: u16 = 255;
+// TYPE * in a prerequisite or a deletion stands for every type
+const ANY: QueryType = QueryType::UNKNOWN(255);
+
+/// The sections of an UPDATE as RFC 2136 names them
+struct Update {
+    zone: DnsQuestion,
+    prerequisites: Vec<UpdateRecord>,
+    updates: Vec<UpdateRecord>,
+}
+
+/// Why an update was turned down, the rcode to answer with and the reason to log
+type Rejection = (ResCode, String);
+
+/// Set the clients allowed to update the zones, only the first call has any effect
+pub fn set_allowed(networks: Vec<Network>) {
+    let _ = ALLOWED.set(networks);
+}
+
+/// Networks allowed to update the zones, none unless --allow-update is given
+pub fn allowed() -> &'static [Network] {
+    ALLOWED.get().map_or(&[], |x| x.as_slice())
+}
+
+/// Hold off updates until the guard is dropped, for a zone swapped in from elsewhere
+pub fn applying() -> MutexGuard<'static, ()> {
+    APPLYING.lock().unwrap()
+}
+
+/// Answer an UPDATE from a client, applying it if everything it asks for holds
+/// The response carries the zone section back and nothing else, and is to be signed with the signer if there is one
+pub fn answer(header: &DnsHeader, req: &[u8], client: IpAddr) -> (DnsPacket, Option<Signer>) {
+    let mut response = packet::response_to(header);
+
+    let signed = match tsig::read(req) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Malformed UPDATE from {}: {}", client, e);
+            response.header.res_code = ResCode::FORM_ERR;
+            return (response, None);
+        }
+    };
+    let signer = signed.as_ref().map(|x| x.signer());
+
+    let update = match read(req) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Malformed UPDATE from {}: {}", client, e);
+            response.header.res_code = ResCode::FORM_ERR;
+            return (response, signer);
+        }
+    };
+    response.questions.push(update.zone.clone());
+
+    let name = idna::to_unicode(&update.zone.name);
+    let from = match &signed {
+        Some(x) => format!("{} (key {})", client, x.key_name),
+        None => client.to_string(),
+    };
+    match apply(&update, client, signed.as_ref()) {
+        Ok(done) => info!("Applied an UPDATE of {} from {}, {}", name, from, done),
+        Err((res_code, why)) => {
+            info!("Rejected an UPDATE of {} from {} with {}, {}", name, from, res_code, why);
+            response.header.res_code = res_code;
+        }
+    }
+
+    (response, signer)
+}
+
+/// Replay the journal of a zone loaded from a master file, the updates made since the file's serial
+/// A journal none of whose changes start from the file's serial was left behind by an edit of the file, and is ignored
+pub fn replay(zone: Zone) -> Result<Zone> {
+    let path = match &zone.path {
+        Some(x) => journal_path(x),
+        None => return Ok(zone),
+    };
+    let text = match fs::read_to_string(&path) {
+        Ok(x) => x,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(zone),
+        Err(e) => return Err(DnsError::Config(format!("{}: {}", path, e))),
+    };
+
+    let records: Vec<DnsRecord> = zone::read_records(&zone.origin, &text, &path)?.into_iter()
+        .map(|(_, x)| x)
+        .collect();
+    let deltas = zone::split_deltas(&records).ok_or_else(|| DnsError::Config(format!("{}: the last change is cut short", path)))?;
+
+    let start = match deltas.iter().position(|x| zone::soa_serial(&x.from) == zone.serial()) {
+        Some(x) => x,
+        None => {
+            if !deltas.is_empty() {
+                warn!("Ignoring the journal {}, none of its changes start from the zone file's serial {}", path, zone.serial());
+            }
+            return Ok(zone);
+        }
+    };
+
+    let replayed = zone.apply(&deltas[start..]).map_err(|e| DnsError::Config(format!("{}: {}", path, e)))?;
+    info!("Replayed {} updates of {} from {}, now at serial {}", deltas.len() - start, idna::to_unicode(&zone.origin), path, replayed.serial());
+
+    Ok(replayed)
+}
+
+/// Read an UPDATE's sections, the zone section holding a single SOA question
+fn read(req: &[u8]) -> Result<Update> {
+    let mut buf = PacketBuffer::from_bytes(req);
+    let mut header = DnsHeader::new();
+    header.read(&mut buf)?;
+
+    if header.ques_count != 1 {
+        return Err(DnsError::MalformedUpdate(format!("the zone section holds {} zones instead of one", header.ques_count)));
+    }
+    let mut zone = DnsQuestion::new(String::new(), QueryType::UNKNOWN(0));
+    zone.read(&mut buf)?;
+    if zone.q_type != QueryType::SOA {
+        return Err(DnsError::MalformedUpdate(format!("the zone section asks for {} instead of SOA", zone.q_type)));
+    }
+
+    let prerequisites = (0..header.ans_count).map(|_| UpdateRecord::read(&mut buf)).collect::<std::result::Result<Vec<_>, _>>()?;
+    let updates = (0..header.auth_count).map(|_| UpdateRecord::read(&mut buf)).collect::<std::result::Result<Vec<_>, _>>()?;
+
+    Ok(Update {
+        zone: zone,
+        prerequisites: prerequisites,
+        updates: updates,
+    })
+}
+
+/// Check an update and apply it to its zone, describing what changed
+fn apply(update: &Update, client: IpAddr, signed: Option<&Signed>) -> std::result::Result<String, Rejection> {
+    // a good signature lets any client through, without one the client has to be allowed
+    match signed {
+        Some(x) if !x.is_valid() => return Err((ResCode::NOT_AUTH, format!("{} for the TSIG key", x.error()))),
+        Some(_) => (),
+        None if tsig::is_required(Operation::UPDATE) => {
+            return Err((ResCode::REFUSED, "it isn't signed and --require-tsig update is given".to_string()));
+        }
+        None if !allowed().iter().any(|x| x.contains(client)) => {
+            return Err((ResCode::REFUSED, "the client isn't allowed by --allow-update".to_string()));
+        }
+        None => (),
+    }
+
+    let _applying = applying();
+
+    let origin = dns_name::normalize(&update.zone.name);
+    let zone = match zone::find(&origin) {
+        Some(x) if x.origin == origin => x,
+        _ => return Err((ResCode::NOT_AUTH, "it isn't a zone served here".to_string())),
+    };
+    if secondary::is_secondary(&origin) {
+        return Err((ResCode::REFUSED, "it's a secondary zone, updates go to its primary".to_string()));
+    }
+
+    if let Some(x) = update.prerequisites.iter().chain(&update.updates).find(|x| !dns_name::is_subdomain(&x.name, &origin)) {
+        return Err((ResCode::NOT_ZONE, format!("{} is outside the zone", x.name)));
+    }
+    check_prerequisites(&zone, &update.prerequisites)?;
+    prescan(&update.updates)?;
+
+    let mut records: Vec<DnsRecord> = zone.records()
+        .filter(|x| !matches!(x, DnsRecord::SOA { .. }))
+        .cloned()
+        .collect();
+    let mut soa = zone.soa.clone();
+    for x in &update.updates {
+        change(&mut records, &mut soa, &origin, x);
+    }
+
+    let old: Vec<&DnsRecord> = zone.records().filter(|x| !matches!(x, DnsRecord::SOA { .. })).collect();
+    let deleted: Vec<DnsRecord> = old.iter().filter(|x| !records.contains(x)).map(|x| (*x).clone()).collect();
+    let added: Vec<DnsRecord> = records.iter().filter(|x| !old.contains(x)).cloned().collect();
+    if deleted.is_empty() && added.is_empty() && soa == zone.soa {
+        return Ok("nothing changed".to_string());
+    }
+
+    // an update that didn't bring a newer SOA of its own moves the serial on by one
+    if zone::soa_serial(&soa) == zone.serial() {
+        if let DnsRecord::SOA { serial, .. } = &mut soa {
+            *serial = serial.wrapping_add(1);
+        }
+    }
+
+    let delta = Delta {
+        from: zone.soa.clone(),
+        to: soa.clone(),
+        deleted: deleted,
+        added: added,
+    };
+
+    records.push(soa);
+    let mut updated = Zone::from_records(&origin, records).map_err(|e| (ResCode::REFUSED, e.to_string()))?;
+    updated.path = zone.path.clone();
+
+    // written down before it's served, so an update that was answered is never lost
+    if let Some(path) = &zone.path {
+        let path = journal_path(path);
+        append(&path, &delta).map_err(|e| (ResCode::SERV_FAIL, format!("the journal {} couldn't be written: {}", path, e)))?;
+    }
+
+    let done = format!("serial {}, {} deleted and {} added records", updated.serial(), delta.deleted.len(), delta.added.len());
+    zone::replace(updated);
+
+    Ok(done)
+}
+
+/// Check every prerequisite against a zone (RFC 2136 section 3.2)
+/// Those of class IN are values, and the RRsets they name have to hold exactly those values
+fn check_prerequisites(zone: &Zone, prerequisites: &[UpdateRecord]) -> std::result::Result<(), Rejection> {
+    let mut values: Vec<&DnsRecord> = Vec::new();
+
+    for x in prerequisites {
+        if x.ttl != 0 {
+            return Err((ResCode::FORM_ERR, format!("a prerequisite for {} has a TTL", x.name)));
+        }
+
+        let mut rrset = zone.records().filter(|r| dns_name::eq_ignore_case(r.domain(), &x.name) && (x.q_type == ANY || r.query_type() == x.q_type));
+        match (x.class, &x.record) {
+            (CLASS_ANY, None) if x.q_type == ANY => {
+                if rrset.next().is_none() {
+                    return Err((ResCode::NX_DOMAIN, format!("{} doesn't exist", x.name)));
+                }
+            }
+            (CLASS_ANY, None) => {
+                if rrset.next().is_none() {
+                    return Err((ResCode::NX_RR_SET, format!("{} has no {} records", x.name, x.q_type)));
+                }
+            }
+            (CLASS_NONE, None) if x.q_type == ANY => {
+                if rrset.next().is_some() {
+                    return Err((ResCode::YX_DOMAIN, format!("{} exists", x.name)));
+                }
+            }
+            (CLASS_NONE, None) => {
+                if rrset.next().is_some() {
+                    return Err((ResCode::YX_RR_SET, format!("{} has {} records", x.name, x.q_type)));
+                }
+            }
+            (CLASS_IN, Some(record)) => values.push(record),
+            _ => return Err((ResCode::FORM_ERR, format!("a prerequisite for {} of class {} isn't valid", x.name, x.class))),
+        }
+    }
+
+    for value in &values {
+        let in_rrset = |r: &&DnsRecord| dns_name::eq_ignore_case(r.domain(), value.domain()) && r.query_type() == value.query_type();
+        let rrset: Vec<&DnsRecord> = zone.records().filter(in_rrset).collect();
+        let wanted: Vec<&DnsRecord> = values.iter().copied().filter(in_rrset).collect();
+
+        if !rrset.iter().all(|x| wanted.iter().any(|y| same(x, y))) || !wanted.iter().all(|x| rrset.iter().any(|y| same(x, y))) {
+            return Err((ResCode::NX_RR_SET, format!("the {} records of {} aren't the ones given", value.query_type(), value.domain())));
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that every update is one RFC 2136 section 3.4.1.3 allows, and of a type the zones can hold
+fn prescan(updates: &[UpdateRecord]) -> std::result::Result<(), Rejection> {
+    for x in updates {
+        // AXFR, IXFR, MAILB, MAILA and *, and OPT, are never records of a zone
+        let meta = (251..=255).contains(&x.q_type.to_u16()) || x.q_type == QueryType::OPT;
+
+        match (x.class, &x.record) {
+            (CLASS_IN, Some(DnsRecord::UNKNOWN { .. })) if !meta => {
+                return Err((ResCode::REFUSED, format!("type {} isn't supported in zones", x.q_type.to_u16())));
+            }
+            (CLASS_IN, Some(_)) if !meta => (),
+            (CLASS_ANY, None) if x.ttl == 0 && (!meta || x.q_type == ANY) => (),
+            (CLASS_NONE, Some(_)) if x.ttl == 0 && !meta => (),
+            _ => return Err((ResCode::FORM_ERR, format!("an update of {} {} of class {} isn't valid", x.name, x.q_type, x.class))),
+        }
+    }
+
+    Ok(())
+}
+
+/// Apply one update to a zone's records and SOA, skipping what RFC 2136 section 3.4.2 says to skip:
+/// a CNAME alongside other records, an SOA that isn't newer, and the SOA and NS records of the origin when deleting
+fn change(records: &mut Vec<DnsRecord>, soa: &mut DnsRecord, origin: &str, x: &UpdateRecord) {
+    let at_origin = dns_name::normalize(&x.name) == origin;
+    let owned = |r: &DnsRecord| dns_name::eq_ignore_case(r.domain(), &x.name);
+    let is_cname = |r: &DnsRecord| matches!(r, DnsRecord::CNAME { .. });
+
+    let apex_ns = |r: &DnsRecord| at_origin && r.query_type() == QueryType::NS;
+
+    match (x.class, &x.record) {
+        (CLASS_IN, Some(record @ DnsRecord::SOA { .. })) if at_origin && xfr::serial_newer(zone::soa_serial(record), zone::soa_serial(soa)) => {
+            *soa = record.clone();
+        }
+        (CLASS_IN, Some(DnsRecord::SOA { .. })) => (),
+        // a CNAME can't join other records, nor other records a CNAME
+        (CLASS_IN, Some(record)) if records.iter().any(|r| owned(r) && is_cname(r) != is_cname(record)) => (),
+        (CLASS_IN, Some(record)) => {
+            // a record that's already there only takes the new TTL, a CNAME replaces the one before it
+            let replaced = |r: &DnsRecord| same(r, record) || (owned(r) && is_cname(r) && is_cname(record));
+            records.retain(|r| !replaced(r));
+            records.push(record.clone());
+        }
+        (CLASS_ANY, None) if x.q_type == ANY => records.retain(|r| !owned(r) || apex_ns(r)),
+        (CLASS_ANY, None) if at_origin && x.q_type == QueryType::NS => (),
+        (CLASS_ANY, None) => records.retain(|r| !(owned(r) && r.query_type() == x.q_type)),
+        (CLASS_NONE, Some(DnsRecord::SOA { .. })) => (),
+        // the origin keeps its last NS record
+        (CLASS_NONE, Some(record)) if apex_ns(record) && records.iter().filter(|r| owned(r) && apex_ns(r)).count() <= 1 => (),
+        (CLASS_NONE, Some(record)) => records.retain(|r| !same(r, record)),
+        _ => (),
+    }
+}
+
+/// Whether two records are the same record, whatever their TTLs and the case of their owners
+fn same(a: &DnsRecord, b: &DnsRecord) -> bool {
+    dns_name::eq_ignore_case(a.domain(), b.domain()) && a.rdata_eq(b)
+}
+
+/// Append a change to a journal, as master file lines in the order IXFR sends them
+fn append(path: &str, delta: &Delta) -> Result<()> {
+    let text: String = iter::once(&delta.from)
+        .chain(&delta.deleted)
+        .chain(iter::once(&delta.to))
+        .chain(&delta.added)
+        .map(|x| zone::master_line(x) + "\n")
+        .collect();
+
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(text.as_bytes())?;
+    file.sync_data()?;
+
+    Ok(())
+}
+
+/// The journal of a zone file
+fn journal_path(path: &str) -> String {
+    format!("{}.jnl", path)
+}
@@ -0,0 +1,642 @@
+//! Upstream resolvers for forwarding and how one is picked for each lookup
+//! Every lookup's latency and outcome feed a moving average per upstream, which the
+//! fastest strategy uses to prefer the quickest healthy upstream
+//! Each upstream also keeps a histogram of its latencies in power of two milliseconds up to 8s,
+//! and counts of its timeouts, connection errors, FORMERR and SERVFAIL answers, all atomics bumped on the query path
+//! Upstreams left unused for a while are probed again so a recovered one can win back traffic
+//! The race strategy sends each lookup to the two best upstreams at once and takes the first answer
+//! Upstreams that only answer once EDNS is dropped from a query are remembered for a while,
+//! so their lookups skip straight to plain queries
+
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{ Mutex, OnceLock, PoisonError };
+use std::sync::atomic::{ AtomicU64, AtomicUsize, Ordering };
+use std::time::{ Duration, Instant };
+
+use crate::error::DnsError;
+use crate::json;
+
+#[cfg(feature = "tls")]
+use crate::doh_client::HttpsUpstream;
+#[cfg(feature = "tls")]
+use crate::dot_client::TlsUpstream;
+use crate::packet::{ DnsPacket, ResCode };
+
+type Error = DnsError;
+type Result<T> = std::result::Result<T, Error>;
+
+// Weight of the newest sample in the moving averages
+const SMOOTHING: f64 = 0.2;
+
+// Upstreams failing more often than this are skipped while a healthier one exists
+const MAX_FAILURE_RATE: f64 = 0.5;
+
+// Upstreams not picked for this long get the next lookup, so their stats stay current
+const PROBE_INTERVAL: Duration = Duration::from_secs(30);
+
+// How long an upstream that failed EDNS queries gets plain ones before EDNS is tried again
+const EDNS_RETRY_AFTER: Duration = Duration::from_secs(600);
+
+// Addresses that failed EDNS queries but answered plain ones, shared by every thread
+// Kept by address rather than per Upstream since recursive lookups build theirs per query
+static NO_EDNS: Mutex<Vec<NoEdns>> = Mutex::new(Vec::new());
+
+struct NoEdns {
+    addr: SocketAddr,
+    until: Instant, // EDNS is tried again from here on
+    fallbacks: u64, // lookups only answered once retried without EDNS
+}
+
+// Entries kept before expired ones are dropped, recursion meets many servers
+const MAX_NO_EDNS: usize = 256;
+
+/// Upper bounds of the latency histogram's buckets in milliseconds, a last bucket takes anything slower
+pub const BUCKET_BOUNDS_MS: [u64; 14] = [1, 2, 4, 8, 16, 32, 64, 128, 256, 512, 1024, 2048, 4096, 8192];
+pub const BUCKETS: usize = BUCKET_BOUNDS_MS.len() + 1;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+
+// The latency average before any lookup succeeded
+const NO_LATENCY: u64 = u64::MAX;
+
+// The health of every address in a set of upstreams, shared by every set it's in
+// Only configured upstreams are here, recursive lookups don't pick theirs through Upstreams
+static HEALTH: Mutex<Vec<&'static Health>> = Mutex::new(Vec::new());
+
+// When the server began using upstreams, last uses are kept as time since
+static EPOCH: OnceLock<Instant> = OnceLock::new();
+
+/// How the upstream for a lookup is picked
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    /// The healthy upstream with the lowest average latency
+    FASTEST,
+    /// Each upstream in turn
+    ROUND_ROBIN,
+    /// The first healthy upstream in the order given
+    SEQUENTIAL,
+    /// The two fastest healthy upstreams at once, the first to answer wins
+    /// Doubles the queries sent upstream, only plain UDP upstreams of the same address family are raced
+    RACE,
+}
+
+impl Strategy {
+    /// Parse a strategy as given on the command line
+    pub fn from_name(name: &str) -> Option<Strategy> {
+        match name {
+            "fastest" => Some(Strategy::FASTEST),
+            "round-robin" => Some(Strategy::ROUND_ROBIN),
+            "sequential" => Some(Strategy::SEQUENTIAL),
+            "race" => Some(Strategy::RACE),
+            _ => None,
+        }
+    }
+}
+
+/// How queries reach an upstream
+#[derive(Debug)]
+pub enum Protocol {
+    /// Plain DNS over UDP, falling back to TCP for truncated responses
+    UDP,
+    /// DNS over HTTPS, RFC 8484
+    #[cfg(feature = "tls")]
+    HTTPS(HttpsUpstream),
+    /// DNS over TLS, RFC 7858
+    #[cfg(feature = "tls")]
+    TLS(TlsUpstream),
+}
+
+/// A resolver queries are forwarded to
+#[derive(Debug)]
+pub struct Upstream {
+    pub addr: SocketAddr,
+    pub protocol: Protocol,
+}
+
+impl Upstream {
+    /// A plain DNS resolver
+    pub fn udp(addr: SocketAddr) -> Upstream {
+        Upstream {
+            addr: addr,
+            protocol: Protocol::UDP,
+        }
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.protocol {
+            Protocol::UDP => write!(f, "{}", self.addr),
+            #[cfg(feature = "tls")]
+            Protocol::HTTPS(x) => write!(f, "{} ({})", x, self.addr),
+            #[cfg(feature = "tls")]
+            Protocol::TLS(x) => write!(f, "{} ({})", x, self.addr),
+        }
+    }
+}
+
+/// How a lookup an upstream didn't answer failed
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// Every attempt went unanswered
+    TIMEOUT,
+    /// The connection or socket failed
+    CONNECTION,
+    /// Anything else, ex. an HTTP error status from a DNS over HTTPS upstream
+    OTHER,
+}
+
+impl Failure {
+    /// What kind of failure an error from a lookup is, the transports fail with an io::Error when the network does
+    pub fn of(e: &DnsError) -> Failure {
+        match e {
+            DnsError::UpstreamTimeout { .. } => Failure::TIMEOUT,
+            // timeouts show up as WouldBlock on unix and TimedOut on windows
+            DnsError::Io(x) if matches!(x.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => Failure::TIMEOUT,
+            DnsError::Io(_) => Failure::CONNECTION,
+            #[cfg(feature = "tls")]
+            DnsError::Tls(_) => Failure::CONNECTION,
+            _ => Failure::OTHER,
+        }
+    }
+}
+
+/// Whether an upstream is getting its share of lookups
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum State {
+    ACTIVE,
+    /// Failing too often, skipped while a healthier upstream exists and probed now and then
+    COOLING_DOWN,
+    /// Answering, but only once EDNS was dropped, so its queries go without it for a while
+    EDNS_DISABLED,
+}
+
+impl State {
+    pub fn name(&self) -> &'static str {
+        match self {
+            State::ACTIVE => "active",
+            State::COOLING_DOWN => "cooling-down",
+            State::EDNS_DISABLED => "edns-disabled",
+        }
+    }
+}
+
+/// What has been seen from one upstream
+#[derive(Copy, Clone, Debug)]
+pub struct UpstreamStats {
+    pub addr: SocketAddr,
+    pub latency: Option<Duration>, // moving average of successful lookups, None until one succeeds
+    pub failure_rate: f64,         // moving average of failed lookups, 0 to 1
+    pub queries: u64,
+    pub failures: u64,
+    pub timeouts: u64,          // failed lookups that went unanswered
+    pub connection_errors: u64, // failed lookups whose connection or socket failed
+    pub form_errs: u64,         // answers with FORMERR
+    pub serv_fails: u64,        // answers with SERVFAIL
+    pub latency_buckets: [u64; BUCKETS], // answered lookups by latency, bounded by BUCKET_BOUNDS_MS
+    pub edns: bool,          // false while queries go without EDNS after it failed
+    pub edns_fallbacks: u64, // lookups only answered once retried without EDNS
+}
+
+impl UpstreamStats {
+    pub fn state(&self) -> State {
+        if self.failure_rate >= MAX_FAILURE_RATE {
+            State::COOLING_DOWN
+        } else if !self.edns {
+            State::EDNS_DISABLED
+        } else {
+            State::ACTIVE
+        }
+    }
+
+    /// The latency a share of answered lookups came in under, ex. 0.99 for the p99, as the upper bound of its bucket
+    /// None before any lookup was answered or when it falls past the last bucket
+    pub fn percentile(&self, share: f64) -> Option<Duration> {
+        let total: u64 = self.latency_buckets.iter().sum();
+        if total == 0 {
+            return None;
+        }
+
+        let rank = ((total as f64 * share).ceil() as u64).max(1);
+        let mut seen = 0;
+        let bucket = self.latency_buckets.iter().position(|x| {
+            seen += x;
+            seen >= rank
+        })?;
+
+        BUCKET_BOUNDS_MS.get(bucket).map(|x| Duration::from_millis(*x))
+    }
+
+    /// The stats as JSON, the histogram keyed by each bucket's bound in milliseconds, ex.
+    /// {"addr":"9.9.9.9:53","state":"active","latency_ms":12.5,"p50_ms":16,"p99_ms":64,"failure_rate":0.01,"queries":90,"failures":1,"timeouts":1,
+    /// "connection_errors":0,"formerr":0,"servfail":2,"edns":true,"edns_fallbacks":0,"histogram":{"1":0,"2":0,...,"8192":0,"+Inf":0}}
+    pub fn to_json(&self) -> String {
+        let bounds = BUCKET_BOUNDS_MS.iter().map(|x| x.to_string()).chain(["+Inf".to_string()]);
+        let histogram: Vec<String> = bounds.zip(self.latency_buckets.iter())
+            .map(|(bound, count)| format!("\"{}\":{}", bound, count))
+            .collect();
+
+        format!(
+            "{{\"addr\":{},\"state\":\"{}\",\"latency_ms\":{},\"p50_ms\":{},\"p99_ms\":{},\"failure_rate\":{:.3},\"queries\":{},\"failures\":{},\"timeouts\":{},\
+             \"connection_errors\":{},\"formerr\":{},\"servfail\":{},\"edns\":{},\"edns_fallbacks\":{},\"histogram\":{{{}}}}}",
+            json::quote(&self.addr.to_string()),
+            self.state().name(),
+            self.latency.map_or_else(|| "null".to_string(), |x| format!("{:.3}", x.as_secs_f64() * 1000.0)),
+            ms_json(self.percentile(0.5)),
+            ms_json(self.percentile(0.99)),
+            self.failure_rate,
+            self.queries,
+            self.failures,
+            self.timeouts,
+            self.connection_errors,
+            self.form_errs,
+            self.serv_fails,
+            self.edns,
+            self.edns_fallbacks,
+            histogram.join(","),
+        )
+    }
+}
+
+fn ms_json(x: Option<Duration>) -> String {
+    x.map_or_else(|| "null".to_string(), |x| x.as_millis().to_string())
+}
+
+/// What every lookup to one address has seen, updated with atomics alone so recording a lookup never waits
+#[derive(Debug)]
+struct Health {
+    addr: SocketAddr,
+    latency: AtomicU64,      // moving average in nanoseconds, NO_LATENCY until a lookup succeeds
+    failure_rate: AtomicU64, // bits of the f64 moving average
+    queries: AtomicU64,
+    failures: AtomicU64,
+    timeouts: AtomicU64,
+    connection_errors: AtomicU64,
+    form_errs: AtomicU64,
+    serv_fails: AtomicU64,
+    buckets: [AtomicU64; BUCKETS],
+    last_used: AtomicU64, // nanoseconds from EPOCH plus one, 0 if never used
+}
+
+impl Health {
+    /// The health of an address, made the first time it's asked for
+    fn of(addr: SocketAddr) -> &'static Health {
+        EPOCH.get_or_init(Instant::now);
+        let mut health = HEALTH.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(x) = health.iter().find(|x| x.addr == addr) {
+            return x;
+        }
+
+        // as many as there are configured upstreams, each kept for the life of the process
+        let x: &'static Health = Box::leak(Box::new(Health {
+            addr: addr,
+            latency: AtomicU64::new(NO_LATENCY),
+            failure_rate: AtomicU64::new(0.0f64.to_bits()),
+            queries: ZERO,
+            failures: ZERO,
+            timeouts: ZERO,
+            connection_errors: ZERO,
+            form_errs: ZERO,
+            serv_fails: ZERO,
+            buckets: [ZERO; BUCKETS],
+            last_used: ZERO,
+        }));
+        health.push(x);
+
+        x
+    }
+
+    fn latency(&self) -> Option<Duration> {
+        match self.latency.load(Ordering::Relaxed) {
+            NO_LATENCY => None,
+            x => Some(Duration::from_nanos(x)),
+        }
+    }
+
+    fn failure_rate(&self) -> f64 {
+        f64::from_bits(self.failure_rate.load(Ordering::Relaxed))
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.failure_rate() < MAX_FAILURE_RATE
+    }
+
+    fn is_stale(&self, now: Instant) -> bool {
+        match self.last_used.load(Ordering::Relaxed) {
+            0 => true,
+            x => since_epoch(now).saturating_sub(x - 1) >= PROBE_INTERVAL.as_nanos() as u64,
+        }
+    }
+
+    fn mark_used(&self, now: Instant) {
+        self.last_used.store(since_epoch(now) + 1, Ordering::Relaxed);
+    }
+
+    fn record_success(&self, latency: Duration, res_code: ResCode) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        match res_code {
+            ResCode::FORM_ERR => self.form_errs.fetch_add(1, Ordering::Relaxed),
+            ResCode::SERV_FAIL => self.serv_fails.fetch_add(1, Ordering::Relaxed),
+            _ => 0,
+        };
+
+        let ms = latency.as_millis() as u64;
+        let bucket = BUCKET_BOUNDS_MS.iter().position(|x| ms < *x).unwrap_or(BUCKET_BOUNDS_MS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+
+        let sample = latency.as_nanos().min(NO_LATENCY as u128 - 1) as u64;
+        average(&self.latency, |avg| match avg {
+            NO_LATENCY => sample,
+            x => (x as f64 * (1.0 - SMOOTHING) + sample as f64 * SMOOTHING) as u64,
+        });
+        average(&self.failure_rate, |rate| (f64::from_bits(rate) * (1.0 - SMOOTHING)).to_bits());
+    }
+
+    fn record_failure(&self, failure: Failure) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        self.failures.fetch_add(1, Ordering::Relaxed);
+        match failure {
+            Failure::TIMEOUT => self.timeouts.fetch_add(1, Ordering::Relaxed),
+            Failure::CONNECTION => self.connection_errors.fetch_add(1, Ordering::Relaxed),
+            Failure::OTHER => 0,
+        };
+
+        average(&self.failure_rate, |rate| (f64::from_bits(rate) * (1.0 - SMOOTHING) + SMOOTHING).to_bits());
+    }
+
+    fn stats(&self) -> UpstreamStats {
+        let mut buckets = [0; BUCKETS];
+        for (count, x) in buckets.iter_mut().zip(self.buckets.iter()) {
+            *count = x.load(Ordering::Relaxed);
+        }
+
+        let (edns, edns_fallbacks) = edns_state(self.addr);
+
+        UpstreamStats {
+            addr: self.addr,
+            latency: self.latency(),
+            failure_rate: self.failure_rate(),
+            queries: self.queries.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            connection_errors: self.connection_errors.load(Ordering::Relaxed),
+            form_errs: self.form_errs.load(Ordering::Relaxed),
+            serv_fails: self.serv_fails.load(Ordering::Relaxed),
+            latency_buckets: buckets,
+            edns: edns,
+            edns_fallbacks: edns_fallbacks,
+        }
+    }
+}
+
+/// Fold a sample into a moving average, retried if another thread got in first so neither sample is lost
+fn average(x: &AtomicU64, update: impl Fn(u64) -> u64) {
+    let _ = x.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |old| Some(update(old)));
+}
+
+fn since_epoch(now: Instant) -> u64 {
+    EPOCH.get().map_or(0, |x| now.saturating_duration_since(*x).as_nanos() as u64)
+}
+
+/// The configured upstreams, shared by every thread answering queries
+#[derive(Debug)]
+pub struct Upstreams {
+    upstreams: Vec<Upstream>,
+    strategy: Strategy,
+    health: Vec<&'static Health>, // of each upstream, in the same order
+    next: AtomicUsize,            // round robin position
+}
+
+impl Upstreams {
+    /// Upstreams in the order given, there must be at least one
+    pub fn new(upstreams: Vec<Upstream>, strategy: Strategy) -> Upstreams {
+        assert!(!upstreams.is_empty(), "At least one upstream is needed");
+
+        Upstreams {
+            health: upstreams.iter().map(|x| Health::of(x.addr)).collect(),
+            upstreams: upstreams,
+            strategy: strategy,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Pick the upstream for a lookup
+    /// With the race strategy this is the better of the two, for callers that can't race
+    pub fn select(&self) -> &Upstream {
+        &self.upstreams[self.select_at(Instant::now()).0]
+    }
+
+    /// Indexes of the upstream for a lookup starting at a given time
+    /// and, with the race strategy, of the one racing it
+    fn select_at(&self, now: Instant) -> (usize, Option<usize>) {
+        let health = &self.health;
+
+        // without a probe now and then, an upstream that was skipped would never be seen to recover
+        let stale = health.iter().position(|x| x.is_stale(now));
+
+        let i = match (self.strategy, stale) {
+            (Strategy::ROUND_ROBIN, _) => self.next.fetch_add(1, Ordering::Relaxed) % health.len(),
+            (_, Some(x)) => x,
+            (Strategy::SEQUENTIAL, None) => health.iter().position(|x| x.is_healthy()).unwrap_or_else(|| least_failing(health, |_| true)),
+            (Strategy::FASTEST, None) | (Strategy::RACE, None) => fastest(health, |_| true),
+        };
+        health[i].mark_used(now);
+
+        if self.strategy != Strategy::RACE || !self.is_raceable(i) {
+            return (i, None);
+        }
+
+        // one socket sends to both, so the rival must be reachable from the same address family
+        let allowed = |j: usize| j != i && self.is_raceable(j) && self.addr_family_matches(i, j);
+        let rival = (0..health.len()).any(allowed).then(|| fastest(health, allowed));
+        if let Some(j) = rival {
+            health[j].mark_used(now);
+        }
+
+        (i, rival)
+    }
+
+    fn is_raceable(&self, i: usize) -> bool {
+        matches!(self.upstreams[i].protocol, Protocol::UDP)
+    }
+
+    fn addr_family_matches(&self, i: usize, j: usize) -> bool {
+        self.upstreams[i].addr.is_ipv4() == self.upstreams[j].addr.is_ipv4()
+    }
+
+    /// Record a lookup an upstream answered, along with how long it took and the rcode it answered with
+    pub fn record_success(&self, addr: SocketAddr, latency: Duration, res_code: ResCode) {
+        if let Some(x) = self.health.iter().find(|x| x.addr == addr) {
+            x.record_success(latency, res_code);
+        }
+    }
+
+    /// Record a lookup an upstream failed to answer
+    pub fn record_failure(&self, addr: SocketAddr, failure: Failure) {
+        if let Some(x) = self.health.iter().find(|x| x.addr == addr) {
+            x.record_failure(failure);
+        }
+    }
+
+    /// Run a lookup against the selected upstream, and the one racing it if any, recording how it went
+    /// The lookup returns the address of the upstream that answered, a race's loser isn't recorded
+    pub fn query(&self, lookup: impl FnOnce(&Upstream, Option<&Upstream>) -> Result<(DnsPacket, SocketAddr)>) -> Result<DnsPacket> {
+        let (i, rival) = self.select_at(Instant::now());
+        let upstream = &self.upstreams[i];
+        let rival = rival.map(|j| &self.upstreams[j]);
+        let start = Instant::now();
+
+        match lookup(upstream, rival) {
+            Ok((res, answered_by)) => {
+                self.record_success(answered_by, start.elapsed(), res.header.res_code);
+                Ok(res)
+            }
+            Err(e) => {
+                let failure = Failure::of(&e);
+                self.record_failure(upstream.addr, failure);
+                if let Some(x) = rival {
+                    self.record_failure(x.addr, failure);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Every upstream, in the order given
+    pub fn all(&self) -> &[Upstream] {
+        &self.upstreams
+    }
+
+    /// Snapshot of every upstream's stats, in the order given
+    pub fn stats(&self) -> Vec<UpstreamStats> {
+        self.health.iter().map(|x| x.stats()).collect()
+    }
+
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+}
+
+/// Snapshot of the stats of every configured upstream, each address once however many sets of upstreams it's in
+pub fn all_stats() -> Vec<UpstreamStats> {
+    let health = HEALTH.lock().unwrap_or_else(PoisonError::into_inner).clone();
+
+    health.iter().map(|x| x.stats()).collect()
+}
+
+/// Whether queries to an address should carry an OPT record
+pub fn edns_enabled(addr: SocketAddr) -> bool {
+    edns_state(addr).0
+}
+
+/// Whether queries to an address carry an OPT record, and how many lookups were only answered once it was left out
+fn edns_state(addr: SocketAddr) -> (bool, u64) {
+    let no_edns = NO_EDNS.lock().unwrap_or_else(PoisonError::into_inner);
+
+    no_edns.iter()
+        .find(|x| x.addr == addr)
+        .map_or((true, 0), |x| (Instant::now() >= x.until, x.fallbacks))
+}
+
+/// Record that an address only answered once EDNS was dropped from the query,
+/// queries to it go without EDNS until EDNS_RETRY_AFTER has passed
+pub fn disable_edns(addr: SocketAddr) {
+    let mut no_edns = NO_EDNS.lock().unwrap_or_else(PoisonError::into_inner);
+    let now = Instant::now();
+    let until = now + EDNS_RETRY_AFTER;
+
+    if no_edns.len() >= MAX_NO_EDNS {
+        no_edns.retain(|x| x.until > now);
+    }
+
+    match no_edns.iter_mut().find(|x| x.addr == addr) {
+        Some(x) => {
+            x.until = until;
+            x.fallbacks += 1;
+        }
+        None => no_edns.push(NoEdns {
+            addr: addr,
+            until: until,
+            fallbacks: 1,
+        }),
+    }
+}
+
+/// Index of the healthy upstream with the lowest latency among those allowed, untried ones first
+/// When none are healthy, the one failing least
+fn fastest(health: &[&Health], allowed: impl Fn(usize) -> bool) -> usize {
+    health.iter()
+        .enumerate()
+        .filter(|(i, x)| allowed(*i) && x.is_healthy())
+        .min_by_key(|(_, x)| x.latency().unwrap_or(Duration::ZERO))
+        .map(|(i, _)| i)
+        .unwrap_or_else(|| least_failing(health, allowed))
+}
+
+fn least_failing(health: &[&Health], allowed: impl Fn(usize) -> bool) -> usize {
+    health.iter()
+        .enumerate()
+        .filter(|(i, _)| allowed(*i))
+        .min_by(|(_, a), (_, b)| a.failure_rate().total_cmp(&b.failure_rate()))
+        .map(|(i, _)| i)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(x: u64) -> Duration {
+        Duration::from_millis(x)
+    }
+
+    #[test]
+    fn fastest_follows_the_latencies_as_they_change() {
+        // addresses of their own, health is kept per address for the whole process
+        let a = "192.0.2.68:5301".parse().unwrap();
+        let b = "192.0.2.68:5302".parse().unwrap();
+        let upstreams = Upstreams::new(vec![Upstream::udp(a), Upstream::udp(b)], Strategy::FASTEST);
+        let start = Instant::now();
+        let at = |secs: u64| upstreams.upstreams[upstreams.select_at(start + Duration::from_secs(secs)).0].addr;
+
+        // neither was ever used, so each is tried once
+        assert_eq!(at(0), a);
+        assert_eq!(at(0), b);
+        upstreams.record_success(a, ms(10), ResCode::NO_ERR);
+        upstreams.record_success(b, ms(50), ResCode::NO_ERR);
+        assert_eq!(at(1), a);
+        assert_eq!(at(2), a);
+
+        // a slows down, one slow answer still leaves it ahead on average, the next doesn't
+        upstreams.record_success(a, ms(200), ResCode::NO_ERR);
+        assert_eq!(at(3), a);
+        upstreams.record_success(a, ms(200), ResCode::NO_ERR);
+        assert_eq!(at(4), b);
+        assert_eq!(at(20), b);
+
+        // left unused for the probe interval, a is tried again and its fast answers win it back
+        assert_eq!(at(4 + PROBE_INTERVAL.as_secs()), a);
+        let mut now = 5 + PROBE_INTERVAL.as_secs();
+        let mut samples = 0;
+        while at(now) == b {
+            upstreams.record_success(a, ms(5), ResCode::NO_ERR);
+            samples += 1;
+            now += 1;
+            assert!(samples < 10, "a never won back its lookups");
+        }
+        assert!(samples > 1, "{}", samples);
+
+        // and one that fails too often is passed over however fast it was
+        for _ in 0..4 {
+            upstreams.record_failure(a, Failure::TIMEOUT);
+        }
+        assert_eq!(at(now + 1), b);
+    }
+}
@@ -0,0 +1,202 @@
+//! Split-horizon views, named sets of client networks given with --view <name>=<cidr>[,<cidr>...]
+//! A query is answered in the view with the most specific network containing the client's address,
+//! ex. internal=192.168.0.0/16 sees its own app.example.com while everyone else gets the public one
+//! A view can have zones (--view-zone), a hosts file (--view-hosts) and upstreams (--view-resolver) of its own,
+//! its hosts file and zones coming ahead of the ones everyone sees and its upstreams replacing --resolver
+//! Clients in no view are in the default view, which is everything configured outside of --view
+//! The answers of a view's own upstreams are kept out of the cache so they never reach clients of another view
+//! The zones and hosts files of the views are read again on SIGHUP
+
+use std::net::IpAddr;
+use std::sync::{ Arc, OnceLock, RwLock };
+
+use log::{ error, info };
+
+use crate::dns_name;
+use crate::error::DnsError;
+use crate::hosts::Hosts;
+use crate::json;
+use crate::packet::{ DnsPacket, QueryType };
+use crate::upstreams::Upstreams;
+use crate::xfr::Network;
+use crate::zone::Zone;
+
+type Result<T> = std::result::Result<T, DnsError>;
+
+static VIEWS: OnceLock<Vec<View>> = OnceLock::new();
+
+/// What the clients of some networks see
+pub struct View {
+    pub name: String,
+    pub networks: Vec<Network>,
+    pub upstreams: Option<Upstreams>, // None to use --resolver or recursion like the default view
+    zones: RwLock<Vec<Arc<Zone>>>,    // longest origin first
+    hosts: RwLock<Option<Hosts>>,
+    hosts_path: Option<String>,
+}
+
+/// Install the views, only the first call has any effect
+pub fn set_views(views: Vec<View>) {
+    let _ = VIEWS.set(views);
+}
+
+/// Every view, in the order given
+pub fn views() -> &'static [View] {
+    VIEWS.get().map_or(&[], |x| x.as_slice())
+}
+
+/// The view a client is in, the one with the longest network containing its address, None for the default view
+/// Of views with equally long networks the first given wins
+pub fn find(client: IpAddr) -> Option<&'static View> {
+    let mut best: Option<(&View, u8)> = None;
+
+    for view in views() {
+        let longest = view.networks.iter().filter(|x| x.contains(client)).map(|x| x.prefix_len).max();
+        match (longest, best) {
+            (Some(x), Some((_, y))) if x <= y => {}
+            (Some(x), _) => best = Some((view, x)),
+            (None, _) => {}
+        }
+    }
+
+    best.map(|(view, _)| view)
+}
+
+/// How a view shows in the query log, nothing at all unless views are configured
+pub fn label(view: Option<&View>) -> String {
+    match view {
+        Some(x) => format!(" (view {})", x.name),
+        None if !views().is_empty() => " (view default)".to_string(),
+        None => String::new(),
+    }
+}
+
+/// Read the zones and hosts files of every view again, keeping what was read before for any that fail
+pub fn reload() {
+    for view in views() {
+        view.reload();
+    }
+}
+
+impl View {
+    /// A view of its networks, zones, hosts file and upstreams, failing if the hosts file can't be read
+    pub fn new(name: &str, networks: Vec<Network>, zones: Vec<Zone>, hosts_path: Option<String>, upstreams: Option<Upstreams>) -> Result<View> {
+        let hosts = match &hosts_path {
+            Some(x) => Some(Hosts::read(x)?),
+            None => None,
+        };
+
+        let mut zones: Vec<Arc<Zone>> = zones.into_iter().map(Arc::new).collect();
+        zones.sort_by_key(|x| std::cmp::Reverse(label_count(&x.origin)));
+
+        Ok(View {
+            name: name.to_string(),
+            networks: networks,
+            upstreams: upstreams,
+            zones: RwLock::new(zones),
+            hosts: RwLock::new(hosts),
+            hosts_path: hosts_path,
+        })
+    }
+
+    /// The longest of the view's own zones containing a name, if any
+    pub fn find_zone(&self, name: &str) -> Option<Arc<Zone>> {
+        self.zones.read().unwrap().iter().find(|x| dns_name::is_subdomain(name, &x.origin)).cloned()
+    }
+
+    /// The answer to a question from the view's own hosts file, None if it has none or the question isn't answered from there
+    pub fn hosts_answer(&self, name: &str, q_type: QueryType) -> Option<DnsPacket> {
+        self.hosts.read().unwrap().as_ref()?.answer(name, q_type)
+    }
+
+    /// The networks, hosts file, zones and upstreams of the view, for the startup log
+    pub fn summary(&self) -> String {
+        let mut parts: Vec<String> = self.networks.iter().map(|x| x.to_string()).collect();
+        if let Some(hosts) = self.hosts.read().unwrap().as_ref() {
+            parts.push(format!("{} names from the hosts file", hosts.name_count()));
+        }
+        for zone in self.zones.read().unwrap().iter() {
+            parts.push(format!("zone {}", json::fqdn(&zone.origin)));
+        }
+        if let Some(upstreams) = &self.upstreams {
+            parts.push(format!("forwarding to {}", upstreams.all().iter().map(|x| x.to_string()).collect::<Vec<_>>().join(", ")));
+        }
+
+        parts.join(", ")
+    }
+
+    /// Read the view's zones and hosts file again, each one that fails keeping the old version
+    fn reload(&self) {
+        let zones = self.zones.read().unwrap().clone();
+        for zone in zones {
+            let path = match &zone.path {
+                Some(x) => x,
+                None => continue,
+            };
+
+            match Zone::load(&zone.origin, path) {
+                Ok(loaded) => {
+                    info!("Reloaded zone {} of view {} from {}, serial {}", json::fqdn(&loaded.origin), self.name, path, loaded.serial());
+                    let mut zones = self.zones.write().unwrap();
+                    zones.retain(|x| x.origin != loaded.origin);
+                    zones.push(Arc::new(loaded));
+                    zones.sort_by_key(|x| std::cmp::Reverse(label_count(&x.origin)));
+                }
+                Err(e) => error!("Failed to reload zone {} of view {}, still serving the old version: {}", json::fqdn(&zone.origin), self.name, e),
+            }
+        }
+
+        if let Some(path) = &self.hosts_path {
+            match Hosts::read(path) {
+                Ok(hosts) => {
+                    info!("Reloaded {} names of view {} from {}", hosts.name_count(), self.name, path);
+                    *self.hosts.write().unwrap() = Some(hosts);
+                }
+                Err(e) => error!("Failed to reload the hosts file of view {}, still answering from the old one: {}", self.name, e),
+            }
+        }
+    }
+}
+
+fn label_count(domain: &str) -> usize {
+    domain.split('.').filter(|x| !x.is_empty()).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    use crate::packet::DnsQuestion;
+    use crate::resolve::{ self, Resolution };
+    use crate::transport::Transport;
+    use crate::upstreams::{ Strategy, Upstream };
+    use crate::zone;
+
+    fn app_zone(addr: &str) -> Zone {
+        let text = format!("$TTL 300\n@ SOA ns hostmaster 1 3600 600 86400 60\n@ NS ns\nns A 192.0.2.53\napp A {}\n", addr);
+        Zone::parse("views.example", &text, "views.example.zone").unwrap()
+    }
+
+    #[test]
+    fn clients_in_a_view_get_its_answers_and_others_the_public_ones() {
+        // the views are for the whole process, loopback clients of the other tests are in none of them
+        let internal = View::new("internal", vec![Network::parse("192.0.2.0/24").unwrap()], vec![app_zone("192.0.2.80")], None, None).unwrap();
+        set_views(vec![internal]);
+        zone::replace(app_zone("198.51.100.80"));
+
+        let upstreams = Upstreams::new(vec![Upstream::udp("192.0.2.53:53".parse().unwrap())], Strategy::SEQUENTIAL);
+        let resolution = Resolution::Forward(Box::leak(Box::new(upstreams)));
+        let ask = |client: [u8; 4]| {
+            let mut query = DnsPacket::new();
+            query.header.query_res = false;
+            query.questions.push(DnsQuestion::new("app.views.example".to_string(), QueryType::A));
+            let res = resolve::handle_query_bytes(&query.to_bytes().unwrap(), IpAddr::V4(client.into()), &resolution, Transport::UDP).unwrap();
+            DnsPacket::from_bytes(&res).unwrap().get_first_addr()
+        };
+
+        assert_eq!(find(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 10))).map(|x| x.name.as_str()), Some("internal"));
+        assert_eq!(ask([192, 0, 2, 10]), Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 80))));
+        assert_eq!(ask([203, 0, 113, 10]), Some(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 80))));
+    }
+}
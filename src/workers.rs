@@ -0,0 +1,183 @@
+//! Fixed size pool of threads answering UDP queries
+//! Each socket's receive loop hands packets to the pool, so a slow upstream only ties up
+//! the worker waiting on it instead of every client
+
+use std::net::{ SocketAddr, UdpSocket };
+use std::sync::{ mpsc, Arc, Mutex };
+use std::thread;
+
+use log::{ error, trace };
+
+use crate::buffer_pool;
+use crate::packet::PacketBuffer;
+use crate::resolve::{ self, Resolution };
+
+// Packets queued per worker before receive loops block and the kernel starts dropping
+const QUEUE_PER_WORKER: usize = 64;
+
+/// A received packet waiting for a worker
+struct Job {
+    udp_socket: Arc<UdpSocket>,
+    req_buf: PacketBuffer,
+    size: usize,
+    source: SocketAddr,
+}
+
+/// Handle for queueing packets on the pool, cloned for each receive loop
+#[derive(Clone)]
+pub struct WorkerPool {
+    sender: mpsc::SyncSender<Job>,
+}
+
+impl WorkerPool {
+    /// Start the given number of workers, each answering with the same resolution
+    pub fn new(workers: usize, resolution: Resolution) -> WorkerPool {
+        let workers = workers.max(1);
+        let (sender, receiver) = mpsc::sync_channel(workers * QUEUE_PER_WORKER);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for _ in 0..workers {
+            let receiver = receiver.clone();
+            thread::spawn(move || work(receiver, resolution));
+        }
+
+        WorkerPool {
+            sender: sender,
+        }
+    }
+
+    /// Receive packets on a socket forever, queueing each for the workers
+    /// Responses are sent from the same socket so they leave from the address the query arrived on
+    pub fn serve_udp(&self, udp_socket: UdpSocket) {
+        let udp_socket = Arc::new(udp_socket);
+
+        loop {
+            let mut req_buf = buffer_pool::acquire();
+
+            let (size, source) = match udp_socket.recv_from(&mut req_buf.buf) {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("An error occurred: {}", e);
+                    continue;
+                }
+            };
+
+            trace!("Received {} bytes from {}", size, source);
+
+            let job = Job {
+                udp_socket: udp_socket.clone(),
+                req_buf: req_buf,
+                size: size,
+                source: source,
+            };
+
+            if self.sender.send(job).is_err() {
+                error!("All workers have stopped");
+                return;
+            }
+        }
+    }
+}
+
+/// Default worker count, one per CPU
+pub fn default_workers() -> usize {
+    thread::available_parallelism().map(|x| x.get()).unwrap_or(4)
+}
+
+/// Answer queued packets until the pool is dropped
+fn work(receiver: Arc<Mutex<mpsc::Receiver<Job>>>, resolution: Resolution) {
+    loop {
+        // the lock is only held while waiting for the next job
+        let job = match receiver.lock() {
+            Ok(x) => x.recv(),
+            Err(_) => return,
+        };
+
+        let job = match job {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+
+        if let Err(e) = resolve::handle_packet(&job.udp_socket, job.req_buf, job.size, job.source, &resolution) {
+            error!("An error occurred: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{ IpAddr, Ipv4Addr };
+    use std::time::{ Duration, Instant };
+
+    use crate::packet::{ DnsPacket, DnsQuestion, DnsRecord, QueryType };
+    use crate::upstreams::{ Strategy, Upstream, Upstreams };
+
+    // how long the upstream takes over names starting with "slow"
+    const SLOW: Duration = Duration::from_millis(1000);
+
+    /// An upstream answering each query on a thread of its own, names starting with "slow" only after SLOW
+    fn upstream() -> SocketAddr {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let addr = socket.local_addr().unwrap();
+
+        thread::spawn(move || loop {
+            let mut buf = [0; 512];
+            let (size, client) = socket.recv_from(&mut buf).unwrap();
+            let socket = socket.clone();
+            thread::spawn(move || {
+                let query = DnsPacket::from_bytes(&buf[..size]).unwrap();
+                if query.questions[0].name.starts_with("slow") {
+                    thread::sleep(SLOW);
+                }
+                let addr_v4 = Ipv4Addr::new(192, 0, 2, 59);
+                let mut res = DnsPacket::response_to(&query)
+                    .question(query.questions[0].clone())
+                    .answer(DnsRecord::A { domain: query.questions[0].name.clone(), addr_v4: addr_v4, ttl: 60 });
+                socket.send_to(&res.to_bytes().unwrap(), client).unwrap();
+            });
+        });
+
+        addr
+    }
+
+    fn query(id: u16, name: &str) -> Vec<u8> {
+        let mut query = DnsPacket::new();
+        query.header.id = id;
+        query.header.rec_des = true;
+        query.questions.push(DnsQuestion::new(name.to_string(), QueryType::A));
+        query.to_bytes().unwrap()
+    }
+
+    #[test]
+    fn slow_upstream_answers_dont_hold_up_the_others() {
+        let upstreams = Upstreams::new(vec![Upstream::udp(upstream())], Strategy::SEQUENTIAL);
+        let pool = WorkerPool::new(4, Resolution::Forward(Box::leak(Box::new(upstreams))));
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        thread::spawn(move || pool.serve_udp(server));
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let started = Instant::now();
+        for id in 1..=3 {
+            client.send_to(&query(id, &format!("slow{}.workers.pine-dns.com", id)), server_addr).unwrap();
+        }
+        client.send_to(&query(4, "fast.workers.pine-dns.com"), server_addr).unwrap();
+
+        let mut answered = Vec::new();
+        let mut buf = [0; 512];
+        while answered.len() < 4 {
+            let (size, _) = client.recv_from(&mut buf).unwrap();
+            let res = DnsPacket::from_bytes(&buf[..size]).unwrap();
+            assert_eq!(res.get_first_addr(), Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 59))));
+            answered.push((res.header.id, started.elapsed()));
+        }
+
+        // the fast query, asked last, is answered first while the slow ones still wait on the upstream
+        assert_eq!(answered[0].0, 4);
+        assert!(answered[0].1 < SLOW, "{:?}", answered);
+        // and the slow ones are waited out side by side rather than one after the other
+        assert!(answered[1..].iter().all(|x| x.1 >= SLOW && x.1 < SLOW * 2), "{:?}", answered);
+    }
+}
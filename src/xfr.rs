@@ -0,0 +1,404 @@
+//! Outgoing zone transfers of the local zones, AXFR (RFC 5936) over TCP to the clients allowed with --allow-transfer
+//! The zone goes out as its SOA, every other record and the SOA again, packed into as many messages as it takes,
+//! each with the id of the request
+//! An IXFR (RFC 1995) from a serial still in the zone's journal gets only the changes since, any other the whole zone
+//! Transfers over UDP, of names other than a zone's origin and to any other client are refused,
+//! unless the request is signed with a --tsig-key, and then every message of the answer is too
+//! Zones served as a secondary are pulled from their primary the same way, with IXFR once there's a version to update
+
+use std::fmt;
+use std::iter;
+use std::net::{ IpAddr, SocketAddr };
+use std::sync::OnceLock;
+use std::time::{ Duration, Instant };
+
+use log::info;
+
+use crate::dns_name;
+use crate::error::DnsError;
+use crate::idna;
+use crate::packet::{ self, DnsHeader, DnsPacket, DnsQuestion, DnsRecord, PacketBuffer, ParseLimits, QueryType, ResCode, Opcode };
+use crate::resolve;
+use crate::stats;
+use crate::transport;
+use crate::tsig::{ self, Operation, Signed };
+use crate::zone::{ self, Delta, Zone };
+
+type Result<T> = std::result::Result<T, DnsError>;
+
+static ALLOWED: OnceLock<Vec<Network>> = OnceLock::new();
+
+/// Full zone transfer
+pub const AXFR: QueryType = QueryType::UNKNOWN(252);
+/// Incremental zone transfer, answered with the full zone when the client's serial isn't in the journal (RFC 1995 section 4)
+pub const IXFR: QueryType = QueryType::UNKNOWN(251);
+
+// Messages of a transfer are filled up to about this many bytes, well below the TCP limit
+const MESSAGE_SIZE: usize = 16384;
+// A transfer message fills its answer section with as many records as fit, a count the usual limits would refuse
+const PULL_LIMITS: ParseLimits = ParseLimits {
+    max_questions: 1,
+    max_answers: u16::MAX,
+    max_authorities: 0,
+    max_resources: 4, // room for an OPT and a TSIG
+};
+// A transfer pulled from a primary gives up after this long, however steadily its messages arrive
+const PULL_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// What pulling a zone brought
+#[derive(Debug)]
+pub enum Transfer {
+    Full(Vec<DnsRecord>), // every record, the SOA only once
+    Incremental(Vec<Delta>),
+    Current, // the primary has nothing newer
+}
+
+/// An address, or a network of them written with its prefix length, ex. 192.0.2.1 or 10.0.0.0/8
+#[derive(Copy, Clone, Debug)]
+pub struct Network {
+    pub addr: IpAddr,
+    pub prefix_len: u8,
+}
+
+impl Network {
+    pub fn parse(text: &str) -> Result<Network> {
+        let (addr, prefix_len) = match text.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (text, None),
+        };
+
+        let addr = addr.parse::<IpAddr>().map_err(|_| DnsError::Config(format!("invalid address {}", addr)))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(x) => x.parse::<u8>().ok().filter(|x| *x <= max).ok_or_else(|| DnsError::Config(format!("invalid prefix length {}", x)))?,
+            None => max,
+        };
+
+        Ok(Network {
+            addr: addr,
+            prefix_len: prefix_len,
+        })
+    }
+
+    /// Whether an address is in the network, addresses of the other family never are
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(x)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(x) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(x)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(x) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Set the clients zones may be transferred to, only the first call has any effect
+pub fn set_allowed(networks: Vec<Network>) {
+    let _ = ALLOWED.set(networks);
+}
+
+/// Networks zones may be transferred to, none unless --allow-transfer is given
+pub fn allowed() -> &'static [Network] {
+    ALLOWED.get().map_or(&[], |x| x.as_slice())
+}
+
+/// Whether a query asks for a zone transfer
+pub fn is_transfer(q_type: QueryType) -> bool {
+    q_type == AXFR || q_type == IXFR
+}
+
+/// Answer a message received over a stream if it asks for a zone transfer, None if it's any other message
+/// All the messages of the transfer are returned, or a single REFUSED response, each signed if the request was
+pub fn answer(req: &[u8], peer: IpAddr) -> Option<Result<Vec<Vec<u8>>>> {
+    let mut req_buf = PacketBuffer::from_bytes(req);
+    let mut header = DnsHeader::new();
+    header.read(&mut req_buf).ok()?;
+    let question = DnsPacket::peek_question(&req_buf).ok()?;
+    if header.opcode != Opcode::QUERY || !is_transfer(question.q_type) {
+        return None;
+    }
+
+    let name = question.name();
+    let mut response = packet::response_to(&header);
+    response.questions.push(DnsQuestion::new(name.clone(), question.q_type));
+
+    let signed = match tsig::read(req) {
+        Ok(x) => x,
+        Err(e) => {
+            info!("Refusing a transfer of {} to {}, its TSIG is malformed: {}", idna::to_unicode(&name), peer, e);
+            return Some(reject(response, ResCode::FORM_ERR));
+        }
+    };
+
+    let messages = transfer(req, peer, question.q_type, response, signed.as_ref());
+    match signed {
+        Some(x) => {
+            let mut signer = x.signer();
+            Some(messages.map(|messages| messages.iter().map(|x| signer.sign(x)).collect()))
+        }
+        None => Some(messages),
+    }
+}
+
+/// The messages of a transfer, or of the response refusing it
+fn transfer(req: &[u8], peer: IpAddr, q_type: QueryType, response: DnsPacket, signed: Option<&Signed>) -> Result<Vec<Vec<u8>>> {
+    let name = response.questions[0].name.clone();
+
+    if let Some(x) = signed.filter(|x| !x.is_valid()) {
+        info!("Refusing a transfer of {} to {}, {} for the TSIG key {}", idna::to_unicode(&name), peer, x.error(), x.key_name);
+        return reject(response, ResCode::NOT_AUTH);
+    }
+
+    let zone = match zone::find(&name) {
+        Some(x) if x.origin == dns_name::normalize(&name) => x,
+        _ => {
+            info!("Refusing a transfer of {} to {}, it isn't a local zone", idna::to_unicode(&name), peer);
+            return reject(response, ResCode::REFUSED);
+        }
+    };
+    // a good signature lets any address through, without one the address has to be allowed
+    if signed.is_none() && tsig::is_required(Operation::TRANSFER) {
+        info!("Refusing a transfer of {} to {}, it isn't signed and --require-tsig transfer is given", idna::to_unicode(&name), peer);
+        return reject(response, ResCode::REFUSED);
+    }
+    if signed.is_none() && !allowed().iter().any(|x| x.contains(peer)) {
+        info!("Refusing a transfer of {} to {}, it isn't allowed by --allow-transfer", idna::to_unicode(&name), peer);
+        return reject(response, ResCode::REFUSED);
+    }
+    if zone.is_expired() {
+        info!("Refusing a transfer of {} to {}, the zone has expired", idna::to_unicode(&name), peer);
+        return reject(response, ResCode::REFUSED);
+    }
+
+    let records = match (q_type, client_serial(req)) {
+        (IXFR, Some(serial)) if !serial_newer(zone.serial(), serial) => {
+            info!("Answering an IXFR of {} from {}, serial {} is current", idna::to_unicode(&name), peer, serial);
+            vec![zone.soa.clone()]
+        }
+        (IXFR, Some(serial)) => match zone.changes_since(serial) {
+            Some(deltas) => {
+                info!("Transferring the changes to {} since serial {} to {}", idna::to_unicode(&name), serial, peer);
+                incremental(&zone, deltas)
+            }
+            None => zone.transfer(),
+        },
+        _ => zone.transfer(),
+    };
+
+    let messages = pack(response, records);
+    if let Ok(messages) = &messages {
+        let key = signed.map_or(String::new(), |x| format!(", signed with the key {}", x.key_name));
+        info!("Transferring {} to {} in {} messages{}", idna::to_unicode(&name), peer, messages.len(), key);
+        stats::count_response(iter::once(q_type), ResCode::NO_ERR);
+    }
+
+    messages
+}
+
+/// The serial of the SOA an IXFR request carries in its authority section
+fn client_serial(req: &[u8]) -> Option<u32> {
+    let packet = DnsPacket::from_bytes(req).ok()?;
+
+    packet.authorities.iter()
+        .find(|x| matches!(x, DnsRecord::SOA { .. }))
+        .map(zone::soa_serial)
+}
+
+/// The records of an incremental transfer: the current SOA, each change as the SOA before, the deleted records,
+/// the SOA after and the added records, then the current SOA again (RFC 1995 section 4)
+fn incremental(zone: &Zone, deltas: &[Delta]) -> Vec<DnsRecord> {
+    let mut records = vec![zone.soa.clone()];
+    for delta in deltas {
+        records.push(delta.from.clone());
+        records.extend(delta.deleted.iter().cloned());
+        records.push(delta.to.clone());
+        records.extend(delta.added.iter().cloned());
+    }
+    records.push(zone.soa.clone());
+
+    records
+}
+
+fn reject(mut response: DnsPacket, res_code: ResCode) -> Result<Vec<Vec<u8>>> {
+    response.header.res_code = res_code;
+
+    Ok(vec![resolve::encode_response(&mut response, transport::MAX_TCP_MESSAGE)?])
+}
+
+/// Split the records of a transfer over messages with the header of the response, the question only in the first
+fn pack(mut response: DnsPacket, records: Vec<DnsRecord>) -> Result<Vec<Vec<u8>>> {
+    response.header.authoritative = true;
+
+    let mut messages = Vec::new();
+    let mut scratch = PacketBuffer::with_size(transport::MAX_TCP_MESSAGE);
+    let mut size = encode(&mut response)?.len();
+
+    for record in records {
+        scratch.pos = 0;
+        let len = record.write(&mut scratch)?;
+
+        if size + len > MESSAGE_SIZE && !response.answers.is_empty() {
+            messages.push(encode(&mut response)?);
+            response.questions.clear();
+            response.answers.clear();
+            size = encode(&mut response)?.len();
+        }
+
+        size += len;
+        response.answers.push(record);
+    }
+    messages.push(encode(&mut response)?);
+
+    Ok(messages)
+}
+
+fn encode(packet: &mut DnsPacket) -> Result<Vec<u8>> {
+    let mut buf = PacketBuffer::with_size(transport::MAX_TCP_MESSAGE);
+    packet.write(&mut buf)?;
+
+    let mut bytes = buf.buf;
+    bytes.truncate(buf.pos);
+
+    Ok(bytes)
+}
+
+/// Pull a zone from its primary over TCP, with IXFR from the serial of the version given and AXFR without one
+/// Every message has to carry the query's id and no error, the transfer ends with the SOA it started with
+pub fn pull(origin: &str, primary: SocketAddr, current: Option<&Zone>) -> Result<Transfer> {
+    let deadline = Instant::now() + PULL_TIMEOUT;
+    let mut stream = transport::connect(&primary, resolve::lookup_timeout())?;
+    stream.set_read_timeout(Some(resolve::lookup_timeout()))?;
+    stream.set_write_timeout(Some(resolve::lookup_timeout()))?;
+
+    let mut query = DnsPacket::new();
+    query.header.id = rand::random::<u16>();
+    query.header.query_res = false;
+    match current {
+        Some(zone) => {
+            query.questions.push(DnsQuestion::new(origin.to_string(), IXFR));
+            query.authorities.push(zone.soa.clone());
+        }
+        None => query.questions.push(DnsQuestion::new(origin.to_string(), AXFR)),
+    }
+    transport::write_tcp_message(&mut stream, &encode(&mut query)?)?;
+
+    let serial = current.map(|x| x.serial());
+    let mut records: Vec<DnsRecord> = Vec::new();
+    while Instant::now() < deadline {
+        let bytes = transport::read_tcp_message(&mut stream)?;
+        let res = DnsPacket::from_buf_with_limits(&mut PacketBuffer::from_bytes(&bytes), &PULL_LIMITS)?;
+        if res.header.id != query.header.id {
+            return Err(DnsError::MismatchedResponse(format!("{} answered with id {} instead of {}", primary, res.header.id, query.header.id)));
+        }
+        if res.header.res_code != ResCode::NO_ERR {
+            return Err(DnsError::Transfer(format!("{} answered {}", primary, res.header.res_code)));
+        }
+
+        for record in res.answers {
+            if records.is_empty() && !matches!(record, DnsRecord::SOA { .. }) {
+                return Err(DnsError::Transfer(format!("the transfer from {} doesn't start with an SOA record", primary)));
+            }
+            records.push(record);
+
+            if let Some(transfer) = complete(&records, serial) {
+                return Ok(transfer);
+            }
+        }
+    }
+
+    Err(DnsError::Transfer(format!("the transfer from {} took longer than {}s", primary, PULL_TIMEOUT.as_secs())))
+}
+
+/// The transfer the records received so far make up, None while more are to come
+/// A lone SOA no newer than the client's answers an IXFR with nothing to send,
+/// a second SOA with an older serial starts the changes of an incremental transfer, anything else is a full one
+fn complete(records: &[DnsRecord], serial: Option<u32>) -> Option<Transfer> {
+    let newest = zone::soa_serial(&records[0]);
+    let is_soa = |x: &DnsRecord| matches!(x, DnsRecord::SOA { .. });
+
+    if records.len() == 1 {
+        return match serial {
+            Some(x) if !serial_newer(newest, x) => Some(Transfer::Current),
+            _ => None,
+        };
+    }
+    if !is_soa(&records[records.len() - 1]) {
+        return None;
+    }
+
+    if !is_soa(&records[1]) || zone::soa_serial(&records[1]) == newest {
+        let mut full = records.to_vec();
+        full.pop();
+
+        return Some(Transfer::Full(full));
+    }
+
+    // the changes, then the SOA they lead up to
+    let deltas = zone::split_deltas(&records[1..records.len() - 1])?;
+    match deltas.last() {
+        Some(x) if zone::soa_serial(&x.to) == newest => Some(Transfer::Incremental(deltas)),
+        _ => None,
+    }
+}
+
+/// Whether serial a is newer than serial b, in sequence space arithmetic that wraps around (RFC 1982)
+pub fn serial_newer(a: u32, b: u32) -> bool {
+    a != b && a.wrapping_sub(b) < 1 << 31
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    /// A version of a zone with fifty hosts, www's address and the serial being what changes
+    fn version(serial: u32, www: &str) -> Zone {
+        let mut text = format!("$TTL 300\n@ SOA ns hostmaster {} 3600 600 86400 60\n@ NS ns\nns A 192.0.2.53\nwww A {}\n", serial, www);
+        for n in 1..=50 {
+            text += &format!("host{} A 192.0.2.{}\n", n, n + 100);
+        }
+
+        Zone::parse("ixfr.example", &text, "ixfr.example.zone").unwrap()
+    }
+
+    #[test]
+    fn ixfr_sends_only_the_changes_since_the_clients_serial() {
+        set_allowed(vec![Network::parse("127.0.0.0/8").unwrap()]);
+        zone::replace(version(1, "192.0.2.1"));
+        zone::replace(version(2, "192.0.2.2"));
+
+        let mut query = DnsPacket::new();
+        query.header.query_res = false;
+        query.questions.push(DnsQuestion::new("ixfr.example".to_string(), IXFR));
+        query.authorities.push(version(1, "192.0.2.1").soa);
+        let messages = answer(&query.to_bytes().unwrap(), IpAddr::V4(Ipv4Addr::LOCALHOST)).unwrap().unwrap();
+
+        let records: Vec<DnsRecord> = messages.iter()
+            .flat_map(|x| DnsPacket::from_bytes(x).unwrap().answers)
+            .collect();
+        // the current SOA, the old one, www's old address, the new SOA, www's new address, the current SOA again
+        assert_eq!(records.len(), 6);
+
+        let deltas = match complete(&records, Some(1)) {
+            Some(Transfer::Incremental(x)) => x,
+            x => panic!("Expected an incremental transfer, got {:?}", x),
+        };
+        assert!(matches!(&deltas[..], [Delta { deleted, added, .. }]
+            if deleted == &[DnsRecord::A { domain: "www.ixfr.example".to_string(), addr_v4: Ipv4Addr::new(192, 0, 2, 1), ttl: 300 }]
+            && added == &[DnsRecord::A { domain: "www.ixfr.example".to_string(), addr_v4: Ipv4Addr::new(192, 0, 2, 2), ttl: 300 }]));
+
+        // the changes take the client's version to the current one
+        let updated = version(1, "192.0.2.1").apply(&deltas).unwrap();
+        assert_eq!(updated.transfer(), version(2, "192.0.2.2").transfer());
+    }
+}
@@ -0,0 +1,1030 @@
+//! Authoritative zones, loaded from RFC 1035 master files with --zone <origin>:<path>
+//! Names inside a zone are answered from it with AA set and never looked up anywhere else:
+//! the records of the name and type, NODATA when the name has none of that type and NXDOMAIN
+//! when it doesn't exist, both with the zone's SOA in the authority section for caching them
+//! Names that don't exist are answered from a wildcard covering them, as if the records were their own (RFC 4592)
+//! CNAMEs are followed as long as they stay inside the zone
+//! The longest zone containing a name wins, ahead of forwarding rules and the cache
+//! Zones transferred from a primary with --secondary are swapped in whole as they're refreshed,
+//! and answer SERVFAIL once they expire
+//! A zone replaced by one with a newer serial keeps what changed between them in a bounded journal,
+//! so clients can be sent just the changes with IXFR (RFC 1995)
+
+use std::collections::{ HashMap, HashSet };
+use std::fs;
+use std::net::{ Ipv4Addr, Ipv6Addr };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::{ Arc, RwLock };
+
+use crate::dns_name;
+use crate::ede::{ self, Ede };
+use crate::error::DnsError;
+use crate::json;
+use crate::notify;
+use crate::packet::{ DnsPacket, DnsRecord, QueryType, QuestionRef, ResCode };
+use crate::resolve::MAX_CNAME_CHAIN;
+use crate::xfr;
+
+type Result<T> = std::result::Result<T, DnsError>;
+
+static ZONES: RwLock<Vec<Arc<Zone>>> = RwLock::new(Vec::new());
+
+// The journal keeps at most this many deleted and added records, dropping the oldest changes first
+const JOURNAL_SIZE: usize = 10000;
+
+// QTYPE * asks for every type the name has
+const ANY: QueryType = QueryType::UNKNOWN(255);
+
+/// The records of one zone
+#[derive(Debug)]
+pub struct Zone {
+    pub origin: String, // normalized, ex. home.lan
+    pub soa: DnsRecord,
+    pub path: Option<String>, // the master file it was loaded from
+    records: HashMap<String, Vec<DnsRecord>>, // by normalized owner
+    names: HashSet<String>, // that exist: the owners and the names between them and the origin
+    expired: AtomicBool, // a secondary zone not refreshed within the SOA's expire time
+    journal: Vec<Delta>, // changes up to this version, oldest first
+}
+
+/// The changes that took a zone from one serial to the next, as IXFR sends them
+#[derive(Clone, Debug)]
+pub struct Delta {
+    pub from: DnsRecord, // the SOA before
+    pub to: DnsRecord,   // the SOA after
+    pub deleted: Vec<DnsRecord>,
+    pub added: Vec<DnsRecord>,
+}
+
+/// A record or directive of a master file, the lines it spans joined
+struct Entry {
+    line: usize,          // where it starts
+    inherits_owner: bool, // starts with a blank, so the owner is the previous entry's
+    tokens: Vec<Token>,
+}
+
+enum Token {
+    Word(String),   // escapes left in, names are unescaped by dns_name
+    Quoted(String), // without the quotes, escapes left in
+}
+
+/// Install the zones, replacing any installed before
+pub fn set_zones(zones: Vec<Zone>) {
+    let mut zones: Vec<Arc<Zone>> = zones.into_iter().map(Arc::new).collect();
+    // most labels first so the first match found is the longest
+    zones.sort_by_key(|x| std::cmp::Reverse(label_count(&x.origin)));
+
+    *ZONES.write().unwrap() = zones;
+}
+
+/// Install a zone, in place of the one with the same origin if there is one
+/// A newer serial adds the changes from the replaced version to the journal, any other starts it over
+/// A serial that changed is announced to the --also-notify servers
+pub fn replace(mut zone: Zone) {
+    let mut zones = ZONES.write().unwrap();
+    let mut changed = false;
+    if let Some(old) = zones.iter().find(|x| x.origin == zone.origin) {
+        zone.journal = old.journal_to(&zone);
+        changed = old.serial() != zone.serial();
+    }
+
+    let zone = Arc::new(zone);
+    zones.retain(|x| x.origin != zone.origin);
+    zones.push(zone.clone());
+    zones.sort_by_key(|x| std::cmp::Reverse(label_count(&x.origin)));
+    drop(zones);
+
+    if changed {
+        notify::send(&zone.origin, &zone.soa);
+    }
+}
+
+/// Every zone, longest origin first
+pub fn zones() -> Vec<Arc<Zone>> {
+    ZONES.read().unwrap().clone()
+}
+
+/// The longest zone containing a name, if any
+pub fn find(name: &str) -> Option<Arc<Zone>> {
+    ZONES.read().unwrap().iter().find(|x| dns_name::is_subdomain(name, &x.origin)).cloned()
+}
+
+/// The longest zone containing a question's name, if any
+pub fn find_question(question: &QuestionRef) -> Option<Arc<Zone>> {
+    ZONES.read().unwrap().iter().find(|x| question.is_within(&x.origin)).cloned()
+}
+
+impl Zone {
+    /// Read a zone from a master file, errors name the file and line
+    pub fn load(origin: &str, path: &str) -> Result<Zone> {
+        let text = fs::read_to_string(path).map_err(|e| DnsError::Config(format!("{}: {}", path, e)))?;
+
+        let mut zone = Zone::parse(origin, &text, path)?;
+        zone.path = Some(path.to_string());
+
+        Ok(zone)
+    }
+
+    /// Parse the text of a master file, with $ORIGIN and $TTL directives and SOA, NS, A, AAAA, CNAME, MX, TXT, SRV and PTR records
+    pub fn parse(origin: &str, text: &str, path: &str) -> Result<Zone> {
+        let zone_origin = dns_name::normalize(origin);
+        let mut soa: Option<DnsRecord> = None;
+        let mut records: HashMap<String, Vec<DnsRecord>> = HashMap::new();
+
+        for (line, record) in read_records(origin, text, path)? {
+            add(&mut records, &mut soa, &zone_origin, record).map_err(|e| DnsError::InvalidZone(format!("{} line {}: {}", path, line, e)))?;
+        }
+
+        let soa = soa.ok_or_else(|| DnsError::InvalidZone(format!("{}: no SOA record for {}", path, display(&zone_origin))))?;
+
+        Ok(Zone {
+            names: names(&records, &zone_origin),
+            origin: zone_origin,
+            soa: soa,
+            path: None,
+            records: records,
+            expired: AtomicBool::new(false),
+            journal: Vec::new(),
+        })
+    }
+
+    /// Build a zone from records received in a transfer, checked as if they were read from a master file
+    pub fn from_records(origin: &str, received: Vec<DnsRecord>) -> Result<Zone> {
+        let zone_origin = dns_name::normalize(origin);
+        let mut soa: Option<DnsRecord> = None;
+        let mut records: HashMap<String, Vec<DnsRecord>> = HashMap::new();
+
+        for record in received {
+            if let DnsRecord::UNKNOWN { .. } | DnsRecord::OPT { .. } = record {
+                return Err(DnsError::InvalidZone(format!("{}: unsupported record type {}", display(&zone_origin), record.query_type().to_u16())));
+            }
+            add(&mut records, &mut soa, &zone_origin, record).map_err(|e| DnsError::InvalidZone(format!("{}: {}", display(&zone_origin), e)))?;
+        }
+
+        let soa = soa.ok_or_else(|| DnsError::InvalidZone(format!("no SOA record for {}", display(&zone_origin))))?;
+
+        Ok(Zone {
+            names: names(&records, &zone_origin),
+            origin: zone_origin,
+            soa: soa,
+            path: None,
+            records: records,
+            expired: AtomicBool::new(false),
+            journal: Vec::new(),
+        })
+    }
+
+    /// A zone without any records yet, expired until its first transfer replaces it
+    pub fn empty(origin: &str) -> Zone {
+        let origin = dns_name::normalize(origin);
+        let soa = DnsRecord::SOA {
+            domain: origin.clone(),
+            m_name: origin.clone(),
+            r_name: origin.clone(),
+            serial: 0,
+            refresh: 0,
+            retry: 0,
+            expire: 0,
+            minimum: 0,
+            ttl: 0,
+        };
+
+        Zone {
+            origin: origin,
+            soa: soa,
+            path: None,
+            records: HashMap::new(),
+            names: HashSet::new(),
+            expired: AtomicBool::new(true),
+            journal: Vec::new(),
+        }
+    }
+
+    /// Serial of the zone's SOA
+    pub fn serial(&self) -> u32 {
+        soa_serial(&self.soa)
+    }
+
+    /// Whether the zone has expired, answering SERVFAIL and refusing transfers until it's refreshed
+    pub fn is_expired(&self) -> bool {
+        self.expired.load(Ordering::Relaxed)
+    }
+
+    /// Expire the zone
+    pub fn expire(&self) {
+        self.expired.store(true, Ordering::Relaxed);
+    }
+
+    /// The changes from a serial up to this version, None if the journal doesn't reach back to it
+    pub fn changes_since(&self, serial: u32) -> Option<&[Delta]> {
+        let start = self.journal.iter().position(|x| soa_serial(&x.from) == serial)?;
+
+        Some(&self.journal[start..])
+    }
+
+    /// A copy of the zone with changes applied in order, all of them or none
+    /// Each has to start from the serial the one before it left, and delete only records the zone has
+    pub fn apply(&self, deltas: &[Delta]) -> Result<Zone> {
+        let mut serial = self.serial();
+        let mut soa = self.soa.clone();
+        let mut records: Vec<DnsRecord> = self.records.values()
+            .flatten()
+            .filter(|x| !matches!(x, DnsRecord::SOA { .. }))
+            .cloned()
+            .collect();
+
+        for delta in deltas {
+            if soa_serial(&delta.from) != serial {
+                return Err(DnsError::InvalidChange(format!("changes from serial {} don't apply to serial {}", soa_serial(&delta.from), serial)));
+            }
+            for record in delta.deleted.iter().filter(|x| !matches!(x, DnsRecord::SOA { .. })) {
+                let i = records.iter().position(|x| x == record)
+                    .ok_or_else(|| DnsError::InvalidChange(format!("serial {} deletes {} {}, which the zone doesn't have", soa_serial(&delta.to), record.domain(), record.query_type())))?;
+                records.swap_remove(i);
+            }
+            records.extend(delta.added.iter().filter(|x| !matches!(x, DnsRecord::SOA { .. })).cloned());
+
+            serial = soa_serial(&delta.to);
+            soa = delta.to.clone();
+        }
+
+        records.push(soa);
+        let mut zone = Zone::from_records(&self.origin, records)?;
+        zone.path = self.path.clone();
+
+        Ok(zone)
+    }
+
+    /// The journal of a newer version of the zone: this version's with the changes between the two,
+    /// empty if the other's serial isn't newer or this one has no records to compare with
+    fn journal_to(&self, newer: &Zone) -> Vec<Delta> {
+        if self.records.is_empty() || !xfr::serial_newer(newer.serial(), self.serial()) {
+            return Vec::new();
+        }
+
+        let mut deleted = Vec::new();
+        let mut added = Vec::new();
+        for (owner, records) in &self.records {
+            let others = newer.records.get(owner).map_or(&[][..], |x| x.as_slice());
+            deleted.extend(records.iter().filter(|x| !matches!(x, DnsRecord::SOA { .. }) && !others.contains(x)).cloned());
+        }
+        for (owner, records) in &newer.records {
+            let others = self.records.get(owner).map_or(&[][..], |x| x.as_slice());
+            added.extend(records.iter().filter(|x| !matches!(x, DnsRecord::SOA { .. }) && !others.contains(x)).cloned());
+        }
+
+        let mut journal = self.journal.clone();
+        journal.push(Delta {
+            from: self.soa.clone(),
+            to: newer.soa.clone(),
+            deleted: deleted,
+            added: added,
+        });
+
+        // the oldest changes go first, and a change too big to keep at all takes the whole journal with it
+        let mut size: usize = journal.iter().map(|x| x.deleted.len() + x.added.len()).sum();
+        while size > JOURNAL_SIZE && !journal.is_empty() {
+            let oldest = journal.remove(0);
+            size -= oldest.deleted.len() + oldest.added.len();
+        }
+
+        journal
+    }
+
+    /// The zone as a master file, one record per line with absolute names, that load reads back
+    pub fn to_master(&self) -> String {
+        let mut records = self.transfer();
+        // the SOA once, at the top
+        records.pop();
+
+        records.iter()
+            .map(|x| master_line(x) + "\n")
+            .collect()
+    }
+
+    /// Every record of the zone, the SOA among them
+    pub fn records(&self) -> impl Iterator<Item = &DnsRecord> {
+        self.records.values().flatten()
+    }
+
+    /// How many records the zone holds
+    pub fn record_count(&self) -> usize {
+        self.records.values().map(|x| x.len()).sum()
+    }
+
+    /// Every record in the order a transfer sends them, the SOA first and last and the rest by owner and type
+    pub fn transfer(&self) -> Vec<DnsRecord> {
+        let mut records: Vec<DnsRecord> = self.records.values()
+            .flatten()
+            .filter(|x| !matches!(x, DnsRecord::SOA { .. }))
+            .cloned()
+            .collect();
+        records.sort_by_cached_key(|x| (dns_name::normalize(x.domain()), x.query_type().to_u16()));
+
+        records.insert(0, self.soa.clone());
+        records.push(self.soa.clone());
+
+        records
+    }
+
+    /// Answer a question for a name inside the zone, with AA set, or SERVFAIL if the zone has expired
+    pub fn answer(&self, qname: &str, q_type: QueryType) -> DnsPacket {
+        let mut res = DnsPacket::new();
+        if self.is_expired() {
+            res.header.res_code = ResCode::SERV_FAIL;
+            res.extended_errors.push(Ede::new(ede::OTHER, "the zone has expired"));
+            return res;
+        }
+        res.header.authoritative = true;
+        res.header.res_code = ResCode::NO_ERR;
+
+        let mut name = dns_name::normalize(qname);
+        let mut seen = vec![name.clone()];
+        for _ in 0..=MAX_CNAME_CHAIN {
+            let synthesized: Vec<DnsRecord>;
+            let records = match self.records.get(&name) {
+                Some(x) => x,
+                // a name with names below it exists even without records of its own
+                None if self.names.contains(&name) => {
+                    res.authorities.push(self.negative_soa());
+                    break;
+                }
+                None => match self.wildcard(&name) {
+                    Some(x) => {
+                        synthesized = x.iter().map(|record| with_domain(record, &name)).collect();
+                        &synthesized
+                    }
+                    None => {
+                        res.header.res_code = ResCode::NX_DOMAIN;
+                        res.authorities.push(self.negative_soa());
+                        break;
+                    }
+                },
+            };
+
+            let matching: Vec<&DnsRecord> = records.iter()
+                .filter(|x| q_type == ANY || x.query_type() == q_type)
+                .collect();
+            if !matching.is_empty() {
+                res.answers.extend(matching.into_iter().cloned());
+                break;
+            }
+
+            match records.iter().find(|x| matches!(x, DnsRecord::CNAME { .. })) {
+                Some(cname @ DnsRecord::CNAME { host, .. }) => {
+                    res.answers.push(cname.clone());
+                    name = dns_name::normalize(host);
+
+                    // the rest of the chain is up to the client, and a loop ends where it comes around
+                    if !dns_name::is_subdomain(&name, &self.origin) || seen.contains(&name) {
+                        break;
+                    }
+                    seen.push(name.clone());
+                }
+                _ => {
+                    res.authorities.push(self.negative_soa());
+                    break;
+                }
+            }
+        }
+
+        res
+    }
+
+    /// The SOA sent with NXDOMAIN and NODATA answers, its TTL capped at its minimum field
+    /// so both bound how long the answer is cached, RFC 2308 section 3
+    fn negative_soa(&self) -> DnsRecord {
+        let mut soa = self.soa.clone();
+        if let DnsRecord::SOA { ttl, minimum, .. } = &mut soa {
+            *ttl = (*ttl).min(*minimum);
+        }
+
+        soa
+    }
+
+    /// The records of the wildcard covering a normalized name that doesn't exist, RFC 4592 section 3.3.1
+    /// Only the * child of the closest encloser, the nearest ancestor that exists, may cover it,
+    /// so a wildcard never reaches below a name that exists or up to its own parent
+    fn wildcard(&self, name: &str) -> Option<&Vec<DnsRecord>> {
+        let mut encloser = name;
+        loop {
+            encloser = encloser.split_once('.').map_or("", |(_, x)| x);
+            if !dns_name::is_subdomain(encloser, &self.origin) {
+                return None;
+            }
+
+            if self.names.contains(encloser) {
+                return match encloser {
+                    "" => self.records.get("*"),
+                    x => self.records.get(&format!("*.{}", x)),
+                };
+            }
+        }
+    }
+}
+
+/// Read the records of master file text with absolute owners, each with the line it starts on
+/// Names not ending in a dot are relative to the current origin, @ is the origin itself
+/// A record without a TTL takes $TTL's, or the last TTL given before it
+pub fn read_records(origin: &str, text: &str, path: &str) -> Result<Vec<(usize, DnsRecord)>> {
+    let mut origin = dns_name::normalize(origin);
+    let mut default_ttl: Option<u32> = None;
+    let mut last_ttl: Option<u32> = None;
+    let mut owner: Option<String> = None;
+    let mut records = Vec::new();
+
+    for entry in entries(text).map_err(|(line, e)| DnsError::InvalidZone(format!("{} line {}: {}", path, line, e)))? {
+        let at = |e: String| -> DnsError { DnsError::InvalidZone(format!("{} line {}: {}", path, entry.line, e)) };
+        let mut fields = entry.tokens.iter();
+
+        if !entry.inherits_owner {
+            let first = match fields.next() {
+                Some(Token::Word(x)) => x,
+                _ => return Err(at("expected an owner name or a directive".to_string())),
+            };
+
+            match first.to_ascii_uppercase().as_str() {
+                "$ORIGIN" => {
+                    origin = match (fields.next(), fields.next()) {
+                        (Some(Token::Word(x)), None) => absolute(x, &origin).map_err(at)?,
+                        _ => return Err(at("$ORIGIN expects a name".to_string())),
+                    };
+                    continue;
+                }
+                "$TTL" => {
+                    default_ttl = match (fields.next(), fields.next()) {
+                        (Some(Token::Word(x)), None) => Some(parse_ttl(x).ok_or_else(|| at(format!("invalid TTL {}", x)))?),
+                        _ => return Err(at("$TTL expects a TTL".to_string())),
+                    };
+                    continue;
+                }
+                x if x.starts_with('$') => return Err(at(format!("{} isn't supported", first))),
+                _ => owner = Some(absolute(first, &origin).map_err(at)?),
+            }
+        }
+
+        let owner = owner.clone().ok_or_else(|| at("no owner name before this record".to_string()))?;
+
+        // the TTL and the class come before the type, in either order
+        let mut ttl = None;
+        let q_type = loop {
+            let word = match fields.next() {
+                Some(Token::Word(x)) => x,
+                _ => return Err(at("expected a record type".to_string())),
+            };
+
+            if let Some(x) = parse_ttl(word) {
+                ttl = Some(x);
+                last_ttl = Some(x);
+                continue;
+            }
+            match word.to_ascii_uppercase().as_str() {
+                "IN" => continue,
+                "CH" | "HS" | "CS" => return Err(at(format!("class {} isn't supported, only IN", word))),
+                _ => break word,
+            }
+        };
+
+        let ttl = ttl.or(default_ttl).or(last_ttl)
+            .ok_or_else(|| at("no TTL given and no $TTL before it".to_string()))?;
+        let rdata: Vec<&Token> = fields.collect();
+
+        records.push((entry.line, record(&owner, q_type, ttl, &rdata, &origin).map_err(at)?));
+    }
+
+    Ok(records)
+}
+
+/// A record as one line of a master file, with absolute names
+pub fn master_line(record: &DnsRecord) -> String {
+    format!("{} {} IN {} {}", json::fqdn(record.domain()), record.ttl(), record.query_type(), record.rdata_string())
+}
+
+/// Split a run of changes, each an SOA, the records it deletes, the SOA after and the records it adds,
+/// None if the last one is cut short
+pub fn split_deltas(records: &[DnsRecord]) -> Option<Vec<Delta>> {
+    let is_soa = |x: &DnsRecord| matches!(x, DnsRecord::SOA { .. });
+
+    let mut deltas = Vec::new();
+    let mut rest = records.iter().peekable();
+    while let Some(from) = rest.next() {
+        if !is_soa(from) {
+            return None;
+        }
+        let deleted: Vec<DnsRecord> = std::iter::from_fn(|| rest.next_if(|x| !is_soa(x))).cloned().collect();
+        let to = rest.next()?;
+        let added: Vec<DnsRecord> = std::iter::from_fn(|| rest.next_if(|x| !is_soa(x))).cloned().collect();
+
+        deltas.push(Delta {
+            from: from.clone(),
+            to: to.clone(),
+            deleted: deleted,
+            added: added,
+        });
+    }
+
+    Some(deltas)
+}
+
+/// The names that exist in a zone, each owner and every name between it and the origin,
+/// so an empty non-terminal is found without going through the owners
+fn names(records: &HashMap<String, Vec<DnsRecord>>, zone_origin: &str) -> HashSet<String> {
+    let mut names = HashSet::new();
+    for owner in records.keys() {
+        let mut name = owner.as_str();
+        // the ancestors of one owner already in the set were added with it
+        while names.insert(name.to_string()) && name != zone_origin {
+            name = name.split_once('.').map_or("", |(_, x)| x);
+        }
+    }
+
+    names
+}
+
+/// Add a record to a zone's records by owner, the only SOA at the origin and a CNAME alone at its name
+fn add(records: &mut HashMap<String, Vec<DnsRecord>>, soa: &mut Option<DnsRecord>, zone_origin: &str, record: DnsRecord) -> std::result::Result<(), String> {
+    let owner = record.domain();
+    if !dns_name::is_subdomain(owner, zone_origin) {
+        return Err(format!("{} is outside the zone {}", owner, display(zone_origin)));
+    }
+
+    let key = dns_name::normalize(owner);
+    let existing = records.entry(key.clone()).or_default();
+    match record {
+        DnsRecord::SOA { .. } if key != zone_origin => {
+            return Err(format!("SOA record for {} instead of the zone's origin {}", owner, display(zone_origin)));
+        }
+        DnsRecord::SOA { .. } if soa.is_some() => return Err("a second SOA record".to_string()),
+        DnsRecord::SOA { .. } => *soa = Some(record.clone()),
+        DnsRecord::CNAME { .. } if !existing.is_empty() => {
+            return Err(format!("{} has a CNAME along with other records", owner));
+        }
+        _ if existing.iter().any(|x| matches!(x, DnsRecord::CNAME { .. })) => {
+            return Err(format!("{} has a CNAME along with other records", owner));
+        }
+        _ => (),
+    }
+    existing.push(record);
+
+    Ok(())
+}
+
+/// A copy of a record owned by another name, for answers synthesized from a wildcard
+fn with_domain(record: &DnsRecord, name: &str) -> DnsRecord {
+    let mut record = record.clone();
+    match &mut record {
+        DnsRecord::UNKNOWN { domain, .. }
+        | DnsRecord::A { domain, .. }
+        | DnsRecord::NS { domain, .. }
+        | DnsRecord::CNAME { domain, .. }
+        | DnsRecord::SOA { domain, .. }
+        | DnsRecord::PTR { domain, .. }
+        | DnsRecord::MX { domain, .. }
+        | DnsRecord::TXT { domain, .. }
+        | DnsRecord::AAAA { domain, .. }
+        | DnsRecord::SRV { domain, .. } => *domain = name.to_string(),
+        // owned by the root, never in a zone
+        DnsRecord::OPT { .. } => (),
+        // answered from the server itself, never in a zone
+        DnsRecord::CHAOS_TXT { .. } => (),
+    }
+
+    record
+}
+
+/// Build a record of a type from its data fields
+fn record(owner: &str, q_type: &str, ttl: u32, rdata: &[&Token], origin: &str) -> std::result::Result<DnsRecord, String> {
+    let words: Vec<&str> = rdata.iter()
+        .map(|x| match x {
+            Token::Word(x) | Token::Quoted(x) => x.as_str(),
+        })
+        .collect();
+    let q_type = q_type.to_ascii_uppercase();
+
+    let expected = match q_type.as_str() {
+        "A" | "AAAA" | "NS" | "CNAME" | "PTR" => 1,
+        "MX" => 2,
+        "SRV" => 4,
+        "SOA" => 7,
+        "TXT" => words.len().max(1),
+        _ => return Err(format!("unsupported record type {}", q_type)),
+    };
+    if words.len() != expected {
+        return Err(format!("wrong number of fields for {}: expected {}, found {}", q_type, expected, words.len()));
+    }
+
+    let domain = owner.to_string();
+    let name = |x: &str| absolute(x, origin);
+    let number = |x: &str| x.parse::<u16>().map_err(|_| format!("invalid number {}", x));
+    let time = |x: &str| parse_ttl(x).ok_or_else(|| format!("invalid time {}", x));
+
+    let record = match q_type.as_str() {
+        "A" => DnsRecord::A {
+            domain: domain,
+            addr_v4: words[0].parse::<Ipv4Addr>().map_err(|_| format!("invalid IPv4 address {}", words[0]))?,
+            ttl: ttl,
+        },
+        "AAAA" => DnsRecord::AAAA {
+            domain: domain,
+            addr: words[0].parse::<Ipv6Addr>().map_err(|_| format!("invalid IPv6 address {}", words[0]))?,
+            ttl: ttl,
+        },
+        "NS" => DnsRecord::NS { domain: domain, host: name(words[0])?, ttl: ttl },
+        "CNAME" => DnsRecord::CNAME { domain: domain, host: name(words[0])?, ttl: ttl },
+        "PTR" => DnsRecord::PTR { domain: domain, host: name(words[0])?, ttl: ttl },
+        "MX" => DnsRecord::MX {
+            domain: domain,
+            priority: number(words[0])?,
+            host: name(words[1])?,
+            ttl: ttl,
+        },
+        "SRV" => DnsRecord::SRV {
+            domain: domain,
+            priority: number(words[0])?,
+            weight: number(words[1])?,
+            port: number(words[2])?,
+            host: name(words[3])?,
+            ttl: ttl,
+        },
+        "SOA" => DnsRecord::SOA {
+            domain: domain,
+            m_name: name(words[0])?,
+            r_name: name(words[1])?,
+            serial: words[2].parse::<u32>().map_err(|_| format!("invalid serial {}", words[2]))?,
+            refresh: time(words[3])?,
+            retry: time(words[4])?,
+            expire: time(words[5])?,
+            minimum: time(words[6])?,
+            ttl: ttl,
+        },
+        _ => DnsRecord::TXT {
+            domain: domain,
+            data: words.iter().map(|x| unescape(x)).collect(),
+            ttl: ttl,
+        },
+    };
+
+    Ok(record)
+}
+
+/// Split master file text into entries, lines inside parentheses joining the entry they continue
+/// Comments run from ; to the end of the line, outside quotes
+fn entries(text: &str) -> std::result::Result<Vec<Entry>, (usize, String)> {
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut depth = 0;
+    let mut opened = 0; // line of the outermost open parenthesis
+
+    for (i, line) in text.lines().enumerate() {
+        let continued = depth > 0;
+        let mut tokens = Vec::new();
+        let mut chars = line.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                ';' => break,
+                '(' => {
+                    if depth == 0 {
+                        opened = i + 1;
+                    }
+                    depth += 1;
+                }
+                ')' if depth == 0 => return Err((i + 1, "unbalanced )".to_string())),
+                ')' => depth -= 1,
+                '"' => {
+                    let mut text = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some('\\') => {
+                                text.push('\\');
+                                text.extend(chars.next());
+                            }
+                            Some(x) => text.push(x),
+                            None => return Err((i + 1, "unterminated quoted string".to_string())),
+                        }
+                    }
+                    tokens.push(Token::Quoted(text));
+                }
+                c if c.is_whitespace() => (),
+                c => {
+                    let mut word = c.to_string();
+                    if c == '\\' {
+                        word.extend(chars.next());
+                    }
+                    while let Some(&x) = chars.peek() {
+                        if x.is_whitespace() || matches!(x, ';' | '(' | ')' | '"') {
+                            break;
+                        }
+                        chars.next();
+                        word.push(x);
+                        if x == '\\' {
+                            word.extend(chars.next());
+                        }
+                    }
+                    tokens.push(Token::Word(word));
+                }
+            }
+        }
+
+        match entries.last_mut() {
+            Some(entry) if continued => entry.tokens.extend(tokens),
+            _ if tokens.is_empty() => (),
+            _ => entries.push(Entry {
+                line: i + 1,
+                inherits_owner: line.starts_with(|x: char| x.is_whitespace()),
+                tokens: tokens,
+            }),
+        }
+    }
+
+    if depth > 0 {
+        return Err((opened, "unbalanced (".to_string()));
+    }
+
+    Ok(entries)
+}
+
+/// A name from a master file made absolute, without the trailing dot
+/// Relative names get the origin appended and @ stands for the origin
+fn absolute(name: &str, origin: &str) -> std::result::Result<String, String> {
+    let name = match name {
+        "@" => origin.to_string(),
+        x if x.ends_with('.') && !x.ends_with("\\.") => x[..x.len() - 1].to_string(),
+        x if origin.is_empty() => x.to_string(),
+        x => format!("{}.{}", x, origin),
+    };
+
+    dns_name::validate(&name).map_err(|e| format!("invalid name {}: {}", name, e))?;
+
+    Ok(name)
+}
+
+/// A TTL or other time in seconds, ex. 3600, or with units as BIND takes them, ex. 1h30m
+fn parse_ttl(text: &str) -> Option<u32> {
+    if !text.starts_with(|x: char| x.is_ascii_digit()) {
+        return None;
+    }
+    if let Ok(x) = text.parse::<u32>() {
+        return Some(x);
+    }
+
+    let mut total: u32 = 0;
+    let mut value: u32 = 0;
+    let mut digits = false;
+    for c in text.chars() {
+        if let Some(x) = c.to_digit(10) {
+            value = value.checked_mul(10)?.checked_add(x)?;
+            digits = true;
+            continue;
+        }
+
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => return None,
+        };
+        if !digits {
+            return None;
+        }
+        total = total.checked_add(value.checked_mul(unit)?)?;
+        value = 0;
+        digits = false;
+    }
+
+    match digits {
+        true => None,
+        false => Some(total),
+    }
+}
+
+/// Text of a character string with its \X and \DDD escapes decoded
+fn unescape(text: &str) -> String {
+    let mut out = Vec::with_capacity(text.len());
+    let bytes = text.as_bytes();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] != b'\\' || i + 1 == bytes.len() {
+            out.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+
+        // \DDD only when all three are digits, \"a is just a quote
+        let digits = &bytes[i + 1..(i + 4).min(bytes.len())];
+        let value = Some(digits)
+            .filter(|x| x.len() == 3 && x.iter().all(u8::is_ascii_digit))
+            .map(|x| x.iter().fold(0u32, |value, digit| value * 10 + (digit - b'0') as u32))
+            .filter(|x| *x <= 0xFF);
+        if let Some(value) = value {
+            out.push(value as u8);
+            i += 4;
+        } else {
+            out.push(bytes[i + 1]);
+            i += 2;
+        }
+    }
+
+    String::from_utf8_lossy(&out).to_string()
+}
+
+/// A zone's origin for messages, the root as "."
+fn display(origin: &str) -> &str {
+    match origin {
+        "" => ".",
+        x => x,
+    }
+}
+
+/// Serial of an SOA record, 0 for any other record
+pub fn soa_serial(record: &DnsRecord) -> u32 {
+    match *record {
+        DnsRecord::SOA { serial, .. } => serial,
+        _ => 0,
+    }
+}
+
+fn label_count(domain: &str) -> usize {
+    domain.split('.').filter(|x| !x.is_empty()).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{ IpAddr, Ipv4Addr };
+
+    use crate::packet::DnsQuestion;
+    use crate::resolve::{ self, Resolution };
+    use crate::transport::Transport;
+    use crate::upstreams::{ Strategy, Upstream, Upstreams };
+
+    const ZONE: &str = "\
+$TTL 300
+@                 SOA  ns.home.lan. hostmaster.home.lan. 1 3600 600 86400 60
+@                 NS   ns
+ns                A    10.0.0.1
+*.dev             A    10.0.0.5
+*.dev             MX   10 mail
+exact.dev         A    10.0.0.6
+host.sub.dev      A    10.0.0.7
+";
+
+    fn zone() -> Zone {
+        Zone::parse("home.lan", ZONE, "home.lan.zone").unwrap()
+    }
+
+    fn addrs(res: &DnsPacket) -> Vec<String> {
+        res.a_records().map(|(domain, addr, _)| format!("{} {}", domain, addr)).collect()
+    }
+
+    #[test]
+    fn wildcard_answers_with_the_query_name() {
+        let res = zone().answer("Any.dev.home.lan", QueryType::A);
+        assert_eq!(res.header.res_code, ResCode::NO_ERR);
+        assert!(res.header.authoritative);
+        assert_eq!(addrs(&res), ["any.dev.home.lan 10.0.0.5"]);
+
+        // a type the wildcard doesn't have is NODATA
+        let res = zone().answer("any.dev.home.lan", QueryType::TXT);
+        assert_eq!(res.header.res_code, ResCode::NO_ERR);
+        assert!(res.answers.is_empty());
+        assert!(matches!(&res.authorities[..], [DnsRecord::SOA { .. }]));
+    }
+
+    #[test]
+    fn exact_match_shadows_the_wildcard() {
+        assert_eq!(addrs(&zone().answer("exact.dev.home.lan", QueryType::A)), ["exact.dev.home.lan 10.0.0.6"]);
+
+        // the exact name has no MX, the wildcard's doesn't stand in for it
+        let res = zone().answer("exact.dev.home.lan", QueryType::MX);
+        assert_eq!(res.header.res_code, ResCode::NO_ERR);
+        assert!(res.answers.is_empty());
+    }
+
+    #[test]
+    fn wildcard_covers_several_labels() {
+        assert_eq!(addrs(&zone().answer("a.b.dev.home.lan", QueryType::A)), ["a.b.dev.home.lan 10.0.0.5"]);
+    }
+
+    #[test]
+    fn empty_non_terminal_isnt_covered() {
+        // sub.dev exists only because host.sub.dev does
+        let res = zone().answer("sub.dev.home.lan", QueryType::A);
+        assert_eq!(res.header.res_code, ResCode::NO_ERR);
+        assert!(res.answers.is_empty());
+
+        // and names below it have it as their closest encloser, which has no wildcard
+        let res = zone().answer("other.sub.dev.home.lan", QueryType::A);
+        assert_eq!(res.header.res_code, ResCode::NX_DOMAIN);
+    }
+
+    #[test]
+    fn wildcard_doesnt_cover_its_parent() {
+        let res = zone().answer("dev.home.lan", QueryType::A);
+        assert_eq!(res.header.res_code, ResCode::NO_ERR);
+        assert!(res.answers.is_empty());
+
+        assert_eq!(zone().answer("nothing.home.lan", QueryType::A).header.res_code, ResCode::NX_DOMAIN);
+    }
+
+    #[test]
+    fn master_file_round_trips() {
+        let text = "\
+$ORIGIN example.com.
+$TTL 3600
+@        IN SOA  ns1 hostmaster ( 2024010101 7200 900 1209600 300 ) ; serial, refresh, retry, expire, minimum
+@           NS   ns1
+@           NS   ns2.example.net.
+@           MX   10 mail
+@           TXT  \"v=spf1 mx -all\" \"a second \\\"string\\\"\"
+ns1      60 A    192.0.2.53
+mail        A    192.0.2.25
+mail        AAAA 2001:db8::25
+www         CNAME @
+_sip._udp   SRV  10 60 5060 sip
+sip         A    192.0.2.60
+$ORIGIN reverse.example.com.
+25          PTR  mail.example.com.
+";
+        let zone = Zone::parse("example.com", text, "example.com.zone").unwrap();
+        let master = zone.to_master();
+        let again = Zone::parse("example.com", &master, "written").unwrap();
+
+        assert_eq!(again.transfer(), zone.transfer());
+        assert_eq!(again.record_count(), 12);
+        let txt = again.records().find_map(|x| match x {
+            DnsRecord::TXT { data, .. } => Some(data.clone()),
+            _ => None,
+        });
+        assert_eq!(txt.unwrap(), ["v=spf1 mx -all", "a second \"string\""]);
+        // and written the same way a second time
+        assert_eq!(again.to_master(), master);
+    }
+
+    #[test]
+    fn loaded_zone_answers_queries_authoritatively() {
+        let mut text = String::from("\
+$TTL 300
+@            SOA   ns1 hostmaster 2024060101 3600 600 86400 60
+@            NS    ns1
+@            NS    ns2
+@            MX    10 mail
+@            TXT   \"v=spf1 mx -all\"
+ns1          A     192.0.2.1
+ns2          A     192.0.2.2
+mail         A     192.0.2.25
+mail         AAAA  2001:db8::25
+www          CNAME web
+web          A     192.0.2.80
+web          AAAA  2001:db8::80
+");
+        for i in 1..=18 {
+            text.push_str(&format!("host{}       A     198.51.100.{}\n", i, i));
+        }
+        let path = std::env::temp_dir().join(format!("pine-dns-zone-{}.zone", std::process::id()));
+        fs::write(&path, &text).unwrap();
+        let zone = Zone::load("office.example", &path.to_string_lossy()).unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!(zone.record_count(), 30);
+
+        // installed alongside the zones of other tests rather than in place of them
+        replace(zone);
+
+        // an upstream that isn't there, the zone's names never reach it
+        let upstreams = Upstreams::new(vec![Upstream::udp("192.0.2.53:53".parse().unwrap())], Strategy::SEQUENTIAL);
+        let resolution = Resolution::Forward(Box::leak(Box::new(upstreams)));
+        let ask = |name: &str, q_type: QueryType| {
+            let mut query = DnsPacket::new();
+            query.questions.push(DnsQuestion::new(name.to_string(), q_type));
+            let res = resolve::handle_query_bytes(&query.to_bytes().unwrap(), IpAddr::V4(Ipv4Addr::LOCALHOST), &resolution, Transport::UDP).unwrap();
+            DnsPacket::from_bytes(&res).unwrap()
+        };
+        let is_soa = |res: &DnsPacket| matches!(&res.authorities[..], [DnsRecord::SOA { domain, .. }] if domain == "office.example");
+
+        let res = ask("host7.office.example", QueryType::A);
+        assert_eq!(res.header.res_code, ResCode::NO_ERR);
+        assert!(res.header.authoritative);
+        assert_eq!(addrs(&res), ["host7.office.example 198.51.100.7"]);
+
+        // the CNAME and the record it leads to in the zone
+        let res = ask("www.office.example", QueryType::A);
+        assert!(res.header.authoritative);
+        assert_eq!(addrs(&res), ["web.office.example 192.0.2.80"]);
+
+        let res = ask("nothing.office.example", QueryType::A);
+        assert_eq!(res.header.res_code, ResCode::NX_DOMAIN);
+        assert!(res.header.authoritative);
+        assert!(res.answers.is_empty());
+        assert!(is_soa(&res));
+
+        // mail has addresses but no MX, NODATA
+        let res = ask("mail.office.example", QueryType::MX);
+        assert_eq!(res.header.res_code, ResCode::NO_ERR);
+        assert!(res.header.authoritative);
+        assert!(res.answers.is_empty());
+        assert!(is_soa(&res));
+    }
+}